@@ -21,9 +21,45 @@ pub struct Cli {
     /// Start a agent
     #[clap(short = 'a', long)]
     pub agent: Option<String>,
-    /// Start a RAG
+    /// Start a RAG, or several as name1,name2
     #[clap(short = 'R', long)]
     pub rag: Option<String>,
+    /// Restrict RAG search by metadata, e.g. path=docs/api/**
+    #[clap(long, value_name = "FILTER")]
+    pub rag_filter: Option<String>,
+    /// Search the RAG and print the hits as JSON, without asking the LLM
+    #[clap(long, value_name = "QUERY", requires = "rag")]
+    pub rag_search: Option<String>,
+    /// Re-embed the RAG with another embedding model
+    #[clap(long, value_name = "MODEL", requires = "rag")]
+    pub reembed: Option<String>,
+    /// Renumber the RAG documents to reclaim space after removals
+    #[clap(long, requires = "rag")]
+    pub compact_rag: bool,
+    /// Export the chunks and embeddings of the RAG as JSONL
+    #[clap(long, value_name = "FILE", requires = "rag")]
+    pub export_rag: Option<String>,
+    /// Leave the embeddings out of the exported RAG
+    #[clap(long, requires = "export_rag")]
+    pub no_vectors: bool,
+    /// Create the RAG from a JSONL export, without embedding it again
+    #[clap(long, value_name = "FILE", requires = "rag")]
+    pub import_rag: Option<String>,
+    /// Keep the RAG up to date as its files change, until Ctrl+C
+    #[clap(long, requires = "rag")]
+    pub watch: bool,
+    /// Stop adding RAG documents at the first file that fails to load
+    #[clap(long)]
+    pub strict: bool,
+    /// Don't reuse or store cached RAG embeddings
+    #[clap(long)]
+    pub no_embedding_cache: bool,
+    /// Don't reuse or store cached chat responses
+    #[clap(long)]
+    pub no_cache: bool,
+    /// Ask the LLM to reply with a JSON object, failing if it doesn't (OpenAI-compatible, Ollama and Gemini models)
+    #[clap(long)]
+    pub json: bool,
     /// Serve the LLM API and WebAPP
     #[clap(long, value_name = "ADDRESS")]
     pub serve: Option<Option<String>>,
@@ -48,7 +84,7 @@ pub struct Cli {
     /// Use light theme
     #[clap(long)]
     pub light_theme: bool,
-    /// Display the message without sending it
+    /// Display the message without sending it, or count what a new rag would embed
     #[clap(long)]
     pub dry_run: bool,
     /// Display information
@@ -69,6 +105,15 @@ pub struct Cli {
     /// List all RAGs
     #[clap(long)]
     pub list_rags: bool,
+    /// List all RAGs with their size, embedding model, files and last modification
+    #[clap(long)]
+    pub rags_info: bool,
+    /// Delete a RAG
+    #[clap(long, value_name = "NAME")]
+    pub delete_rag: Option<String>,
+    /// Rename a RAG
+    #[clap(long, num_args = 2, value_names = ["NAME", "NEW_NAME"])]
+    pub rename_rag: Option<Vec<String>>,
     /// Input text
     #[clap(trailing_var_arg = true)]
     text: Vec<String>,
@@ -1,15 +1,18 @@
 use super::openai::*;
 use super::*;
 
-use anyhow::Result;
-use reqwest::{Client as ReqwestClient, RequestBuilder};
+use anyhow::{bail, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder, Url};
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Deserialize)]
+const API_VERSION: &str = "2024-02-01";
+
+#[derive(Debug, Clone, Deserialize, Default)]
 pub struct AzureOpenAIConfig {
     pub name: Option<String>,
     pub api_base: Option<String>,
     pub api_key: Option<String>,
+    pub api_version: Option<String>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patches: Option<ModelPatches>,
@@ -32,23 +35,43 @@ impl AzureOpenAIClient {
         ),
     ];
 
+    /// The url of `endpoint` on the deployment of the model, e.g.
+    /// `{api_base}/openai/deployments/{deployment_id}/chat/completions?api-version=...`.
+    fn deployment_url(&self, endpoint: &str) -> Result<String> {
+        let api_base = self.get_api_base()?;
+        let api_base = api_base.trim_end_matches('/');
+        if Url::parse(api_base).is_err() {
+            bail!("Invalid 'api_base' in client configuration, expect a url like https://{{RESOURCE}}.openai.azure.com, got '{api_base}'")
+        }
+        let deployment_id = self
+            .model
+            .data()
+            .deployment_id
+            .as_deref()
+            .unwrap_or_else(|| self.model.name());
+        if deployment_id.trim().is_empty() {
+            bail!(
+                "Miss 'deployment_id' of model '{}' in client configuration",
+                self.model.name()
+            )
+        }
+        let api_version = self.config.api_version.as_deref().unwrap_or(API_VERSION);
+        Ok(format!(
+            "{api_base}/openai/deployments/{deployment_id}/{endpoint}?api-version={api_version}"
+        ))
+    }
+
     fn chat_completions_builder(
         &self,
         client: &ReqwestClient,
         data: ChatCompletionsData,
     ) -> Result<RequestBuilder> {
-        let api_base = self.get_api_base()?;
+        let url = self.deployment_url("chat/completions")?;
         let api_key = self.get_api_key()?;
 
         let mut body = openai_build_chat_completions_body(data, &self.model);
         self.patch_chat_completions_body(&mut body);
 
-        let url = format!(
-            "{}/openai/deployments/{}/chat/completions?api-version=2024-02-01",
-            &api_base,
-            self.model.name()
-        );
-
         debug!("AzureOpenAI Chat Completions Request: {url} {body}");
 
         let builder = client.post(url).header("api-key", api_key).json(&body);
@@ -61,17 +84,11 @@ impl AzureOpenAIClient {
         client: &ReqwestClient,
         data: EmbeddingsData,
     ) -> Result<RequestBuilder> {
-        let api_base = self.get_api_base()?;
+        let url = self.deployment_url("embeddings")?;
         let api_key = self.get_api_key()?;
 
         let body = openai_build_embeddings_body(data, &self.model);
 
-        let url = format!(
-            "{}/openai/deployments/{}/embeddings?api-version=2024-02-01",
-            &api_base,
-            self.model.name()
-        );
-
         debug!("AzureOpenAI Embeddings Request: {url} {body}");
 
         let builder = client.post(url).header("api-key", api_key).json(&body);
@@ -86,3 +103,79 @@ impl_client_trait!(
     openai_chat_completions_streaming,
     openai_embeddings
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    fn mock_client(api_base: &str) -> AzureOpenAIClient {
+        AzureOpenAIClient {
+            global_config: Arc::new(RwLock::new(Config::default())),
+            config: AzureOpenAIConfig {
+                api_base: Some(api_base.into()),
+                api_key: Some("azure-key".into()),
+                ..Default::default()
+            },
+            model: Model::new("azure-openai", "gpt-4o"),
+        }
+    }
+
+    #[test]
+    fn test_azure_openai_request() {
+        let mut client = mock_client("https://res.openai.azure.com/");
+        let data = ChatCompletionsData {
+            messages: vec![Message::new(
+                MessageRole::User,
+                MessageContent::Text("hi".into()),
+            )],
+            temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            functions: None,
+            stream: false,
+            response_format: None,
+        };
+        let request = client
+            .chat_completions_builder(&ReqwestClient::new(), data)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://res.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-02-01"
+        );
+        assert_eq!(request.headers()["api-key"], "azure-key");
+        assert!(request.headers().get("authorization").is_none());
+
+        client.config.api_version = Some("2024-10-21".into());
+        client.model.data_mut().deployment_id = Some("prod-gpt4o".into());
+        let request = client
+            .embeddings_builder(
+                &ReqwestClient::new(),
+                EmbeddingsData::new(vec!["hi".into()], false),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://res.openai.azure.com/openai/deployments/prod-gpt4o/embeddings?api-version=2024-10-21"
+        );
+
+        client.model.data_mut().deployment_id = Some(" ".into());
+        let err = client.deployment_url("embeddings").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Miss 'deployment_id' of model 'gpt-4o' in client configuration"
+        );
+        client.config.api_base = Some("res.openai.azure.com".into());
+        let err = client.deployment_url("embeddings").unwrap_err();
+        assert!(err.to_string().starts_with("Invalid 'api_base'"));
+    }
+}
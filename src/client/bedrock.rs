@@ -240,9 +240,15 @@ fn meta_llama_build_chat_completions_body(
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions: _,
         stream: _,
+        response_format,
     } = data;
+
+    check_response_format(&response_format, model)?;
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
     let prompt = generate_prompt(&messages, pt)?;
     let mut body = json!({ "prompt": prompt });
 
@@ -264,9 +270,15 @@ fn mistral_build_chat_completions_body(data: ChatCompletionsData, model: &Model)
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions: _,
         stream: _,
+        response_format,
     } = data;
+
+    check_response_format(&response_format, model)?;
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
     let prompt = generate_prompt(&messages, MISTRAL_PROMPT_FORMAT)?;
     let mut body = json!({ "prompt": prompt });
 
@@ -137,10 +137,16 @@ pub fn claude_build_chat_completions_body(
         mut messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions,
         stream,
+        response_format,
     } = data;
 
+    check_response_format(&response_format, model)?;
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
+
     let system_message = extract_system_message(&mut messages);
 
     let mut network_image_urls = vec![];
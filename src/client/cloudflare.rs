@@ -115,9 +115,10 @@ async fn chat_completions_streaming(
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
     let res = builder.send().await?;
     let status = res.status();
+    let retry_after = parse_retry_after(res.headers());
     let data: Value = res.json().await?;
     if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
+        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
     }
     let res_body: EmbeddingsResBody =
         serde_json::from_value(data).context("Invalid embeddings data")?;
@@ -139,10 +140,16 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions: _,
         stream,
+        response_format,
     } = data;
 
+    check_response_format(&response_format, model)?;
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
+
     let mut body = json!({
         "model": &model.name(),
         "messages": messages,
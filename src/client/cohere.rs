@@ -149,9 +149,10 @@ async fn chat_completions_streaming(
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
     let res = builder.send().await?;
     let status = res.status();
+    let retry_after = parse_retry_after(res.headers());
     let data: Value = res.json().await?;
     if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
+        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
     }
     let res_body: EmbeddingsResBody =
         serde_json::from_value(data).context("Invalid embeddings data")?;
@@ -168,10 +169,15 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         mut messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions,
         stream,
+        response_format,
     } = data;
 
+    check_response_format(&response_format, model)?;
+
     let system_message = extract_system_message(&mut messages);
 
     let mut image_urls = vec![];
@@ -241,6 +247,12 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
     if let Some(v) = top_p {
         body["p"] = v.into();
     }
+    if let Some(v) = presence_penalty {
+        body["presence_penalty"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["frequency_penalty"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
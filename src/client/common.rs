@@ -1,7 +1,7 @@
 use super::*;
 
 use crate::{
-    config::{GlobalConfig, Input},
+    config::{Config, GlobalConfig, Input},
     function::{eval_tool_calls, FunctionDeclaration, ToolCall, ToolResult},
     render::{render_error, render_stream},
     utils::*,
@@ -12,17 +12,29 @@ use async_trait::async_trait;
 use fancy_regex::Regex;
 use indexmap::IndexMap;
 use lazy_static::lazy_static;
-use reqwest::{Client as ReqwestClient, RequestBuilder};
-use serde::Deserialize;
+use parking_lot::Mutex;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Client as ReqwestClient, RequestBuilder,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{future::Future, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    time::Duration,
+};
 use tokio::sync::mpsc::unbounded_channel;
 
 const MODELS_YAML: &str = include_str!("../../models.yaml");
+const RETRY_BASE_DELAY_MS: u64 = 500;
+const MAX_RETRY_AFTER_SECS: u64 = 300;
 
 lazy_static! {
     pub static ref ALL_MODELS: Vec<BuiltinModels> = serde_yaml::from_str(MODELS_YAML).unwrap();
     static ref ESCAPE_SLASH_RE: Regex = Regex::new(r"(?<!\\)/").unwrap();
+    static ref SECRET_CMD_CACHE: Mutex<HashMap<String, String>> = Default::default();
+    static ref PENALTY_WARNED_MODELS: Mutex<HashSet<String>> = Default::default();
 }
 
 #[macro_export]
@@ -126,21 +138,22 @@ macro_rules! register_client {
             anyhow::bail!("Unknown client '{}'", client)
         }
 
-        static mut ALL_CLIENT_MODELS: Option<Vec<$crate::client::Model>> = None;
+        static ALL_CLIENT_MODELS: std::sync::OnceLock<Vec<$crate::client::Model>> = std::sync::OnceLock::new();
 
         pub fn list_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
-            if unsafe { ALL_CLIENT_MODELS.is_none() } {
-                let models: Vec<_> = config
-                    .clients
-                    .iter()
-                    .flat_map(|v| match v {
-                        $(ClientConfig::$config(c) => $client::list_models(c),)+
-                        ClientConfig::Unknown => vec![],
-                    })
-                    .collect();
-                unsafe { ALL_CLIENT_MODELS = Some(models) };
-            }
-            unsafe { ALL_CLIENT_MODELS.as_ref().unwrap().iter().collect() }
+            ALL_CLIENT_MODELS
+                .get_or_init(|| {
+                    config
+                        .clients
+                        .iter()
+                        .flat_map(|v| match v {
+                            $(ClientConfig::$config(c) => $client::list_models(c),)+
+                            ClientConfig::Unknown => vec![],
+                        })
+                        .collect()
+                })
+                .iter()
+                .collect()
         }
 
         pub fn list_chat_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
@@ -294,21 +307,34 @@ macro_rules! impl_client_trait {
 
 #[macro_export]
 macro_rules! config_get_fn {
+    ($field_name:ident, $fn_name:ident, $cmd_field_name:ident) => {
+        fn $fn_name(&self) -> anyhow::Result<String> {
+            match (&self.config.$field_name, &self.config.$cmd_field_name) {
+                (None, Some(cmd)) => $crate::client::run_secret_cmd(cmd),
+                _ => $crate::config_get_fn!(@get self, $field_name),
+            }
+        }
+    };
     ($field_name:ident, $fn_name:ident) => {
         fn $fn_name(&self) -> anyhow::Result<String> {
-            let api_key = self.config.$field_name.clone();
-            api_key
-                .or_else(|| {
-                    let env_prefix = Self::name(&self.config);
-                    let env_name =
-                        format!("{}_{}", env_prefix, stringify!($field_name)).to_ascii_uppercase();
-                    std::env::var(&env_name).ok()
-                })
-                .ok_or_else(|| {
-                    anyhow::anyhow!("Miss '{}' in client configuration", stringify!($field_name))
-                })
+            $crate::config_get_fn!(@get self, $field_name)
         }
     };
+    (@get $self:ident, $field_name:ident) => {
+        $self
+            .config
+            .$field_name
+            .clone()
+            .or_else(|| {
+                let env_prefix = Self::name(&$self.config);
+                let env_name =
+                    format!("{}_{}", env_prefix, stringify!($field_name)).to_ascii_uppercase();
+                std::env::var(&env_name).ok()
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!("Miss '{}' in client configuration", stringify!($field_name))
+            })
+    };
 }
 
 #[macro_export]
@@ -338,6 +364,10 @@ pub trait Client: Sync + Send {
         let timeout = extra.and_then(|v| v.connect_timeout).unwrap_or(10);
         let proxy = extra.and_then(|v| v.proxy.clone());
         builder = set_proxy(builder, proxy.as_ref())?;
+        // Applies to every read rather than the whole request, as streams stay open for long.
+        if let Some(read_timeout) = self.read_timeout() {
+            builder = builder.read_timeout(read_timeout);
+        }
         let client = builder
             .connect_timeout(Duration::from_secs(timeout))
             .build()
@@ -345,16 +375,66 @@ pub trait Client: Sync + Send {
         Ok(client)
     }
 
-    async fn chat_completions(&self, input: Input) -> Result<ChatCompletionsOutput> {
+    /// How long to wait for the next bytes of a response, `None` to wait forever.
+    fn read_timeout(&self) -> Option<Duration> {
+        let secs = self
+            .extra_config()
+            .and_then(|v| v.timeout_secs)
+            .unwrap_or_else(|| self.global_config().read().timeout_secs);
+        match secs {
+            0 => None,
+            _ => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    async fn chat_completions(
+        &self,
+        input: Input,
+        abort_signal: AbortSignal,
+    ) -> Result<ChatCompletionsOutput> {
         if self.global_config().read().dry_run {
             let content = input.echo_messages();
             return Ok(ChatCompletionsOutput::new(&content));
         }
         let client = self.build_client()?;
         let data = input.prepare_completion_data(self.model(), false)?;
-        self.chat_completions_inner(&client, data)
-            .await
-            .with_context(|| "Failed to call chat-completions api")
+        let cache = ResponseCache::init(&self.global_config().read(), self.model(), &data);
+        if let Some(text) = cache.as_ref().and_then(|v| v.get()) {
+            return Ok(ChatCompletionsOutput::new(&text));
+        }
+        let max_retries = self.global_config().read().chat_completions_retries;
+        let mut retries = 0;
+        // Dropping the request on abort closes its connection.
+        let ret = tokio::select! {
+            ret = async {
+                loop {
+                    match self.chat_completions_inner(&client, data.clone()).await {
+                        Err(err) if retries < max_retries && is_retryable_error(&err) => {
+                            retries += 1;
+                            let delay = retry_delay(&err, retries);
+                            debug!(
+                                "retry chat-completions [{retries}/{max_retries}] in {delay:?}, {err:?}"
+                            );
+                            tokio::time::sleep(delay).await;
+                        }
+                        ret => break ret,
+                    }
+                }
+            } => ret,
+            _ = watch_abort_signal(abort_signal) => bail!("Aborted!"),
+        };
+        match ret {
+            Ok(output) => {
+                if let Some(cache) = &cache {
+                    if output.tool_calls.is_empty() {
+                        cache.put(&output.text);
+                    }
+                }
+                Ok(output)
+            }
+            Err(err) => Err(catch_timeout(err, self.read_timeout()))
+                .with_context(|| "Failed to call chat-completions api"),
+        }
     }
 
     async fn chat_completions_streaming(
@@ -376,24 +456,108 @@ pub trait Client: Sync + Send {
                     return Ok(());
                 }
                 let client = self.build_client()?;
-                let data = input.prepare_completion_data(self.model(), true)?;
-                self.chat_completions_streaming_inner(&client, handler, data).await
+                let streaming = self.model().supports_streaming();
+                let data = input.prepare_completion_data(self.model(), streaming)?;
+                let cache = ResponseCache::init(&self.global_config().read(), self.model(), &data);
+                if let Some(text) = cache.as_ref().and_then(|v| v.get()) {
+                    for token in split_content(&text) {
+                        handler.text(token)?;
+                    }
+                    return Ok(());
+                }
+                let max_retries = self.global_config().read().chat_completions_retries;
+                let mut retries = 0;
+                let ret = loop {
+                    let ret = match streaming {
+                        true => {
+                            self.chat_completions_streaming_inner(&client, handler, data.clone())
+                                .await
+                        }
+                        false => {
+                            self.chat_completions_unstreamed(&client, handler, data.clone())
+                                .await
+                        }
+                    };
+                    let ret = match ret {
+                        Err(err)
+                            if handler.is_empty()
+                                && err.downcast_ref::<NonSseResponse>().is_some() =>
+                        {
+                            debug!("fall back to chat-completions without streaming, {err}");
+                            self.chat_completions_unstreamed(&client, handler, data.clone())
+                                .await
+                        }
+                        ret => ret,
+                    };
+                    match ret {
+                        // Once output reached the handler, a retry would repeat it.
+                        Err(err)
+                            if retries < max_retries
+                                && handler.is_empty()
+                                && is_retryable_error(&err) =>
+                        {
+                            retries += 1;
+                            let delay = retry_delay(&err, retries);
+                            debug!(
+                                "retry chat-completions [{retries}/{max_retries}] in {delay:?}, {err:?}"
+                            );
+                            tokio::time::sleep(delay).await;
+                        }
+                        ret => break ret,
+                    }
+                };
+                if let (Ok(()), Some(cache)) = (&ret, &cache) {
+                    if handler.get_tool_calls().is_empty() {
+                        cache.put(handler.get_buffer());
+                    }
+                }
+                ret
             } => {
                 handler.done()?;
-                ret.with_context(|| "Failed to call chat-completions api")
+                ret.map_err(|err| catch_timeout(err, self.read_timeout()))
+                    .with_context(|| "Failed to call chat-completions api")
             }
             _ = watch_abort_signal(abort_signal) => {
+                // What was streamed so far stays with the handler, for the caller to keep.
                 handler.done()?;
                 Ok(())
             },
         }
     }
 
+    /// Request the whole response at once and hand it to `handler`, for providers that can't
+    /// stream it.
+    async fn chat_completions_unstreamed(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        mut data: ChatCompletionsData,
+    ) -> Result<()> {
+        data.stream = false;
+        let output = self.chat_completions_inner(client, data).await?;
+        if !output.text.is_empty() {
+            handler.text(&output.text)?;
+        }
+        for tool_call in output.tool_calls {
+            handler.tool_call(tool_call)?;
+        }
+        if let (Some(input_tokens), Some(output_tokens)) =
+            (output.input_tokens, output.output_tokens)
+        {
+            handler.usage(TokenUsage {
+                input_tokens,
+                output_tokens,
+            });
+        }
+        Ok(())
+    }
+
     async fn embeddings(&self, data: EmbeddingsData) -> Result<Vec<Vec<f32>>> {
         let client = self.build_client()?;
         self.model().guard_max_batch_size(&data)?;
         self.embeddings_inner(&client, data)
             .await
+            .map_err(|err| catch_timeout(err, self.read_timeout()))
             .context("Failed to call embeddings api")
     }
 
@@ -401,6 +565,7 @@ pub trait Client: Sync + Send {
         let client = self.build_client()?;
         self.rerank_inner(&client, data)
             .await
+            .map_err(|err| catch_timeout(err, self.read_timeout()))
             .context("Failed to call rerank api")
     }
 
@@ -453,6 +618,7 @@ impl Default for ClientConfig {
 pub struct ExtraConfig {
     pub proxy: Option<String>,
     pub connect_timeout: Option<u64>,
+    pub timeout_secs: Option<u64>,
 }
 
 pub type ModelPatches = IndexMap<String, ModelPatch>;
@@ -479,13 +645,17 @@ pub fn select_model_patch<'a>(
     None
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ChatCompletionsData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
     pub functions: Option<Vec<FunctionDeclaration>>,
     pub stream: bool,
+    /// The OpenAI `response_format`, e.g. `{"type": "json_object"}`.
+    pub response_format: Option<Value>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -504,9 +674,103 @@ impl ChatCompletionsOutput {
             ..Default::default()
         }
     }
+
+    pub fn usage(&self) -> Option<TokenUsage> {
+        Some(TokenUsage {
+            input_tokens: self.input_tokens?,
+            output_tokens: self.output_tokens?,
+        })
+    }
 }
 
-#[derive(Debug)]
+/// A chat completion stored on disk, keyed by the model and the request it answers.
+/// Entries older than `response_cache_ttl` seconds are ignored, 0 keeps them forever.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    path: std::path::PathBuf,
+    ttl: Option<Duration>,
+}
+
+impl ResponseCache {
+    /// The cache entry of `data`, if `response_cache` is enabled.
+    pub fn init(config: &Config, model: &Model, data: &ChatCompletionsData) -> Option<Self> {
+        if !config.response_cache {
+            return None;
+        }
+        let dir = config.response_cache_path().ok()?;
+        // Every field of the request but `stream` selects the response, whole function
+        // declarations included.
+        let mut key = serde_json::to_value(data).ok()?;
+        let object = key.as_object_mut()?;
+        object.remove("stream");
+        object.insert("model".into(), model.id().into());
+        let ttl = match config.response_cache_ttl {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        Some(Self {
+            path: dir.join(sha256(&key.to_string())),
+            ttl,
+        })
+    }
+
+    pub fn get(&self) -> Option<String> {
+        let modified = std::fs::metadata(&self.path).ok()?.modified().ok()?;
+        if let Some(ttl) = self.ttl {
+            if modified.elapsed().ok()? > ttl {
+                return None;
+            }
+        }
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        debug!("cached chat-completions {}", self.path.display());
+        Some(text)
+    }
+
+    /// Store `text`, unless it is empty. Failures only skip caching.
+    pub fn put(&self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let ret = self
+            .path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .unwrap_or(Ok(()))
+            .and_then(|_| std::fs::write(&self.path, text));
+        if let Err(err) = ret {
+            debug!("failed to cache chat-completions, {err}");
+        }
+    }
+}
+
+/// Tokens billed for chat completions, as reported by the api.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl TokenUsage {
+    /// The cost in dollars, if `model` has prices per million tokens.
+    pub fn cost(&self, model: &Model) -> Option<f64> {
+        let data = model.data();
+        if data.input_price.is_none() && data.output_price.is_none() {
+            return None;
+        }
+        let input_cost = self.input_tokens as f64 * data.input_price.unwrap_or_default();
+        let output_cost = self.output_tokens as f64 * data.output_price.unwrap_or_default();
+        Some((input_cost + output_cost) / 1_000_000.0)
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct EmbeddingsData {
     pub texts: Vec<String>,
     pub query: bool,
@@ -547,6 +811,33 @@ pub struct RerankResult {
 
 pub type PromptAction<'a> = (&'a str, &'a str, bool, PromptKind);
 
+/// Run `cmd` and take its trimmed output as a secret, e.g. `pass show openai/key`. The output is
+/// cached for the rest of the process.
+pub fn run_secret_cmd(cmd: &str) -> Result<String> {
+    let mut cache = SECRET_CMD_CACHE.lock();
+    if let Some(secret) = cache.get(cmd) {
+        return Ok(secret.clone());
+    }
+    let args = shell_words::split(cmd).with_context(|| format!("Invalid command `{cmd}`"))?;
+    let (program, args) = args
+        .split_first()
+        .with_context(|| format!("Invalid command `{cmd}`"))?;
+    let (success, stdout, stderr) = run_command_with_output(program, args, None)
+        .with_context(|| format!("Unable to run `{cmd}`, Perhaps '{program}' is not installed?"))?;
+    if !success {
+        bail!(
+            "The command `{cmd}` exited with non-zero: {}",
+            stderr.trim()
+        )
+    }
+    let secret = stdout.trim();
+    if secret.is_empty() {
+        bail!("The command `{cmd}` printed nothing")
+    }
+    cache.insert(cmd.to_string(), secret.to_string());
+    Ok(secret.to_string())
+}
+
 pub fn create_config(prompts: &[PromptAction], client: &str) -> Result<(String, Value)> {
     let mut config = json!({
         "type": client,
@@ -591,12 +882,15 @@ pub fn create_openai_compatible_client_config(client: &str) -> Result<Option<(St
     }
 }
 
+/// Stream a reply to the terminal, returning its text, the results of its tool calls and whether
+/// it was aborted. The text of an aborted reply is what arrived before the abort, and its tool
+/// calls, which may be incomplete, aren't run.
 pub async fn chat_completion_streaming(
     input: &Input,
     client: &dyn Client,
     config: &GlobalConfig,
     abort: AbortSignal,
-) -> Result<(String, Vec<ToolResult>)> {
+) -> Result<(String, Vec<ToolResult>, bool)> {
     let (tx, rx) = unbounded_channel();
     let mut handler = SseHandler::new(tx, abort.clone());
 
@@ -607,13 +901,20 @@ pub async fn chat_completion_streaming(
     if let Err(err) = rend_ret {
         render_error(err, config.read().highlight);
     }
+    let usage = handler.get_usage();
     let (output, calls) = handler.take();
     match send_ret {
         Ok(_) => {
+            if let Some(usage) = usage {
+                config.write().record_usage(client.model(), usage);
+            }
             if !output.is_empty() && !output.ends_with('\n') {
                 println!();
             }
-            Ok((output, eval_tool_calls(config, calls)?))
+            if abort.aborted() {
+                return Ok((output, vec![], true));
+            }
+            Ok((output, eval_tool_calls(config, calls)?, false))
         }
         Err(err) => {
             if !output.is_empty() {
@@ -641,11 +942,246 @@ where
     Ok(())
 }
 
+/// A failed api request, classified so callers can tell whether sending it again may help.
+#[derive(Debug)]
+pub enum ClientError {
+    RateLimited {
+        message: String,
+        /// The delay asked for by a `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
+    Unauthorized(String),
+    BadRequest(String),
+    ServerError(String),
+    Network(reqwest::Error),
+    Other(String),
+}
+
+impl ClientError {
+    /// Classify an error response by its HTTP status, or by the error type or code in its body
+    /// when the status alone says little.
+    pub fn from_status(
+        status: u16,
+        code: Option<&str>,
+        message: String,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        match (status, code.unwrap_or_default()) {
+            // Retrying won't bring the quota back.
+            (_, "insufficient_quota" | "billing_hard_limit_reached") => Self::Other(message),
+            (401 | 403, _)
+            | (_, "authentication_error" | "permission_error" | "invalid_api_key") => {
+                Self::Unauthorized(message)
+            }
+            (429, _) | (_, "rate_limit_error" | "rate_limit_exceeded") => Self::RateLimited {
+                message,
+                retry_after,
+            },
+            (500.., _) | (_, "overloaded_error" | "server_error") => Self::ServerError(message),
+            (400 | 404 | 413 | 422, _) | (_, "invalid_request_error") => Self::BadRequest(message),
+            _ => Self::Other(message),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::ServerError(_) => true,
+            Self::Network(err) => err.is_timeout() || err.is_connect() || err.is_request(),
+            _ => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_builder() {
+            Self::Other(err.to_string())
+        } else {
+            Self::Network(err)
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RateLimited { message, .. }
+            | Self::Unauthorized(message)
+            | Self::BadRequest(message)
+            | Self::ServerError(message)
+            | Self::Other(message) => write!(f, "{message}"),
+            Self::Network(_) => write!(f, "Failed to reach the api"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `response_format` asks for JSON output.
+pub fn is_json_response_format(response_format: &Value) -> bool {
+    matches!(
+        response_format["type"].as_str(),
+        Some("json_object" | "json_schema")
+    )
+}
+
+/// Fail on a `response_format` for clients whose api has no JSON mode, rather than dropping it.
+pub fn check_response_format(response_format: &Option<Value>, model: &Model) -> Result<()> {
+    if response_format.is_some() {
+        bail!(
+            "JSON mode (--json) is not supported by '{}', only by OpenAI-compatible, Ollama and Gemini models",
+            model.id()
+        );
+    }
+    Ok(())
+}
+
+/// Warn, once per model, that `model` ignores the presence and frequency penalties.
+pub fn warn_unsupported_penalties(
+    presence_penalty: Option<f64>,
+    frequency_penalty: Option<f64>,
+    model: &Model,
+) {
+    if (presence_penalty.is_some() || frequency_penalty.is_some())
+        && PENALTY_WARNED_MODELS.lock().insert(model.id())
+    {
+        eprintln!(
+            "{}",
+            warning_text(&format!(
+                "WARNING: '{}' does not support presence_penalty or frequency_penalty, ignoring them.",
+                model.id()
+            ))
+        );
+    }
+}
+
+/// The output of a model in JSON mode that doesn't parse as JSON.
+#[derive(Debug)]
+pub struct InvalidJsonError {
+    pub source: serde_json::Error,
+}
+
+impl std::fmt::Display for InvalidJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The model did not reply with valid JSON, {}",
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for InvalidJsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Parse the output of a model in JSON mode, tolerating a surrounding code block.
+pub fn parse_json_output(content: &str) -> std::result::Result<Value, InvalidJsonError> {
+    let text = content.trim();
+    let text = match text.starts_with("```") {
+        true => extract_block(text),
+        false => text.to_string(),
+    };
+    serde_json::from_str(&text).map_err(|source| InvalidJsonError { source })
+}
+
 pub fn catch_error(data: &Value, status: u16) -> Result<()> {
+    catch_error_with_retry_after(data, status, None)
+}
+
+/// Like `catch_error`, also keeping the delay of a `Retry-After` header read by `parse_retry_after`.
+pub fn catch_error_with_retry_after(
+    data: &Value,
+    status: u16,
+    retry_after: Option<Duration>,
+) -> Result<()> {
     if (200..300).contains(&status) {
         return Ok(());
     }
     debug!("Invalid response, status: {status}, data: {data}");
+    catch_error_message(data, status).map_err(|err| {
+        ClientError::from_status(status, catch_error_code(data), err.to_string(), retry_after)
+            .into()
+    })
+}
+
+/// Read a `Retry-After` header, given either in seconds or as an HTTP date.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let seconds = (date.timestamp() - chrono::Utc::now().timestamp()).max(0);
+    Some(Duration::from_secs(seconds as u64))
+}
+
+/// Whether a request may succeed if sent again: rate limits, server errors and network failures.
+pub fn is_retryable_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        if let Some(err) = err.downcast_ref::<ClientError>() {
+            err.is_retryable()
+        } else if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            err.is_timeout() || err.is_connect() || err.is_request()
+        } else {
+            false
+        }
+    })
+}
+
+/// The delay before the `retries`th retry: what the server asked for, capped at
+/// `MAX_RETRY_AFTER_SECS`, otherwise exponential backoff with up to 50% jitter so that parallel
+/// requests don't retry in lockstep.
+pub fn retry_delay(err: &anyhow::Error, retries: usize) -> Duration {
+    let retry_after = err
+        .chain()
+        .find_map(|err| err.downcast_ref::<ClientError>()?.retry_after());
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(Duration::from_secs(MAX_RETRY_AFTER_SECS));
+    }
+    let base = RETRY_BASE_DELAY_MS << (retries - 1).min(10);
+    let jitter = rand::random::<u64>() % (base / 2 + 1);
+    Duration::from_millis(base + jitter)
+}
+
+/// Explain a request that ran into the read timeout, keeping the original error in the chain.
+pub fn catch_timeout(err: anyhow::Error, read_timeout: Option<Duration>) -> anyhow::Error {
+    let timed_out = err.chain().any(|err| {
+        err.downcast_ref::<reqwest::Error>()
+            .is_some_and(|err| err.is_timeout())
+    });
+    match read_timeout {
+        Some(read_timeout) if timed_out => err.context(format!(
+            "Request timed out after {}s",
+            read_timeout.as_secs()
+        )),
+        _ => err,
+    }
+}
+
+/// The error type or code in an error body, e.g. `invalid_api_key`.
+fn catch_error_code(data: &Value) -> Option<&str> {
+    let error = &data["error"];
+    error["code"].as_str().or_else(|| error["type"].as_str())
+}
+
+fn catch_error_message(data: &Value, status: u16) -> Result<()> {
     if let Some(error) = data["error"].as_object() {
         if let (Some(typ), Some(message)) = (
             json_str_from_map(error, "type"),
@@ -770,7 +1306,7 @@ fn to_json(kind: &PromptKind, value: &str) -> Value {
     }
 }
 
-fn split_content(text: &str) -> Vec<&str> {
+pub fn split_content(text: &str) -> Vec<&str> {
     if text.is_ascii() {
         text.split_inclusive(|c: char| c.is_ascii_whitespace())
             .collect()
@@ -778,3 +1314,183 @@ fn split_content(text: &str) -> Vec<&str> {
         unicode_segmentation::UnicodeSegmentation::graphemes(text, true).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_cache_key() {
+        let config = Config {
+            response_cache: true,
+            response_cache_dir: Some("/tmp/responses".into()),
+            ..Default::default()
+        };
+        let model = Model::new("openai", "gpt-4o");
+        let data = ChatCompletionsData {
+            messages: vec![Message::new(
+                MessageRole::User,
+                MessageContent::Text("hello".into()),
+            )],
+            temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            functions: None,
+            stream: false,
+            response_format: None,
+        };
+        let path =
+            |data: &ChatCompletionsData| ResponseCache::init(&config, &model, data).unwrap().path;
+        let streaming = ChatCompletionsData {
+            stream: true,
+            ..data.clone()
+        };
+        assert_eq!(path(&streaming), path(&data));
+        let top_p = ChatCompletionsData {
+            top_p: Some(0.5),
+            ..data.clone()
+        };
+        assert_ne!(path(&top_p), path(&data));
+        let presence_penalty = ChatCompletionsData {
+            presence_penalty: Some(0.5),
+            ..data.clone()
+        };
+        let frequency_penalty = ChatCompletionsData {
+            frequency_penalty: Some(0.5),
+            ..data.clone()
+        };
+        assert_ne!(path(&presence_penalty), path(&data));
+        assert_ne!(path(&frequency_penalty), path(&data));
+        assert_ne!(path(&presence_penalty), path(&frequency_penalty));
+        let json_mode = ChatCompletionsData {
+            response_format: Some(json!({ "type": "json_object" })),
+            ..data.clone()
+        };
+        assert_ne!(path(&json_mode), path(&data));
+        assert!(path(&data).starts_with("/tmp/responses"));
+        let other_model = Model::new("openai", "gpt-4o-mini");
+        let other = ResponseCache::init(&config, &other_model, &data).unwrap();
+        assert_ne!(other.path, path(&data));
+    }
+
+    #[test]
+    fn test_check_response_format() {
+        let model = Model::new("claude", "claude-3-5-sonnet-20240620");
+        assert!(check_response_format(&None, &model).is_ok());
+        let err =
+            check_response_format(&Some(json!({ "type": "json_object" })), &model).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "JSON mode (--json) is not supported by 'claude:claude-3-5-sonnet-20240620', only by OpenAI-compatible, Ollama and Gemini models"
+        );
+    }
+
+    #[test]
+    fn test_parse_json_output() {
+        assert_eq!(
+            parse_json_output("{\"colors\": [\"red\"]}").unwrap(),
+            json!({ "colors": ["red"] })
+        );
+        assert_eq!(
+            parse_json_output("```json\n{\"ok\": true}\n```").unwrap(),
+            json!({ "ok": true })
+        );
+        let err = parse_json_output("Sure! {\"ok\": true}").unwrap_err();
+        assert!(err.source.is_syntax());
+        assert!(err
+            .to_string()
+            .starts_with("The model did not reply with valid JSON"));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), None);
+
+        let err = catch_error_with_retry_after(
+            &json!({"error": {"type": "rate_limit", "message": "slow down"}}),
+            429,
+            Some(Duration::from_secs(2)),
+        )
+        .unwrap_err();
+        let err = err.downcast_ref::<ClientError>().unwrap();
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(2)));
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn test_catch_error_classifies_client_errors() {
+        let classify = |data: Value, status: u16| {
+            let err = catch_error(&data, status).unwrap_err();
+            err.downcast::<ClientError>().unwrap()
+        };
+        let err = classify(
+            json!({"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "code": "invalid_api_key"}}),
+            401,
+        );
+        assert!(matches!(err, ClientError::Unauthorized(_)));
+        assert!(!err.is_retryable());
+        let err = classify(
+            json!({"error": {"message": "Rate limit reached", "type": "requests", "code": "rate_limit_exceeded"}}),
+            429,
+        );
+        assert!(matches!(err, ClientError::RateLimited { .. }));
+        assert!(err.is_retryable());
+        let err = classify(
+            json!({"error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "code": "insufficient_quota"}}),
+            429,
+        );
+        assert!(matches!(err, ClientError::Other(_)));
+        assert!(!err.is_retryable());
+        let err = classify(
+            json!({"error": {"message": "This model's maximum context length is 8192 tokens", "type": "invalid_request_error", "code": "context_length_exceeded"}}),
+            400,
+        );
+        match err {
+            ClientError::BadRequest(message) => assert_eq!(
+                message,
+                "This model's maximum context length is 8192 tokens (type: invalid_request_error)"
+            ),
+            err => panic!("unexpected {err:?}"),
+        }
+        let err = classify(
+            json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}),
+            529,
+        );
+        assert!(matches!(err, ClientError::ServerError(_)));
+        assert!(err.is_retryable());
+        let err = classify(json!({"error": "Bad gateway"}), 502);
+        assert!(matches!(err, ClientError::ServerError(_)));
+        let err = classify(
+            json!({"type": "error", "error": {"type": "authentication_error", "message": "invalid x-api-key"}}),
+            400,
+        );
+        assert!(matches!(err, ClientError::Unauthorized(_)));
+        let err = classify(json!({"message": "I'm a teapot"}), 418);
+        assert!(matches!(err, ClientError::Other(_)));
+    }
+
+    #[tokio::test]
+    async fn test_network_client_error() {
+        let err = reqwest::Client::new()
+            .get("http://127.0.0.1:1")
+            .send()
+            .await
+            .map_err(ClientError::from)
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Network(_)));
+        let err = anyhow::Error::from(err);
+        assert!(is_retryable_error(&err));
+        assert!(err.downcast_ref::<ClientError>().is_some());
+    }
+}
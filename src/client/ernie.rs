@@ -36,6 +36,7 @@ impl ErnieClient {
     ) -> Result<RequestBuilder> {
         let access_token = get_access_token(self.name())?;
 
+        check_response_format(&data.response_format, &self.model)?;
         let mut body = build_chat_completions_body(data, &self.model);
         self.patch_chat_completions_body(&mut body);
 
@@ -231,10 +232,15 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Valu
         mut messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions,
         stream,
+        response_format: _,
     } = data;
 
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
+
     let system_message = extract_system_message(&mut messages);
 
     let messages: Vec<Value> = messages
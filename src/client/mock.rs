@@ -0,0 +1,149 @@
+use super::*;
+
+use crate::config::GlobalConfig;
+use crate::utils::sha256;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+const DEFAULT_DIMENSION: usize = 256;
+
+/// Answers without a network: chat echoes the prompt and embeddings are hashed from the text.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MockConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelData>,
+    pub patches: Option<ModelPatches>,
+    pub extra: Option<ExtraConfig>,
+}
+
+#[async_trait]
+impl Client for MockClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        Ok(ChatCompletionsOutput::new(&echo_prompt(&data)))
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        _client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        let text = echo_prompt(&data);
+        for token in split_content(&text) {
+            handler.text(token)?;
+        }
+        Ok(())
+    }
+
+    async fn embeddings_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let dimension = self
+            .model
+            .data()
+            .output_vector_size
+            .unwrap_or(DEFAULT_DIMENSION);
+        let output = data
+            .texts
+            .iter()
+            .map(|text| mock_embedding(text, dimension))
+            .collect();
+        Ok(output)
+    }
+}
+
+impl MockClient {
+    pub const PROMPTS: [PromptAction<'static>; 0] = [];
+
+    /// A client answering offline with the settings of `model`, for dry runs.
+    pub fn new(global_config: &GlobalConfig, model: &Model) -> Self {
+        Self {
+            global_config: global_config.clone(),
+            config: MockConfig::default(),
+            model: model.clone(),
+        }
+    }
+}
+
+fn echo_prompt(data: &ChatCompletionsData) -> String {
+    data.messages
+        .iter()
+        .rev()
+        .find(|v| v.role.is_user())
+        .map(|v| v.content.to_text())
+        .unwrap_or_default()
+}
+
+/// Hash each word of `text` into one of `dimension` buckets, so texts sharing words end up
+/// close, then normalize the vector.
+fn mock_embedding(text: &str, dimension: usize) -> Vec<f32> {
+    let mut vector = vec![0.0; dimension.max(1)];
+    for word in text.unicode_words() {
+        let hash = sha256(&word.to_lowercase());
+        let value = u64::from_str_radix(&hash[..16], 16).unwrap_or_default();
+        let index = (value % vector.len() as u64) as usize;
+        vector[index] += if value >> 63 == 0 { 1.0 } else { -1.0 };
+    }
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Input;
+    use crate::utils::create_abort_signal;
+
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+
+    #[test]
+    fn test_mock_embedding() {
+        let a = mock_embedding("Deploys happen on Tuesdays", 256);
+        assert_eq!(a.len(), 256);
+        assert_eq!(a, mock_embedding("deploys happen on tuesdays", 256));
+        assert!((cosine(&a, &a) - 1.0).abs() < 1e-5);
+        let b = mock_embedding("When do deploys happen?", 256);
+        let c = mock_embedding("Lunch is at noon", 256);
+        assert!(cosine(&a, &b) > cosine(&a, &c));
+        assert_eq!(mock_embedding("", 4), vec![0.0; 4]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_chat_completions() {
+        let config: GlobalConfig = Default::default();
+        let client = MockClient::new(&config, &Model::new("mock", "echo"));
+        let input = Input::from_str(&config, "Hello there", None);
+        let output = client
+            .chat_completions(input.clone(), create_abort_signal())
+            .await
+            .unwrap();
+        assert_eq!(output.text, "Hello there");
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap();
+        assert_eq!(handler.take().0, "Hello there");
+    }
+}
@@ -49,6 +49,7 @@ register_client!(
     (replicate, "replicate", ReplicateConfig, ReplicateClient),
     (ernie, "ernie", ErnieConfig, ErnieClient),
     (qianwen, "qianwen", QianwenConfig, QianwenClient),
+    (mock, "mock", MockConfig, MockClient),
 );
 
 pub const OPENAI_COMPATIBLE_PLATFORMS: [(&str, &str); 12] = [
@@ -1,6 +1,6 @@
 use super::{
     list_chat_models, list_embedding_models, list_reranker_models,
-    message::{Message, MessageContent},
+    message::{Message, MessageContent, MessageContentPart},
     EmbeddingsData,
 };
 
@@ -12,6 +12,8 @@ use serde::{Deserialize, Serialize};
 
 const PER_MESSAGES_TOKENS: usize = 5;
 const BASIS_TOKENS: usize = 2;
+/// What OpenAI charges for a 1024x1024 image at high detail, the image size isn't known here.
+const IMAGE_TOKENS: usize = 765;
 
 #[derive(Debug, Clone)]
 pub struct Model {
@@ -188,6 +190,10 @@ impl Model {
         self.data.supports_vision
     }
 
+    pub fn supports_streaming(&self) -> bool {
+        self.data.supports_streaming.unwrap_or(true)
+    }
+
     pub fn supports_function_calling(&self) -> bool {
         self.data.supports_function_calling
     }
@@ -200,6 +206,10 @@ impl Model {
         self.data.max_batch_size.unwrap_or(1)
     }
 
+    pub fn max_concurrent_requests(&self) -> Option<usize> {
+        self.data.max_concurrent_requests
+    }
+
     pub fn max_tokens_param(&self) -> Option<isize> {
         if self.data.require_max_tokens {
             self.data.max_output_tokens
@@ -224,14 +234,35 @@ impl Model {
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
         messages
             .iter()
-            .map(|v| match &v.content {
-                MessageContent::Text(text) => estimate_token_length(text),
-                MessageContent::Array(_) => 0,
-                MessageContent::ToolResults(_) => 0,
-            })
+            .map(|v| self.content_tokens(&v.content))
             .sum()
     }
 
+    fn content_tokens(&self, content: &MessageContent) -> usize {
+        let tokenizer_kind = self.tokenizer_kind();
+        match content {
+            MessageContent::Text(text) => tokenizer_kind.estimate(text),
+            MessageContent::Array(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    MessageContentPart::Text { text } => tokenizer_kind.estimate(text),
+                    MessageContentPart::ImageUrl { .. } => IMAGE_TOKENS,
+                })
+                .sum(),
+            MessageContent::ToolResults((results, text)) => {
+                let results = serde_json::to_string(results).unwrap_or_default();
+                tokenizer_kind.estimate(&results) + tokenizer_kind.estimate(text)
+            }
+        }
+    }
+
+    /// The configured tokenizer kind, otherwise the one of the model family.
+    pub fn tokenizer_kind(&self) -> TokenizerKind {
+        self.data
+            .tokenizer_kind
+            .unwrap_or_else(|| TokenizerKind::detect(&self.data.name))
+    }
+
     pub fn total_tokens(&self, messages: &[Message]) -> usize {
         if messages.is_empty() {
             return 0;
@@ -245,14 +276,67 @@ impl Model {
         }
     }
 
-    pub fn guard_max_input_tokens(&self, messages: &[Message]) -> Result<()> {
-        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
-        if let Some(max_input_tokens) = self.data.max_input_tokens {
-            if total_tokens >= max_input_tokens {
-                bail!("Exceed max_input_tokens limit")
+    /// Drop the oldest messages until `messages` and `reserve_output` fit in `max_input_tokens`.
+    /// System messages and everything from the last user message on are always kept, it is an
+    /// error if they alone don't fit.
+    pub fn fit_messages(
+        &self,
+        mut messages: Vec<Message>,
+        reserve_output: usize,
+    ) -> Result<Vec<Message>> {
+        let Some(allowed_tokens) = self.allowed_input_tokens() else {
+            return Ok(messages);
+        };
+        let fits = |messages: &[Message]| {
+            self.total_tokens(messages) + BASIS_TOKENS + reserve_output <= allowed_tokens
+        };
+        let mut last_user = messages.iter().rposition(|v| v.role.is_user());
+        while !fits(&messages) {
+            let oldest = messages
+                .iter()
+                .position(|v| !v.role.is_system())
+                .filter(|index| last_user.is_some_and(|v| *index < v));
+            let Some(index) = oldest else {
+                break;
+            };
+            messages.remove(index);
+            last_user = last_user.map(|v| v - 1);
+            // Conversations start with a user message.
+            while let Some(index) = messages
+                .iter()
+                .position(|v| !v.role.is_system())
+                .filter(|index| !messages[*index].role.is_user())
+            {
+                messages.remove(index);
+                last_user = last_user.map(|v| v - 1);
             }
         }
-        Ok(())
+        let total_tokens = self.total_tokens(&messages) + BASIS_TOKENS;
+        if total_tokens + reserve_output > allowed_tokens {
+            bail!(
+                "Exceed max_input_tokens limit, the prompt is {total_tokens} tokens but the limit is {}",
+                allowed_tokens.saturating_sub(reserve_output)
+            )
+        }
+        Ok(messages)
+    }
+
+    /// Tokens that can still be added to `messages` without exceeding `max_input_tokens`.
+    pub fn remaining_input_tokens(&self, messages: &[Message]) -> Option<usize> {
+        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
+        self.allowed_input_tokens()
+            .map(|v| v.saturating_sub(total_tokens))
+    }
+
+    /// `max_input_tokens`, less the tokens reserved for the response when the request sets
+    /// its size.
+    fn allowed_input_tokens(&self) -> Option<usize> {
+        let max_input_tokens = self.data.max_input_tokens?;
+        let reserved = match self.max_tokens_param() {
+            Some(v) if v > 0 => v as usize,
+            _ => 0,
+        };
+        Some(max_input_tokens.saturating_sub(reserved))
     }
 
     pub fn guard_max_batch_size(&self, data: &EmbeddingsData) -> Result<()> {
@@ -271,20 +355,26 @@ pub struct ModelData {
     pub max_input_tokens: Option<usize>,
     pub input_price: Option<f64>,
     pub output_price: Option<f64>,
+    /// The azure deployment serving the model, when it is not named after the model.
+    pub deployment_id: Option<String>,
 
     // chat-only properties
     pub max_output_tokens: Option<isize>,
     #[serde(default)]
     pub require_max_tokens: bool,
+    pub tokenizer_kind: Option<TokenizerKind>,
     #[serde(default)]
     pub supports_vision: bool,
     #[serde(default)]
     pub supports_function_calling: bool,
+    /// Whether the model can stream its response, assumed unless set to false.
+    pub supports_streaming: Option<bool>,
 
     // embedding-only properties
     pub output_vector_size: Option<usize>,
     pub default_chunk_size: Option<usize>,
     pub max_batch_size: Option<usize>,
+    pub max_concurrent_requests: Option<usize>,
 }
 
 impl ModelData {
@@ -296,6 +386,53 @@ impl ModelData {
     }
 }
 
+/// How tokens are estimated, as models of different families tokenize text differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerKind {
+    /// Word based, close to OpenAI's cl100k encoding.
+    Cl100k,
+    /// About 3.5 characters of English text per token, as Anthropic documents for Claude.
+    Claude,
+    /// About 4 characters per token, as Google documents for Gemini.
+    Chars,
+}
+
+impl TokenizerKind {
+    pub fn detect(model_name: &str) -> Self {
+        let model_name = model_name.to_ascii_lowercase();
+        if model_name.contains("claude") {
+            Self::Claude
+        } else if model_name.contains("gemini") || model_name.contains("gemma") {
+            Self::Chars
+        } else {
+            Self::Cl100k
+        }
+    }
+
+    pub fn estimate(&self, text: &str) -> usize {
+        // Text outside of ASCII, e.g. CJK, takes about a token per character either way.
+        let per_char = |ascii_chars_per_token: f32| -> usize {
+            let output: f32 = text
+                .chars()
+                .map(|c| {
+                    if c.is_ascii() {
+                        1.0 / ascii_chars_per_token
+                    } else {
+                        1.0
+                    }
+                })
+                .sum();
+            output.ceil() as usize
+        };
+        match self {
+            Self::Cl100k => estimate_token_length(text),
+            Self::Claude => per_char(3.5),
+            Self::Chars => per_char(4.0),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct BuiltinModels {
     pub platform: String,
@@ -305,3 +442,150 @@ pub struct BuiltinModels {
 fn default_model_type() -> String {
     "chat".into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MessageRole;
+
+    #[test]
+    fn test_tokenizer_kind_estimate() {
+        // Reference counts of the tokenizers, or of the rules of thumb for those not public.
+        let cases = [
+            (TokenizerKind::Cl100k, "Hello world", 2),
+            (
+                TokenizerKind::Cl100k,
+                "The quick brown fox jumps over the lazy dog.",
+                10,
+            ),
+            (
+                TokenizerKind::Claude,
+                "The quick brown fox jumps over the lazy dog.",
+                13,
+            ),
+            (TokenizerKind::Claude, "你好世界", 4),
+            (
+                TokenizerKind::Chars,
+                "The quick brown fox jumps over the lazy dog.",
+                11,
+            ),
+            (TokenizerKind::Chars, "", 0),
+        ];
+        for (kind, text, expect) in cases {
+            let estimate = kind.estimate(text);
+            // Estimates are rounded up, so allow one token more on short texts.
+            let diff = estimate.abs_diff(expect);
+            assert!(
+                diff <= 1 || diff as f32 / expect as f32 <= 0.3,
+                "{kind:?} {text:?}: {estimate} vs {expect}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_messages_tokens_by_model_family() {
+        let text = "x".repeat(280);
+        let messages = vec![Message::new(
+            MessageRole::User,
+            MessageContent::Array(vec![MessageContentPart::Text { text: text.clone() }]),
+        )];
+        assert_eq!(
+            TokenizerKind::detect("claude-3-5-sonnet"),
+            TokenizerKind::Claude
+        );
+        assert_eq!(
+            TokenizerKind::detect("gemini-1.5-pro"),
+            TokenizerKind::Chars
+        );
+        assert_eq!(TokenizerKind::detect("gpt-4o"), TokenizerKind::Cl100k);
+        assert_eq!(
+            Model::new("claude", "claude-3-5-sonnet").messages_tokens(&messages),
+            80
+        );
+        assert_eq!(
+            Model::new("gemini", "gemini-1.5-pro").messages_tokens(&messages),
+            70
+        );
+
+        let mut model = Model::new("openai", "gpt-4o");
+        model.data_mut().tokenizer_kind = Some(TokenizerKind::Chars);
+        assert_eq!(model.messages_tokens(&messages), 70);
+    }
+
+    #[test]
+    fn test_fit_messages_limit() {
+        let messages = vec![Message::new(
+            MessageRole::User,
+            MessageContent::Text("x".repeat(400)),
+        )];
+        let mut model = Model::new("gemini", "gemini-1.5-pro");
+        let total_tokens = model.total_tokens(&messages) + BASIS_TOKENS;
+        for (max_input_tokens, fits) in [
+            (total_tokens - 1, false),
+            (total_tokens, true),
+            (total_tokens + 1, true),
+        ] {
+            model.data_mut().max_input_tokens = Some(max_input_tokens);
+            let ret = model.fit_messages(messages.clone(), 0);
+            assert_eq!(ret.is_ok(), fits, "{max_input_tokens}");
+            let remaining = model.remaining_input_tokens(&messages).unwrap();
+            assert_eq!(remaining, max_input_tokens.saturating_sub(total_tokens));
+        }
+        // The response is reserved its size out of the limit.
+        model.set_max_tokens(Some(10), true);
+        let err = model.fit_messages(messages, 0).unwrap_err();
+        let limit = total_tokens + 1 - 10;
+        assert!(err.to_string().contains(&format!(
+            "the prompt is {total_tokens} tokens but the limit is {limit}"
+        )));
+    }
+
+    #[test]
+    fn test_fit_messages() {
+        let message = |role, text: &str| Message::new(role, MessageContent::Text(text.into()));
+        let mut messages = vec![message(MessageRole::System, "be brief")];
+        for i in 0..20 {
+            messages.push(message(
+                MessageRole::User,
+                &format!("question {i} {}", "x".repeat(80)),
+            ));
+            messages.push(message(
+                MessageRole::Assistant,
+                &format!("answer {i} {}", "y".repeat(80)),
+            ));
+        }
+        messages.push(message(MessageRole::User, "last question"));
+        let mut model = Model::new("gemini", "gemini-1.5-pro");
+        let reserve_output = 50;
+
+        model.data_mut().max_input_tokens = None;
+        assert_eq!(
+            model
+                .fit_messages(messages.clone(), reserve_output)
+                .unwrap()
+                .len(),
+            42
+        );
+
+        model.data_mut().max_input_tokens = Some(300);
+        let output = model
+            .fit_messages(messages.clone(), reserve_output)
+            .unwrap();
+        assert!(output.len() < messages.len() && output.len() > 2);
+        assert!(model.total_tokens(&output) + BASIS_TOKENS + reserve_output <= 300);
+        assert_eq!(output[0].content.to_text(), "be brief");
+        assert!(output[1].role.is_user());
+        assert_eq!(output.last().unwrap().content.to_text(), "last question");
+        // The newest messages are the ones kept.
+        assert!(output[output.len() - 2]
+            .content
+            .to_text()
+            .starts_with("answer 19"));
+
+        model.data_mut().max_input_tokens = Some(20);
+        let output = model.fit_messages(messages.clone(), 0).unwrap();
+        assert_eq!(output.len(), 2);
+        let err = model.fit_messages(messages, reserve_output).unwrap_err();
+        assert!(err.to_string().contains("Exceed max_input_tokens limit"));
+    }
+}
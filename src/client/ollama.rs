@@ -136,9 +136,10 @@ async fn chat_completions_streaming(
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
     let res = builder.send().await?;
     let status = res.status();
+    let retry_after = parse_retry_after(res.headers());
     let data = res.json().await?;
     if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
+        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
     }
     let res_body: EmbeddingsResBody =
         serde_json::from_value(data).context("Invalid embeddings data")?;
@@ -156,8 +157,11 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions: _,
         stream,
+        response_format,
     } = data;
 
     let mut is_tool_call = false;
@@ -232,6 +236,18 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
     if let Some(v) = top_p {
         body["options"]["top_p"] = v.into();
     }
+    if let Some(v) = presence_penalty {
+        body["options"]["presence_penalty"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["options"]["frequency_penalty"] = v.into();
+    }
+    if response_format
+        .as_ref()
+        .is_some_and(is_json_response_format)
+    {
+        body["format"] = "json".into();
+    }
 
     Ok(body)
 }
@@ -1,6 +1,7 @@
 use super::*;
 
 use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
@@ -11,6 +12,7 @@ const API_BASE: &str = "https://api.openai.com/v1";
 pub struct OpenAIConfig {
     pub name: Option<String>,
     pub api_key: Option<String>,
+    pub api_key_cmd: Option<String>,
     pub api_base: Option<String>,
     pub organization_id: Option<String>,
     #[serde(default)]
@@ -20,7 +22,7 @@ pub struct OpenAIConfig {
 }
 
 impl OpenAIClient {
-    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_key, get_api_key, api_key_cmd);
     config_get_fn!(api_base, get_api_base);
 
     pub const PROMPTS: [PromptAction<'static>; 1] =
@@ -34,7 +36,12 @@ impl OpenAIClient {
         let api_key = self.get_api_key()?;
         let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
 
+        let stream = data.stream;
         let mut body = openai_build_chat_completions_body(data, &self.model);
+        if stream {
+            // Makes the last chunk carry the token usage.
+            body["stream_options"] = json!({ "include_usage": true });
+        }
         self.patch_chat_completions_body(&mut body);
 
         let url = format!("{api_base}/chat/completions");
@@ -71,11 +78,12 @@ impl OpenAIClient {
 }
 
 pub async fn openai_chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = builder.send().await.map_err(ClientError::from)?;
     let status = res.status();
+    let retry_after = parse_retry_after(res.headers());
     let data: Value = res.json().await?;
     if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
+        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
     }
 
     debug!("non-stream-data: {data}");
@@ -86,66 +94,87 @@ pub async fn openai_chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let mut function_index = 0;
-    let mut function_name = String::new();
-    let mut function_arguments = String::new();
-    let mut function_id = String::new();
+    let mut tool_calls = IndexMap::new();
     let handle = |message: SseMmessage| -> Result<bool> {
         if message.data == "[DONE]" {
-            if !function_name.is_empty() {
-                handler.tool_call(ToolCall::new(
-                    function_name.clone(),
-                    json!(function_arguments),
-                    Some(function_id.clone()),
-                ))?;
-            }
+            openai_flush_tool_calls(handler, &mut tool_calls)?;
             return Ok(true);
         }
         let data: Value = serde_json::from_str(&message.data)?;
         debug!("stream-data: {data}");
-        if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
-            handler.text(text)?;
-        } else if let (Some(function), index, id) = (
-            data["choices"][0]["delta"]["tool_calls"][0]["function"].as_object(),
-            data["choices"][0]["delta"]["tool_calls"][0]["index"].as_u64(),
-            data["choices"][0]["delta"]["tool_calls"][0]["id"].as_str(),
-        ) {
-            let index = index.unwrap_or_default();
-            if index != function_index {
-                if !function_name.is_empty() {
-                    handler.tool_call(ToolCall::new(
-                        function_name.clone(),
-                        json!(function_arguments),
-                        Some(function_id.clone()),
-                    ))?;
-                }
-                function_name.clear();
-                function_arguments.clear();
-                function_id.clear();
-                function_index = index;
-            }
-            if let Some(name) = function.get("name").and_then(|v| v.as_str()) {
-                function_name = name.to_string();
+        openai_handle_stream_data(&data, handler, &mut tool_calls)?;
+        Ok(false)
+    };
+
+    sse_stream(builder, handle).await
+}
+
+/// A tool call being streamed: its id, name and the arguments received so far.
+type StreamingToolCall = (String, String, String);
+
+/// Handle a chunk of a chat completions stream. Tool calls arrive in fragments tagged with
+/// their index, which are gathered in `tool_calls` until the choice finishes.
+fn openai_handle_stream_data(
+    data: &Value,
+    handler: &mut SseHandler,
+    tool_calls: &mut IndexMap<u64, StreamingToolCall>,
+) -> Result<()> {
+    let choice = &data["choices"][0];
+    if let Some(text) = choice["delta"]["content"].as_str() {
+        handler.text(text)?;
+    }
+    if let Some(calls) = choice["delta"]["tool_calls"].as_array() {
+        for call in calls {
+            let index = call["index"].as_u64().unwrap_or_default();
+            let (id, name, arguments) = tool_calls.entry(index).or_default();
+            if let Some(value) = call["id"].as_str() {
+                *id = value.to_string();
             }
-            if let Some(arguments) = function.get("arguments").and_then(|v| v.as_str()) {
-                function_arguments.push_str(arguments);
+            if let Some(value) = call["function"]["name"].as_str() {
+                *name = value.to_string();
             }
-            if let Some(id) = id {
-                function_id = id.to_string();
+            if let Some(value) = call["function"]["arguments"].as_str() {
+                arguments.push_str(value);
             }
         }
-        Ok(false)
-    };
+    }
+    if choice["finish_reason"].is_string() {
+        openai_flush_tool_calls(handler, tool_calls)?;
+    }
+    if let (Some(input_tokens), Some(output_tokens)) = (
+        data["usage"]["prompt_tokens"].as_u64(),
+        data["usage"]["completion_tokens"].as_u64(),
+    ) {
+        handler.usage(TokenUsage {
+            input_tokens,
+            output_tokens,
+        });
+    }
+    Ok(())
+}
 
-    sse_stream(builder, handle).await
+fn openai_flush_tool_calls(
+    handler: &mut SseHandler,
+    tool_calls: &mut IndexMap<u64, StreamingToolCall>,
+) -> Result<()> {
+    tool_calls.sort_keys();
+    for (_, (id, name, arguments)) in tool_calls.drain(..) {
+        if name.is_empty() {
+            continue;
+        }
+        let id = if id.is_empty() { None } else { Some(id) };
+        handler.tool_call(ToolCall::new(name, json!(arguments), id))?;
+    }
+    Ok(())
 }
 
 pub async fn openai_embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+    let res = builder.send().await.map_err(ClientError::from)?;
     let status = res.status();
+    let retry_after = parse_retry_after(res.headers());
     let data: Value = res.json().await?;
     if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
+        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
     }
     let res_body: EmbeddingsResBody =
         serde_json::from_value(data).context("Invalid embeddings data")?;
@@ -168,11 +197,14 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions,
         stream,
+        response_format,
     } = data;
 
-    let messages: Vec<Value> = messages
+    let mut messages: Vec<Value> = messages
         .into_iter()
         .flat_map(|message| {
             let Message { role, content } = message;
@@ -207,6 +239,19 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
         })
         .collect();
 
+    if let Some(response_format) = &response_format {
+        // OpenAI rejects JSON mode unless the messages mention JSON.
+        let mentions_json = messages
+            .iter()
+            .any(|v| v["content"].to_string().to_lowercase().contains("json"));
+        if is_json_response_format(response_format) && !mentions_json {
+            messages.insert(
+                0,
+                json!({ "role": MessageRole::System, "content": "Respond with valid JSON." }),
+            );
+        }
+    }
+
     let mut body = json!({
         "model": &model.name(),
         "messages": messages,
@@ -221,9 +266,18 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if let Some(v) = presence_penalty {
+        body["presence_penalty"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["frequency_penalty"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
+    if let Some(v) = response_format {
+        body["response_format"] = v;
+    }
     if let Some(functions) = functions {
         body["tools"] = functions
             .iter()
@@ -291,3 +345,510 @@ impl_client_trait!(
     openai_chat_completions_streaming,
     openai_embeddings
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{Config, Input};
+    use crate::utils::{create_abort_signal, estimate_token_length};
+
+    use parking_lot::RwLock;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Serve one raw HTTP response per connection, keeping the connection open for `stall`
+    /// after writing it. Returns the url and the number of connections accepted.
+    async fn mock_server(replies: Vec<(&'static str, Duration)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            for (reply, stall) in replies {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(mock_reply(stream, reply, stall));
+            }
+        });
+        (format!("http://{addr}"), connections)
+    }
+
+    async fn mock_reply(mut stream: TcpStream, reply: &'static str, stall: Duration) {
+        let mut request = vec![];
+        let mut buf = [0; 4096];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        let head = String::from_utf8_lossy(&request).to_lowercase();
+        let (head, body) = head.split_once("\r\n\r\n").unwrap();
+        let content_length: usize = head
+            .lines()
+            .find_map(|v| v.strip_prefix("content-length:"))
+            .map(|v| v.trim().parse().unwrap())
+            .unwrap_or_default();
+        let mut remaining = content_length.saturating_sub(body.len());
+        while remaining > 0 {
+            let n = stream.read(&mut buf).await.unwrap();
+            remaining = remaining.saturating_sub(n);
+        }
+        stream.write_all(reply.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(stall).await;
+    }
+
+    fn mock_client(api_base: String, extra: ExtraConfig) -> OpenAIClient {
+        OpenAIClient {
+            global_config: Arc::new(RwLock::new(Config::default())),
+            config: OpenAIConfig {
+                api_key: Some("sk-test".into()),
+                api_base: Some(api_base),
+                extra: Some(extra),
+                ..Default::default()
+            },
+            model: Model::new("openai", "gpt-4o"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_openai_read_timeout() {
+        let extra = ExtraConfig {
+            timeout_secs: Some(1),
+            ..Default::default()
+        };
+        let stall = Duration::from_secs(5);
+        let stream_reply = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\r\n\
+            data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n";
+        let replies = vec![("", stall), (stream_reply, stall)];
+        let (api_base, connections) = mock_server(replies).await;
+        let client = mock_client(api_base, extra);
+        let input = Input::from_str(&client.global_config, "hello", None);
+
+        client.global_config.write().chat_completions_retries = 0;
+        let err = client.chat_completions(input.clone(), create_abort_signal()).await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Request timed out after 1s"),
+            "{err:#}"
+        );
+
+        // The first chunk arrives in time, the stream then stalls. Timeouts are retried, but not
+        // once a chunk was handled.
+        client.global_config.write().chat_completions_retries = 1;
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        let err = client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Request timed out after 1s"),
+            "{err:#}"
+        );
+        assert_eq!(handler.take().0, "Hi");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    }
+
+    #[tokio::test]
+    async fn test_openai_retry_rate_limit() {
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\n\
+            content-type: application/json\r\ncontent-length: 61\r\nconnection: close\r\n\r\n\
+            {\"error\":{\"type\":\"rate_limit\",\"message\":\"Slow down please.\"}}";
+        let completion = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+            content-length: 61\r\nconnection: close\r\n\r\n\
+            {\"choices\":[{\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"}}]}";
+        let stream = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n\
+            data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\ndata: [DONE]\n\n";
+        let replies = vec![rate_limited, completion, rate_limited, stream, rate_limited];
+        let replies = replies.into_iter().map(|v| (v, Duration::ZERO)).collect();
+        let (api_base, connections) = mock_server(replies).await;
+        let client = mock_client(api_base, ExtraConfig::default());
+        let input = Input::from_str(&client.global_config, "hello", None);
+
+        let output = client.chat_completions(input.clone(), create_abort_signal()).await.unwrap();
+        assert_eq!(output.text, "Hi");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap();
+        assert_eq!(handler.take().0, "Hi");
+        assert_eq!(connections.load(Ordering::SeqCst), 4);
+
+        client.global_config.write().chat_completions_retries = 0;
+        let err = client.chat_completions(input, create_abort_signal()).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Slow down please."), "{err:#}");
+        assert_eq!(connections.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_openai_streaming_fallback() {
+        let completion = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+            content-length: 120\r\nconnection: close\r\n\r\n\
+            {\"choices\":[{\"message\":{\"role\":\"assistant\",\"content\":\"Hello there\"}}],\
+            \"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2}}";
+        let replies = vec![completion; 3];
+        let replies = replies.into_iter().map(|v| (v, Duration::ZERO)).collect();
+        let (api_base, connections) = mock_server(replies).await;
+        let mut client = mock_client(api_base, ExtraConfig::default());
+        let input = Input::from_str(&client.global_config, "hello", None);
+
+        // JSON despite `stream: true`, the request is sent again without streaming.
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap();
+        assert_eq!(handler.get_usage().unwrap().output_tokens, 2);
+        assert_eq!(handler.take().0, "Hello there");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        client.model.data_mut().supports_streaming = Some(false);
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap();
+        assert_eq!(handler.take().0, "Hello there");
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_openai_abort() {
+        let stall = Duration::from_secs(10);
+        let stream_reply = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\r\n\
+            data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n";
+        let replies = vec![("", stall), (stream_reply, stall), (stream_reply, stall)];
+        let (api_base, connections) = mock_server(replies).await;
+        let client = mock_client(api_base, ExtraConfig::default());
+        let input = Input::from_str(&client.global_config, "hello", None);
+
+        let abort_signal = create_abort_signal();
+        let abort = abort_signal.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            abort.set_ctrlc();
+        });
+        let start = Instant::now();
+        let err = client
+            .chat_completions(input.clone(), abort_signal)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Aborted!");
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Aborted once the first chunk is rendered, which stays with the handler.
+        let abort_signal = create_abort_signal();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, abort_signal.clone());
+        let abort = abort_signal.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if let SseEvent::Text(_) = event {
+                    abort.set_ctrlc();
+                }
+            }
+        });
+        let start = Instant::now();
+        client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap();
+        assert!(abort_signal.aborted());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(handler.take().0, "Hi");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // The partial reply reaches the caller, flagged as aborted, so it can still be recorded.
+        let abort_signal = create_abort_signal();
+        let abort = abort_signal.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            abort.set_ctrlc();
+        });
+        let (output, tool_results, aborted) =
+            chat_completion_streaming(&input, &client, &client.global_config, abort_signal)
+                .await
+                .unwrap();
+        assert_eq!(output, "Hi");
+        assert!(tool_results.is_empty());
+        assert!(aborted);
+    }
+
+    #[tokio::test]
+    async fn test_openai_response_cache() {
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\n\
+            content-type: application/json\r\ncontent-length: 61\r\nconnection: close\r\n\r\n\
+            {\"error\":{\"type\":\"rate_limit\",\"message\":\"Slow down please.\"}}";
+        let completion = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+            content-length: 61\r\nconnection: close\r\n\r\n\
+            {\"choices\":[{\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"}}]}";
+        let replies = vec![(rate_limited, Duration::ZERO), (completion, Duration::ZERO)];
+        let (api_base, connections) = mock_server(replies).await;
+        let dir = std::env::temp_dir().join(format!("aichat-responses-{}", std::process::id()));
+        let client = mock_client(api_base, ExtraConfig::default());
+        client.global_config.write().response_cache = true;
+        client.global_config.write().response_cache_dir = Some(dir.clone());
+        client.global_config.write().chat_completions_retries = 0;
+        let input = Input::from_str(&client.global_config, "hello", None);
+
+        // Errors are not cached.
+        assert!(client.chat_completions(input.clone(), create_abort_signal()).await.is_err());
+        let output = client.chat_completions(input.clone(), create_abort_signal()).await.unwrap();
+        assert_eq!(output.text, "Hi");
+        let output = client.chat_completions(input.clone(), create_abort_signal()).await.unwrap();
+        assert_eq!(output.text, "Hi");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        client
+            .chat_completions_streaming(&input, &mut handler)
+            .await
+            .unwrap();
+        assert_eq!(handler.take().0, "Hi");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_openai_api_key_cmd() {
+        let mut client = mock_client("http://localhost".into(), ExtraConfig::default());
+        client.config.api_key_cmd = Some("echo '  sk-from-cmd  '".into());
+        assert_eq!(client.get_api_key().unwrap(), "sk-test");
+        client.config.api_key = None;
+        assert_eq!(client.get_api_key().unwrap(), "sk-from-cmd");
+
+        // The output is cached, the command runs once.
+        let dir = std::env::temp_dir().join(format!("aichat-api-key-cmd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let counter = dir.join("calls").display().to_string();
+        let cmd = format!("sh -c 'echo x >> {counter}; wc -l < {counter}'");
+        client.config.api_key_cmd = Some(cmd);
+        assert_eq!(client.get_api_key().unwrap(), "1");
+        assert_eq!(client.get_api_key().unwrap(), "1");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        client.config.api_key_cmd = Some("sh -c 'echo denied >&2; exit 1'".into());
+        let err = client.get_api_key().unwrap_err();
+        assert!(err.to_string().contains("exited with non-zero: denied"));
+        client.config.api_key_cmd = Some("true".into());
+        let err = client.get_api_key().unwrap_err();
+        assert!(err.to_string().contains("printed nothing"));
+    }
+
+    #[test]
+    fn test_openai_build_body_with_image() {
+        let model = Model::new("openai", "gpt-4o");
+        let content = MessageContent::Array(vec![
+            MessageContentPart::Text {
+                text: "What is in this image?".into(),
+            },
+            MessageContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: "data:image/png;base64,iVBORw0KGgo=".into(),
+                },
+            },
+        ]);
+        let messages = vec![Message::new(MessageRole::User, content)];
+        assert_eq!(
+            model.messages_tokens(&messages),
+            estimate_token_length("What is in this image?") + 765
+        );
+        let data = ChatCompletionsData {
+            messages,
+            temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            functions: None,
+            stream: false,
+            response_format: None,
+        };
+        let body = openai_build_chat_completions_body(data, &model);
+        assert_eq!(
+            body["messages"],
+            json!([{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is in this image?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                ]
+            }])
+        );
+    }
+
+    #[test]
+    fn test_openai_build_body_with_sampling_params() {
+        let model = Model::new("openai", "gpt-4o");
+        let build_body = |top_p, presence_penalty, frequency_penalty| {
+            let data = ChatCompletionsData {
+                messages: vec![Message::new(MessageRole::User, MessageContent::Text("hi".into()))],
+                temperature: None,
+                top_p,
+                presence_penalty,
+                frequency_penalty,
+                functions: None,
+                stream: false,
+                response_format: None,
+            };
+            openai_build_chat_completions_body(data, &model)
+        };
+        let fields = ["top_p", "presence_penalty", "frequency_penalty"];
+        let body = build_body(None, None, None);
+        for field in fields {
+            assert!(body.get(field).is_none(), "{field}");
+        }
+        let body = build_body(Some(0.9), None, None);
+        assert_eq!(body["top_p"], 0.9);
+        assert!(body.get("presence_penalty").is_none());
+        let body = build_body(None, Some(0.5), None);
+        assert_eq!(body["presence_penalty"], 0.5);
+        assert!(body.get("frequency_penalty").is_none());
+        let body = build_body(None, None, Some(-1.0));
+        assert_eq!(body["frequency_penalty"], -1.0);
+        assert!(body.get("top_p").is_none());
+    }
+
+    #[test]
+    fn test_openai_build_body_with_response_format() {
+        let model = Model::new("openai", "gpt-4o");
+        let build_body = |text: &str| {
+            let data = ChatCompletionsData {
+                messages: vec![Message::new(MessageRole::User, MessageContent::Text(text.into()))],
+                temperature: None,
+                top_p: None,
+                presence_penalty: None,
+                frequency_penalty: None,
+                functions: None,
+                stream: false,
+                response_format: Some(json!({ "type": "json_object" })),
+            };
+            openai_build_chat_completions_body(data, &model)
+        };
+        let body = build_body("List three colors as JSON");
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        let body = build_body("List three colors");
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "List three colors");
+    }
+
+    #[test]
+    fn test_openai_extract_tool_calls() {
+        let data = json!({
+            "id": "chatcmpl-9xyz",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_abc123",
+                        "type": "function",
+                        "function": {
+                            "name": "get_current_weather",
+                            "arguments": "{\"location\": \"Boston, MA\"}"
+                        }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 82, "completion_tokens": 17}
+        });
+        let output = openai_extract_chat_completions(&data).unwrap();
+        assert_eq!(output.text, "");
+        assert_eq!(output.tool_calls.len(), 1);
+        let call = &output.tool_calls[0];
+        assert_eq!(call.name, "get_current_weather");
+        assert_eq!(call.arguments, json!("{\"location\": \"Boston, MA\"}"));
+        assert_eq!(call.id.as_deref(), Some("call_abc123"));
+        assert_eq!(
+            output.usage(),
+            Some(TokenUsage {
+                input_tokens: 82,
+                output_tokens: 17
+            })
+        );
+    }
+
+    #[test]
+    fn test_openai_stream_usage() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":"Hi"}}],"usage":null}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"stop"}],"usage":null}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":1200,"completion_tokens":300,"total_tokens":1500}}"#,
+        ];
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        let mut tool_calls = IndexMap::new();
+        for chunk in chunks {
+            let data: Value = serde_json::from_str(chunk).unwrap();
+            openai_handle_stream_data(&data, &mut handler, &mut tool_calls).unwrap();
+        }
+        let usage = handler.get_usage().unwrap();
+        assert_eq!(
+            usage,
+            TokenUsage {
+                input_tokens: 1200,
+                output_tokens: 300
+            }
+        );
+        assert_eq!(handler.take().0, "Hi");
+
+        let mut model = Model::new("openai", "gpt-4o");
+        assert_eq!(usage.cost(&model), None);
+        model.data_mut().input_price = Some(5.0);
+        model.data_mut().output_price = Some(15.0);
+        assert_eq!(usage.cost(&model), Some(0.0105));
+    }
+
+    #[test]
+    fn test_openai_stream_tool_calls() {
+        // Two parallel calls whose fragments interleave, as some providers stream them.
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":"","tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_2","type":"function","function":{"name":"get_time","arguments":"{\"tz\":"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Paris\"}"}},{"index":1,"function":{"arguments":"\"UTC\"}"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ];
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        let mut tool_calls = IndexMap::new();
+        for chunk in chunks {
+            let data: Value = serde_json::from_str(chunk).unwrap();
+            openai_handle_stream_data(&data, &mut handler, &mut tool_calls).unwrap();
+        }
+        openai_flush_tool_calls(&mut handler, &mut tool_calls).unwrap();
+        let (text, calls) = handler.take();
+        assert_eq!(text, "");
+        let calls: Vec<_> = calls
+            .iter()
+            .map(|v| (v.id.as_deref(), v.name.as_str(), v.arguments.as_str()))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                (Some("call_1"), "get_weather", Some(r#"{"city":"Paris"}"#)),
+                (Some("call_2"), "get_time", Some(r#"{"tz":"UTC"}"#)),
+            ]
+        );
+    }
+}
@@ -2,7 +2,7 @@ use super::openai::*;
 use super::rag_dedicated::*;
 use super::*;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 
@@ -11,6 +11,8 @@ pub struct OpenAICompatibleConfig {
     pub name: Option<String>,
     pub api_base: Option<String>,
     pub api_key: Option<String>,
+    /// Replaces the `/chat/completions` path appended to `api_base`.
+    #[serde(alias = "chat_endpoint_path")]
     pub chat_endpoint: Option<String>,
     #[serde(default)]
     pub models: Vec<ModelData>,
@@ -51,6 +53,9 @@ impl OpenAICompatibleClient {
             .chat_endpoint
             .as_deref()
             .unwrap_or("/chat/completions");
+        if !chat_endpoint.starts_with('/') {
+            bail!("Invalid 'chat_endpoint' in client configuration, expect a path starting with '/', got '{chat_endpoint}'")
+        }
 
         let url = format!("{api_base}{chat_endpoint}");
 
@@ -133,3 +138,59 @@ impl_client_trait!(
     openai_embeddings,
     rag_dedicated_rerank
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_openai_compatible_chat_endpoint() {
+        let config: OpenAICompatibleConfig = serde_json::from_value(serde_json::json!({
+            "name": "gateway",
+            "api_base": "https://gateway.example.com/v1",
+        }))
+        .unwrap();
+        let mut client = OpenAICompatibleClient {
+            global_config: Arc::new(RwLock::new(Config::default())),
+            config,
+            model: Model::new("gateway", "llama3"),
+        };
+        let url = |client: &OpenAICompatibleClient| {
+            let data = ChatCompletionsData {
+                messages: vec![Message::new(
+                    MessageRole::User,
+                    MessageContent::Text("hi".into()),
+                )],
+                temperature: None,
+                top_p: None,
+                presence_penalty: None,
+                frequency_penalty: None,
+                functions: None,
+                stream: false,
+                response_format: None,
+            };
+            let builder = client.chat_completions_builder(&ReqwestClient::new(), data)?;
+            Ok::<_, anyhow::Error>(builder.build()?.url().to_string())
+        };
+        assert_eq!(
+            url(&client).unwrap(),
+            "https://gateway.example.com/v1/chat/completions"
+        );
+
+        client.config = serde_json::from_value(serde_json::json!({
+            "api_base": "https://gateway.example.com",
+            "chat_endpoint_path": "/v1/chat",
+        }))
+        .unwrap();
+        assert_eq!(url(&client).unwrap(), "https://gateway.example.com/v1/chat");
+
+        client.config.chat_endpoint = Some("v1/chat".into());
+        let err = url(&client).unwrap_err();
+        assert!(err.to_string().contains("expect a path starting with '/'"));
+    }
+}
@@ -198,10 +198,16 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions,
         stream: _,
+        response_format,
     } = data;
 
+    check_response_format(&response_format, model)?;
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
+
     let mut has_upload = false;
     let input = if model.supports_vision() {
         let messages: Vec<Value> = messages
@@ -137,10 +137,16 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions: _,
         stream,
+        response_format,
     } = data;
 
+    check_response_format(&response_format, model)?;
+    warn_unsupported_penalties(presence_penalty, frequency_penalty, model);
+
     let prompt = generate_prompt(&messages, smart_prompt_format(model.name()))?;
 
     let mut input = json!({
@@ -1,7 +1,7 @@
-use super::{catch_error, ToolCall};
+use super::{catch_error_with_retry_after, parse_retry_after, ClientError, TokenUsage, ToolCall};
 use crate::utils::AbortSignal;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
 use futures_util::{Stream, StreamExt};
 use reqwest::RequestBuilder;
 use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
@@ -13,6 +13,7 @@ pub struct SseHandler {
     abort: AbortSignal,
     buffer: String,
     tool_calls: Vec<ToolCall>,
+    usage: Option<TokenUsage>,
 }
 
 impl SseHandler {
@@ -22,6 +23,7 @@ impl SseHandler {
             abort,
             buffer: String::new(),
             tool_calls: Vec::new(),
+            usage: None,
         }
     }
 
@@ -55,6 +57,27 @@ impl SseHandler {
         Ok(())
     }
 
+    pub fn usage(&mut self, usage: TokenUsage) {
+        self.usage = Some(usage);
+    }
+
+    pub fn get_usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+
+    pub fn get_buffer(&self) -> &str {
+        &self.buffer
+    }
+
+    pub fn get_tool_calls(&self) -> &[ToolCall] {
+        &self.tool_calls
+    }
+
+    /// Whether nothing was handled yet.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.tool_calls.is_empty()
+    }
+
     pub fn get_abort(&self) -> AbortSignal {
         self.abort.clone()
     }
@@ -86,6 +109,26 @@ pub struct SseMmessage {
     pub data: String,
 }
 
+/// A successful streaming request answered with something other than an event stream, e.g. by
+/// a provider ignoring `stream: true`.
+#[derive(Debug)]
+pub struct NonSseResponse {
+    pub content_type: String,
+    pub text: String,
+}
+
+impl std::fmt::Display for NonSseResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid response event-stream. content-type: {}, data: {}",
+            self.content_type, self.text
+        )
+    }
+}
+
+impl std::error::Error for NonSseResponse {}
+
 pub async fn sse_stream<F>(builder: RequestBuilder, mut handle: F) -> Result<()>
 where
     F: FnMut(SseMmessage) -> Result<bool>,
@@ -107,6 +150,7 @@ where
                 match err {
                     EventSourceError::StreamEnded => {}
                     EventSourceError::InvalidStatusCode(status, res) => {
+                        let retry_after = parse_retry_after(res.headers());
                         let text = res.text().await?;
                         let data: Value = match text.parse() {
                             Ok(data) => data,
@@ -117,14 +161,19 @@ where
                                 );
                             }
                         };
-                        catch_error(&data, status.as_u16())?;
+                        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
                     }
                     EventSourceError::InvalidContentType(header_value, res) => {
                         let text = res.text().await?;
-                        bail!(
-                            "Invalid response event-stream. content-type: {}, data: {text}",
-                            header_value.to_str().unwrap_or_default()
-                        );
+                        return Err(NonSseResponse {
+                            content_type: header_value.to_str().unwrap_or_default().to_string(),
+                            text,
+                        }
+                        .into());
+                    }
+                    // Keep the reqwest error in the chain, so that timeouts can be told apart.
+                    EventSourceError::Transport(err) => {
+                        return Err(ClientError::from(err).into());
                     }
                     _ => {
                         bail!("{}", err);
@@ -141,13 +190,12 @@ pub async fn json_stream<S, F, E>(mut stream: S, mut handle: F) -> Result<()>
 where
     S: Stream<Item = Result<bytes::Bytes, E>> + Unpin,
     F: FnMut(&str) -> Result<()>,
-    E: std::error::Error,
+    E: std::error::Error + Send + Sync + 'static,
 {
     let mut parser = JsonStreamParser::default();
     let mut unparsed_bytes = vec![];
     while let Some(chunk_bytes) = stream.next().await {
-        let chunk_bytes =
-            chunk_bytes.map_err(|err| anyhow!("Failed to read json stream, {err}"))?;
+        let chunk_bytes = chunk_bytes.context("Failed to read json stream")?;
         unparsed_bytes.extend(chunk_bytes);
         match std::str::from_utf8(&unparsed_bytes) {
             Ok(text) => {
@@ -176,9 +176,10 @@ pub async fn gemini_chat_completions_streaming(
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
     let res = builder.send().await?;
     let status = res.status();
+    let retry_after = parse_retry_after(res.headers());
     let data: Value = res.json().await?;
     if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
+        catch_error_with_retry_after(&data, status.as_u16(), retry_after)?;
     }
     let res_body: EmbeddingsResBody =
         serde_json::from_value(data).context("Invalid embeddings data")?;
@@ -254,10 +255,21 @@ pub fn gemini_build_chat_completions_body(
         mut messages,
         temperature,
         top_p,
+        presence_penalty,
+        frequency_penalty,
         functions,
         stream: _,
+        response_format,
     } = data;
 
+    let json_mode = match &response_format {
+        Some(v) if v["type"] == "json_object" => true,
+        _ => {
+            check_response_format(&response_format, model)?;
+            false
+        }
+    };
+
     let system_message = if model.name().starts_with("gemini-1.5-") {
         extract_system_message(&mut messages)
     } else {
@@ -347,6 +359,15 @@ pub fn gemini_build_chat_completions_body(
     if let Some(v) = top_p {
         body["generationConfig"]["topP"] = v.into();
     }
+    if let Some(v) = presence_penalty {
+        body["generationConfig"]["presencePenalty"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["generationConfig"]["frequencyPenalty"] = v.into();
+    }
+    if json_mode {
+        body["generationConfig"]["responseMimeType"] = "application/json".into();
+    }
 
     if let Some(functions) = functions {
         // Gemini doesn't support functions with parameters that have empty properties, so we need to patch it.
@@ -447,3 +468,57 @@ fn default_adc_file() -> Option<PathBuf> {
     path.push("application_default_credentials.json");
     Some(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_data(response_format: Option<Value>) -> ChatCompletionsData {
+        ChatCompletionsData {
+            messages: vec![Message::new(
+                MessageRole::User,
+                MessageContent::Text("List colors as JSON".into()),
+            )],
+            temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            functions: None,
+            stream: false,
+            response_format,
+        }
+    }
+
+    #[test]
+    fn test_gemini_build_body_with_response_format() {
+        let model = Model::new("gemini", "gemini-1.5-pro");
+        let data = build_data(Some(json!({ "type": "json_object" })));
+        let body = gemini_build_chat_completions_body(data, &model).unwrap();
+        assert_eq!(
+            body["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+
+        let data = build_data(None);
+        let body = gemini_build_chat_completions_body(data, &model).unwrap();
+        assert!(body["generationConfig"].get("responseMimeType").is_none());
+
+        let data = build_data(Some(json!({ "type": "json_schema" })));
+        let err = gemini_build_chat_completions_body(data, &model).unwrap_err();
+        assert!(err.to_string().contains("not supported by 'gemini:gemini-1.5-pro'"));
+    }
+    #[test]
+    fn test_gemini_build_body_with_penalties() {
+        let model = Model::new("gemini", "gemini-1.5-pro");
+        let body = gemini_build_chat_completions_body(build_data(None), &model).unwrap();
+        assert!(body["generationConfig"].get("presencePenalty").is_none());
+        assert!(body["generationConfig"].get("frequencyPenalty").is_none());
+
+        let mut data = build_data(None);
+        data.presence_penalty = Some(0.5);
+        data.frequency_penalty = Some(-1.0);
+        let body = gemini_build_chat_completions_body(data, &model).unwrap();
+        assert_eq!(body["generationConfig"]["presencePenalty"], 0.5);
+        assert_eq!(body["generationConfig"]["frequencyPenalty"], -1.0);
+    }
+}
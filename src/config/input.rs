@@ -5,11 +5,12 @@ use crate::client::{
     MessageContentPart, MessageRole, Model,
 };
 use crate::function::{ToolResult, ToolResults};
-use crate::utils::{base64_encode, sha256, AbortSignal};
+use crate::utils::{base64_encode, estimate_token_length, sha256, warning_text, AbortSignal};
 
 use anyhow::{bail, Context, Result};
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
+use serde_json::json;
 use std::{collections::HashMap, fs::File, io::Read, path::Path};
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
@@ -30,6 +31,7 @@ pub struct Input {
     data_urls: HashMap<String, String>,
     tool_call: Option<ToolResults>,
     rag_name: Option<String>,
+    rag_chunks: Option<(usize, usize)>,
     role: Role,
     with_session: bool,
     with_agent: bool,
@@ -48,6 +50,7 @@ impl Input {
             data_urls: Default::default(),
             tool_call: None,
             rag_name: None,
+            rag_chunks: None,
             role,
             with_session,
             with_agent,
@@ -86,6 +89,7 @@ impl Input {
             data_urls,
             tool_call: Default::default(),
             rag_name: None,
+            rag_chunks: None,
             role,
             with_session,
             with_agent,
@@ -144,39 +148,27 @@ impl Input {
             return Ok(());
         }
         if !self.text.is_empty() {
-            let rag = self.config.read().rag.clone();
-            if let Some(rag) = rag {
-                let (top_k, min_score_vector_search, min_score_keyword_search) = {
-                    let config = self.config.read();
-                    (
-                        config.rag_top_k,
-                        config.rag_min_score_vector_search,
-                        config.rag_min_score_keyword_search,
-                    )
-                };
-                let rerank = match self.config.read().rag_reranker_model.clone() {
-                    Some(reranker_model_id) => {
-                        let min_score = self.config.read().rag_min_score_rerank;
-                        let rerank_model =
-                            Model::retrieve_reranker(&self.config.read(), &reranker_model_id)?;
-                        let rerank_client = init_client(&self.config, Some(rerank_model))?;
-                        Some((rerank_client, min_score))
-                    }
-                    None => None,
-                };
-                let embeddings = rag
-                    .search(
-                        &self.text,
-                        top_k,
-                        min_score_vector_search,
-                        min_score_keyword_search,
-                        rerank,
-                        abort_signal,
-                    )
-                    .await?;
-                let text = self.config.read().rag_template(&embeddings, &self.text);
+            let rags = self.config.read().rags();
+            if !rags.is_empty() {
+                let max_context_tokens = self.rag_context_budget()?;
+                let rags: Vec<&Rag> = rags.iter().map(|v| v.as_ref()).collect();
+                let context = Rag::search_many(
+                    &rags,
+                    &self.config,
+                    &self.text,
+                    max_context_tokens,
+                    abort_signal,
+                )
+                .await?;
+                self.rag_chunks = Some((context.used, context.retrieved));
+                if context.is_empty() {
+                    debug!("no relevant rag hits, skip the rag template");
+                    return Ok(());
+                }
+                let text = self.config.read().rag_template(&context.text, &self.text);
                 self.patched_text = Some(text);
-                self.rag_name = Some(rag.name().to_string());
+                let names: Vec<&str> = rags.iter().map(|v| v.name()).collect();
+                self.rag_name = Some(names.join(","));
             }
         }
         Ok(())
@@ -186,6 +178,19 @@ impl Input {
         self.rag_name.as_deref()
     }
 
+    /// How many of the retrieved chunks were used, and how many were retrieved.
+    pub fn rag_chunks(&self) -> Option<(usize, usize)> {
+        self.rag_chunks
+    }
+
+    /// Tokens left for retrieved chunks once the conversation and the rag template are counted.
+    fn rag_context_budget(&self) -> Result<Option<usize>> {
+        let messages = self.build_messages()?;
+        let template = self.config.read().rag_template(" ", "");
+        let remaining = self.role().model().remaining_input_tokens(&messages);
+        Ok(remaining.map(|v| v.saturating_sub(estimate_token_length(&template))))
+    }
+
     pub fn merge_tool_call(mut self, output: String, tool_results: Vec<ToolResult>) -> Self {
         match self.tool_call.as_mut() {
             Some(exist_tool_results) => {
@@ -210,16 +215,37 @@ impl Input {
             bail!("The current model does not support vision. Is the model configured with `supports_vision: true`?");
         }
         let messages = self.build_messages()?;
-        self.config.read().model.guard_max_input_tokens(&messages)?;
+        let messages_len = messages.len();
+        let messages = self.config.read().model.fit_messages(messages, 0)?;
+        if messages.len() < messages_len {
+            eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "WARNING: Left out the {} oldest messages to stay within max_input_tokens.",
+                    messages_len - messages.len()
+                ))
+            );
+        }
         let temperature = self.role().temperature();
         let top_p = self.role().top_p();
+        let (presence_penalty, frequency_penalty) = {
+            let config = self.config.read();
+            (config.presence_penalty, config.frequency_penalty)
+        };
         let functions = self.config.read().select_functions(model, self.role());
+        let response_format = match self.config.read().json_mode {
+            true => Some(json!({ "type": "json_object" })),
+            false => None,
+        };
         Ok(ChatCompletionsData {
             messages,
             temperature,
             top_p,
+            presence_penalty,
+            frequency_penalty,
             functions,
             stream,
+            response_format,
         })
     }
 
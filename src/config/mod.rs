@@ -10,11 +10,15 @@ use self::session::Session;
 
 use crate::client::{
     create_client_config, list_chat_models, list_client_types, list_reranker_models, ClientConfig,
-    Model, OPENAI_COMPATIBLE_PLATFORMS,
+    Model, TokenUsage, OPENAI_COMPATIBLE_PLATFORMS,
 };
 use crate::function::{FunctionDeclaration, Functions, ToolResult};
-use crate::rag::Rag;
-use crate::render::{MarkdownRender, RenderOptions};
+use crate::rag::{
+    check_chunk_overrides, check_search_options, delete_saved_rag, list_saved_rags, rag_file_path,
+    rename_saved_rag, saved_rag_path, ChunkSizeUnit, EmbeddingCache, MetadataFilter, Rag,
+    RagChunkOverride, RagDistance, RagSearchMode, RagVectorCompression, SearchHit, RAG_FILE_SUFFIX,
+};
+use crate::render::{load_theme, MarkdownRender, RenderOptions};
 use crate::utils::*;
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -45,6 +49,8 @@ const ENV_FILE_NAME: &str = ".env";
 const MESSAGES_FILE_NAME: &str = "messages.md";
 const SESSIONS_DIR_NAME: &str = "sessions";
 const RAGS_DIR_NAME: &str = "rags";
+const EMBEDDING_CACHE_DIR_NAME: &str = "embedding_cache";
+const RESPONSE_CACHE_DIR_NAME: &str = "response_cache";
 const FUNCTIONS_DIR_NAME: &str = "functions";
 const FUNCTIONS_FILE_NAME: &str = "functions.json";
 const FUNCTIONS_BIN_DIR_NAME: &str = "bin";
@@ -70,12 +76,14 @@ __CONTEXT__
 When answer to user:
 - If you don't know, just say that you don't know.
 - If you don't know when you are not sure, ask for clarification.
-Avoid mentioning that you obtained the information from the context.
+When using information from the context, cite its source as given in the context, e.g. [source: guide.md#3].
 And answer according to the language of the user's question.
 
 Given the context information, answer the query.
 Query: __INPUT__"#;
 
+const RAG_CHUNK_TEMPLATE: &str = "[source: __SOURCE__#__CHUNK_INDEX__]\n__CONTENT__";
+
 const LEFT_PROMPT: &str = "{color.green}{?session {?agent {agent}>}{session}{?role /}}{!session {?agent {agent}>}}{role}{?rag @{rag}}{color.cyan}{?session )}{!session >}{color.reset} ";
 const RIGHT_PROMPT: &str = "{color.purple}{?session {?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}";
 
@@ -87,6 +95,13 @@ pub struct Config {
     pub model_id: String,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub timeout_secs: u64,
+    pub chat_completions_retries: usize,
+    pub response_cache: bool,
+    pub response_cache_ttl: u64,
+    pub response_cache_dir: Option<PathBuf>,
 
     pub dry_run: bool,
     pub save: bool,
@@ -114,15 +129,56 @@ pub struct Config {
     pub rag_top_k: usize,
     pub rag_chunk_size: Option<usize>,
     pub rag_chunk_overlap: Option<usize>,
+    pub rag_chunk_size_unit: ChunkSizeUnit,
+    pub rag_chunk_overrides: IndexMap<String, RagChunkOverride>,
+    pub rag_vector_compression: RagVectorCompression,
     pub rag_min_score_vector_search: f32,
     pub rag_min_score_keyword_search: f32,
+    pub rag_min_score_fused: f32,
     pub rag_min_score_rerank: f32,
+    #[serde(alias = "rag_vector_weight")]
+    pub rag_rrf_vector_weight: f32,
+    #[serde(alias = "rag_text_weight")]
+    pub rag_rrf_text_weight: f32,
+    pub rag_mmr_lambda: f32,
+    pub rag_recency_weight: f32,
+    pub rag_recency_half_life_days: f32,
+    pub rag_multi_query: usize,
+    pub rag_neighbor_chunks: usize,
+    pub rag_rrf_k: usize,
+    pub rag_search_mode: RagSearchMode,
+    pub rag_ef_search: usize,
+    pub rag_distance: RagDistance,
+    pub rag_hnsw_max_connections: Option<usize>,
+    pub rag_hnsw_ef_construction: Option<usize>,
+    pub rag_hnsw_max_layers: Option<usize>,
+    pub rag_bm25_language: String,
+    pub rag_bm25_stopwords: Option<Vec<String>>,
+    pub rag_bm25_stemming: bool,
+    pub rag_bm25_cjk: bool,
+    pub rag_crawl_max_depth: usize,
+    pub rag_crawl_max_pages: usize,
+    pub rag_sitemap_include: Option<String>,
+    pub rag_sitemap_exclude: Option<String>,
+    pub rag_csv_columns: Option<Vec<String>>,
+    pub rag_strict: bool,
+    pub rag_dedup: bool,
+    pub rag_dedup_similarity: Option<f64>,
+    pub rag_respect_gitignore: bool,
+    pub rag_batch_files: usize,
+    pub rag_embedding_cache: bool,
+    pub rag_embedding_cache_size: usize,
+    pub rag_embedding_retries: usize,
+    pub rag_embedding_concurrency: usize,
+    pub rag_cost_limit: Option<f64>,
     #[serde(default)]
     pub document_loaders: HashMap<String, String>,
     pub rag_template: Option<String>,
+    pub rag_chunk_template: Option<String>,
 
     pub highlight: bool,
     pub light_theme: bool,
+    pub render_theme: Option<String>,
     pub left_prompt: Option<String>,
     pub right_prompt: Option<String>,
 
@@ -136,6 +192,13 @@ pub struct Config {
     pub session: Option<Session>,
     #[serde(skip)]
     pub rag: Option<Arc<Rag>>,
+    /// Rags searched along with `rag`, e.g. from `.rag docs,notes`.
+    #[serde(skip)]
+    pub extra_rags: Vec<Arc<Rag>>,
+    #[serde(skip)]
+    pub rag_filter: Option<MetadataFilter>,
+    #[serde(skip)]
+    pub json_mode: bool,
     #[serde(skip)]
     pub agent: Option<Agent>,
     #[serde(skip)]
@@ -146,6 +209,8 @@ pub struct Config {
     pub working_mode: WorkingMode,
     #[serde(skip)]
     pub last_message: Option<(Input, String)>,
+    #[serde(skip)]
+    pub last_usage: Option<(TokenUsage, Option<f64>)>,
 }
 
 impl Default for Config {
@@ -154,6 +219,13 @@ impl Default for Config {
             model_id: Default::default(),
             temperature: None,
             top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            timeout_secs: 300,
+            chat_completions_retries: 3,
+            response_cache: false,
+            response_cache_ttl: 86400,
+            response_cache_dir: None,
 
             dry_run: false,
             save: false,
@@ -176,11 +248,49 @@ impl Default for Config {
             rag_top_k: 4,
             rag_chunk_size: None,
             rag_chunk_overlap: None,
+            rag_chunk_size_unit: Default::default(),
+            rag_chunk_overrides: Default::default(),
+            rag_vector_compression: Default::default(),
             rag_min_score_vector_search: 0.0,
             rag_min_score_keyword_search: 0.0,
+            rag_min_score_fused: 0.0,
             rag_min_score_rerank: 0.0,
+            rag_rrf_vector_weight: 1.0,
+            rag_rrf_text_weight: 1.0,
+            rag_mmr_lambda: 0.0,
+            rag_recency_weight: 0.0,
+            rag_recency_half_life_days: 30.0,
+            rag_multi_query: 0,
+            rag_neighbor_chunks: 0,
+            rag_rrf_k: 60,
+            rag_search_mode: Default::default(),
+            rag_ef_search: 30,
+            rag_distance: Default::default(),
+            rag_hnsw_max_connections: None,
+            rag_hnsw_ef_construction: None,
+            rag_hnsw_max_layers: None,
+            rag_bm25_language: "english".into(),
+            rag_bm25_stopwords: None,
+            rag_bm25_stemming: false,
+            rag_bm25_cjk: false,
+            rag_crawl_max_depth: 3,
+            rag_crawl_max_pages: 100,
+            rag_sitemap_include: None,
+            rag_sitemap_exclude: None,
+            rag_csv_columns: None,
+            rag_strict: false,
+            rag_dedup: true,
+            rag_dedup_similarity: None,
+            rag_respect_gitignore: false,
+            rag_batch_files: 0,
+            rag_embedding_cache: true,
+            rag_embedding_cache_size: 10000,
+            rag_embedding_retries: 3,
+            rag_embedding_concurrency: 4,
+            rag_cost_limit: None,
             document_loaders: Default::default(),
             rag_template: None,
+            rag_chunk_template: None,
 
             save_session: None,
             compress_threshold: 4000,
@@ -189,6 +299,7 @@ impl Default for Config {
 
             highlight: true,
             light_theme: false,
+            render_theme: None,
             left_prompt: None,
             right_prompt: None,
 
@@ -198,11 +309,15 @@ impl Default for Config {
             role: None,
             session: None,
             rag: None,
+            extra_rags: vec![],
+            rag_filter: None,
+            json_mode: false,
             agent: None,
             model: Default::default(),
             functions: Default::default(),
             working_mode: WorkingMode::Command,
             last_message: None,
+            last_usage: None,
         }
     }
 }
@@ -226,6 +341,13 @@ impl Config {
         if let Some(wrap) = config.wrap.clone() {
             config.set_wrap(&wrap)?;
         }
+        check_chunk_overrides(&config.rag_chunk_overrides)?;
+        if let Some(v) = config.rag_dedup_similarity {
+            if v <= 0.0 || v > 1.0 {
+                bail!("Invalid rag_dedup_similarity {v}, it must be above 0 and at most 1");
+            }
+        }
+        check_search_options(&config)?;
 
         config.working_mode = working_mode;
 
@@ -235,7 +357,7 @@ impl Config {
         config.setup_model()?;
         config.setup_highlight();
         config.setup_light_theme()?;
-        config.setup_document_loaders();
+        config.setup_render_theme();
 
         Ok(config)
     }
@@ -306,6 +428,23 @@ impl Config {
         }
     }
 
+    pub fn embedding_cache_dir() -> Result<PathBuf> {
+        Self::local_path(EMBEDDING_CACHE_DIR_NAME)
+    }
+
+    pub fn response_cache_path(&self) -> Result<PathBuf> {
+        match &self.response_cache_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Self::local_path(RESPONSE_CACHE_DIR_NAME),
+        }
+    }
+
+    pub fn clear_embedding_cache() -> Result<()> {
+        let count = EmbeddingCache::new(Self::embedding_cache_dir()?, 0).clear()?;
+        println!("✨ Removed {count} cached embeddings");
+        Ok(())
+    }
+
     pub fn functions_dir() -> Result<PathBuf> {
         match env::var(get_env_name("functions_dir")) {
             Ok(value) => Ok(PathBuf::from(value)),
@@ -326,14 +465,12 @@ impl Config {
     }
 
     pub fn rag_file(&self, name: &str) -> Result<PathBuf> {
-        let path = if self.agent.is_none() {
-            Self::rags_dir()?.join(format!("{name}.bin"))
-        } else {
+        let dir = if self.agent.is_none() {
             Self::rags_dir()?
-                .join(AGENTS_DIR_NAME)
-                .join(format!("{name}.bin"))
+        } else {
+            Self::rags_dir()?.join(AGENTS_DIR_NAME)
         };
-        Ok(path)
+        Ok(rag_file_path(&dir, name))
     }
 
     pub fn agents_config_dir() -> Result<PathBuf> {
@@ -508,6 +645,14 @@ impl Config {
             ),
             ("temperature", format_option_value(&role.temperature())),
             ("top_p", format_option_value(&role.top_p())),
+            (
+                "presence_penalty",
+                format_option_value(&self.presence_penalty),
+            ),
+            (
+                "frequency_penalty",
+                format_option_value(&self.frequency_penalty),
+            ),
             ("dry_run", self.dry_run.to_string()),
             ("save", self.save.to_string()),
             ("keybindings", self.keybindings.stringify().into()),
@@ -522,8 +667,24 @@ impl Config {
                 format_option_value(&self.rag_reranker_model),
             ),
             ("rag_top_k", self.rag_top_k.to_string()),
+            (
+                "rag_rrf_vector_weight",
+                self.rag_rrf_vector_weight.to_string(),
+            ),
+            ("rag_rrf_text_weight", self.rag_rrf_text_weight.to_string()),
+            ("rag_mmr_lambda", self.rag_mmr_lambda.to_string()),
+            ("rag_recency_weight", self.rag_recency_weight.to_string()),
+            (
+                "rag_recency_half_life_days",
+                self.rag_recency_half_life_days.to_string(),
+            ),
+            ("rag_multi_query", self.rag_multi_query.to_string()),
+            ("rag_neighbor_chunks", self.rag_neighbor_chunks.to_string()),
+            ("rag_search_mode", self.rag_search_mode.as_str().into()),
+            ("rag_filter", format_option_value(&self.rag_filter)),
             ("highlight", self.highlight.to_string()),
             ("light_theme", self.light_theme.to_string()),
+            ("render_theme", format_option_value(&self.render_theme)),
             ("config_file", display_path(&Self::config_file()?)),
             ("roles_file", display_path(&Self::roles_file()?)),
             ("env_file", display_path(&Self::env_file()?)),
@@ -551,6 +712,22 @@ impl Config {
         Ok(output)
     }
 
+    /// Set a rag search setting, keeping the previous value if the new one is invalid.
+    fn set_search_option<T: std::str::FromStr>(
+        &mut self,
+        value: &str,
+        field: fn(&mut Self) -> &mut T,
+    ) -> Result<()> {
+        if let Some(value) = parse_value(value)? {
+            let previous = std::mem::replace(field(self), value);
+            if let Err(err) = check_search_options(self) {
+                *field(self) = previous;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
     pub fn update(&mut self, data: &str) -> Result<()> {
         let parts: Vec<&str> = data.split_whitespace().collect();
         if parts.len() != 2 {
@@ -571,6 +748,12 @@ impl Config {
                 let value = parse_value(value)?;
                 self.set_top_p(value);
             }
+            "presence_penalty" => {
+                self.presence_penalty = parse_value(value)?;
+            }
+            "frequency_penalty" => {
+                self.frequency_penalty = parse_value(value)?;
+            }
             "rag_reranker_model" => {
                 self.rag_reranker_model = if value == "null" {
                     None
@@ -579,10 +762,40 @@ impl Config {
                 }
             }
             "rag_top_k" => {
+                self.set_search_option(value, |v| &mut v.rag_top_k)?;
+            }
+            "rag_rrf_vector_weight" => {
+                self.set_search_option(value, |v| &mut v.rag_rrf_vector_weight)?;
+            }
+            "rag_rrf_text_weight" => {
+                self.set_search_option(value, |v| &mut v.rag_rrf_text_weight)?;
+            }
+            "rag_mmr_lambda" => {
+                self.set_search_option(value, |v| &mut v.rag_mmr_lambda)?;
+            }
+            "rag_recency_weight" => {
+                self.set_search_option(value, |v| &mut v.rag_recency_weight)?;
+            }
+            "rag_recency_half_life_days" => {
+                self.set_search_option(value, |v| &mut v.rag_recency_half_life_days)?;
+            }
+            "rag_multi_query" => {
                 if let Some(value) = parse_value(value)? {
-                    self.rag_top_k = value;
+                    self.rag_multi_query = value;
                 }
             }
+            "rag_neighbor_chunks" => {
+                if let Some(value) = parse_value(value)? {
+                    self.rag_neighbor_chunks = value;
+                }
+            }
+            "rag_search_mode" => {
+                self.rag_search_mode = value.parse()?;
+            }
+            "rag_filter" => {
+                let value: Option<String> = parse_value(value)?;
+                self.rag_filter = value.map(|v| MetadataFilter::parse(&v)).transpose()?;
+            }
             "function_calling" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 if value && self.functions.is_empty() {
@@ -922,6 +1135,7 @@ impl Config {
         self.last_message = None;
     }
 
+    /// Use a rag, or several given as a comma-separated list of names, which must all exist.
     pub async fn use_rag(
         config: &GlobalConfig,
         rag: Option<&str>,
@@ -940,6 +1154,13 @@ impl Config {
                 }
                 Rag::init(config, TEMP_RAG_NAME, &rag_path, &[], abort_signal).await?
             }
+            Some(names) if names.contains(',') => {
+                let mut rags = Self::load_rags(config, names)?.into_iter();
+                let rag = rags.next().context("No rag")?;
+                config.write().extra_rags = rags.map(Arc::new).collect();
+                config.write().rag = Some(Arc::new(rag));
+                return Ok(());
+            }
             Some(name) => {
                 let rag_path = config.read().rag_file(name)?;
                 if !rag_path.exists() {
@@ -949,10 +1170,48 @@ impl Config {
                 }
             }
         };
+        config.write().extra_rags.clear();
         config.write().rag = Some(Arc::new(rag));
         Ok(())
     }
 
+    /// The rags in use, `rag` first.
+    pub fn rags(&self) -> Vec<Arc<Rag>> {
+        self.rag
+            .iter()
+            .chain(self.extra_rags.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Search existing rags, given as a comma-separated list of names, without involving the
+    /// chat model.
+    pub async fn search_rag(
+        config: &GlobalConfig,
+        names: &str,
+        query: &str,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<SearchHit>> {
+        let rags = Self::load_rags(config, names)?;
+        let rags: Vec<&Rag> = rags.iter().collect();
+        Rag::search_many_detailed(&rags, config, query, abort_signal).await
+    }
+
+    fn load_rags(config: &GlobalConfig, names: &str) -> Result<Vec<Rag>> {
+        let mut rags = vec![];
+        for name in names.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            let rag_path = config.read().rag_file(name)?;
+            if !rag_path.exists() {
+                bail!("Unknown rag '{name}'")
+            }
+            rags.push(Rag::load(config, name, &rag_path)?);
+        }
+        if rags.is_empty() {
+            bail!("No rag named in '{names}'")
+        }
+        Ok(rags)
+    }
+
     pub async fn rebuild_rag(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<()> {
         let rag_name = match config.read().rag.clone() {
             Some(v) => v.name().to_string(),
@@ -965,6 +1224,177 @@ impl Config {
         Ok(())
     }
 
+    pub fn compact_rag(config: &GlobalConfig) -> Result<()> {
+        let rag_name = match config.read().rag.clone() {
+            Some(v) => v.name().to_string(),
+            None => bail!("No RAG"),
+        };
+        let rag_path = config.read().rag_file(&rag_name)?;
+        let mut rag = Rag::load(config, &rag_name, &rag_path)?;
+        if rag.compact()? {
+            rag.save(&rag_path)?;
+            println!("✨ Compacted rag '{rag_name}'");
+        } else {
+            println!("Rag '{rag_name}' is already compact");
+        }
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    /// Delete a saved rag, asking first on a terminal, and stop using it if it is in use.
+    pub fn delete_rag(config: &GlobalConfig, name: &str) -> Result<()> {
+        let rags_dir = Self::rags_dir()?;
+        saved_rag_path(&rags_dir, name)?;
+        if *IS_STDOUT_TERMINAL {
+            let ans = Confirm::new(&format!("Delete rag '{name}'?"))
+                .with_default(false)
+                .prompt()?;
+            if !ans {
+                return Ok(());
+            }
+        }
+        delete_saved_rag(&rags_dir, name)?;
+        config.write().detach_rag(name);
+        println!("✨ Deleted rag '{name}'");
+        Ok(())
+    }
+
+    /// Rename a saved rag, reloading it under its new name if it is in use.
+    pub fn rename_rag(config: &GlobalConfig, name: &str, new_name: &str) -> Result<()> {
+        let rags_dir = Self::rags_dir()?;
+        rename_saved_rag(&rags_dir, name, new_name)?;
+        if config.read().agent.is_none() {
+            let rags = config.read().rags();
+            if rags.iter().any(|v| v.name() == name) {
+                let rag_path = config.read().rag_file(new_name)?;
+                let mut rags = rags
+                    .into_iter()
+                    .map(|v| match v.name() == name {
+                        true => Rag::load(config, new_name, &rag_path).map(Arc::new),
+                        false => Ok(v),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter();
+                let mut config = config.write();
+                config.rag = rags.next();
+                config.extra_rags = rags.collect();
+            }
+        }
+        println!("✨ Renamed rag '{name}' to '{new_name}'");
+        Ok(())
+    }
+
+    /// Stop using the saved rag `name`, the next rag in use taking its place.
+    fn detach_rag(&mut self, name: &str) {
+        if self.agent.is_some() {
+            return;
+        }
+        let mut rags = self
+            .rags()
+            .into_iter()
+            .filter(|v| v.name() != name)
+            .collect::<Vec<_>>()
+            .into_iter();
+        self.rag = rags.next();
+        self.extra_rags = rags.collect();
+    }
+
+    pub fn export_rag(config: &GlobalConfig, path: &Path, with_vectors: bool) -> Result<()> {
+        let rag = match config.read().rag.clone() {
+            Some(v) => v,
+            None => bail!("No RAG"),
+        };
+        let count = rag.export_jsonl(path, with_vectors)?;
+        println!("✨ Exported {count} chunks to '{}'", path.display());
+        Ok(())
+    }
+
+    pub fn import_rag(config: &GlobalConfig, name: &str, path: &Path) -> Result<()> {
+        let rag = Rag::import_jsonl(config, name, path)?;
+        println!("✨ Imported rag '{name}' from '{}'", path.display());
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    pub async fn watch_rag(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<()> {
+        let rag_name = match config.read().rag.clone() {
+            Some(v) => v.name().to_string(),
+            None => bail!("No RAG"),
+        };
+        let rag_path = config.read().rag_file(&rag_name)?;
+        let mut rag = Rag::load(config, &rag_name, &rag_path)?;
+        rag.watch(config, &rag_path, abort_signal).await?;
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    pub async fn reembed_rag(
+        config: &GlobalConfig,
+        model_id: &str,
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let rag_name = match config.read().rag.clone() {
+            Some(v) => v.name().to_string(),
+            None => bail!("No RAG"),
+        };
+        let rag_path = config.read().rag_file(&rag_name)?;
+        let mut rag = Rag::load(config, &rag_name, &rag_path)?;
+        rag.reembed(config, model_id, &rag_path, abort_signal)
+            .await?;
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    pub async fn add_rag_text(
+        config: &GlobalConfig,
+        label: &str,
+        text: &str,
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let rag_name = match config.read().rag.clone() {
+            Some(v) => v.name().to_string(),
+            None => bail!("No RAG"),
+        };
+        let rag_path = config.read().rag_file(&rag_name)?;
+        let mut rag = Rag::load(config, &rag_name, &rag_path)?;
+        let items = vec![(label.to_string(), text.to_string())];
+        let dedup = config.read().rag_dedup;
+        let spinner = create_spinner("Starting").await;
+        tokio::select! {
+            ret = rag.add_texts(items, dedup, Some(spinner.clone())) => {
+                spinner.stop();
+                ret?;
+            }
+            _ = watch_abort_signal(abort_signal) => {
+                spinner.stop();
+                bail!("Aborted!")
+            },
+        };
+        if !rag.is_temp() {
+            rag.save(&rag_path)?;
+            println!("✨ Saved rag to '{}'", rag_path.display());
+        }
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    pub async fn remove_rag_documents(config: &GlobalConfig, paths: &[String]) -> Result<()> {
+        let rag_name = match config.read().rag.clone() {
+            Some(v) => v.name().to_string(),
+            None => bail!("No RAG"),
+        };
+        let rag_path = config.read().rag_file(&rag_name)?;
+        let mut rag = Rag::load(config, &rag_name, &rag_path)?;
+        let removed = rag.remove_paths(paths).await?;
+        println!("Removed {} files", removed.len());
+        if !removed.is_empty() && !rag.is_temp() {
+            rag.save(&rag_path)?;
+            println!("✨ Saved rag to '{}'", rag_path.display());
+        }
+        config.write().rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
     pub fn rag_info(&self) -> Result<String> {
         if let Some(rag) = &self.rag {
             rag.export()
@@ -975,6 +1405,7 @@ impl Config {
 
     pub fn exit_rag(&mut self) -> Result<()> {
         self.rag.take();
+        self.extra_rags.clear();
         Ok(())
     }
 
@@ -988,7 +1419,7 @@ impl Config {
                 let mut names = vec![];
                 for entry in rd.flatten() {
                     let name = entry.file_name();
-                    if let Some(name) = name.to_string_lossy().strip_suffix(".bin") {
+                    if let Some(name) = name.to_string_lossy().strip_suffix(RAG_FILE_SUFFIX) {
                         names.push(name.to_string());
                     }
                 }
@@ -999,6 +1430,45 @@ impl Config {
         }
     }
 
+    /// The saved rags with their size, embedding model, number of files and last modification.
+    pub fn saved_rags_info(&self) -> Result<String> {
+        let temp_rag = self.rag.as_deref().filter(|v| v.is_temp());
+        let rags = list_saved_rags(&Self::rags_dir()?, temp_rag)?;
+        let name_width = rags.iter().map(|v| v.name.len()).max().unwrap_or_default();
+        let lines: Vec<String> = rags
+            .into_iter()
+            .map(|rag| {
+                let modified = rag
+                    .modified
+                    .and_then(|v| chrono::DateTime::from_timestamp(v as i64, 0))
+                    .map(|v| {
+                        v.with_timezone(&chrono::Local)
+                            .format("%Y-%m-%d %H:%M")
+                            .to_string()
+                    })
+                    .unwrap_or_default();
+                let files = rag
+                    .num_files
+                    .map(|v| format!("{v} files"))
+                    .unwrap_or_else(|| "-".into());
+                let mut line = format!(
+                    "{:name_width$}  {:>10}  {:<32}  {:>10}  {modified}",
+                    rag.name,
+                    rag.size
+                        .map(|v| format!("{v} bytes"))
+                        .unwrap_or_else(|| "-".into()),
+                    rag.embedding_model.as_deref().unwrap_or("(corrupted)"),
+                    files,
+                );
+                if rag.temporary {
+                    line.push_str("  (temporary)");
+                }
+                line
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
     pub fn rag_template(&self, embeddings: &str, text: &str) -> String {
         if embeddings.is_empty() {
             return text.to_string();
@@ -1010,6 +1480,12 @@ impl Config {
             .replace("__INPUT__", text)
     }
 
+    pub fn rag_chunk_template(&self) -> &str {
+        self.rag_chunk_template
+            .as_deref()
+            .unwrap_or(RAG_CHUNK_TEMPLATE)
+    }
+
     pub async fn use_agent(
         config: &GlobalConfig,
         name: &str,
@@ -1024,6 +1500,7 @@ impl Config {
         }
         let agent = Agent::init(config, name, abort_signal).await?;
         config.write().rag = agent.rag();
+        config.write().extra_rags.clear();
         config.write().agent = Some(agent);
         let session = session
             .map(|v| v.to_string())
@@ -1229,6 +1706,8 @@ impl Config {
                     "max_output_tokens",
                     "temperature",
                     "top_p",
+                    "presence_penalty",
+                    "frequency_penalty",
                     "dry_run",
                     "save",
                     "save_session",
@@ -1237,6 +1716,15 @@ impl Config {
                     "use_tools",
                     "rag_reranker_model",
                     "rag_top_k",
+                    "rag_rrf_vector_weight",
+                    "rag_rrf_text_weight",
+                    "rag_mmr_lambda",
+                    "rag_recency_weight",
+                    "rag_recency_half_life_days",
+                    "rag_multi_query",
+                    "rag_neighbor_chunks",
+                    "rag_search_mode",
+                    "rag_filter",
                     "highlight",
                 ]
                 .into_iter()
@@ -1262,6 +1750,10 @@ impl Config {
                     complete_option_bool(save_session)
                 }
                 "function_calling" => complete_bool(self.function_calling),
+                "rag_search_mode" => ["hybrid", "vector_only", "text_only"]
+                    .into_iter()
+                    .map(|v| v.to_string())
+                    .collect(),
                 "use_tools" => {
                     let mut prefix = String::new();
                     if let Some((v, _)) = args[1].rsplit_once(',') {
@@ -1308,7 +1800,13 @@ impl Config {
     }
 
     pub fn render_options(&self) -> Result<RenderOptions> {
-        let theme = if self.highlight {
+        let render_theme = match (self.highlight, &self.render_theme) {
+            (true, Some(name)) => load_theme(name)?,
+            _ => None,
+        };
+        let theme = if render_theme.is_some() {
+            render_theme
+        } else if self.highlight {
             let theme_mode = if self.light_theme { "light" } else { "dark" };
             let theme_filename = format!("{theme_mode}.tmTheme");
             let theme_path = Self::local_path(&theme_filename)?;
@@ -1402,8 +1900,10 @@ impl Config {
             output.insert("consume_percent", percent.to_string());
             output.insert("user_messages_len", session.user_messages_len().to_string());
         }
-        if let Some(rag) = &self.rag {
-            output.insert("rag", rag.name().to_string());
+        let rags = self.rags();
+        if !rags.is_empty() {
+            let names: Vec<&str> = rags.iter().map(|v| v.name()).collect();
+            output.insert("rag", names.join(","));
         }
         if let Some(agent) = &self.agent {
             output.insert("agent", agent.name().to_string());
@@ -1436,9 +1936,29 @@ impl Config {
 
     pub fn before_chat_completion(&mut self, input: &Input) -> Result<()> {
         self.last_message = Some((input.clone(), String::new()));
+        self.last_usage = None;
         Ok(())
     }
 
+    /// Keep the token usage of the last chat completion, adding it up in the session.
+    pub fn record_usage(&mut self, model: &Model, usage: TokenUsage) {
+        let cost = usage.cost(model);
+        self.last_usage = Some((usage, cost));
+        if let Some(session) = self.session.as_mut() {
+            session.add_usage(usage, cost);
+        }
+    }
+
+    pub fn usage_info(&self) -> Option<String> {
+        let (usage, cost) = self.last_usage?;
+        let mut output = format_usage(usage, cost);
+        if let Some(session) = &self.session {
+            let (usage, cost) = session.usage();
+            output.push_str(&format!(" (session: {})", format_usage(usage, cost)));
+        }
+        Some(output)
+    }
+
     pub fn after_chat_completion(
         &mut self,
         input: &Input,
@@ -1592,15 +2112,33 @@ impl Config {
     }
 
     fn setup_highlight(&mut self) {
+        // Any value but an empty or false one turns color off, see https://no-color.org
         if let Ok(value) = env::var("NO_COLOR") {
-            let mut no_color = false;
-            set_bool(&mut no_color, &value);
-            if no_color {
+            if !matches!(value.as_str(), "" | "0" | "false") {
                 self.highlight = false;
             }
         }
     }
 
+    fn setup_render_theme(&mut self) {
+        let name = match &self.render_theme {
+            Some(v) if v != "auto" => v,
+            _ => return,
+        };
+        let err = match load_theme(name) {
+            Ok(Some(_)) => return,
+            Ok(None) => format!("Unknown render_theme '{name}'"),
+            Err(err) => format!("{err:?}"),
+        };
+        eprintln!(
+            "{}",
+            warning_text(&format!(
+                "WARNING: {err}, falling back to the builtin theme"
+            ))
+        );
+        self.render_theme = None;
+    }
+
     fn setup_light_theme(&mut self) -> Result<()> {
         if self.light_theme {
             return Ok(());
@@ -1615,19 +2153,6 @@ impl Config {
         };
         Ok(())
     }
-
-    fn setup_document_loaders(&mut self) {
-        [
-            ("pdf", "pdftotext $1 -"),
-            ("docx", "pandoc --to plain $1"),
-            (RECURSIVE_URL_LOADER, "rag-crawler $1 $2"),
-        ]
-        .into_iter()
-        .for_each(|(k, v)| {
-            let (k, v) = (k.to_string(), v.to_string());
-            self.document_loaders.entry(k).or_insert(v);
-        });
-    }
 }
 
 pub fn load_env_file() -> Result<()> {
@@ -1763,6 +2288,17 @@ pub(crate) fn ensure_parent_exists(path: &Path) -> Result<()> {
     Ok(())
 }
 
+fn format_usage(usage: TokenUsage, cost: Option<f64>) -> String {
+    let mut output = format!(
+        "{} input + {} output tokens",
+        usage.input_tokens, usage.output_tokens
+    );
+    if let Some(cost) = cost {
+        output.push_str(&format!(", ${cost:.4}"));
+    }
+    output
+}
+
 fn set_bool(target: &mut bool, value: &str) {
     match value {
         "1" | "true" => *target = true,
@@ -1798,3 +2334,59 @@ fn complete_option_bool(value: Option<bool>) -> Vec<String> {
         None => vec!["true".to_string(), "false".to_string()],
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rag_template() {
+        let config = Config::default();
+        let hit = SearchHit {
+            path: "guide.md".into(),
+            chunk_index: 3,
+            score_vector: None,
+            score_text: None,
+            score: 0.5,
+            content: "Deploys happen on Tuesdays".into(),
+            rag: None,
+        };
+        let context = hit.render(config.rag_chunk_template());
+        let text = config.rag_template(&context, "When are deploys?");
+        assert!(text
+            .contains("<context>\n[source: guide.md#3]\nDeploys happen on Tuesdays\n</context>"));
+        assert!(text.contains("cite its source as given in the context"));
+        assert!(text.ends_with("Query: When are deploys?"));
+        assert_eq!(
+            config.rag_template("", "When are deploys?"),
+            "When are deploys?"
+        );
+
+        let config = Config {
+            rag_template: Some("__CONTEXT__|__INPUT__".into()),
+            ..Default::default()
+        };
+        assert_eq!(config.rag_template("a", "b"), "a|b");
+    }
+
+    #[test]
+    fn test_update_search_options() {
+        let mut config = Config::default();
+        config.update("rag_mmr_lambda 0.5").unwrap();
+        assert_eq!(config.rag_mmr_lambda, 0.5);
+        assert!(config.update("rag_mmr_lambda 2").is_err());
+        assert_eq!(config.rag_mmr_lambda, 0.5);
+        assert!(config.update("rag_rrf_vector_weight -1").is_err());
+        config.update("rag_rrf_text_weight 0").unwrap();
+        assert!(config.update("rag_rrf_vector_weight 0").is_err());
+        assert_eq!(config.rag_rrf_vector_weight, 1.0);
+        assert!(config.update("rag_recency_weight -0.5").is_err());
+        config.update("rag_recency_half_life_days 7").unwrap();
+        assert_eq!(config.rag_recency_half_life_days, 7.0);
+        assert!(config.update("rag_recency_half_life_days 0").is_err());
+        assert_eq!(config.rag_recency_half_life_days, 7.0);
+        let top_k = config.rag_ef_search + 1;
+        assert!(config.update(&format!("rag_top_k {top_k}")).is_err());
+        assert!(check_search_options(&config).is_ok());
+    }
+}
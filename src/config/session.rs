@@ -1,7 +1,7 @@
 use super::input::*;
 use super::*;
 
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{Message, MessageContent, MessageRole, TokenUsage};
 use crate::render::MarkdownRender;
 
 use anyhow::{bail, Context, Result};
@@ -47,6 +47,10 @@ pub struct Session {
     dirty: bool,
     #[serde(skip)]
     compressing: bool,
+    #[serde(skip)]
+    usage: TokenUsage,
+    #[serde(skip)]
+    cost: Option<f64>,
 }
 
 impl Session {
@@ -187,6 +191,10 @@ impl Session {
             items.push(("max_input_tokens", max_input_tokens.to_string()));
         }
 
+        if self.usage != TokenUsage::default() {
+            items.push(("usage", format_usage(self.usage, self.cost)));
+        }
+
         let mut lines: Vec<String> = items
             .iter()
             .map(|(name, value)| format!("{name:<20}{value}"))
@@ -355,6 +363,18 @@ impl Session {
         Ok(())
     }
 
+    /// Add up the usage of a chat completion, for the cost of this run of the session.
+    pub fn add_usage(&mut self, usage: TokenUsage, cost: Option<f64>) {
+        self.usage += usage;
+        if let Some(cost) = cost {
+            *self.cost.get_or_insert(0.0) += cost;
+        }
+    }
+
+    pub fn usage(&self) -> (TokenUsage, Option<f64>) {
+        (self.usage, self.cost)
+    }
+
     pub fn add_message(&mut self, input: &Input, output: &str) -> Result<()> {
         if input.continue_output().is_some() {
             if let Some(message) = self.messages.last_mut() {
@@ -0,0 +1,21 @@
+//! The library behind the `aichat` binary.
+//!
+//! Besides the CLI, it lets other programs build, load and search rags, see [`rag::Rag::build`].
+//! The hidden modules are public for the binary only and may change at any time.
+
+pub mod client;
+pub mod config;
+#[doc(hidden)]
+pub mod function;
+pub mod rag;
+#[doc(hidden)]
+pub mod render;
+#[doc(hidden)]
+pub mod repl;
+#[doc(hidden)]
+pub mod serve;
+#[macro_use]
+pub mod utils;
+
+#[macro_use]
+extern crate log;
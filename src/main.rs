@@ -1,27 +1,21 @@
 mod cli;
-mod client;
-mod config;
-mod function;
-mod rag;
-mod render;
-mod repl;
-mod serve;
-#[macro_use]
-mod utils;
 
 #[macro_use]
 extern crate log;
 
 use crate::cli::Cli;
-use crate::client::{chat_completion_streaming, list_chat_models, ChatCompletionsOutput};
-use crate::config::{
+use aichat::client::{
+    chat_completion_streaming, list_chat_models, parse_json_output, ChatCompletionsOutput,
+};
+use aichat::config::{
     list_agents, load_env_file, Config, GlobalConfig, Input, WorkingMode, CODE_ROLE,
     EXPLAIN_SHELL_ROLE, SHELL_ROLE, TEMP_SESSION_NAME,
 };
-use crate::function::{eval_tool_calls, need_send_tool_results};
-use crate::render::render_error;
-use crate::repl::Repl;
-use crate::utils::{
+use aichat::function::{eval_tool_calls, need_send_tool_results};
+use aichat::rag::MetadataFilter;
+use aichat::render::render_error;
+use aichat::repl::Repl;
+use aichat::utils::{
     create_abort_signal, create_spinner, detect_shell, extract_block, get_env_name, run_command,
     AbortSignal, Shell, CODE_BLOCK_RE, IS_STDOUT_TERMINAL,
 };
@@ -34,6 +28,7 @@ use is_terminal::IsTerminal;
 use parking_lot::RwLock;
 use simplelog::{format_description, ConfigBuilder, LevelFilter, SimpleLogger, WriteLogger};
 use std::io::{stderr, stdin, Read};
+use std::path::Path;
 use std::process;
 use std::sync::Arc;
 
@@ -58,7 +53,7 @@ async fn main() -> Result<()> {
     let abort_signal = create_abort_signal();
 
     if let Some(addr) = cli.serve {
-        return serve::run(config, addr).await;
+        return aichat::serve::run(config, addr).await;
     }
     if cli.list_models {
         for model in list_chat_models(&config.read()) {
@@ -84,6 +79,19 @@ async fn main() -> Result<()> {
         println!("{rags}");
         return Ok(());
     }
+    if cli.rags_info {
+        let info = config.read().saved_rags_info()?;
+        println!("{info}");
+        return Ok(());
+    }
+    if let Some(name) = &cli.delete_rag {
+        Config::delete_rag(&config, name)?;
+        return Ok(());
+    }
+    if let Some([name, new_name]) = cli.rename_rag.as_deref() {
+        Config::rename_rag(&config, name, new_name)?;
+        return Ok(());
+    }
     if let Some(wrap) = &cli.wrap {
         config.write().set_wrap(wrap)?;
     }
@@ -93,6 +101,26 @@ async fn main() -> Result<()> {
     if cli.dry_run {
         config.write().dry_run = true;
     }
+    if cli.strict {
+        config.write().rag_strict = true;
+    }
+    if cli.no_embedding_cache {
+        config.write().rag_embedding_cache = false;
+    }
+    if cli.no_cache {
+        config.write().response_cache = false;
+    }
+    if cli.json {
+        config.write().json_mode = true;
+    }
+    if let Some(filter) = &cli.rag_filter {
+        config.write().rag_filter = Some(MetadataFilter::parse(filter)?);
+    }
+    if let (Some(rag), Some(query)) = (&cli.rag, &cli.rag_search) {
+        let hits = Config::search_rag(&config, rag, query, abort_signal.clone()).await?;
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+        return Ok(());
+    }
 
     if let Some(agent) = &cli.agent {
         let session = cli.session.as_ref().map(|v| match v {
@@ -116,8 +144,34 @@ async fn main() -> Result<()> {
                 .use_session(session.as_ref().map(|v| v.as_str()))?;
         }
         if let Some(rag) = &cli.rag {
+            if let Some(path) = &cli.import_rag {
+                Config::import_rag(&config, rag, Path::new(path))?;
+                return Ok(());
+            }
             Config::use_rag(&config, Some(rag), abort_signal.clone()).await?;
         }
+        if cli.compact_rag {
+            Config::compact_rag(&config)?;
+            return Ok(());
+        }
+        if let Some(path) = &cli.export_rag {
+            Config::export_rag(&config, Path::new(path), !cli.no_vectors)?;
+            return Ok(());
+        }
+        if let Some(model_id) = &cli.reembed {
+            Config::reembed_rag(&config, model_id, abort_signal.clone()).await?;
+            return Ok(());
+        }
+        if cli.watch {
+            let abort = abort_signal.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    abort.set_ctrlc();
+                }
+            });
+            Config::watch_rag(&config, abort_signal.clone()).await?;
+            return Ok(());
+        }
     }
     if cli.list_sessions {
         let sessions = config.read().list_sessions().join("\n");
@@ -144,7 +198,7 @@ async fn main() -> Result<()> {
         }
         let input = create_input(&config, text, file).await?;
         let shell = detect_shell();
-        shell_execute(&config, &shell, input).await?;
+        shell_execute(&config, &shell, input, abort_signal.clone()).await?;
         return Ok(());
     }
     config.write().apply_prelude()?;
@@ -174,12 +228,18 @@ async fn start_directive(
     let client = input.create_client()?;
     let extract_code = !*IS_STDOUT_TERMINAL && code_mode;
     config.write().before_chat_completion(&input)?;
-    let (output, tool_results) = if no_stream || extract_code {
+    let (output, tool_results, aborted) = if no_stream || extract_code {
+        let ret = client
+            .chat_completions(input.clone(), abort_signal.clone())
+            .await?;
+        if let Some(usage) = ret.usage() {
+            config.write().record_usage(client.model(), usage);
+        }
         let ChatCompletionsOutput {
             text, tool_calls, ..
-        } = client.chat_completions(input.clone()).await?;
+        } = ret;
         if !tool_calls.is_empty() {
-            (String::new(), eval_tool_calls(config, tool_calls)?)
+            (String::new(), eval_tool_calls(config, tool_calls)?, false)
         } else {
             let text = if extract_code && text.trim_start().starts_with("```") {
                 extract_block(&text)
@@ -191,11 +251,14 @@ async fn start_directive(
             } else {
                 println!("{}", text);
             }
-            (text, vec![])
+            (text, vec![], false)
         }
     } else {
         chat_completion_streaming(&input, client.as_ref(), config, abort_signal.clone()).await?
     };
+    if config.read().json_mode && tool_results.is_empty() && !aborted {
+        parse_json_output(&output)?;
+    }
     config
         .write()
         .after_chat_completion(&input, &output, &tool_results)?;
@@ -222,16 +285,25 @@ async fn start_interactive(config: &GlobalConfig) -> Result<()> {
 }
 
 #[async_recursion::async_recursion]
-async fn shell_execute(config: &GlobalConfig, shell: &Shell, mut input: Input) -> Result<()> {
+async fn shell_execute(
+    config: &GlobalConfig,
+    shell: &Shell,
+    mut input: Input,
+    abort_signal: AbortSignal,
+) -> Result<()> {
     let client = input.create_client()?;
     config.write().before_chat_completion(&input)?;
     let ret = if *IS_STDOUT_TERMINAL {
         let spinner = create_spinner("Generating").await;
-        let ret = client.chat_completions(input.clone()).await;
+        let ret = client
+            .chat_completions(input.clone(), abort_signal.clone())
+            .await;
         spinner.stop();
         ret
     } else {
-        client.chat_completions(input.clone()).await
+        client
+            .chat_completions(input.clone(), abort_signal.clone())
+            .await
     };
     let mut eval_str = ret?.text;
     if let Ok(true) = CODE_BLOCK_RE.is_match(&eval_str) {
@@ -264,7 +336,7 @@ async fn shell_execute(config: &GlobalConfig, shell: &Shell, mut input: Input) -
                     let revision = Text::new("Enter your revision:").prompt()?;
                     let text = format!("{}\n{revision}", input.text());
                     input.set_text(text);
-                    return shell_execute(config, shell, input).await;
+                    return shell_execute(config, shell, input, abort_signal).await;
                 }
                 "📖 Explain" => {
                     let role = config.read().retrieve_role(EXPLAIN_SHELL_ROLE)?;
@@ -1,13 +1,36 @@
 use rayon::prelude::*;
-use std::collections::HashMap;
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::f64;
 use unicode_segmentation::UnicodeSegmentation;
 
-#[derive(Debug, Clone)]
+const DEFAULT_STOPWORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// Share of CJK characters in the corpus above which it is tokenized into CJK bigrams anyway.
+const CJK_AUTO_RATIO: f64 = 0.2;
+
+/// Stored with the rag, so that queries are tokenized the same way as the corpus.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BM25Options {
+    #[serde(skip)]
     k1: f64,
+    #[serde(skip)]
     b: f64,
+    #[serde(skip)]
     epsilon: f64,
+    /// The language of the stemmer.
+    pub language: String,
+    /// Words left out of the index, the built-in English list if not set.
+    pub stopwords: Option<Vec<String>>,
+    pub stemming: bool,
+    /// Split runs of CJK characters into overlapping bigrams, as they aren't separated by spaces.
+    pub cjk: bool,
 }
 
 impl Default for BM25Options {
@@ -16,10 +39,41 @@ impl Default for BM25Options {
             k1: 1.5,
             b: 0.75,
             epsilon: 0.25,
+            language: "english".into(),
+            stopwords: None,
+            stemming: false,
+            cjk: false,
         }
     }
 }
 
+impl BM25Options {
+    pub fn stemmer_algorithm(language: &str) -> Option<Algorithm> {
+        let algorithm = match language.to_lowercase().as_str() {
+            "arabic" => Algorithm::Arabic,
+            "danish" => Algorithm::Danish,
+            "dutch" => Algorithm::Dutch,
+            "english" => Algorithm::English,
+            "finnish" => Algorithm::Finnish,
+            "french" => Algorithm::French,
+            "german" => Algorithm::German,
+            "greek" => Algorithm::Greek,
+            "hungarian" => Algorithm::Hungarian,
+            "italian" => Algorithm::Italian,
+            "norwegian" => Algorithm::Norwegian,
+            "portuguese" => Algorithm::Portuguese,
+            "romanian" => Algorithm::Romanian,
+            "russian" => Algorithm::Russian,
+            "spanish" => Algorithm::Spanish,
+            "swedish" => Algorithm::Swedish,
+            "tamil" => Algorithm::Tamil,
+            "turkish" => Algorithm::Turkish,
+            _ => return None,
+        };
+        Some(algorithm)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BM25<T> {
     options: BM25Options,
@@ -32,14 +86,21 @@ pub struct BM25<T> {
 }
 
 impl<T: Clone> BM25<T> {
-    pub fn new(corpus: Vec<(T, String)>, options: BM25Options) -> Self {
+    pub fn new(corpus: Vec<(T, String)>, mut options: BM25Options) -> Self {
         let mut doc_ids = vec![];
         let mut docs = vec![];
         for (id, value) in corpus {
             doc_ids.push(id);
             docs.push(value);
         }
-        let tokenized_docs = docs.into_par_iter().map(|text| tokenize(&text)).collect();
+        if !options.cjk && cjk_ratio(&docs) > CJK_AUTO_RATIO {
+            options.cjk = true;
+        }
+        let tokenizer = Tokenizer::new(&options);
+        let tokenized_docs = docs
+            .into_par_iter()
+            .map(|text| tokenizer.tokenize(&text))
+            .collect();
 
         let mut bm25 = BM25 {
             options,
@@ -57,11 +118,28 @@ impl<T: Clone> BM25<T> {
         bm25
     }
 
-    pub fn search(&self, query: &str, top_k: usize, min_score: Option<f64>) -> Vec<T> {
+    /// The number of distinct terms in the corpus.
+    pub fn vocab_size(&self) -> usize {
+        self.idf.len()
+    }
+
+    pub fn search(&self, query: &str, top_k: usize, min_score: Option<f64>) -> Vec<(T, f64)> {
+        self.search_with(query, top_k, min_score, |_| true)
+    }
+
+    /// Like `search`, but only ranks the documents accepted by `filter`.
+    pub fn search_with(
+        &self,
+        query: &str,
+        top_k: usize,
+        min_score: Option<f64>,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<(T, f64)> {
         let scores = self.get_scores(query);
         let mut indexed_scores: Vec<(T, f64)> = scores
             .into_iter()
             .enumerate()
+            .filter(|(i, _)| filter(&self.doc_ids[*i]))
             .filter_map(|(i, v)| match min_score {
                 Some(minimum_score) => {
                     if v < minimum_score {
@@ -74,17 +152,14 @@ impl<T: Clone> BM25<T> {
             })
             .collect();
         indexed_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        indexed_scores.truncate(top_k);
         indexed_scores
-            .into_iter()
-            .take(top_k)
-            .map(|(id, _)| id)
-            .collect()
     }
 
     pub fn get_scores(&self, query: &str) -> Vec<f64> {
         let mut score = vec![0.0; self.corpus_size];
 
-        for q in tokenize(query) {
+        for q in Tokenizer::new(&self.options).tokenize(query) {
             if let Some(idf) = self.idf.get(&q) {
                 for (i, doc) in self.doc_freqs.iter().enumerate() {
                     let q_freq = doc.get(&q).unwrap_or(&0);
@@ -147,22 +222,116 @@ impl<T: Clone> BM25<T> {
     }
 }
 
-fn tokenize(text: &str) -> Vec<String> {
-    text.unicode_words()
-        .filter_map(|v| {
-            if [
-                "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into",
-                "is", "it", "no", "not", "of", "on", "or", "such", "that", "the", "their", "then",
-                "there", "these", "they", "this", "to", "was", "will", "with",
-            ]
-            .contains(&v)
-            {
-                None
-            } else {
-                Some(v.to_string())
+struct Tokenizer {
+    stopwords: HashSet<String>,
+    stemmer: Option<Stemmer>,
+    cjk: bool,
+}
+
+impl Tokenizer {
+    fn new(options: &BM25Options) -> Self {
+        let stopwords = match &options.stopwords {
+            Some(words) => words.iter().cloned().collect(),
+            None => DEFAULT_STOPWORDS.iter().map(|v| v.to_string()).collect(),
+        };
+        let stemmer = match options.stemming {
+            true => BM25Options::stemmer_algorithm(&options.language).map(Stemmer::create),
+            false => None,
+        };
+        Self {
+            stopwords,
+            stemmer,
+            cjk: options.cjk,
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        if !self.cjk {
+            return self.tokenize_words(text.unicode_words());
+        }
+        let mut output = vec![];
+        let mut words = vec![];
+        let mut cjk_run = String::new();
+        let mut cjk_run_end = 0;
+        for (index, word) in text.unicode_word_indices() {
+            if !word.chars().all(is_cjk) {
+                words.push(word);
+                continue;
             }
-        })
-        .collect()
+            if index != cjk_run_end {
+                output.extend(cjk_bigrams(&cjk_run));
+                cjk_run.clear();
+            }
+            cjk_run.push_str(word);
+            cjk_run_end = index + word.len();
+        }
+        output.extend(cjk_bigrams(&cjk_run));
+        output.extend(self.tokenize_words(words.into_iter()));
+        output
+    }
+
+    fn tokenize_words<'a>(&self, words: impl Iterator<Item = &'a str>) -> Vec<String> {
+        words
+            .filter_map(|word| match &self.stemmer {
+                // Stemmers only handle lowercase words.
+                Some(stemmer) => {
+                    let word = word.to_lowercase();
+                    if self.stopwords.contains(&word) {
+                        None
+                    } else {
+                        Some(stemmer.stem(&word).to_string())
+                    }
+                }
+                None => {
+                    if self.stopwords.contains(word) {
+                        None
+                    } else {
+                        Some(word.to_string())
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Han ideographs, hiragana and katakana, which are written without spaces between words.
+fn is_cjk(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{3040}'..='\u{30FF}'
+            | '\u{31F0}'..='\u{31FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{20000}'..='\u{2A6DF}'
+    )
+}
+
+fn cjk_ratio(docs: &[String]) -> f64 {
+    let (mut cjk, mut total) = (0, 0);
+    for ch in docs.iter().flat_map(|v| v.chars()) {
+        if ch.is_whitespace() {
+            continue;
+        }
+        total += 1;
+        if is_cjk(ch) {
+            cjk += 1;
+        }
+    }
+    match total {
+        0 => 0.0,
+        _ => cjk as f64 / total as f64,
+    }
+}
+
+/// Overlapping pairs of characters, or the character itself if it stands alone.
+fn cjk_bigrams(run: &str) -> Vec<String> {
+    let chars: Vec<char> = run.chars().collect();
+    match chars.len() {
+        0 => vec![],
+        1 => vec![run.to_string()],
+        _ => chars.windows(2).map(|v| v.iter().collect()).collect(),
+    }
 }
 
 #[cfg(test)]
@@ -171,10 +340,49 @@ mod tests {
 
     #[test]
     fn test_tokenize() {
+        let tokenizer = Tokenizer::new(&BM25Options::default());
         assert_eq!(
-            tokenize("a quick fox jumps over the lazy dog"),
+            tokenizer.tokenize("a quick fox jumps over the lazy dog"),
             vec!["quick", "fox", "jumps", "over", "lazy", "dog"]
         );
+
+        let options = BM25Options {
+            stopwords: Some(vec!["over".into()]),
+            stemming: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            Tokenizer::new(&options).tokenize("The quick fox Jumps over running dogs"),
+            vec!["the", "quick", "fox", "jump", "run", "dog"]
+        );
+    }
+
+    #[test]
+    fn test_bm25_cjk() {
+        let corpus = vec![
+            (0, "我们正在研究机器学习模型的训练方法。".into()),
+            (1, "习惯了器材市场，学会去机场。".into()),
+            (2, "今天的天气很好。".into()),
+        ];
+        let options = BM25Options {
+            cjk: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            Tokenizer::new(&options).tokenize("机器学习 with rust"),
+            vec!["机器", "器学", "学习", "rust"]
+        );
+
+        let bm25 = BM25::new(corpus.clone(), options);
+        let scores = bm25.get_scores("机器学习");
+        assert!(scores[0] > 0.0);
+        assert_eq!(scores[1], 0.0);
+
+        // Detected from the corpus without the option.
+        let bm25 = BM25::new(corpus, BM25Options::default());
+        let top_n = bm25.search("机器学习", 1, Some(0.0));
+        assert_eq!(top_n[0].0, 0);
+        assert_eq!(bm25.get_scores("机器学习")[1], 0.0);
     }
 
     #[test]
@@ -189,7 +397,11 @@ mod tests {
         let scores = bm25.get_scores("windy London");
         assert_eq!(scores, [0.0, 0.9372947225064051, 0.0]);
 
-        let top_n = bm25.search("windy London", 3, None);
+        let top_n: Vec<_> = bm25
+            .search("windy London", 3, None)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
         assert_eq!(top_n, vec![1, 0, 2])
     }
 }
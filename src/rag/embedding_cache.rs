@@ -0,0 +1,159 @@
+use super::*;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Embeddings of document chunks keyed by model and text, one file per entry.
+/// Entries are evicted least-recently-used first, tracked through file mtimes.
+#[derive(Debug, Clone)]
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    max_entries: usize,
+    /// The number of entries, counted once from the directory and then kept up to date.
+    len: Arc<Mutex<Option<usize>>>,
+}
+
+impl EmbeddingCache {
+    pub fn new(dir: PathBuf, max_entries: usize) -> Self {
+        Self {
+            dir,
+            max_entries,
+            len: Default::default(),
+        }
+    }
+
+    /// Look up an embedding, treating a corrupt or truncated entry as a miss.
+    pub fn get(&self, model_id: &str, text: &str) -> Option<Vec<f32>> {
+        let path = self.entry_path(model_id, text);
+        let content = fs::read(&path).ok()?;
+        let vector: Vec<f32> = bincode::deserialize(&content).ok()?;
+        let size = bincode::serialized_size(&vector).ok()?;
+        if vector.is_empty() || size != content.len() as u64 {
+            return None;
+        }
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(vector)
+    }
+
+    pub fn put(&self, model_id: &str, text: &str, vector: &Vec<f32>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.entry_path(model_id, text);
+        let is_new = !path.exists();
+        fs::write(path, bincode::serialize(vector)?)?;
+        if let (true, Some(len)) = (is_new, self.len.lock().as_mut()) {
+            *len += 1;
+        }
+        Ok(())
+    }
+
+    /// Remove every entry, returning how many were removed.
+    pub fn clear(&self) -> Result<usize> {
+        let mut count = 0;
+        let mut len = self.len.lock();
+        *len = None;
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(v) => v,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            fs::remove_file(entry?.path())?;
+            count += 1;
+        }
+        *len = Some(0);
+        Ok(count)
+    }
+
+    /// Drop the least recently used entries beyond `max_entries`. The directory is only scanned
+    /// when the tracked entry count exceeds the limit, or to count the entries the first time.
+    pub fn evict(&self) -> Result<usize> {
+        let mut len = self.len.lock();
+        let count = match *len {
+            Some(v) => v,
+            None => fs::read_dir(&self.dir)?.count(),
+        };
+        *len = Some(count);
+        if count <= self.max_entries {
+            return Ok(0);
+        }
+        *len = None;
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let mtime = entry.metadata()?.modified()?;
+            entries.push((mtime, entry.path()));
+        }
+        if entries.len() <= self.max_entries {
+            *len = Some(entries.len());
+            return Ok(0);
+        }
+        entries.sort();
+        let count = entries.len() - self.max_entries;
+        for (_, path) in entries.into_iter().take(count) {
+            fs::remove_file(path)?;
+        }
+        *len = Some(self.max_entries);
+        Ok(count)
+    }
+
+    fn entry_path(&self, model_id: &str, text: &str) -> PathBuf {
+        self.dir
+            .join(sha256(&format!("{model_id}:{}", sha256(text))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_cache() {
+        let dir = std::env::temp_dir().join(format!("aichat-embeddings-{}", std::process::id()));
+        let cache = EmbeddingCache::new(dir.clone(), 2);
+        assert_eq!(cache.get("model-a", "foo"), None);
+        cache.put("model-a", "foo", &vec![1.0, 2.0]).unwrap();
+        assert_eq!(cache.get("model-a", "foo"), Some(vec![1.0, 2.0]));
+        assert_eq!(cache.get("model-b", "foo"), None);
+
+        let now = SystemTime::now();
+        cache.put("model-a", "bar", &vec![3.0]).unwrap();
+        cache.put("model-a", "baz", &vec![4.0]).unwrap();
+        for (text, age) in [("foo", 1), ("bar", 3), ("baz", 2)] {
+            let file = File::options()
+                .write(true)
+                .open(cache.entry_path("model-a", text))
+                .unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+        assert_eq!(cache.evict().unwrap(), 1);
+        assert_eq!(cache.get("model-a", "bar"), None);
+        assert_eq!(cache.get("model-a", "foo"), Some(vec![1.0, 2.0]));
+        assert_eq!(cache.get("model-a", "baz"), Some(vec![4.0]));
+
+        // Under the limit, eviction relies on the tracked count without listing the directory.
+        fs::write(dir.join("stray"), "").unwrap();
+        assert_eq!(cache.evict().unwrap(), 0);
+        fs::remove_file(dir.join("stray")).unwrap();
+
+        // A corrupt entry is a miss rather than an empty embedding.
+        let path = cache.entry_path("model-a", "foo");
+        fs::write(&path, bincode::serialize(&Vec::<f32>::new()).unwrap()).unwrap();
+        assert_eq!(cache.get("model-a", "foo"), None);
+        fs::write(&path, [1, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(cache.get("model-a", "foo"), None);
+        cache.put("model-a", "foo", &vec![1.0, 2.0]).unwrap();
+        assert_eq!(cache.get("model-a", "foo"), Some(vec![1.0, 2.0]));
+
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert_eq!(cache.get("model-a", "foo"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
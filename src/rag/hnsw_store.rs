@@ -0,0 +1,321 @@
+use super::*;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Bumped whenever the sidecar layout changes so stale graphs get rebuilt.
+/// Version 2 identifies vectors by their position instead of their document id.
+const HNSW_SIDECAR_VERSION: u32 = 2;
+
+/// The metric used to compare embeddings, fixed when the rag is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RagDistance {
+    #[default]
+    Cosine,
+    Dot,
+    L2,
+}
+
+impl RagDistance {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cosine => "cosine",
+            Self::Dot => "dot",
+            Self::L2 => "l2",
+        }
+    }
+
+    /// Convert a graph distance into a score where higher is better.
+    pub fn score(&self, distance: f32) -> f32 {
+        match self {
+            Self::Cosine => 1.0 - distance,
+            Self::Dot if distance <= 1.0 => 1.0 / distance - 1.0,
+            Self::Dot => 1.0 - distance,
+            Self::L2 => 1.0 / (1.0 + distance),
+        }
+    }
+}
+
+/// Inner product mapped to a positive value that shrinks as the product grows, as hnsw requires
+/// non-negative distances. Unlike `DistDot`, it accepts unnormalized vectors.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DistDotProduct;
+
+impl Distance<f32> for DistDotProduct {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        let dot: f32 = va.iter().zip(vb).map(|(a, b)| a * b).sum();
+        if dot >= 0.0 {
+            1.0 / (1.0 + dot)
+        } else {
+            1.0 - dot
+        }
+    }
+}
+
+/// The HNSW graph, dispatched over the distance the rag was created with.
+enum HnswGraph {
+    Cosine(Hnsw<'static, f32, DistCosine>),
+    Dot(Hnsw<'static, f32, DistDotProduct>),
+    L2(Hnsw<'static, f32, DistL2>),
+}
+
+/// The HNSW graph of a rag.
+pub struct RagHnsw {
+    graph: HnswGraph,
+}
+
+macro_rules! dispatch_hnsw {
+    ($self:expr, $hnsw:ident => $body:expr) => {
+        match &$self.graph {
+            HnswGraph::Cosine($hnsw) => $body,
+            HnswGraph::Dot($hnsw) => $body,
+            HnswGraph::L2($hnsw) => $body,
+        }
+    };
+}
+
+impl RagHnsw {
+    pub fn new(
+        distance: RagDistance,
+        max_nb_connection: usize,
+        max_elements: usize,
+        max_layer: usize,
+        ef_construction: usize,
+    ) -> Self {
+        let (m, n, l, ef) = (max_nb_connection, max_elements, max_layer, ef_construction);
+        let graph = match distance {
+            RagDistance::Cosine => HnswGraph::Cosine(Hnsw::new(m, n, l, ef, DistCosine {})),
+            RagDistance::Dot => HnswGraph::Dot(Hnsw::new(m, n, l, ef, DistDotProduct {})),
+            RagDistance::L2 => HnswGraph::L2(Hnsw::new(m, n, l, ef, DistL2 {})),
+        };
+        Self { graph }
+    }
+
+    /// Reload the graph dumped as `basename` in `dir`.
+    fn load(dir: PathBuf, basename: String, distance: RagDistance) -> Result<Self> {
+        let loader = hnsw_loader(dir, basename);
+        // hnsw_rs asserts on malformed dumps rather than returning an error.
+        let graph = catch_unwind(AssertUnwindSafe(|| match distance {
+            RagDistance::Cosine => loader
+                .load_hnsw_with_dist(DistCosine {})
+                .map(HnswGraph::Cosine),
+            RagDistance::Dot => loader
+                .load_hnsw_with_dist(DistDotProduct)
+                .map(HnswGraph::Dot),
+            RagDistance::L2 => loader.load_hnsw_with_dist(DistL2 {}).map(HnswGraph::L2),
+        }))
+        .map_err(|_| anyhow!("Failed to load hnsw"))??;
+        Ok(Self { graph })
+    }
+
+    pub fn parallel_insert(&self, list: &Vec<(&[f32], usize)>) {
+        dispatch_hnsw!(self, hnsw => hnsw.parallel_insert_slice(list))
+    }
+
+    pub fn search_filter(
+        &self,
+        data: &[f32],
+        knbn: usize,
+        ef_arg: usize,
+        filter: Option<&dyn FilterT>,
+    ) -> Vec<Neighbour> {
+        dispatch_hnsw!(self, hnsw => hnsw.search_filter(data, knbn, ef_arg, filter))
+    }
+
+    pub fn parallel_search(
+        &self,
+        datas: &Vec<Vec<f32>>,
+        knbn: usize,
+        ef: usize,
+    ) -> Vec<Vec<Neighbour>> {
+        dispatch_hnsw!(self, hnsw => hnsw.parallel_search(datas, knbn, ef))
+    }
+
+    pub fn get_nb_point(&self) -> usize {
+        dispatch_hnsw!(self, hnsw => hnsw.get_nb_point())
+    }
+
+    fn file_dump(&self, filename: &String) -> Result<String> {
+        dispatch_hnsw!(self, hnsw => hnsw.file_dump(filename))
+    }
+}
+
+/// The loader of the graph dumped as `basename` in `dir`. hnsw_rs ties a reloaded graph to its
+/// loader, so each dump gets one loader that lives as long as the process and serves every
+/// reload; it only holds the paths, as the data isn't memory-mapped.
+fn hnsw_loader(dir: PathBuf, basename: String) -> &'static HnswIo {
+    static LOADERS: OnceLock<Mutex<HashMap<PathBuf, &'static HnswIo>>> = OnceLock::new();
+    let mut loaders = LOADERS.get_or_init(Default::default).lock();
+    loaders
+        .entry(dir.join(&basename))
+        .or_insert_with(|| Box::leak(Box::new(HnswIo::new(dir, basename))))
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct HnswSidecar {
+    version: u32,
+    fingerprint: String,
+}
+
+/// Load the graph dumped next to `rag_path`, or `None` if it is missing or doesn't match `fingerprint`.
+pub fn load_hnsw(
+    rag_path: &Path,
+    fingerprint: &str,
+    nb_points: usize,
+    distance: RagDistance,
+) -> Option<RagHnsw> {
+    let (dir, basename) = sidecar_basename(rag_path)?;
+    let content = std::fs::read_to_string(sidecar_path(rag_path)?).ok()?;
+    let sidecar: HnswSidecar = serde_json::from_str(&content).ok()?;
+    let expect = HnswSidecar {
+        version: HNSW_SIDECAR_VERSION,
+        fingerprint: fingerprint.to_string(),
+    };
+    if sidecar != expect {
+        debug!("stale hnsw sidecar for {}", rag_path.display());
+        return None;
+    }
+    let hnsw = RagHnsw::load(dir, basename, distance).ok()?;
+    if hnsw.get_nb_point() != nb_points {
+        return None;
+    }
+    Some(hnsw)
+}
+
+/// Dump the graph next to `rag_path` along with the fingerprint of the data it was built from.
+pub fn save_hnsw(rag_path: &Path, hnsw: &RagHnsw, fingerprint: &str) -> Result<()> {
+    let (dir, basename) = sidecar_basename(rag_path).context("Invalid rag path")?;
+    let sidecar_path = sidecar_path(rag_path).context("Invalid rag path")?;
+    let _ = std::fs::remove_file(&sidecar_path);
+    hnsw.file_dump(&dir.join(basename).display().to_string())?;
+    let sidecar = HnswSidecar {
+        version: HNSW_SIDECAR_VERSION,
+        fingerprint: fingerprint.to_string(),
+    };
+    std::fs::write(&sidecar_path, serde_json::to_string(&sidecar)?)?;
+    Ok(())
+}
+
+/// The files the graph of the rag at `rag_path` is dumped to, along with its sidecar.
+pub fn hnsw_paths(rag_path: &Path) -> Vec<PathBuf> {
+    match sidecar_basename(rag_path) {
+        Some((dir, basename)) => ["hnsw.graph", "hnsw.data", "hnsw.json"]
+            .iter()
+            .map(|extension| dir.join(format!("{basename}.{extension}")))
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn sidecar_basename(rag_path: &Path) -> Option<(PathBuf, String)> {
+    let dir = rag_path.parent()?.to_path_buf();
+    let basename = rag_path.file_stem()?.to_string_lossy().to_string();
+    Some((dir, basename))
+}
+
+fn sidecar_path(rag_path: &Path) -> Option<PathBuf> {
+    let (dir, basename) = sidecar_basename(rag_path)?;
+    Some(dir.join(format!("{basename}.hnsw.json")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hnsw_sidecar_roundtrip() {
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        for index in 0..5000 {
+            let vector: Vec<f32> = (0..8)
+                .map(|v| ((index * 7 + v) % 13) as f32 + 1.0)
+                .collect();
+            data.vectors
+                .insert(pack_document_id(0, index), vector.into());
+        }
+        let dir = std::env::temp_dir().join(format!("aichat-hnsw-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rag_path = dir.join("test.bin");
+        let fingerprint = data.hnsw_fingerprint();
+
+        let distance = RagDistance::Cosine;
+        let nb_points = data.vectors.len();
+
+        assert!(load_hnsw(&rag_path, &fingerprint, nb_points, distance).is_none());
+        let built = data.build_hnsw().unwrap();
+        save_hnsw(&rag_path, &built, &fingerprint).unwrap();
+        // The search of an approximate graph can miss, so compare with the graph that was saved.
+        let query = data.vectors[7].to_f32();
+        let expect: Vec<_> = built
+            .search_filter(&query, 5, 30, None)
+            .iter()
+            .map(|v| (v.d_id, v.distance))
+            .collect();
+        // Reloads of the same dump share its loader.
+        for _ in 0..3 {
+            let hnsw = load_hnsw(&rag_path, &fingerprint, nb_points, distance).unwrap();
+            assert_eq!(hnsw.get_nb_point(), 5000);
+            let neighbours: Vec<_> = hnsw
+                .search_filter(&query, 5, 30, None)
+                .iter()
+                .map(|v| (v.d_id, v.distance))
+                .collect();
+            assert_eq!(neighbours, expect);
+        }
+        assert!(load_hnsw(&rag_path, "stale", nb_points, distance).is_none());
+
+        // Same ids, other values, as after re-embedding.
+        let mut vector = data.vectors[0].to_f32().into_owned();
+        vector[0] += 1.0;
+        data.vectors[0] = vector.into();
+        let stale = data.hnsw_fingerprint();
+        assert_ne!(stale, fingerprint);
+        assert!(load_hnsw(&rag_path, &stale, nb_points, distance).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rag_distance_nearest_neighbour() {
+        let vectors = [
+            vec![1.0, 0.0],
+            vec![10.0, 1.0],
+            vec![0.0, 1.0],
+            vec![0.9, 0.1],
+        ];
+        let query = vec![1.0, 0.0];
+        for (distance, expect) in [
+            (RagDistance::Cosine, 0),
+            (RagDistance::Dot, 1),
+            (RagDistance::L2, 0),
+        ] {
+            let hnsw = RagHnsw::new(distance, 16, vectors.len(), 16, 200);
+            let list: Vec<_> = vectors.iter().map(|v| v.as_slice()).zip(0..).collect();
+            hnsw.parallel_insert(&list);
+            let neighbours = hnsw.search_filter(&query, vectors.len(), 30, None);
+            assert_eq!(neighbours[0].d_id, expect, "{}", distance.as_str());
+            let scores: Vec<f32> = neighbours
+                .iter()
+                .map(|v| distance.score(v.distance))
+                .collect();
+            assert!(
+                scores.windows(2).all(|v| v[0] >= v[1]),
+                "{}",
+                distance.as_str()
+            );
+        }
+        assert_eq!(
+            RagDistance::Dot.score(DistDotProduct.eval(&[2.0], &[1.5])),
+            3.0
+        );
+        assert_eq!(
+            RagDistance::Dot.score(DistDotProduct.eval(&[2.0], &[-1.5])),
+            -3.0
+        );
+        assert_eq!(RagDistance::L2.score(0.0), 1.0);
+        assert!(RagDistance::L2.score(3.0) < RagDistance::L2.score(1.0));
+    }
+}
@@ -1,30 +1,167 @@
 use super::*;
 
-use anyhow::{Context, Result};
-use std::collections::HashMap;
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use futures_util::{stream, StreamExt};
+use lazy_static::lazy_static;
+use quick_xml::events::{BytesStart, Event};
+use reqwest::Url;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::Read;
+use std::sync::Arc;
 
 pub const EXTENSION_METADATA: &str = "__extension__";
 pub const PATH_METADATA: &str = "__path__";
+pub const SOURCE_METADATA: &str = "source";
+pub const CHUNK_INDEX_METADATA: &str = "chunk_index";
+pub const PAGE_METADATA: &str = "page";
+pub const SECTION_METADATA: &str = "section";
+pub const ROW_METADATA: &str = "row";
+pub const TITLE_METADATA: &str = "title";
+pub const CHAPTER_METADATA: &str = "chapter";
+pub const CELL_TYPE_METADATA: &str = "cell_type";
+pub const CELL_INDEX_METADATA: &str = "cell_index";
+pub const SITEMAP_METADATA: &str = "sitemap";
+/// How many documents share the vector of a document, set only when more than one.
+pub const DEDUP_COUNT_METADATA: &str = "dedup_count";
+/// Files larger than this are skipped rather than loaded into memory.
+pub const MAX_LOAD_FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// Extensions loaded natively from binary formats, exempt from the binary content check.
+const BINARY_EXTENSIONS: [&str; 4] = ["pdf", "docx", "odt", "epub"];
+/// Stream outputs of notebook cells longer than this are dropped.
+const NOTEBOOK_OUTPUT_MAX_LEN: usize = 2000;
+/// Pages of a sitemap fetched at once.
+const SITEMAP_CONCURRENCY: usize = 8;
+
+lazy_static! {
+    static ref HREF_RE: Regex = Regex::new(r#"(?i)<a\s[^>]*?href\s*=\s*["']([^"']+)["']"#).unwrap();
+}
+
+/// A loaded file or page. When `sections` is non-empty, it replaces `contents` when splitting.
+#[derive(Debug, Default)]
+pub struct LoadedFile {
+    pub contents: String,
+    pub metadata: RagMetadata,
+    pub sections: Vec<RagDocument>,
+}
+
+impl LoadedFile {
+    pub fn new(contents: String, path: &str, extension: &str) -> Self {
+        let mut metadata: RagMetadata = Default::default();
+        metadata.insert(PATH_METADATA.into(), path.to_string());
+        metadata.insert(EXTENSION_METADATA.into(), extension.to_string());
+        Self {
+            contents,
+            metadata,
+            sections: vec![],
+        }
+    }
+
+    /// Split the text a PDF loader extracted into pages, which it separates with `PAGE_BREAK`.
+    pub fn from_pdf_text(contents: String, path: &str) -> Self {
+        let mut file = Self::new(contents, path, DEFAULT_EXTENSION);
+        let pages: Vec<&str> = file.contents.split(PAGE_BREAK).collect();
+        if pages.len() > 1 {
+            file.sections = pages
+                .into_iter()
+                .enumerate()
+                .map(|(index, page)| {
+                    let mut document = RagDocument::new(page);
+                    let page_number = (index + 1).to_string();
+                    document.metadata.insert(PAGE_METADATA.into(), page_number);
+                    document
+                })
+                .collect();
+        }
+        file
+    }
+
+    /// Convert a page to markdown, keeping its title as metadata.
+    pub fn from_html(html: &str, path: &str) -> Self {
+        let mut file = Self::new(html_to_md(html), path, "md");
+        if let Some(title) = html_title(html) {
+            file.metadata.insert(TITLE_METADATA.into(), title);
+        }
+        file
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoaderOptions {
+    pub loaders: HashMap<String, String>,
+    pub crawl_max_depth: usize,
+    pub crawl_max_pages: usize,
+    pub sitemap_include: Option<String>,
+    pub sitemap_exclude: Option<String>,
+    pub csv_columns: Option<Vec<String>>,
+    pub strict: bool,
+    pub dedup: bool,
+    pub respect_gitignore: bool,
+    pub batch_files: usize,
+    /// Don't print progress and warnings, for rags built without a terminal.
+    pub quiet: bool,
+    /// Fail instead of embedding chunks estimated to cost more, unless asked to confirm.
+    pub cost_limit: Option<f64>,
+    /// Ask before embedding anything.
+    pub confirm_cost: bool,
+    /// Exclusion patterns for crawled and sitemap pages, either a url or a url prefix ending in `**`.
+    pub url_excludes: Vec<String>,
+}
+
+impl LoaderOptions {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            loaders: config.document_loaders.clone(),
+            crawl_max_depth: config.rag_crawl_max_depth,
+            crawl_max_pages: config.rag_crawl_max_pages,
+            sitemap_include: config.rag_sitemap_include.clone(),
+            sitemap_exclude: config.rag_sitemap_exclude.clone(),
+            csv_columns: config.rag_csv_columns.clone(),
+            strict: config.rag_strict,
+            dedup: config.rag_dedup,
+            respect_gitignore: config.rag_respect_gitignore,
+            batch_files: config.rag_batch_files,
+            quiet: false,
+            cost_limit: config.rag_cost_limit,
+            confirm_cost: *IS_STDOUT_TERMINAL,
+            url_excludes: vec![],
+        }
+    }
+
+    pub fn is_excluded_url(&self, url: &str) -> bool {
+        self.url_excludes
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("**") {
+                Some(prefix) => url.starts_with(prefix),
+                None => url == pattern,
+            })
+    }
+
+    pub fn print(&self, message: &str) {
+        if !self.quiet {
+            println!("{message}");
+        }
+    }
+}
 
 pub async fn load_recursive_url(
-    loaders: &HashMap<String, String>,
+    options: &LoaderOptions,
     path: &str,
-) -> Result<Vec<(String, RagMetadata)>> {
+    spinner: &Option<Spinner>,
+) -> Result<Vec<LoadedFile>> {
     let extension = RECURSIVE_URL_LOADER;
-    let loader_command = loaders
-        .get(extension)
-        .with_context(|| format!("Document loader '{extension}' not configured"))?;
+    let loader_command = match options.loaders.get(extension) {
+        Some(v) => v,
+        None => return crawl_website(options, path, spinner).await,
+    };
     let contents = run_loader_command(path, extension, loader_command)?;
     let pages: Vec<WebPage> = serde_json::from_str(&contents).context(r#"The crawler response is invalid. It should follow the JSON format: `[{"path":"...", "text":"..."}]`."#)?;
     let output = pages
         .into_iter()
-        .map(|v| {
-            let WebPage { path, text } = v;
-            let mut metadata: RagMetadata = Default::default();
-            metadata.insert(PATH_METADATA.into(), path);
-            metadata.insert(EXTENSION_METADATA.into(), "md".into());
-            (text, metadata)
-        })
+        .filter(|v| !options.is_excluded_url(&v.path))
+        .map(|v| LoadedFile::new(v.text, &v.path, "md"))
         .collect();
     Ok(output)
 }
@@ -35,65 +172,1541 @@ struct WebPage {
     text: String,
 }
 
-pub async fn load_path(
-    loaders: &HashMap<String, String>,
-    path: &str,
-) -> Result<Vec<(String, RagMetadata)>> {
-    let file_paths = expand_glob_paths(&[path]).await?;
+/// Crawl same-origin pages under `start_url` breadth-first, bounded by depth and page count.
+async fn crawl_website(
+    options: &LoaderOptions,
+    start_url: &str,
+    spinner: &Option<Spinner>,
+) -> Result<Vec<LoadedFile>> {
+    let start_url = Url::parse(start_url).with_context(|| format!("Invalid url '{start_url}'"))?;
+    let max_pages = options.crawl_max_pages;
+    let mut visited = HashSet::from([canonical_url(&start_url)]);
+    let mut queue = VecDeque::from([(start_url.clone(), 0)]);
+    let mut output = vec![];
+    while let Some((url, depth)) = queue.pop_front() {
+        if output.len() >= max_pages {
+            break;
+        }
+        progress(
+            spinner,
+            format!("Crawling [{}/{max_pages}] {url}", output.len() + 1),
+        );
+        let (final_url, html) = match fetch_html(url.as_str()).await {
+            Ok(Some(v)) => v,
+            Ok(None) => continue,
+            Err(err) => {
+                options.print(&warning_text(&format!("Skip {url}: {err}")));
+                continue;
+            }
+        };
+        let final_url = match Url::parse(&final_url) {
+            Ok(v) if is_crawl_target(&start_url, &v) => v,
+            _ => continue,
+        };
+        let page_url = canonical_url(&final_url);
+        if final_url != url && !visited.insert(page_url.clone()) {
+            continue;
+        }
+        if depth < options.crawl_max_depth {
+            for link in extract_links(&final_url, &html) {
+                let link_url = canonical_url(&link);
+                if is_crawl_target(&start_url, &link)
+                    && !options.is_excluded_url(&link_url)
+                    && visited.insert(link_url)
+                {
+                    queue.push_back((link, depth + 1));
+                }
+            }
+        }
+        if options.is_excluded_url(&page_url) {
+            continue;
+        }
+        output.push(LoadedFile::from_html(&html, &page_url));
+    }
+    Ok(output)
+}
+
+fn is_crawl_target(start_url: &Url, url: &Url) -> bool {
+    url.origin() == start_url.origin() && url.path().starts_with(start_url.path())
+}
+
+fn extract_links(base_url: &Url, html: &str) -> Vec<Url> {
+    HREF_RE
+        .captures_iter(html)
+        .filter_map(|v| {
+            let href = v.ok()?.get(1)?.as_str();
+            let url = base_url.join(href).ok()?;
+            if ["http", "https"].contains(&url.scheme()) {
+                Some(url)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn canonical_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    let mut value = url.to_string();
+    if url.path() != "/" && url.query().is_none() && value.ends_with('/') {
+        value.pop();
+    }
+    value
+}
+
+/// Load a web page, or every page listed by a sitemap, recognized by its name or root element.
+pub async fn load_web_page(
+    options: &LoaderOptions,
+    url: &str,
+    spinner: &Option<Spinner>,
+) -> Result<Vec<LoadedFile>> {
+    if is_sitemap_url(url) {
+        return load_sitemap(options, url, None, spinner).await;
+    }
+    let file = load_url(&options.loaders, url).await?;
+    let is_xml = file.metadata.get(EXTENSION_METADATA).map(|v| v.as_str()) == Some("xml");
+    if is_xml && matches!(parse_sitemap(&file.contents), Ok(Some(_))) {
+        return load_sitemap(options, url, Some(file.contents), spinner).await;
+    }
+    Ok(vec![file])
+}
+
+fn is_sitemap_url(url: &str) -> bool {
+    let path = Url::parse(url)
+        .map(|v| v.path().to_lowercase())
+        .unwrap_or_default();
+    let name = path.rsplit('/').next().unwrap_or_default();
+    name.contains("sitemap") && name.ends_with(".xml")
+}
+
+/// Fetch the pages listed by a sitemap and the sitemaps it indexes, `contents` being the
+/// sitemap itself if already fetched.
+///
+/// Pages are fetched within this future, so dropping it on abort stops fetching right away.
+async fn load_sitemap(
+    options: &LoaderOptions,
+    url: &str,
+    contents: Option<String>,
+    spinner: &Option<Spinner>,
+) -> Result<Vec<LoadedFile>> {
+    let include = sitemap_regex(&options.sitemap_include, "rag_sitemap_include")?;
+    let exclude = sitemap_regex(&options.sitemap_exclude, "rag_sitemap_exclude")?;
+    let is_selected = |page: &str| {
+        !options.is_excluded_url(page)
+            && include
+                .as_ref()
+                .map(|v| v.is_match(page).unwrap_or_default())
+                .unwrap_or(true)
+            && !exclude
+                .as_ref()
+                .map(|v| v.is_match(page).unwrap_or_default())
+                .unwrap_or_default()
+    };
+    let mut visited = HashSet::from([url.to_string()]);
+    let mut queue = VecDeque::from([(url.to_string(), contents)]);
+    let mut seen = HashSet::new();
+    let mut pages = vec![];
+    while let Some((sitemap_url, contents)) = queue.pop_front() {
+        progress(spinner, format!("Reading sitemap {sitemap_url}"));
+        let sitemap = match read_sitemap(&sitemap_url, contents).await {
+            Ok(v) => v,
+            Err(err) if sitemap_url == url => return Err(err),
+            Err(err) => {
+                options.print(&warning_text(&format!("Skip {sitemap_url}: {err}")));
+                continue;
+            }
+        };
+        for nested_url in sitemap.sitemaps {
+            if visited.insert(nested_url.clone()) {
+                queue.push_back((nested_url, None));
+            }
+        }
+        for page in sitemap.pages {
+            if is_selected(&page) && seen.insert(page.clone()) {
+                pages.push(page);
+            }
+        }
+    }
+    let max_pages = options.crawl_max_pages;
+    if pages.len() > max_pages {
+        options.print(&warning_text(&format!(
+            "Sitemap {url} lists {} pages, loading the first {max_pages}",
+            pages.len()
+        )));
+        pages.truncate(max_pages);
+    }
+
+    let total = pages.len();
+    let tasks = pages.into_iter().map(|page| async move {
+        let ret = load_url(&options.loaders, &page).await;
+        (page, ret)
+    });
+    let mut tasks = stream::iter(tasks).buffered(SITEMAP_CONCURRENCY);
     let mut output = vec![];
-    let file_paths_len = file_paths.len();
-    match file_paths_len {
-        0 => {}
-        1 => output.push(load_file(loaders, &file_paths[0]).await?),
-        _ => {
-            for path in file_paths {
-                println!("🚀 Loading file {path}");
-                output.push(load_file(loaders, &path).await?)
+    let mut done = 0;
+    while let Some((page, ret)) = tasks.next().await {
+        done += 1;
+        progress(spinner, format!("Fetched [{done}/{total}] pages of {url}"));
+        match ret {
+            Ok(mut file) => {
+                file.metadata
+                    .insert(SITEMAP_METADATA.into(), url.to_string());
+                output.push(file);
             }
-            println!("✨ Load directory completed");
+            Err(err) => options.print(&warning_text(&format!("Skip {page}: {err}"))),
         }
     }
     Ok(output)
 }
 
-pub async fn load_file(
-    loaders: &HashMap<String, String>,
+fn sitemap_regex(pattern: &Option<String>, name: &str) -> Result<Option<Regex>> {
+    pattern
+        .as_deref()
+        .map(|v| Regex::new(v).with_context(|| format!("Invalid {name} '{v}'")))
+        .transpose()
+}
+
+async fn read_sitemap(url: &str, contents: Option<String>) -> Result<Sitemap> {
+    let contents = match contents {
+        Some(v) => v,
+        None => fetch(&Default::default(), url, false).await?.0,
+    };
+    match parse_sitemap(&contents).with_context(|| format!("Invalid sitemap '{url}'"))? {
+        Some(v) => Ok(v),
+        None => bail!("'{url}' is not a sitemap"),
+    }
+}
+
+/// The `<loc>` entries of a sitemap.
+#[derive(Debug, Default, PartialEq)]
+struct Sitemap {
+    pages: Vec<String>,
+    sitemaps: Vec<String>,
+}
+
+/// Parse a `<urlset>` or a `<sitemapindex>`, or return `None` for any other xml document.
+fn parse_sitemap(xml: &str) -> Result<Option<Sitemap>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut root = None;
+    let mut in_loc = false;
+    let mut locs = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let name = e.local_name();
+                match root {
+                    None => match name.as_ref() {
+                        b"urlset" | b"sitemapindex" => root = Some(name.as_ref().to_vec()),
+                        _ => return Ok(None),
+                    },
+                    Some(_) => in_loc = name.as_ref() == b"loc",
+                }
+            }
+            Event::Empty(_) if root.is_none() => return Ok(None),
+            Event::Text(e) if in_loc => locs.push(e.unescape()?.trim().to_string()),
+            Event::CData(e) if in_loc => {
+                locs.push(String::from_utf8_lossy(&e.into_inner()).trim().to_string())
+            }
+            Event::End(_) => in_loc = false,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    let locs = locs.into_iter().filter(|v| !v.is_empty()).collect();
+    let output = match root.as_deref() {
+        Some(b"urlset") => Sitemap {
+            pages: locs,
+            ..Default::default()
+        },
+        Some(_) => Sitemap {
+            sitemaps: locs,
+            ..Default::default()
+        },
+        None => return Ok(None),
+    };
+    Ok(Some(output))
+}
+
+/// The outcome of loading every file under a path.
+#[derive(Debug, Default)]
+pub struct LoadedPath {
+    pub files: Vec<LoadedFile>,
+    /// Paths skipped as unchanged since they were last loaded.
+    pub unchanged: Vec<String>,
+    /// Paths that failed to load, with the reason.
+    pub failed: Vec<(String, anyhow::Error)>,
+    /// Files and directories left out by exclusion patterns, ignore files or as binary.
+    pub excluded: usize,
+}
+
+/// Every file of a run failed to load, with the reasons.
+#[derive(Debug)]
+pub struct LoadFailures(pub Vec<(String, anyhow::Error)>);
+
+impl std::fmt::Display for LoadFailures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "All {} files failed to load:", self.0.len())?;
+        for (path, err) in &self.0 {
+            write!(f, "\n  {path}: {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LoadFailures {}
+
+/// Load every file under `path`. A file failing to load is recorded in `failed` and the rest
+/// are still loaded, unless `options.strict` is set.
+pub async fn load_path(
+    options: &LoaderOptions,
     path: &str,
-) -> Result<(String, RagMetadata)> {
+    excludes: &[String],
+    is_unchanged: impl Fn(&str) -> bool,
+) -> Result<LoadedPath> {
+    let listed = list_paths(&[path], excludes, options.respect_gitignore).await?;
+    let mut output = LoadedPath {
+        excluded: listed.excluded,
+        ..Default::default()
+    };
+    let file_paths_len = listed.files.len();
+    let mut pending = vec![];
+    for path in listed.files {
+        if is_unchanged(&path) {
+            output.unchanged.push(path);
+            continue;
+        }
+        if let Some(reason) = skip_reason(&path) {
+            options.print(&warning_text(&format!("Skip {path}: {reason}")));
+            continue;
+        }
+        if is_binary_file(options, &path) {
+            debug!("skip binary file {path}");
+            output.excluded += 1;
+            continue;
+        }
+        pending.push(path);
+    }
+    // Files are loaded concurrently but collected in listing order.
+    let pending_len = pending.len();
+    let options = Arc::new(options.clone());
+    let tasks = pending.into_iter().map(|path| {
+        let options = options.clone();
+        let task_path = path.clone();
+        let task = spawn_task(async move { load_file(&options, &task_path).await });
+        async move { (path, task.await) }
+    });
+    let mut tasks = stream::iter(tasks).buffered(ingest_concurrency());
+    let mut done = 0;
+    while let Some((path, ret)) = tasks.next().await {
+        done += 1;
+        if file_paths_len > 1 {
+            options.print(&format!("🚀 Loaded file {path} [{done}/{pending_len}]"));
+        }
+        match ret {
+            Ok(file) => output.files.push(file),
+            Err(err) if options.strict => {
+                return Err(err.context(format!("Failed to load {path}")));
+            }
+            Err(err) => {
+                options.print(&warning_text(&format!("Failed to load {path}: {err}")));
+                output.failed.push((path, err));
+            }
+        }
+    }
+    if file_paths_len > 1 {
+        options.print("✨ Load directory completed");
+    }
+    Ok(output)
+}
+
+/// How many files are loaded or split at once.
+pub fn ingest_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|v| v.get())
+        .unwrap_or(4)
+}
+
+/// Run `future` as its own task, so that a panic fails only this task.
+pub async fn spawn_task<T: Send + 'static>(
+    future: impl Future<Output = Result<T>> + Send + 'static,
+) -> Result<T> {
+    tokio::spawn(future).await.map_err(|err| anyhow!("{err}"))?
+}
+
+/// Why `path` should not be loaded at all, e.g. it is too large.
+fn skip_reason(path: &str) -> Option<String> {
+    let size = std::fs::metadata(path).map(|v| v.len()).unwrap_or_default();
+    if size > MAX_LOAD_FILE_SIZE {
+        return Some(format!(
+            "larger than {}MB",
+            MAX_LOAD_FILE_SIZE / 1024 / 1024
+        ));
+    }
+    None
+}
+
+/// Sniff the head of `path` for a NUL byte, unless it is in a format with a loader.
+fn is_binary_file(options: &LoaderOptions, path: &str) -> bool {
+    let extension = get_patch_extension(path).unwrap_or_default();
+    if options.loaders.contains_key(&extension) || BINARY_EXTENSIONS.contains(&extension.as_str()) {
+        return false;
+    }
+    let mut head = [0u8; 8192];
+    let len = std::fs::File::open(path)
+        .and_then(|mut file| file.read(&mut head))
+        .unwrap_or_default();
+    head[..len].contains(&0)
+}
+
+pub async fn load_file(options: &LoaderOptions, path: &str) -> Result<LoadedFile> {
     let extension = get_patch_extension(path).unwrap_or_else(|| DEFAULT_EXTENSION.into());
-    match loaders.get(&extension) {
+    match options.loaders.get(&extension) {
         Some(loader_command) => load_with_command(path, &extension, loader_command),
-        None => load_plain(path, &extension).await,
+        None => match extension.as_str() {
+            "pdf" => load_pdf(options, path).await,
+            "docx" | "odt" => load_office(path, &extension).await,
+            "epub" => load_epub(path).await,
+            "ipynb" => load_notebook(path).await,
+            "csv" | "tsv" | "jsonl" => load_records(options, path, &extension).await,
+            "html" | "htm" => load_html(path).await,
+            _ => load_plain(path, &extension).await,
+        },
     }
 }
 
-pub async fn load_url(
-    loaders: &HashMap<String, String>,
-    path: &str,
-) -> Result<(String, RagMetadata)> {
+pub async fn load_url(loaders: &HashMap<String, String>, path: &str) -> Result<LoadedFile> {
     let (contents, extension) = fetch(loaders, path, false).await?;
-    let mut metadata: RagMetadata = Default::default();
-    metadata.insert(PATH_METADATA.into(), path.into());
-    metadata.insert(EXTENSION_METADATA.into(), extension);
-    Ok((contents, metadata))
+    if extension == "pdf" {
+        return Ok(LoadedFile::from_pdf_text(contents, path));
+    }
+    Ok(LoadedFile::new(contents, path, &extension))
 }
 
-async fn load_plain(path: &str, extension: &str) -> Result<(String, RagMetadata)> {
+async fn load_plain(path: &str, extension: &str) -> Result<LoadedFile> {
     let contents = tokio::fs::read_to_string(path).await?;
-    let mut metadata: RagMetadata = Default::default();
-    metadata.insert(PATH_METADATA.into(), path.to_string());
-    metadata.insert(EXTENSION_METADATA.into(), extension.to_string());
-    Ok((contents, metadata))
+    Ok(LoadedFile::new(contents, path, extension))
 }
 
-fn load_with_command(
-    path: &str,
-    extension: &str,
-    loader_command: &str,
-) -> Result<(String, RagMetadata)> {
+async fn load_html(path: &str) -> Result<LoadedFile> {
+    let html = tokio::fs::read_to_string(path).await?;
+    Ok(LoadedFile::from_html(&html, path))
+}
+
+fn load_with_command(path: &str, extension: &str, loader_command: &str) -> Result<LoadedFile> {
     let contents = run_loader_command(path, extension, loader_command)?;
-    let mut metadata: RagMetadata = Default::default();
-    metadata.insert(PATH_METADATA.into(), path.to_string());
-    metadata.insert(EXTENSION_METADATA.into(), DEFAULT_EXTENSION.to_string());
-    Ok((contents, metadata))
+    if extension == "pdf" {
+        return Ok(LoadedFile::from_pdf_text(contents, path));
+    }
+    Ok(LoadedFile::new(contents, path, DEFAULT_EXTENSION))
+}
+
+async fn load_pdf(options: &LoaderOptions, path: &str) -> Result<LoadedFile> {
+    let data = tokio::fs::read(path).await?;
+    let mut sections = vec![];
+    for (page_number, text) in read_pdf_pages(&data)? {
+        if text.is_empty() {
+            let message = format!("Skip page {page_number} of {path}: no text, maybe an image");
+            options.print(&warning_text(&message));
+            continue;
+        }
+        let mut document = RagDocument::new(text);
+        document
+            .metadata
+            .insert(PAGE_METADATA.into(), page_number.to_string());
+        sections.push(document);
+    }
+    let contents = sections
+        .iter()
+        .map(|v| v.page_content.as_str())
+        .collect::<Vec<&str>>()
+        .join(&PAGE_BREAK.to_string());
+    let mut file = LoadedFile::new(contents, path, DEFAULT_EXTENSION);
+    file.sections = sections;
+    Ok(file)
+}
+
+async fn load_office(path: &str, extension: &str) -> Result<LoadedFile> {
+    let data = tokio::fs::read(path).await?;
+    let (entry, dialect) = match extension {
+        "odt" => ("content.xml", OfficeDialect::Odt),
+        _ => ("word/document.xml", OfficeDialect::Docx),
+    };
+    let xml = read_zip_entry(&data, entry).context("Invalid document archive")?;
+    let sections = parse_office_xml(&xml, dialect).context("Invalid document content")?;
+    let contents = sections
+        .iter()
+        .map(|v| v.page_content.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n\n");
+    let mut file = LoadedFile::new(contents, path, "md");
+    file.sections = sections;
+    Ok(file)
+}
+
+async fn load_epub(path: &str) -> Result<LoadedFile> {
+    let data = tokio::fs::read(path).await?;
+    let sections = read_epub(&data)?;
+    let contents = sections
+        .iter()
+        .map(|v| v.page_content.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n\n");
+    let mut file = LoadedFile::new(contents, path, "md");
+    file.sections = sections;
+    Ok(file)
+}
+
+/// Convert the chapters of an epub to markdown in reading order, one document per chapter
+/// titled from the table of contents.
+fn read_epub(data: &[u8]) -> Result<Vec<RagDocument>> {
+    let container =
+        read_zip_entry(data, "META-INF/container.xml").context("Invalid epub archive")?;
+    let package_path = xml_elements(&container)?
+        .into_iter()
+        .find(|(name, _)| name == "rootfile")
+        .and_then(|(_, mut attrs)| attrs.swap_remove("full-path"))
+        .context("No package document in the epub")?;
+    let package = read_zip_entry(data, &package_path).context("Invalid epub package")?;
+    let package_dir = epub_dir(&package_path);
+
+    // Fonts may be obfuscated through encryption.xml as well, only encrypted chapters matter.
+    let encrypted: HashSet<String> = match read_zip_entry(data, "META-INF/encryption.xml") {
+        Ok(xml) => xml_elements(&xml)?
+            .into_iter()
+            .filter(|(name, _)| name == "CipherReference")
+            .filter_map(|(_, mut attrs)| attrs.swap_remove("URI"))
+            .map(|uri| resolve_epub_path("", &uri))
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+    let has_rights = read_zip_entry(data, "META-INF/rights.xml").is_ok();
+
+    let mut manifest: HashMap<String, IndexMap<String, String>> = HashMap::new();
+    let mut spine = vec![];
+    let mut toc_id = None;
+    for (name, mut attrs) in xml_elements(&package)? {
+        match name.as_str() {
+            "item" => {
+                if let Some(id) = attrs.get("id").cloned() {
+                    manifest.insert(id, attrs);
+                }
+            }
+            "spine" => toc_id = attrs.swap_remove("toc"),
+            "itemref" => spine.extend(attrs.swap_remove("idref")),
+            _ => {}
+        }
+    }
+    let toc_item = manifest.values().find(|attrs| {
+        attrs
+            .get("properties")
+            .is_some_and(|v| v.split_whitespace().any(|v| v == "nav"))
+    });
+    let toc_item = toc_item.or_else(|| toc_id.as_ref().and_then(|id| manifest.get(id)));
+    let mut titles: HashMap<String, String> = HashMap::new();
+    if let Some(href) = toc_item.and_then(|attrs| attrs.get("href")) {
+        let toc_path = resolve_epub_path(package_dir, href);
+        if let Ok(xml) = read_zip_entry(data, &toc_path) {
+            for (href, title) in parse_epub_toc(&xml)? {
+                let path = resolve_epub_path(epub_dir(&toc_path), &href);
+                titles.entry(path).or_insert(title);
+            }
+        }
+    }
+
+    let mut output = vec![];
+    for idref in spine {
+        let Some(attrs) = manifest.get(&idref) else {
+            continue;
+        };
+        let (Some(href), Some(media_type)) = (attrs.get("href"), attrs.get("media-type")) else {
+            continue;
+        };
+        if !media_type.contains("html") {
+            continue;
+        }
+        let path = resolve_epub_path(package_dir, href);
+        if has_rights || encrypted.contains(&path) {
+            bail!("The epub is DRM-protected, remove the protection to index it");
+        }
+        let html =
+            read_zip_entry(data, &path).with_context(|| format!("Invalid chapter {path}"))?;
+        let text = html_to_md(&html).trim().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        let mut document = RagDocument::new(text);
+        if let Some(title) = titles.get(&path).cloned().or_else(|| html_title(&html)) {
+            document.metadata.insert(CHAPTER_METADATA.into(), title);
+        }
+        output.push(document);
+    }
+    if output.is_empty() {
+        bail!("No chapters in the epub");
+    }
+    Ok(output)
+}
+
+/// List the elements of an xml document with their attributes, both without namespace prefixes.
+fn xml_elements(xml: &str) -> Result<Vec<(String, IndexMap<String, String>)>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut output = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                let mut attrs = IndexMap::new();
+                for attr in e.attributes().flatten() {
+                    let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).to_string();
+                    attrs.insert(key, attr.unescape_value()?.to_string());
+                }
+                output.push((name, attrs));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+/// Collect the targets and titles of an EPUB 3 navigation document or an EPUB 2 NCX.
+fn parse_epub_toc(xml: &str) -> Result<Vec<(String, String)>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut output = vec![];
+    let mut label = String::new();
+    let mut in_label = false;
+    let mut anchor: Option<(String, String)> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"text" => {
+                in_label = true;
+                label.clear();
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"a" => {
+                if let Some(href) = e.try_get_attribute("href")? {
+                    anchor = Some((href.unescape_value()?.to_string(), String::new()));
+                }
+            }
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"content" => {
+                if let Some(src) = e.try_get_attribute("src")? {
+                    let title = label.trim();
+                    if !title.is_empty() {
+                        output.push((src.unescape_value()?.to_string(), title.to_string()));
+                    }
+                }
+            }
+            Event::Text(e) => {
+                let text = e
+                    .unescape()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&e).to_string());
+                if in_label {
+                    label.push_str(&text);
+                }
+                if let Some((_, title)) = anchor.as_mut() {
+                    title.push_str(&text);
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"text" => in_label = false,
+            Event::End(e) if e.local_name().as_ref() == b"a" => {
+                if let Some((href, title)) = anchor.take() {
+                    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if !title.is_empty() {
+                        output.push((href, title));
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+fn epub_dir(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(dir, _)| dir)
+        .unwrap_or_default()
+}
+
+/// Resolve an url-encoded `href` relative to the archive directory `dir`, dropping its fragment.
+fn resolve_epub_path(dir: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let href = urlencoding::decode(href)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| href.to_string());
+    let mut parts: Vec<&str> = dir.split('/').filter(|v| !v.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+async fn load_notebook(path: &str) -> Result<LoadedFile> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    let sections = read_notebook(&contents).context("Invalid notebook")?;
+    let contents = sections
+        .iter()
+        .map(|v| v.page_content.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n\n");
+    let mut file = LoadedFile::new(contents, path, "ipynb");
+    file.sections = sections;
+    Ok(file)
+}
+
+/// Split a jupyter notebook into one document per markdown or code cell. Code cells keep only
+/// their short plain-text stream outputs and are split with the separators of the kernel language.
+fn read_notebook(contents: &str) -> Result<Vec<RagDocument>> {
+    let notebook: Value = serde_json::from_str(contents)?;
+    let cells = notebook["cells"].as_array().context("Missing cells")?;
+    let metadata = &notebook["metadata"];
+    let code_extension = match metadata["language_info"]["file_extension"].as_str() {
+        Some(v) => v.trim_start_matches('.').to_string(),
+        None => match metadata["kernelspec"]["language"]
+            .as_str()
+            .unwrap_or("python")
+        {
+            "python" => "py".into(),
+            "javascript" => "js".into(),
+            "typescript" => "ts".into(),
+            "rust" => "rs".into(),
+            v => v.to_lowercase(),
+        },
+    };
+    let mut sections = vec![];
+    for (index, cell) in cells.iter().enumerate() {
+        let cell_type = cell["cell_type"].as_str().unwrap_or_default();
+        let extension = match cell_type {
+            "markdown" => "md",
+            "code" => code_extension.as_str(),
+            _ => continue,
+        };
+        let mut text = notebook_text(&cell["source"]);
+        if cell_type == "code" {
+            for output in cell["outputs"].as_array().into_iter().flatten() {
+                if output["output_type"].as_str() != Some("stream") {
+                    continue;
+                }
+                let output = notebook_text(&output["text"]);
+                if !output.trim().is_empty() && output.len() <= NOTEBOOK_OUTPUT_MAX_LEN {
+                    text.push_str(&format!("\n\nOutput:\n{}", output.trim_end()));
+                }
+            }
+        }
+        if text.trim().is_empty() {
+            continue;
+        }
+        let mut document = RagDocument::new(text);
+        for (key, value) in [
+            (EXTENSION_METADATA, extension.to_string()),
+            (CELL_TYPE_METADATA, cell_type.to_string()),
+            (CELL_INDEX_METADATA, index.to_string()),
+        ] {
+            document.metadata.insert(key.into(), value);
+        }
+        sections.push(document);
+    }
+    Ok(sections)
+}
+
+/// Notebook text fields are either a string or a list of lines.
+fn notebook_text(value: &Value) -> String {
+    match value {
+        Value::String(v) => v.clone(),
+        Value::Array(lines) => lines.iter().filter_map(|v| v.as_str()).collect(),
+        _ => String::new(),
+    }
+}
+
+async fn load_records(options: &LoaderOptions, path: &str, extension: &str) -> Result<LoadedFile> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    let columns = options.csv_columns.as_deref();
+    let records = match extension {
+        "jsonl" => parse_jsonl_records(&contents, columns)?,
+        "tsv" => parse_csv_records(&contents, b'\t', columns)?,
+        _ => parse_csv_records(&contents, b',', columns)?,
+    };
+    let sections: Vec<RagDocument> = records
+        .into_iter()
+        .map(|(row, fields)| {
+            let text = fields
+                .iter()
+                .map(|(key, value)| format!("{key}: {value}"))
+                .collect::<Vec<String>>()
+                .join("\n");
+            let mut document = RagDocument::new(text);
+            document
+                .metadata
+                .insert(ROW_METADATA.into(), row.to_string());
+            document
+        })
+        .collect();
+    let contents = sections
+        .iter()
+        .map(|v| v.page_content.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n\n");
+    let mut file = LoadedFile::new(contents, path, DEFAULT_EXTENSION);
+    file.sections = sections;
+    Ok(file)
+}
+
+type Record = (usize, Vec<(String, String)>);
+
+fn parse_csv_records(
+    contents: &str,
+    delimiter: u8,
+    columns: Option<&[String]>,
+) -> Result<Vec<Record>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let headers: Vec<String> = reader.headers()?.iter().map(|v| v.to_string()).collect();
+    let mut output = vec![];
+    for (index, record) in reader.records().enumerate() {
+        let record = record?;
+        let fields = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(key, value)| !value.is_empty() && is_selected_column(columns, key))
+            .map(|(key, value)| (key.clone(), value.to_string()))
+            .collect::<Vec<_>>();
+        if !fields.is_empty() {
+            output.push((index + 1, fields));
+        }
+    }
+    Ok(output)
+}
+
+fn parse_jsonl_records(contents: &str, columns: Option<&[String]>) -> Result<Vec<Record>> {
+    let mut output = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(line)
+            .with_context(|| format!("Invalid JSON at line {}", index + 1))?;
+        let fields = match value {
+            Value::Object(map) => map
+                .into_iter()
+                .filter(|(key, value)| !value.is_null() && is_selected_column(columns, key))
+                .map(|(key, value)| match value {
+                    Value::String(value) => (key, value),
+                    value => (key, value.to_string()),
+                })
+                .collect::<Vec<_>>(),
+            value => vec![("value".into(), value.to_string())],
+        };
+        if !fields.is_empty() {
+            output.push((index + 1, fields));
+        }
+    }
+    Ok(output)
+}
+
+fn is_selected_column(columns: Option<&[String]>, key: &str) -> bool {
+    columns
+        .map(|columns| columns.iter().any(|v| v == key))
+        .unwrap_or(true)
+}
+
+fn read_zip_entry(data: &[u8], name: &str) -> Result<String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let mut file = archive.by_name(name)?;
+    let mut output = String::new();
+    file.read_to_string(&mut output)?;
+    Ok(output)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OfficeDialect {
+    Docx,
+    Odt,
+}
+
+impl OfficeDialect {
+    fn paragraph_tags(&self) -> &'static [&'static [u8]] {
+        match self {
+            OfficeDialect::Docx => &[b"w:p"],
+            OfficeDialect::Odt => &[b"text:p", b"text:h"],
+        }
+    }
+
+    fn text_row_cell_tags(&self) -> (&'static [u8], &'static [u8], &'static [u8]) {
+        match self {
+            OfficeDialect::Docx => (b"w:t", b"w:tr", b"w:tc"),
+            OfficeDialect::Odt => (b"", b"table:table-row", b"table:table-cell"),
+        }
+    }
+}
+
+/// Extract markdown-like text from a docx/odt body, one section per heading.
+fn parse_office_xml(xml: &str, dialect: OfficeDialect) -> Result<Vec<RagDocument>> {
+    let paragraph_tags = dialect.paragraph_tags();
+    let (text_tag, row_tag, cell_tag) = dialect.text_row_cell_tags();
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut sections: Vec<(Option<String>, Vec<String>)> = vec![(None, vec![])];
+    let mut paragraph: Option<String> = None;
+    let mut heading_level: Option<usize> = None;
+    let mut in_text = dialect == OfficeDialect::Odt;
+    let mut row: Option<Vec<String>> = None;
+    let mut cell: Option<String> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let name = e.name();
+                let name = name.as_ref();
+                if paragraph_tags.contains(&name) {
+                    paragraph = Some(String::new());
+                    heading_level = odt_heading_level(&e)?;
+                } else if name == text_tag {
+                    in_text = true;
+                } else if name == row_tag {
+                    row = Some(vec![]);
+                } else if name == cell_tag {
+                    cell = Some(String::new());
+                }
+            }
+            Event::Empty(e) => {
+                let name = e.name();
+                let name = name.as_ref();
+                if name == b"w:pStyle" {
+                    heading_level = docx_heading_level(&e)?;
+                } else if let Some(paragraph) = paragraph.as_mut() {
+                    match name {
+                        b"w:tab" | b"text:tab" => paragraph.push('\t'),
+                        b"w:br" | b"text:line-break" => paragraph.push('\n'),
+                        b"text:s" => paragraph.push(' '),
+                        _ => {}
+                    }
+                }
+            }
+            Event::Text(e) => {
+                if let (true, Some(paragraph)) = (in_text, paragraph.as_mut()) {
+                    paragraph.push_str(&e.unescape()?);
+                }
+            }
+            Event::End(e) => {
+                let name = e.name();
+                let name = name.as_ref();
+                if paragraph_tags.contains(&name) {
+                    let text = paragraph.take().unwrap_or_default();
+                    let text = text.trim();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    if let Some(cell) = cell.as_mut() {
+                        if !cell.is_empty() {
+                            cell.push(' ');
+                        }
+                        cell.push_str(text);
+                    } else if let Some(level) = heading_level {
+                        let heading = format!("{} {text}", "#".repeat(level.clamp(1, 6)));
+                        sections.push((Some(text.to_string()), vec![heading]));
+                    } else if let Some((_, blocks)) = sections.last_mut() {
+                        blocks.push(text.to_string());
+                    }
+                } else if name == text_tag {
+                    in_text = false;
+                } else if name == cell_tag {
+                    if let (Some(row), Some(cell)) = (row.as_mut(), cell.take()) {
+                        row.push(cell);
+                    }
+                } else if name == row_tag {
+                    let cells = row.take().unwrap_or_default();
+                    if cells.iter().any(|v| !v.is_empty()) {
+                        if let Some((_, blocks)) = sections.last_mut() {
+                            blocks.push(cells.join(" | "));
+                        }
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    let output = sections
+        .into_iter()
+        .filter(|(_, blocks)| !blocks.is_empty())
+        .map(|(heading, blocks)| {
+            let mut document = RagDocument::new(blocks.join("\n\n"));
+            if let Some(heading) = heading {
+                document.metadata.insert(SECTION_METADATA.into(), heading);
+            }
+            document
+        })
+        .collect();
+    Ok(output)
+}
+
+fn docx_heading_level(e: &BytesStart) -> Result<Option<usize>> {
+    let style = match e.try_get_attribute("w:val")? {
+        Some(v) => v.unescape_value()?.to_lowercase(),
+        None => return Ok(None),
+    };
+    if style == "title" {
+        return Ok(Some(1));
+    }
+    let level = style
+        .strip_prefix("heading")
+        .map(|v| v.trim().parse().unwrap_or(1));
+    Ok(level)
+}
+
+fn odt_heading_level(e: &BytesStart) -> Result<Option<usize>> {
+    if e.name().as_ref() != b"text:h" {
+        return Ok(None);
+    }
+    let level = match e.try_get_attribute("text:outline-level")? {
+        Some(v) => v.unescape_value()?.parse().unwrap_or(1),
+        None => 1,
+    };
+    Ok(Some(level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_docx_xml() {
+        let xml = r#"<w:document><w:body>
+<w:p><w:r><w:t>Intro text</w:t></w:r></w:p>
+<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Pricing</w:t></w:r></w:p>
+<w:p><w:r><w:t xml:space="preserve">Plans &amp; </w:t></w:r><w:r><w:t>prices</w:t></w:r></w:p>
+<w:tbl>
+<w:tr><w:tc><w:p><w:r><w:t>Plan</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>Price</w:t></w:r></w:p></w:tc></w:tr>
+<w:tr><w:tc><w:p><w:r><w:t>Pro</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>$10</w:t></w:r></w:p></w:tc></w:tr>
+</w:tbl>
+</w:body></w:document>"#;
+        let sections = parse_office_xml(xml, OfficeDialect::Docx).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].page_content, "Intro text");
+        assert!(sections[0].metadata.is_empty());
+        assert_eq!(
+            sections[1].page_content,
+            "# Pricing\n\nPlans & prices\n\nPlan | Price\n\nPro | $10"
+        );
+        assert_eq!(sections[1].metadata[SECTION_METADATA], "Pricing");
+    }
+
+    #[test]
+    fn test_parse_odt_xml() {
+        let xml = r#"<office:text>
+<text:h text:outline-level="2">Setup</text:h>
+<text:p>Run<text:s/><text:span>install</text:span></text:p>
+</office:text>"#;
+        let sections = parse_office_xml(xml, OfficeDialect::Odt).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].page_content, "## Setup\n\nRun install");
+        assert_eq!(sections[0].metadata[SECTION_METADATA], "Setup");
+    }
+
+    #[test]
+    fn test_parse_csv_records() {
+        let contents = "name,price,notes\nPro,10,\"Billed, monthly\"\nFree,0,\n";
+        let records = parse_csv_records(contents, b',', None).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (
+                    1,
+                    vec![
+                        ("name".into(), "Pro".into()),
+                        ("price".into(), "10".into()),
+                        ("notes".into(), "Billed, monthly".into())
+                    ]
+                ),
+                (
+                    2,
+                    vec![("name".into(), "Free".into()), ("price".into(), "0".into())]
+                ),
+            ]
+        );
+        let columns = vec!["price".to_string()];
+        let records = parse_csv_records("name\tprice\nPro\t10\n", b'\t', Some(&columns)).unwrap();
+        assert_eq!(records, vec![(1, vec![("price".into(), "10".into())])]);
+    }
+
+    #[test]
+    fn test_parse_jsonl_records() {
+        let contents = "{\"name\":\"Pro\",\"price\":10,\"tags\":null}\n\n{\"name\":\"Free\"}\n";
+        let records = parse_jsonl_records(contents, None).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (
+                    1,
+                    vec![("name".into(), "Pro".into()), ("price".into(), "10".into())]
+                ),
+                (3, vec![("name".into(), "Free".into())]),
+            ]
+        );
+        assert!(parse_jsonl_records("{\"name\":", None).is_err());
+    }
+
+    #[test]
+    fn test_read_zip_entry_corrupt() {
+        assert!(read_zip_entry(b"not a zip archive", "word/document.xml").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_path_collects_failures() {
+        let dir = std::env::temp_dir().join(format!("aichat-load-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("valid.md"), "# Valid").unwrap();
+        std::fs::write(dir.join("corrupt.docx"), "not a zip archive").unwrap();
+        std::fs::write(dir.join("image.bin"), [0u8, 1, 2, 3]).unwrap();
+        let path = dir.display().to_string();
+
+        let mut options = LoaderOptions::default();
+        let output = load_path(&options, &path, &[], |_| false).await.unwrap();
+        assert_eq!(output.files.len(), 1);
+        assert_eq!(output.files[0].contents, "# Valid");
+        assert_eq!(output.failed.len(), 1);
+        assert!(output.failed[0].0.ends_with("corrupt.docx"));
+        assert_eq!(output.excluded, 1);
+
+        options.strict = true;
+        let err = load_path(&options, &path, &[], |_| false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("corrupt.docx"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_pdf() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.pdf");
+        let file = load_file(&LoaderOptions::default(), path).await.unwrap();
+        let pages: Vec<(&str, &str)> = file
+            .sections
+            .iter()
+            .map(|v| (v.metadata[PAGE_METADATA].as_str(), v.page_content.as_str()))
+            .collect();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].0, "1");
+        assert!(pages[0].1.starts_with("Installation guide"));
+        assert_eq!(pages[1].0, "3");
+        assert!(pages[1].1.starts_with("Configuration reference"));
+    }
+
+    #[tokio::test]
+    async fn test_load_page_breaks() {
+        let dir = std::env::temp_dir().join(format!("aichat-page-breaks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text_path = dir.join("notes.txt").display().to_string();
+        let pdf_path = dir.join("manual.pdf").display().to_string();
+        std::fs::write(&text_path, "one\x0ctwo").unwrap();
+        std::fs::write(&pdf_path, "one\x0ctwo").unwrap();
+
+        let mut options = LoaderOptions::default();
+        let file = load_file(&options, &text_path).await.unwrap();
+        assert!(file.sections.is_empty());
+        assert_eq!(file.contents, "one\x0ctwo");
+
+        options.loaders.insert("pdf".into(), "cat $1".into());
+        let file = load_file(&options, &pdf_path).await.unwrap();
+        let pages: Vec<(&str, &str)> = file
+            .sections
+            .iter()
+            .map(|v| (v.metadata[PAGE_METADATA].as_str(), v.page_content.as_str()))
+            .collect();
+        assert_eq!(pages, [("1", "one"), ("2", "two")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn build_epub(files: &[(&str, &str)]) -> Vec<u8> {
+        use std::io::Write;
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, content) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_read_epub() {
+        let container = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+        let package = r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="cover" href="images/cover.jpg" media-type="image/jpeg"/>
+    <item id="c1" href="text/chapter%201.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="text/chapter2.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="ncx"><itemref idref="c2"/><itemref idref="cover"/><itemref idref="c1"/></spine>
+</package>"#;
+        let ncx = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/"><navMap>
+  <navPoint id="p1"><navLabel><text>Chapter One &amp; More</text></navLabel><content src="text/chapter%201.xhtml"/></navPoint>
+  <navPoint id="p2"><navLabel><text>Chapter Two</text></navLabel><content src="text/chapter2.xhtml#start"/></navPoint>
+</navMap></ncx>"#;
+        let chapter1 = r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>c1</title></head>
+<body><h1>One</h1><p>First <em>chapter</em> text.</p></body></html>"#;
+        let chapter2 = r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>c2</title></head>
+<body><h1>Two</h1><p>Second chapter text.</p></body></html>"#;
+        let mut files = vec![
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", container),
+            ("OEBPS/content.opf", package),
+            ("OEBPS/toc.ncx", ncx),
+            ("OEBPS/text/chapter 1.xhtml", chapter1),
+            ("OEBPS/text/chapter2.xhtml", chapter2),
+        ];
+        let sections = read_epub(&build_epub(&files)).unwrap();
+        let chapters: Vec<(&str, &str)> = sections
+            .iter()
+            .map(|v| {
+                (
+                    v.metadata[CHAPTER_METADATA].as_str(),
+                    v.page_content.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            chapters,
+            [
+                ("Chapter Two", "# Two\n\nSecond chapter text."),
+                ("Chapter One & More", "# One\n\nFirst *chapter* text."),
+            ]
+        );
+
+        // Obfuscated fonts are fine, encrypted chapters are not.
+        let font_encryption = r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+<enc:EncryptedData><enc:CipherData><enc:CipherReference URI="OEBPS/fonts/serif.otf"/></enc:CipherData></enc:EncryptedData>
+</encryption>"#;
+        files.push(("META-INF/encryption.xml", font_encryption));
+        assert_eq!(read_epub(&build_epub(&files)).unwrap().len(), 2);
+        let drm_encryption = font_encryption.replace("fonts/serif.otf", "text/chapter%201.xhtml");
+        files.pop();
+        files.push(("META-INF/encryption.xml", &drm_encryption));
+        let err = read_epub(&build_epub(&files)).unwrap_err();
+        assert!(err.to_string().contains("DRM-protected"));
+
+        let nav = r#"<nav epub:type="toc"><ol><li><a href="c1.xhtml#x">Intro
+<span>Part</span></a></li></ol></nav>"#;
+        assert_eq!(
+            parse_epub_toc(nav).unwrap(),
+            [("c1.xhtml#x".to_string(), "Intro Part".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_html() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.html");
+        let file = load_file(&LoaderOptions::default(), path).await.unwrap();
+        assert_eq!(file.metadata[EXTENSION_METADATA], "md");
+        assert_eq!(file.metadata[TITLE_METADATA], "Widgets & Gadgets Guide");
+        assert!(file.contents.starts_with("# Getting started"));
+        assert!(!file.contents.contains("<code") && !file.contents.contains("</"));
+    }
+
+    #[tokio::test]
+    async fn test_load_notebook() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.ipynb");
+        let file = load_file(&LoaderOptions::default(), path).await.unwrap();
+        assert_eq!(file.metadata[EXTENSION_METADATA], "ipynb");
+        let cells: Vec<(&str, &str, &str)> = file
+            .sections
+            .iter()
+            .map(|v| {
+                (
+                    v.metadata[CELL_INDEX_METADATA].as_str(),
+                    v.metadata[CELL_TYPE_METADATA].as_str(),
+                    v.metadata[EXTENSION_METADATA].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            cells,
+            [
+                ("0", "markdown", "md"),
+                ("1", "code", "py"),
+                ("2", "code", "py"),
+                ("5", "markdown", "md"),
+            ]
+        );
+        assert!(file.sections[0]
+            .page_content
+            .starts_with("# Sales analysis\n\n"));
+        assert!(file.sections[1]
+            .page_content
+            .ends_with("print(f\"rows: {len(df)}\")\n\nOutput:\nrows: 4"));
+        assert_eq!(
+            file.sections[2].page_content,
+            "df.plot(x=\"quarter\", y=\"revenue\")"
+        );
+        assert!(!file.contents.contains("iVBOR"));
+
+        let output = "x".repeat(NOTEBOOK_OUTPUT_MAX_LEN + 1);
+        let notebook = serde_json::json!({
+            "cells": [{
+                "cell_type": "code",
+                "source": "print(x)",
+                "outputs": [{"output_type": "stream", "name": "stdout", "text": output}],
+            }],
+            "metadata": {"kernelspec": {"language": "R"}},
+        });
+        let sections = read_notebook(&notebook.to_string()).unwrap();
+        assert_eq!(sections[0].page_content, "print(x)");
+        assert_eq!(sections[0].metadata[EXTENSION_METADATA], "r");
+        assert!(read_notebook("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_task_panic() {
+        let ret: Result<()> = spawn_task(async { panic!("broken file") }).await;
+        assert!(ret.unwrap_err().to_string().contains("panicked"));
+        assert_eq!(spawn_task(async { Ok(1) }).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let base_url = Url::parse("https://docs.example.com/guide/").unwrap();
+        let html = r#"<a href="intro.html#setup">Intro</a> <a class="x" href='/guide/api/'>API</a>
+<a href="mailto:me@example.com">Mail</a> <a href="https://other.com/">Other</a>"#;
+        let links: Vec<String> = extract_links(&base_url, html)
+            .iter()
+            .map(canonical_url)
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                "https://docs.example.com/guide/intro.html",
+                "https://docs.example.com/guide/api",
+                "https://other.com/",
+            ]
+        );
+        let targets: Vec<Url> = extract_links(&base_url, html)
+            .into_iter()
+            .filter(|v| is_crawl_target(&base_url, v))
+            .collect();
+        assert_eq!(targets.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_sitemap() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/</loc><lastmod>2024-01-01</lastmod></url>
+  <url><loc> https://example.com/docs?a=1&amp;b=2 </loc></url>
+  <url><loc><![CDATA[https://example.com/blog]]></loc></url>
+</urlset>"#;
+        let sitemap = parse_sitemap(xml).unwrap().unwrap();
+        assert_eq!(
+            sitemap.pages,
+            [
+                "https://example.com/",
+                "https://example.com/docs?a=1&b=2",
+                "https://example.com/blog"
+            ]
+        );
+        assert!(sitemap.sitemaps.is_empty());
+
+        let xml = r#"<sitemapindex><sitemap><loc>https://example.com/a.xml</loc></sitemap></sitemapindex>"#;
+        let sitemap = parse_sitemap(xml).unwrap().unwrap();
+        assert_eq!(sitemap.sitemaps, ["https://example.com/a.xml"]);
+        assert!(sitemap.pages.is_empty());
+
+        assert_eq!(parse_sitemap("<rss><channel/></rss>").unwrap(), None);
+        assert!(is_sitemap_url("https://example.com/sitemap_index.xml"));
+        assert!(is_sitemap_url("https://example.com/post-sitemap.XML?v=2"));
+        assert!(!is_sitemap_url("https://example.com/feed.xml"));
+        assert!(!is_sitemap_url("https://example.com/sitemap"));
+    }
+
+    /// Serve each path with its content type and body after a delay in milliseconds, with
+    /// `{base}` in the bodies replaced by the server address.
+    async fn serve(routes: Vec<(&'static str, &'static str, String, u64)>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let routes: Arc<Vec<_>> = Arc::new(
+            routes
+                .into_iter()
+                .map(|(path, content_type, body, delay)| {
+                    (path, content_type, body.replace("{base}", &base_url), delay)
+                })
+                .collect(),
+        );
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let routes = routes.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buf = [0; 4096];
+                    while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let path = request.split(' ').nth(1).unwrap_or_default();
+                    let reply = match routes.iter().find(|(v, ..)| *v == path) {
+                        Some((_, content_type, body, delay)) => {
+                            tokio::time::sleep(std::time::Duration::from_millis(*delay)).await;
+                            format!("HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\n\r\n{body}", body.len())
+                        }
+                        None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".into(),
+                    };
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                });
+            }
+        });
+        base_url
+    }
+
+    #[tokio::test]
+    async fn test_crawl_website_excludes() {
+        let page = |links: &[&str]| {
+            let links: String = links
+                .iter()
+                .map(|v| format!("<a href=\"{v}\">{v}</a>"))
+                .collect();
+            format!("<html><body>{links}</body></html>")
+        };
+        let base_url = serve(vec![
+            (
+                "/docs",
+                "text/html",
+                page(&["/docs/a", "/docs/private/b"]),
+                0,
+            ),
+            ("/docs/a", "text/html", page(&["/docs/private/c"]), 0),
+            ("/docs/private/b", "text/html", page(&[]), 0),
+            ("/docs/private/c", "text/html", page(&[]), 0),
+        ])
+        .await;
+        let options = LoaderOptions {
+            crawl_max_depth: 3,
+            crawl_max_pages: 100,
+            quiet: true,
+            url_excludes: vec![format!("{base_url}/docs/private/**")],
+            ..Default::default()
+        };
+        let url = format!("{base_url}/docs");
+        let files = load_recursive_url(&options, &url, &None).await.unwrap();
+        let paths: Vec<&str> = files
+            .iter()
+            .map(|v| v.metadata[PATH_METADATA].trim_start_matches(&base_url))
+            .collect();
+        assert_eq!(paths, ["/docs", "/docs/a"]);
+    }
+
+    #[tokio::test]
+    async fn test_load_sitemap() {
+        let page = |title: &str| format!("<html><body><h1>{title}</h1></body></html>");
+        let urlset = |paths: &[&str]| {
+            let urls: String = paths
+                .iter()
+                .map(|v| format!("<url><loc>{{base}}{v}</loc></url>"))
+                .collect();
+            format!("<urlset>{urls}</urlset>")
+        };
+        let index = "<sitemapindex><sitemap><loc>{base}/docs.xml</loc></sitemap>\
+<sitemap><loc>{base}/blog.xml</loc></sitemap><sitemap><loc>{base}/gone.xml</loc></sitemap>\
+<sitemap><loc>{base}/index.xml</loc></sitemap></sitemapindex>";
+        let base_url = serve(vec![
+            ("/index.xml", "application/xml", index.into(), 0),
+            (
+                "/docs.xml",
+                "application/xml",
+                urlset(&["/docs/a", "/docs/b", "/docs/a"]),
+                0,
+            ),
+            (
+                "/blog.xml",
+                "text/xml",
+                urlset(&["/blog/c", "/docs/b", "/missing"]),
+                0,
+            ),
+            (
+                "/slow.xml",
+                "application/xml",
+                urlset(&["/docs/a", "/slow"]),
+                0,
+            ),
+            ("/docs/a", "text/html", page("Page A"), 0),
+            ("/docs/b", "text/html", page("Page B"), 0),
+            ("/blog/c", "text/html", page("Page C"), 0),
+            ("/slow", "text/html", page("Slow"), 10_000),
+        ])
+        .await;
+        let options = LoaderOptions {
+            crawl_max_pages: 100,
+            quiet: true,
+            ..Default::default()
+        };
+        let paths = |files: &[LoadedFile]| -> Vec<String> {
+            files
+                .iter()
+                .map(|v| {
+                    v.metadata[PATH_METADATA]
+                        .trim_start_matches(&base_url)
+                        .to_string()
+                })
+                .collect()
+        };
+
+        // Detected by the root element, as the name doesn't look like a sitemap.
+        let url = format!("{base_url}/index.xml");
+        let files = load_web_page(&options, &url, &None).await.unwrap();
+        assert_eq!(paths(&files), ["/docs/a", "/docs/b", "/blog/c"]);
+        assert!(files[0].contents.contains("Page A"));
+        assert_eq!(files[0].metadata[SITEMAP_METADATA], url);
+
+        let options_excluding = LoaderOptions {
+            url_excludes: vec![format!("{base_url}/blog/**"), format!("{base_url}/docs/a")],
+            ..options.clone()
+        };
+        let files = load_web_page(&options_excluding, &url, &None)
+            .await
+            .unwrap();
+        assert_eq!(paths(&files), ["/docs/b"]);
+
+        let options = LoaderOptions {
+            crawl_max_pages: 1,
+            sitemap_include: Some("/docs/".into()),
+            sitemap_exclude: Some("/a$".into()),
+            ..options
+        };
+        let files = load_web_page(&options, &url, &None).await.unwrap();
+        assert_eq!(paths(&files), ["/docs/b"]);
+
+        let options = LoaderOptions {
+            sitemap_include: Some("(".into()),
+            ..options
+        };
+        let err = load_web_page(&options, &url, &None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Invalid rag_sitemap_include '('");
+
+        // Dropping the future on abort stops the pending fetches.
+        let options = LoaderOptions {
+            crawl_max_pages: 100,
+            quiet: true,
+            ..Default::default()
+        };
+        let url = format!("{base_url}/slow.xml");
+        let start = std::time::Instant::now();
+        tokio::select! {
+            _ = load_web_page(&options, &url, &None) => panic!("the slow page was loaded"),
+            _ = tokio::time::sleep(std::time::Duration::from_millis(200)) => {}
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    }
 }
@@ -1,5 +1,10 @@
 use self::bm25::*;
+pub use self::embedding_cache::EmbeddingCache;
+pub use self::hnsw_store::RagDistance;
+use self::hnsw_store::*;
 use self::loader::*;
+use self::shingles::ShingleIndex;
+pub use self::splitter::ChunkSizeUnit;
 use self::splitter::*;
 
 use crate::client::*;
@@ -7,28 +12,74 @@ use crate::config::*;
 use crate::utils::*;
 
 mod bm25;
+mod embedding_cache;
+mod hnsw_store;
 mod loader;
+mod shingles;
 mod splitter;
 
 use anyhow::bail;
 use anyhow::{anyhow, Context, Result};
+use fancy_regex::Regex;
+use futures_util::{future::try_join_all, stream, StreamExt};
 use hnsw_rs::prelude::*;
 use indexmap::{IndexMap, IndexSet};
-use inquire::{required, validator::Validation, Select, Text};
+use inquire::{required, validator::Validation, Confirm, Select, Text};
+use lazy_static::lazy_static;
+use notify::{RecursiveMode, Watcher};
 use path_absolutize::Absolutize;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::HashMap;
-use std::{fmt::Debug, io::BufReader, path::Path};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Fewer bytes shared by the end of a chunk and the start of the next are taken as chance.
+const MIN_CHUNK_OVERLAP: usize = 8;
+/// How many more candidates than `rag_top_k` are handed to the reranker.
+const RERANK_CANDIDATES_FACTOR: usize = 4;
+/// How long the files must stay untouched before a watched rag is re-indexed, so that the
+/// burst of events of a single save triggers one re-index.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Ends the name of every saved rag, see `rag_file_path`.
+pub const RAG_FILE_SUFFIX: &str = ".bin";
+/// Starts every rag file saved with a version. Files without it are the unversioned layout of
+/// earlier releases, read through `LegacyRagData` as version 0.
+const RAG_FILE_MAGIC: &[u8] = b"AIRAG";
+/// Version of the saved rag layout, the first field after `RAG_FILE_MAGIC`.
+/// Version 2 packs document ids into 32-bit halves of a u64 on every target.
+/// Version 3 stores mtimes in nanoseconds and the size of each file. The mtimes of older rags
+/// are scaled up from seconds and their sizes left at 0, so their files are hashed again on the
+/// next rebuild.
+const RAG_DATA_VERSION: u32 = 3;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+lazy_static! {
+    static ref LIST_MARKER_RE: Regex = Regex::new(r"^\d+[.)]\s*").unwrap();
+}
+/// Prefix of the path of a text snippet added without a backing file.
+const TEXT_PATH_PREFIX: &str = "text:";
+/// How many files a new rag embeds between checkpoints when `rag_batch_files` is 0.
+const INIT_BATCH_FILES: usize = 100;
 
 pub struct Rag {
     name: String,
     path: String,
     embedding_model: Model,
-    hnsw: Hnsw<'static, f32, DistCosine>,
+    hnsw: RagHnsw,
     bm25: BM25<DocumentId>,
     data: RagData,
     embedding_client: Box<dyn Client>,
+    embedding_cache: Option<EmbeddingCache>,
+    embedding_retries: usize,
+    embedding_concurrency: usize,
+    dedup_similarity: Option<f64>,
 }
 
 impl Debug for Rag {
@@ -51,18 +102,24 @@ impl Rag {
         abort_signal: AbortSignal,
     ) -> Result<Self> {
         debug!("init rag: {name}");
-        let (embedding_model, chunk_size, chunk_overlap) = Self::config(config)?;
-        let data = RagData::new(embedding_model.id(), chunk_size, chunk_overlap);
-        let mut rag = Self::create(config, name, save_path, data)?;
-        let mut paths = doc_paths.to_vec();
-        if paths.is_empty() {
-            paths = add_documents()?;
-        };
-        debug!("doc paths: {paths:?}");
-        let loaders = config.read().document_loaders.clone();
+        let resolver = TerminalRagConfigResolver;
+        let (mut rag, paths) = Self::prepare(config, name, save_path, doc_paths, None, &resolver)?;
+        let mut options = LoaderOptions::new(&config.read());
+        if options.batch_files == 0 {
+            options.batch_files = INIT_BATCH_FILES;
+        }
+        // A dry run embeds offline and never checkpoints, caches or saves the fake vectors.
+        let dry_run = config.read().dry_run;
+        if dry_run {
+            rag.embedding_client = Box::new(MockClient::new(config, &rag.embedding_model));
+            rag.embedding_cache = None;
+            options.batch_files = 0;
+            options.cost_limit = None;
+            options.confirm_cost = false;
+        }
         let spinner = create_spinner("Starting").await;
         tokio::select! {
-            ret = rag.load_paths(loaders, &paths, Some(spinner.clone())) => {
+            ret = rag.load_paths(options, &paths, Some(spinner.clone())) => {
                 spinner.stop();
                 ret?;
             }
@@ -71,26 +128,181 @@ impl Rag {
                 bail!("Aborted!")
             },
         };
+        if dry_run {
+            println!("{}", rag.dry_run_report());
+            return Ok(rag);
+        }
         if !rag.is_temp() {
             rag.save(save_path)?;
+            let _ = std::fs::remove_file(partial_path(save_path));
             println!("✨ Saved rag to '{}'", save_path.display());
         }
         Ok(rag)
     }
 
+    /// Create a rag like `init`, but without prompting or printing anything: every choice comes
+    /// from `options`, then from the config, then from defaults.
+    pub async fn build(config: &GlobalConfig, mut options: RagBuildOptions) -> Result<Self> {
+        debug!("build rag: {}", options.name);
+        let embedding_client = options.embedding_client.take();
+        let (mut rag, paths) = Self::prepare(
+            config,
+            &options.name,
+            &options.save_path,
+            &options.document_paths,
+            embedding_client,
+            &options,
+        )?;
+        let mut loader_options = LoaderOptions::new(&config.read());
+        if loader_options.batch_files == 0 {
+            loader_options.batch_files = INIT_BATCH_FILES;
+        }
+        loader_options.quiet = true;
+        loader_options.confirm_cost = false;
+        rag.load_paths(loader_options, &paths, None).await?;
+        if !rag.is_temp() {
+            rag.save(&options.save_path)?;
+            let _ = std::fs::remove_file(partial_path(&options.save_path));
+        }
+        Ok(rag)
+    }
+
+    /// What building the rag for real would embed, and roughly cost.
+    fn dry_run_report(&self) -> String {
+        let chunks: Vec<&str> = self
+            .data
+            .files
+            .values()
+            .flat_map(|file| file.documents.iter().map(|v| v.page_content.as_str()))
+            .collect();
+        let tokens: usize = chunks.iter().map(|v| estimate_token_length(v)).sum();
+        let mut output = format!(
+            "Dry run: {} files, {} chunks, about {tokens} tokens to embed with {}",
+            self.data.files.len(),
+            chunks.len(),
+            self.embedding_model.id()
+        );
+        if let Some(cost) = self.embedding_cost(tokens) {
+            output.push_str(&format!(", costing about ${cost:.4}"));
+        }
+        output
+    }
+
+    /// Settle the settings of a new rag, or resume an interrupted one, and the paths to load.
+    fn prepare(
+        config: &GlobalConfig,
+        name: &str,
+        save_path: &Path,
+        doc_paths: &[String],
+        embedding_client: Option<Box<dyn Client>>,
+        resolver: &dyn RagConfigResolver,
+    ) -> Result<(Self, Vec<String>)> {
+        let partial_path = partial_path(save_path);
+        let data = match Self::resume_checkpoint(name, &partial_path, resolver)? {
+            Some(data) => data,
+            None => {
+                let embedding_model = match &embedding_client {
+                    Some(client) => client.model().clone(),
+                    None => Self::resolve_embedding_model(config, resolver)?,
+                };
+                let (chunk_size, chunk_overlap) =
+                    Self::resolve_chunk_config(config, &embedding_model, resolver)?;
+                let mut data = RagData::new(embedding_model.id(), chunk_size, chunk_overlap);
+                data.chunk_size_unit = config.read().rag_chunk_size_unit;
+                data.set_chunk_overrides(&config.read())?;
+                data.set_vector_compression(config.read().rag_vector_compression);
+                data.set_hnsw_params(&config.read())?;
+                data.set_bm25_params(&config.read())?;
+                data
+            }
+        };
+        let rag = match embedding_client {
+            Some(client) => Self::create_with_client(config, name, save_path, data, client)?,
+            None => Self::create(config, name, save_path, data)?,
+        };
+        let mut paths = doc_paths.to_vec();
+        if paths.is_empty() {
+            paths = rag.data.document_paths.clone();
+        }
+        if paths.is_empty() {
+            paths = resolver.document_paths()?;
+        };
+        debug!("doc paths: {paths:?}");
+        Ok((rag, paths))
+    }
+
+    /// Load the checkpoint left by an interrupted `init`, if there is one and the resolver wants
+    /// to resume from it. A declined checkpoint is removed.
+    fn resume_checkpoint(
+        name: &str,
+        partial_path: &Path,
+        resolver: &dyn RagConfigResolver,
+    ) -> Result<Option<RagData>> {
+        if name == TEMP_RAG_NAME || !partial_path.exists() {
+            return Ok(None);
+        }
+        if !resolver.resume_checkpoint()? {
+            std::fs::remove_file(partial_path).with_context(|| {
+                format!("Failed to remove checkpoint '{}'", partial_path.display())
+            })?;
+            return Ok(None);
+        }
+        let err = || format!("Failed to resume rag '{name}'");
+        let content = std::fs::read(partial_path).with_context(err)?;
+        let data = RagData::decode(&content).with_context(err)?;
+        data.check_dimension().with_context(err)?;
+        resolver.report(&format!(
+            "Resume from checkpoint with {} indexed files",
+            data.files.len()
+        ));
+        Ok(Some(data))
+    }
+
     pub fn load(config: &GlobalConfig, name: &str, path: &Path) -> Result<Self> {
         let err = || format!("Failed to load rag '{name}'");
-        let file = std::fs::File::open(path).with_context(err)?;
-        let reader = BufReader::new(file);
-        let data: RagData = bincode::deserialize_from(reader).with_context(err)?;
+        let content = std::fs::read(path).with_context(err)?;
+        let data = RagData::decode(&content).with_context(|| {
+            format!(
+                "Failed to load rag '{name}', '{}' may be corrupted. Remove it and create the rag again",
+                path.display()
+            )
+        })?;
+        data.check_dimension().with_context(err)?;
         Self::create(config, name, path, data)
     }
 
     pub fn create(config: &GlobalConfig, name: &str, path: &Path, data: RagData) -> Result<Self> {
-        let hnsw = data.build_hnsw();
-        let bm25 = data.build_bm25();
         let embedding_model = Model::retrieve_embedding(&config.read(), &data.embedding_model)?;
-        let embedding_client = init_client(config, Some(embedding_model.clone()))?;
+        let embedding_client = init_client(config, Some(embedding_model))?;
+        Self::create_with_client(config, name, path, data, embedding_client)
+    }
+
+    fn create_with_client(
+        config: &GlobalConfig,
+        name: &str,
+        path: &Path,
+        data: RagData,
+        embedding_client: Box<dyn Client>,
+    ) -> Result<Self> {
+        let fingerprint = data.hnsw_fingerprint();
+        let hnsw = match load_hnsw(path, &fingerprint, data.vectors.len(), data.distance) {
+            Some(hnsw) => hnsw,
+            None => data.build_hnsw()?,
+        };
+        let bm25 = data.build_bm25();
+        let embedding_model = embedding_client.model().clone();
+        let embedding_cache = {
+            let config = config.read();
+            if config.rag_embedding_cache {
+                let dir = Config::embedding_cache_dir()?;
+                Some(EmbeddingCache::new(dir, config.rag_embedding_cache_size))
+            } else {
+                None
+            }
+        };
+        let embedding_retries = config.read().rag_embedding_retries;
+        let embedding_concurrency = config.read().rag_embedding_concurrency;
+        let dedup_similarity = config.read().rag_dedup_similarity;
         let rag = Rag {
             name: name.to_string(),
             path: path.display().to_string(),
@@ -99,6 +311,10 @@ impl Rag {
             hnsw,
             bm25,
             embedding_client,
+            embedding_cache,
+            embedding_retries,
+            embedding_concurrency,
+            dedup_similarity,
         };
         Ok(rag)
     }
@@ -110,11 +326,10 @@ impl Rag {
         abort_signal: AbortSignal,
     ) -> Result<()> {
         debug!("rebuild rag: {}", self.name);
-        let loaders = config.read().document_loaders.clone();
+        let options = LoaderOptions::new(&config.read());
         let spinner = create_spinner("Starting").await;
-        let paths = self.data.document_paths.clone();
         tokio::select! {
-            ret = self.load_paths(loaders, &paths, Some(spinner.clone())) => {
+            ret = self.refresh(options, Some(spinner.clone())) => {
                 spinner.stop();
                 ret?;
             }
@@ -130,95 +345,208 @@ impl Rag {
         Ok(())
     }
 
-    pub fn config(config: &GlobalConfig) -> Result<(Model, usize, usize)> {
-        let (embedding_model_id, chunk_size, chunk_overlap) = {
-            let config = config.read();
-            (
-                config.rag_embedding_model.clone(),
-                config.rag_chunk_size,
-                config.rag_chunk_overlap,
-            )
-        };
-        let embedding_model_id = match embedding_model_id {
-            Some(value) => {
-                println!("Select embedding model: {value}");
-                value
+    /// Re-index the changed files whenever the local document paths change, saving the rag after
+    /// each re-index, until `abort_signal` fires.
+    pub async fn watch(
+        &mut self,
+        config: &GlobalConfig,
+        save_path: &Path,
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        let patterns: Vec<String> = self
+            .data
+            .document_paths
+            .iter()
+            .filter(|path| !path.starts_with('!') && !Self::is_url_path(path))
+            .cloned()
+            .collect();
+        let mut roots: Vec<PathBuf> = vec![];
+        for pattern in &patterns {
+            let root = PathBuf::from(glob_base_path(pattern)?);
+            if root.exists() && !roots.iter().any(|v| root.starts_with(v)) {
+                roots.retain(|v| !v.starts_with(&root));
+                roots.push(root);
             }
-            None => {
-                let models = list_embedding_models(&config.read());
-                if models.is_empty() {
-                    bail!("No available embedding model");
-                }
-                if *IS_STDOUT_TERMINAL {
-                    select_embedding_model(&models)?
-                } else {
-                    let value = models[0].id();
-                    println!("Select embedding model: {value}");
-                    value
+        }
+        if roots.is_empty() {
+            bail!("No local document paths to watch");
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .with_context(|| "Failed to watch document paths")?;
+        for root in &roots {
+            watcher
+                .watch(root, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {}", root.display()))?;
+            println!("Watching {}", root.display());
+        }
+        loop {
+            let mut changed: IndexSet<String> = IndexSet::new();
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => changed.extend(changed_paths(event, &patterns)),
+                    None => break,
+                },
+                _ = watch_abort_signal(abort_signal.clone()) => break,
+            }
+            loop {
+                tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(event) => changed.extend(changed_paths(event, &patterns)),
+                        None => break,
+                    },
+                    _ = tokio::time::sleep(WATCH_DEBOUNCE) => break,
                 }
             }
-        };
-        let embedding_model = Model::retrieve_embedding(&config.read(), &embedding_model_id)?;
-
-        let chunk_size = match chunk_size {
-            Some(value) => {
-                println!("Set chunk size: {value}");
-                value
+            if changed.is_empty() {
+                continue;
             }
-            None => {
-                if *IS_STDOUT_TERMINAL {
-                    set_chunk_size(&embedding_model)?
-                } else {
-                    let value = embedding_model.default_chunk_size();
-                    println!("Set chunk size: {value}");
-                    value
-                }
+            for path in &changed {
+                println!("Changed {path}");
             }
-        };
-        let chunk_overlap = match chunk_overlap {
-            Some(value) => {
-                println!("Set chunk overlay: {value}");
-                value
+            let mut options = LoaderOptions::new(&config.read());
+            options.confirm_cost = false;
+            let ret = tokio::select! {
+                ret = self.refresh(options, None) => ret,
+                _ = watch_abort_signal(abort_signal.clone()) => break,
+            };
+            if let Err(err) = ret {
+                let message = format!("Failed to update rag '{}': {err}", self.name);
+                println!("{}", warning_text(&message));
+                continue;
             }
-            None => {
-                let value = chunk_size / 20;
-                if *IS_STDOUT_TERMINAL {
-                    set_chunk_overlay(value)?
-                } else {
-                    println!("Set chunk overlay: {value}");
-                    value
-                }
+            if !self.is_temp() {
+                self.save(save_path)?;
+                println!("✨ Saved rag to '{}'", save_path.display());
             }
+        }
+        Ok(())
+    }
+
+    fn resolve_embedding_model(
+        config: &GlobalConfig,
+        resolver: &dyn RagConfigResolver,
+    ) -> Result<Model> {
+        let configured = config.read().rag_embedding_model.clone();
+        let models = list_embedding_models(&config.read());
+        let embedding_model_id = resolver.embedding_model(configured, &models)?;
+        Model::retrieve_embedding(&config.read(), &embedding_model_id)
+    }
+
+    fn resolve_chunk_config(
+        config: &GlobalConfig,
+        embedding_model: &Model,
+        resolver: &dyn RagConfigResolver,
+    ) -> Result<(usize, usize)> {
+        let (chunk_size, chunk_overlap, unit) = {
+            let config = config.read();
+            (
+                config.rag_chunk_size,
+                config.rag_chunk_overlap,
+                config.rag_chunk_size_unit,
+            )
         };
+        let chunk_size = resolver.chunk_size(chunk_size, embedding_model, unit)?;
+        if let (ChunkSizeUnit::Tokens, Some(max_input_tokens)) =
+            (unit, embedding_model.max_input_tokens())
+        {
+            if chunk_size > max_input_tokens {
+                bail!("The chunk size exceeds the model's max_input_tokens {max_input_tokens}");
+            }
+        }
+        let chunk_overlap = resolver.chunk_overlap(chunk_overlap, chunk_size)?;
+        Ok((chunk_size, chunk_overlap))
+    }
 
-        Ok((embedding_model, chunk_size, chunk_overlap))
+    /// Renumber the documents densely after files were removed, then rebuild the indexes.
+    ///
+    /// Document ids change, so any id obtained before compacting no longer points at the same
+    /// document. Returns false if the rag was already compact.
+    pub fn compact(&mut self) -> Result<bool> {
+        if !self.data.compact() {
+            debug!("rag '{}' is already compact", self.name);
+            return Ok(false);
+        }
+        self.hnsw = self.data.build_hnsw()?;
+        self.bm25 = self.data.build_bm25();
+        Ok(true)
     }
 
     pub fn save(&self, path: &Path) -> Result<()> {
+        self.save_data(path)?;
+        if let Err(err) = save_hnsw(path, &self.hnsw, &self.data.hnsw_fingerprint()) {
+            debug!("Failed to save hnsw of rag '{}', {err}", self.name);
+        }
+        Ok(())
+    }
+
+    /// Write the data without the HNSW graph, replacing `path` only once it is fully written.
+    fn save_data(&self, path: &Path) -> Result<()> {
         ensure_parent_exists(path)?;
-        let mut file = std::fs::File::create(path)?;
-        bincode::serialize_into(&mut file, &self.data)
-            .with_context(|| format!("Failed to save rag '{}'", self.name))?;
+        let err = || format!("Failed to save rag '{}'", self.name);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(std::fs::File::create(&tmp_path).with_context(err)?);
+        self.data.encode(&mut writer).with_context(err)?;
+        writer.flush().with_context(err)?;
+        drop(writer);
+        std::fs::rename(&tmp_path, path).with_context(err)?;
         Ok(())
     }
 
+    /// The indexed files with their chunk counts, in indexing order.
+    pub fn files(&self) -> Vec<RagFileInfo> {
+        self.data
+            .files
+            .values()
+            .map(|file| RagFileInfo {
+                path: file.path.clone(),
+                chunk_count: file.documents.len(),
+                total_chars: file
+                    .documents
+                    .iter()
+                    .map(|v| chunk_content(&v.page_content).chars().count())
+                    .sum(),
+            })
+            .collect()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.data.files.values().map(|v| v.documents.len()).sum()
+    }
+
     pub fn export(&self) -> Result<String> {
         let files: Vec<_> = self
-            .data
-            .files
-            .iter()
-            .map(|(_, v)| {
+            .files()
+            .into_iter()
+            .map(|v| {
                 json!({
                     "path": v.path,
-                    "num_chunks": v.documents.len(),
+                    "num_chunks": v.chunk_count,
+                    "num_chars": v.total_chars,
                 })
             })
             .collect();
+        let dimension = self
+            .data
+            .dimension
+            .or_else(|| self.data.vectors.values().next().map(|v| v.dimension()));
+        let size = std::fs::metadata(&self.path).ok().map(|v| v.len());
         let data = json!({
             "path": self.path,
+            "size": size,
             "embedding_model": self.embedding_model.id(),
             "chunk_size": self.data.chunk_size,
             "chunk_overlap": self.data.chunk_overlap,
+            "chunk_size_unit": self.data.chunk_size_unit,
+            "chunk_overrides": self.data.chunk_overrides,
+            "vector_compression": self.data.vector_compression,
+            "bm25": self.data.bm25,
+            "bm25_vocab_size": self.bm25.vocab_size(),
+            "num_chunks": self.chunk_count(),
+            "num_vectors": self.data.vectors.len(),
+            "dimension": dimension,
             "document_paths": self.data.document_paths,
             "files": files,
         });
@@ -227,6 +555,34 @@ impl Rag {
         Ok(output)
     }
 
+    /// Write the chunks and their embeddings to `path` as JSONL, returning how many were written.
+    pub fn export_jsonl(&self, path: &Path, with_vectors: bool) -> Result<usize> {
+        ensure_parent_exists(path)?;
+        let err = || format!("Failed to export rag '{}'", self.name);
+        let file = std::fs::File::create(path).with_context(err)?;
+        let mut writer = BufWriter::new(file);
+        let count = self
+            .data
+            .write_jsonl(&mut writer, with_vectors)
+            .with_context(err)?;
+        writer.flush().with_context(err)?;
+        Ok(count)
+    }
+
+    /// Create the rag `name` from a JSONL export, reusing its embeddings.
+    pub fn import_jsonl(config: &GlobalConfig, name: &str, path: &Path) -> Result<Self> {
+        let save_path = config.read().rag_file(name)?;
+        if save_path.exists() {
+            bail!("Rag '{name}' already exists");
+        }
+        let err = || format!("Failed to import rag from '{}'", path.display());
+        let content = std::fs::read_to_string(path).with_context(err)?;
+        let data = RagData::read_jsonl(&content).with_context(err)?;
+        let rag = Self::create(config, name, &save_path, data)?;
+        rag.save(&save_path)?;
+        Ok(rag)
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -235,32 +591,232 @@ impl Rag {
         self.name == TEMP_RAG_NAME
     }
 
-    pub async fn search(
-        &self,
+    /// Search the rags and render the hits, keeping within `max_context_tokens` by dropping the
+    /// lowest ranked hits first.
+    pub async fn search_many(
+        rags: &[&Rag],
+        config: &GlobalConfig,
+        text: &str,
+        max_context_tokens: Option<usize>,
+        abort_signal: AbortSignal,
+    ) -> Result<RagContext> {
+        let hits = Self::search_many_detailed(rags, config, text, abort_signal).await?;
+        let template = config.read().rag_chunk_template().to_string();
+        let chunks = render_hits(&hits, &template, max_context_tokens);
+        if chunks.len() < hits.len() {
+            debug!(
+                "drop {} hits beyond the context budget",
+                hits.len() - chunks.len()
+            );
+        }
+        Ok(RagContext {
+            text: chunks.join("\n\n"),
+            used: chunks.len(),
+            retrieved: hits.len(),
+        })
+    }
+
+    /// Search the rags, returning the hits sorted by their fused (or reranked) score, or in the
+    /// order picked by maximal marginal relevance when `rag_mmr_lambda` is set. The rags are
+    /// searched concurrently and their hits fused by rank, see `fuse_rag_hits`.
+    pub async fn search_many_detailed(
+        rags: &[&Rag],
+        config: &GlobalConfig,
         text: &str,
-        top_k: usize,
-        min_score_vector_search: f32,
-        min_score_keyword_search: f32,
-        rerank: Option<(Box<dyn Client>, f32)>,
         abort_signal: AbortSignal,
-    ) -> Result<String> {
-        let spinner = create_spinner("Searching").await;
+    ) -> Result<Vec<SearchHit>> {
+        let options = SearchOptions::new(&config.read())?;
+        let reranker_model_id = config.read().rag_reranker_model.clone();
+        let rerank = match &reranker_model_id {
+            Some(reranker_model_id) => {
+                let min_score = config.read().rag_min_score_rerank;
+                let rerank_model = Model::retrieve_reranker(&config.read(), reranker_model_id)?;
+                let rerank_client = init_client(config, Some(rerank_model))?;
+                Some((rerank_client, min_score))
+            }
+            None => None,
+        };
+        // Scripts reading the output of `--rag-search` don't want the spinner.
+        let spinner = match *IS_STDOUT_TERMINAL {
+            true => Some(create_spinner("Searching").await),
+            false => None,
+        };
         let ret = tokio::select! {
-            ret = self.hybird_search(text, top_k, min_score_vector_search, min_score_keyword_search, rerank) => {
+            ret = async {
+                let paraphrases = match options.multi_query {
+                    0 => vec![],
+                    count => {
+                        progress(&spinner, "Expanding query".into());
+                        let paraphrases =
+                            expand_query(config, text, count, abort_signal.clone()).await;
+                        progress(&spinner, "Searching".into());
+                        paraphrases.unwrap_or_else(|err| {
+                            let message =
+                                format!("Failed to expand query, searching with it alone: {err}");
+                            eprintln!("{}", warning_text(&message));
+                            vec![]
+                        })
+                    }
+                };
+                debug!("paraphrases: {paraphrases:?}");
+                let searches = rags.iter().map(|rag| {
+                    rag.hybird_search(text, &paraphrases, &options, rerank.as_ref())
+                });
+                let mut list_of_hits = try_join_all(searches).await?;
+                match list_of_hits.len() {
+                    1 => Ok(list_of_hits.remove(0)),
+                    _ => {
+                        let names = rags.iter().map(|v| v.name().to_string());
+                        let list_of_hits = names.zip(list_of_hits).collect();
+                        Ok(fuse_rag_hits(list_of_hits, options.rrf_k, options.top_k))
+                    }
+                }
+            } => {
                 ret
             }
+            _ = watch_abort_signal(abort_signal.clone()) => {
+                bail!("Aborted!")
+            },
+        };
+        if let Some(spinner) = spinner {
+            spinner.stop();
+        }
+        ret
+    }
+
+    pub async fn reembed(
+        &mut self,
+        config: &GlobalConfig,
+        model_id: &str,
+        save_path: &Path,
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        debug!("reembed rag: {} with {model_id}", self.name);
+        let embedding_model = Model::retrieve_embedding(&config.read(), model_id)?;
+        let embedding_client = init_client(config, Some(embedding_model.clone()))?;
+        if let Some(max_input_tokens) = embedding_model.max_input_tokens() {
+            let oversized = self
+                .data
+                .files
+                .values()
+                .flat_map(|file| &file.documents)
+                .filter(|v| estimate_token_length(&v.page_content) > max_input_tokens)
+                .count();
+            if oversized > 0 {
+                let message = format!(
+                    "{oversized} chunks exceed the max_input_tokens ({max_input_tokens}) of '{model_id}'"
+                );
+                println!("{}", warning_text(&message));
+                let resplit = *IS_STDOUT_TERMINAL
+                    && Confirm::new("Re-split the documents with a new chunk size?")
+                        .with_default(true)
+                        .prompt()?;
+                if resplit {
+                    let unit = self.data.chunk_size_unit;
+                    let chunk_size = set_chunk_size(&embedding_model, unit)?;
+                    let chunk_overlap =
+                        set_chunk_overlay(self.data.chunk_overlap.min(chunk_size / 2))?;
+                    self.data.chunk_size = chunk_size;
+                    self.data.chunk_overlap = chunk_overlap;
+                    self.data.embedding_model = embedding_model.id();
+                    self.data.dimension = None;
+                    self.data.files.clear();
+                    self.data.vectors.clear();
+                    self.data.duplicates.clear();
+                    self.embedding_model = embedding_model;
+                    self.embedding_client = embedding_client;
+                    return self.rebuild(config, save_path, abort_signal).await;
+                }
+            }
+        }
+        let spinner = create_spinner("Starting").await;
+        tokio::select! {
+            ret = self.update_embeddings(embedding_model, embedding_client, Some(spinner.clone())) => {
+                spinner.stop();
+                ret?;
+            }
             _ = watch_abort_signal(abort_signal) => {
+                spinner.stop();
                 bail!("Aborted!")
             },
         };
-        spinner.stop();
-        let output = ret?.join("\n\n");
-        Ok(output)
+        if !self.is_temp() {
+            self.save(save_path)?;
+            println!("✨ Saved rag to '{}'", save_path.display());
+        }
+        Ok(())
+    }
+
+    /// Re-embed the stored chunks with another model, replacing every vector at once.
+    pub async fn update_embeddings(
+        &mut self,
+        embedding_model: Model,
+        embedding_client: Box<dyn Client>,
+        spinner: Option<Spinner>,
+    ) -> Result<()> {
+        let mut document_ids = vec![];
+        let mut texts = vec![];
+        let mut sources = vec![];
+        for (file_id, file) in &self.data.files {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                let document_id = pack_document_id(*file_id, document_index);
+                if self.data.duplicates.contains_key(&document_id) {
+                    continue;
+                }
+                document_ids.push(document_id);
+                let text = self
+                    .data
+                    .embedding_text(document_id, &document.page_content);
+                texts.push(text.to_string());
+                sources.push(format!("{} chunk {document_index}", file.path));
+            }
+        }
+        let old_model = std::mem::replace(&mut self.embedding_model, embedding_model);
+        let old_client = std::mem::replace(&mut self.embedding_client, embedding_client);
+        let ret = self
+            .create_embeddings(EmbeddingsData::new(texts, false), &sources, spinner.clone())
+            .await
+            .and_then(|embeddings| {
+                if embeddings.len() != document_ids.len() {
+                    bail!(
+                        "Expected {} embeddings, but got {}",
+                        document_ids.len(),
+                        embeddings.len()
+                    );
+                }
+                let dimension = check_dimension(None, embeddings.iter().map(|v| v.len()))?;
+                Ok((dimension, embeddings))
+            });
+        let (dimension, embeddings) = match ret {
+            Ok(v) => v,
+            Err(err) => {
+                self.embedding_model = old_model;
+                self.embedding_client = old_client;
+                return Err(err);
+            }
+        };
+        self.data.embedding_model = self.embedding_model.id();
+        self.data.dimension = dimension;
+        self.data.vectors.clear();
+        self.data.store_vectors(document_ids, embeddings);
+        progress(&spinner, "Building vector index".into());
+        self.hnsw = self.data.build_hnsw()?;
+        Ok(())
+    }
+
+    /// Re-walk the known document paths, re-indexing changed files and pruning deleted ones.
+    pub async fn refresh(
+        &mut self,
+        options: LoaderOptions,
+        spinner: Option<Spinner>,
+    ) -> Result<()> {
+        let paths = self.data.document_paths.clone();
+        self.load_paths(options, &paths, spinner).await
     }
 
     pub async fn load_paths<T: AsRef<str>>(
         &mut self,
-        loaders: HashMap<String, String>,
+        mut options: LoaderOptions,
         paths: &[T],
         spinner: Option<Spinner>,
     ) -> Result<()> {
@@ -268,207 +824,743 @@ impl Rag {
             let _ = spinner.set_message(String::new());
         }
 
+        let stamps: HashMap<String, (u64, u64)> = self
+            .data
+            .files
+            .values()
+            .filter(|file| file.mtime > 0)
+            .map(|file| (file.path.clone(), (file.mtime, file.size)))
+            .collect();
+        let is_unchanged = |path: &str| {
+            stamps
+                .get(path)
+                .map(|stamp| *stamp == file_stamp(path))
+                .unwrap_or_default()
+        };
+
         let mut document_paths = vec![];
+        let mut excludes = vec![];
+        let mut include_paths = vec![];
+        for path in paths {
+            match path.as_ref().strip_prefix('!') {
+                Some(pattern) if Self::is_url_path(pattern) => {
+                    document_paths.push(format!("!{pattern}"));
+                    options.url_excludes.push(pattern.to_string());
+                }
+                Some(pattern) => {
+                    let pattern = if pattern.contains(['/', '\\']) && !pattern.starts_with("**") {
+                        Path::new(pattern).absolutize()?.display().to_string()
+                    } else {
+                        pattern.to_string()
+                    };
+                    document_paths.push(format!("!{pattern}"));
+                    excludes.push(pattern);
+                }
+                None => include_paths.push(path.as_ref()),
+            }
+        }
+
         let mut files = vec![];
-        let paths_len = paths.len();
-        for (index, path) in paths.iter().enumerate() {
-            let path = path.as_ref();
-            println!("Load {path} [{}/{paths_len}]", index + 1);
+        let mut failed_paths = vec![];
+        let mut unchanged_paths: HashSet<String> = HashSet::new();
+        let mut excluded = 0;
+        let paths_len = include_paths.len();
+        for (index, path) in include_paths.into_iter().enumerate() {
+            options.print(&format!("Load {path} [{}/{paths_len}]", index + 1));
             if Self::is_url_path(path) {
-                if let Some(path) = path.strip_suffix("**") {
-                    files.extend(load_recursive_url(&loaders, path).await?);
-                } else {
-                    files.push(load_url(&loaders, path).await?);
+                let ret = match path.strip_suffix("**") {
+                    Some(path) => load_recursive_url(&options, path, &spinner).await,
+                    None => load_web_page(&options, path, &spinner).await,
+                };
+                match ret {
+                    Ok(loaded_files) => files.extend(loaded_files),
+                    Err(err) => {
+                        options.print(&warning_text(&format!(
+                            "Skip {path} as it failed to load: {err}"
+                        )));
+                        unchanged_paths.extend(
+                            self.data
+                                .files
+                                .values()
+                                .filter(|file| {
+                                    match_path(path, &file.path)
+                                        || file
+                                            .documents
+                                            .first()
+                                            .and_then(|v| v.metadata.get(SITEMAP_METADATA))
+                                            == Some(&path.to_string())
+                                })
+                                .map(|file| file.path.clone()),
+                        );
+                    }
                 }
                 document_paths.push(path.to_string());
             } else {
                 let path = Path::new(path);
                 let path = path.absolutize()?.display().to_string();
-                files.extend(load_path(&loaders, &path).await?);
+                if self.data.document_paths.contains(&path)
+                    && !Path::new(&glob_base_path(&path)?).exists()
+                {
+                    options.print(&warning_text(&format!(
+                        "Prune {path} as it no longer exists"
+                    )));
+                    continue;
+                }
+                progress(&spinner, "Listing paths".into());
+                let loaded = load_path(&options, &path, &excludes, is_unchanged).await?;
+                excluded += loaded.excluded;
+                progress(&spinner, format!("Listing paths, {excluded} excluded"));
+                files.extend(loaded.files);
+                unchanged_paths.extend(loaded.unchanged);
+                // Keep what was indexed from files that fail to load now.
+                unchanged_paths.extend(loaded.failed.iter().map(|(path, _)| path.clone()));
+                failed_paths.extend(loaded.failed);
                 document_paths.push(path);
             }
         }
+        if !failed_paths.is_empty() {
+            // Nothing to keep indexing, so leave the rag as it was.
+            if files.is_empty() && unchanged_paths.len() == failed_paths.len() {
+                return Err(LoadFailures(failed_paths).into());
+            }
+            let mut message = format!(
+                "Loaded {} files, {} failed:",
+                files.len(),
+                failed_paths.len()
+            );
+            for (path, err) in &failed_paths {
+                message.push_str(&format!("\n  {path}: {err}"));
+            }
+            options.print(&warning_text(&message));
+        }
+
+        // Text snippets have no source to reload from.
+        unchanged_paths.extend(
+            self.data
+                .files
+                .values()
+                .filter(|file| Self::is_text_path(&file.path))
+                .map(|file| file.path.clone()),
+        );
 
         let mut to_deleted: IndexMap<String, FileId> = Default::default();
         for (file_id, file) in &self.data.files {
-            to_deleted.insert(file.hash.clone(), *file_id);
+            if !unchanged_paths.contains(&file.path) {
+                to_deleted.insert(file.hash.clone(), *file_id);
+            }
         }
 
-        let mut rag_files = vec![];
-        for (contents, mut metadata) in files {
-            let path = match metadata.swap_remove(PATH_METADATA) {
+        let mut pending = vec![];
+        for mut file in files {
+            let path = match file.metadata.swap_remove(PATH_METADATA) {
                 Some(v) => v,
                 None => continue,
             };
-            let hash = sha256(&contents);
+            let hash = sha256(&file.contents);
+            let (mtime, size) = file_stamp(&path);
             if let Some(file_id) = to_deleted.get(&hash) {
-                if self.data.files[file_id].path == path {
-                    to_deleted.swap_remove(&hash);
-                    continue;
+                if let Some(file) = self.data.files.get_mut(file_id) {
+                    if file.path == path {
+                        file.mtime = mtime;
+                        file.size = size;
+                        to_deleted.swap_remove(&hash);
+                        continue;
+                    }
                 }
             }
-            let extension = metadata
-                .swap_remove(EXTENSION_METADATA)
-                .unwrap_or_else(|| DEFAULT_EXTENSION.into());
-            let separator = get_separators(&extension);
-            let splitter = RecursiveCharacterTextSplitter::new(
-                self.data.chunk_size,
-                self.data.chunk_overlap,
-                &separator,
-            );
+            if file
+                .contents
+                .trim_matches(|c: char| c.is_whitespace() || c == PAGE_BREAK)
+                .is_empty()
+            {
+                options.print(&warning_text(&format!("No text extracted from {path}")));
+                continue;
+            }
+            pending.push((path, hash, mtime, size, file));
+        }
 
-            let metadata = metadata
-                .iter()
-                .map(|(k, v)| format!("{k}: {v}\n"))
-                .collect::<Vec<String>>()
-                .join("");
-            let split_options = SplitterChunkHeaderOptions::default().with_chunk_header(&format!(
-                "<document_metadata>\npath: {path}\n{metadata}</document_metadata>\n\n"
-            ));
-            let document = RagDocument::new(contents);
-            let split_documents = splitter.split_documents(&[document], &split_options);
-            rag_files.push(RagFile {
-                hash: hash.clone(),
-                path,
-                documents: split_documents,
+        // Files are split concurrently but kept in order, so document ids stay deterministic.
+        let pending_len = pending.len();
+        let size_unit = self.data.chunk_size_unit;
+        let tasks = pending
+            .into_iter()
+            .map(|(path, hash, mtime, size, mut file)| {
+                let extension = file
+                    .metadata
+                    .swap_remove(EXTENSION_METADATA)
+                    .unwrap_or_else(|| DEFAULT_EXTENSION.into());
+                let chunk_params = self.data.chunk_params(&extension);
+                let task_path = path.clone();
+                let task = tokio::task::spawn_blocking(move || {
+                    split_loaded_file(&task_path, &extension, file, chunk_params, size_unit)
+                });
+                async move {
+                    let ret = task.await.map_err(|err| anyhow!("{err}"));
+                    (path, hash, mtime, size, ret)
+                }
             });
+        let mut tasks = stream::iter(tasks).buffered(ingest_concurrency());
+        let mut rag_files = vec![];
+        let mut failed_splits = vec![];
+        let mut done = 0;
+        while let Some((path, hash, mtime, size, ret)) = tasks.next().await {
+            done += 1;
+            progress(&spinner, format!("Splitting files [{done}/{pending_len}]"));
+            match ret {
+                Ok(documents) => rag_files.push(RagFile {
+                    hash,
+                    path,
+                    mtime,
+                    size,
+                    documents,
+                }),
+                Err(err) => {
+                    options.print(&warning_text(&format!("Failed to split {path}: {err}")));
+                    failed_splits.push(path);
+                }
+            }
         }
+        drop(tasks);
+        // Keep what was indexed from files that fail to split now.
+        to_deleted.retain(|_, file_id| {
+            !self
+                .data
+                .files
+                .get(file_id)
+                .is_some_and(|file| failed_splits.contains(&file.path))
+        });
 
-        let mut next_file_id = self.data.next_file_id;
-        let mut files = vec![];
-        let mut document_ids = vec![];
-        let mut embeddings = vec![];
+        self.check_embedding_cost(&options, &rag_files, &spinner)?;
 
-        if !rag_files.is_empty() {
-            let mut texts = vec![];
-            for file in rag_files.into_iter() {
-                for (document_index, document) in file.documents.iter().enumerate() {
-                    document_ids.push(combine_document_id(next_file_id, document_index));
-                    texts.push(document.page_content.clone())
-                }
-                files.push((next_file_id, file));
-                next_file_id += 1;
+        let batch_files = match options.batch_files {
+            0 => rag_files.len().max(1),
+            n => n,
+        };
+        // Saving after each batch lets an interrupted run keep what it has indexed so far. Stale
+        // files go first, so that a checkpoint never holds two versions of the same file. A rag
+        // that was never saved is checkpointed next to its path, for `init` to resume from.
+        let checkpoint = options.batch_files > 0 && !self.is_temp() && !self.path.is_empty();
+        let checkpoint_path = match Path::new(&self.path) {
+            path if path.exists() => path.to_path_buf(),
+            path => partial_path(path),
+        };
+        if checkpoint {
+            self.data.del(to_deleted.values().cloned().collect());
+            to_deleted.clear();
+            self.data.document_paths = document_paths.clone();
+        }
+        let mut batches = vec![];
+        let mut rag_files = rag_files.into_iter().peekable();
+        while rag_files.peek().is_some() {
+            batches.push(rag_files.by_ref().take(batch_files).collect::<Vec<_>>());
+        }
+        let batches_len = batches.len();
+        let mut skipped = 0;
+        for (index, batch) in batches.into_iter().enumerate() {
+            if batches_len > 1 {
+                options.print(&format!("Index batch [{}/{batches_len}]", index + 1));
+            }
+            skipped += self.add_files(batch, options.dedup, &spinner).await?;
+            if checkpoint && index + 1 < batches_len {
+                progress(&spinner, "Saving checkpoint".into());
+                self.save_data(&checkpoint_path)?;
             }
-
-            let embeddings_data = EmbeddingsData::new(texts, false);
-            embeddings = self
-                .create_embeddings(embeddings_data, spinner.clone())
-                .await?;
         }
-
         self.data.del(to_deleted.values().cloned().collect());
-        self.data.add(next_file_id, files, document_ids, embeddings);
         self.data.document_paths = document_paths;
 
-        progress(&spinner, "Building store".into());
-        self.hnsw = self.data.build_hnsw();
-        self.bm25 = self.data.build_bm25();
-
-        Ok(())
+        if skipped > 0 {
+            progress(
+                &spinner,
+                format!("Building store, skipped {skipped} duplicate chunks"),
+            );
+        } else {
+            progress(&spinner, "Building store".into());
+        }
+        self.hnsw = self.data.build_hnsw()?;
+        self.bm25 = self.data.build_bm25();
+
+        Ok(())
+    }
+
+    /// The cost in dollars of embedding `tokens`, if the embedding model has a price.
+    fn embedding_cost(&self, tokens: usize) -> Option<f64> {
+        let price = self.embedding_model.data().input_price?;
+        Some(tokens as f64 * price / 1_000_000.0)
+    }
+
+    /// Estimate the cost of embedding `rag_files`, then ask whether to go on when interactive,
+    /// or fail if it exceeds `rag_cost_limit` otherwise.
+    fn check_embedding_cost(
+        &self,
+        options: &LoaderOptions,
+        rag_files: &[RagFile],
+        spinner: &Option<Spinner>,
+    ) -> Result<()> {
+        let chunks: usize = rag_files.iter().map(|v| v.documents.len()).sum();
+        if chunks == 0 {
+            return Ok(());
+        }
+        let tokens: usize = rag_files
+            .iter()
+            .flat_map(|v| &v.documents)
+            .map(|v| estimate_token_length(&v.page_content))
+            .sum();
+        let cost = self.embedding_cost(tokens);
+        let mut estimate = format!(
+            "{} chunks (~{} tokens",
+            format_count(chunks),
+            format_tokens(tokens)
+        );
+        if let Some(cost) = cost {
+            estimate.push_str(&format!(", est. ${cost:.2}"));
+        }
+        estimate.push(')');
+        if options.confirm_cost {
+            progress(spinner, String::new());
+            let ans = Confirm::new(&format!("About to embed {estimate}. Continue?"))
+                .with_default(true)
+                .prompt()?;
+            if !ans {
+                bail!("Aborted!");
+            }
+            return Ok(());
+        }
+        if let (Some(cost), Some(limit)) = (cost, options.cost_limit) {
+            if cost > limit {
+                bail!("Embedding {estimate} exceeds rag_cost_limit ${limit}");
+            }
+        }
+        options.print(&format!("Embedding {estimate}"));
+        Ok(())
+    }
+
+    /// Embed the documents of `rag_files` and add them, returning how many duplicate chunks
+    /// were skipped.
+    async fn add_files(
+        &mut self,
+        rag_files: Vec<RagFile>,
+        dedup: bool,
+        spinner: &Option<Spinner>,
+    ) -> Result<usize> {
+        let mut next_file_id = self.data.next_file_id;
+        let mut files = vec![];
+        let mut document_ids = vec![];
+        let mut texts = vec![];
+        let mut sources = vec![];
+        let mut duplicates = vec![];
+        let mut canonical_ids = if dedup {
+            self.data.canonical_ids()
+        } else {
+            HashMap::new()
+        };
+        let mut similar = self
+            .dedup_similarity
+            .filter(|_| dedup)
+            .map(|threshold| self.data.shingle_index(threshold));
+        for file in rag_files.into_iter() {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                let document_id = combine_document_id(next_file_id, document_index)?;
+                if dedup {
+                    let content = chunk_content(&document.page_content);
+                    let hash = sha256(content);
+                    let canonical_id = canonical_ids
+                        .get(&hash)
+                        .copied()
+                        .or_else(|| similar.as_ref()?.find(content));
+                    if let Some(canonical_id) = canonical_id {
+                        duplicates.push((document_id, canonical_id));
+                        continue;
+                    }
+                    canonical_ids.insert(hash, document_id);
+                    if let Some(similar) = &mut similar {
+                        similar.insert(document_id, content);
+                    }
+                }
+                document_ids.push(document_id);
+                texts.push(document.page_content.clone());
+                sources.push(format!("{} chunk {document_index}", file.path));
+            }
+            files.push((next_file_id, file));
+            next_file_id += 1;
+        }
+        // Chunks that just became shared are embedded without their file header, including
+        // ones already indexed, whose vector gets replaced.
+        let positions: HashMap<DocumentId, usize> = document_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, i))
+            .collect();
+        let mut newly_shared: Vec<DocumentId> = duplicates
+            .iter()
+            .map(|(_, canonical_id)| *canonical_id)
+            .filter(|id| !self.data.shared_ids.contains_key(id))
+            .collect();
+        newly_shared.sort_unstable();
+        newly_shared.dedup();
+        for id in newly_shared {
+            match positions.get(&id) {
+                Some(i) => texts[*i] = chunk_content(&texts[*i]).to_string(),
+                None => {
+                    let Some(document) = self.data.get(id) else {
+                        continue;
+                    };
+                    let (file_id, document_index) = split_document_id(id);
+                    let path = &self.data.files[&file_id].path;
+                    document_ids.push(id);
+                    texts.push(chunk_content(&document.page_content).to_string());
+                    sources.push(format!("{path} chunk {document_index}"));
+                }
+            }
+        }
+
+        let embeddings_data = EmbeddingsData::new(texts, false);
+        let embeddings = self
+            .create_embeddings(embeddings_data, &sources, spinner.clone())
+            .await?;
+
+        let skipped = duplicates.len();
+        self.data
+            .add(next_file_id, files, document_ids, embeddings)?;
+        self.data.duplicates.extend(duplicates);
+        self.data.update_dedup_counts();
+        Ok(skipped)
+    }
+
+    /// Drop every indexed file matching one of `paths`, returning the paths actually removed.
+    pub async fn remove_paths(&mut self, paths: &[String]) -> Result<Vec<String>> {
+        let mut targets = vec![];
+        for path in paths {
+            if Self::is_url_path(path) || Self::is_text_path(path) {
+                targets.push(path.clone());
+            } else {
+                targets.push(Path::new(path).absolutize()?.display().to_string());
+            }
+        }
+        let removed = self.data.remove_paths(&targets);
+        if !removed.is_empty() {
+            self.hnsw = self.data.build_hnsw()?;
+            self.bm25 = self.data.build_bm25();
+        }
+        Ok(removed)
     }
 
     pub fn is_url_path(path: &str) -> bool {
         path.starts_with("http://") || path.starts_with("https://")
     }
 
+    pub fn is_text_path(path: &str) -> bool {
+        path.starts_with(TEXT_PATH_PREFIX)
+    }
+
+    /// Index text snippets given as `(label, content)` under the path `text:<label>`, replacing
+    /// any snippet already indexed with the same label.
+    pub async fn add_texts(
+        &mut self,
+        items: Vec<(String, String)>,
+        dedup: bool,
+        spinner: Option<Spinner>,
+    ) -> Result<()> {
+        let items: IndexMap<String, String> = items.into_iter().collect();
+        let mut rag_files = vec![];
+        for (label, content) in items {
+            let path = format!("{TEXT_PATH_PREFIX}{label}");
+            if content.trim().is_empty() {
+                bail!("No text given for {path}");
+            }
+            let split_options = SplitterChunkHeaderOptions::default().with_chunk_header(&format!(
+                "<document_metadata>\npath: {path}\n</document_metadata>\n\n"
+            ));
+            let (chunk_size, chunk_overlap) = self.data.chunk_params(DEFAULT_EXTENSION);
+            let mut documents = split_by_extension(
+                DEFAULT_EXTENSION,
+                chunk_size,
+                chunk_overlap,
+                self.data.chunk_size_unit,
+                &[RagDocument::new(content.as_str())],
+                &split_options,
+            );
+            for (index, document) in documents.iter_mut().enumerate() {
+                document
+                    .metadata
+                    .insert(SOURCE_METADATA.into(), path.clone());
+                document
+                    .metadata
+                    .insert(CHUNK_INDEX_METADATA.into(), index.to_string());
+            }
+            rag_files.push(RagFile {
+                hash: sha256(&content),
+                path,
+                mtime: 0,
+                size: 0,
+                documents,
+            });
+        }
+        let replaced: Vec<FileId> = self
+            .data
+            .files
+            .iter()
+            .filter(|(_, file)| rag_files.iter().any(|v| v.path == file.path))
+            .map(|(file_id, _)| *file_id)
+            .collect();
+        // Drop the replaced snippets only once the new ones are embedded.
+        self.add_files(rag_files, dedup, &spinner).await?;
+        self.data.del(replaced);
+        progress(&spinner, "Building store".into());
+        self.hnsw = self.data.build_hnsw()?;
+        self.bm25 = self.data.build_bm25();
+        Ok(())
+    }
+
+    /// Search with `query`, also looking up `paraphrases` of it in the vector index.
     async fn hybird_search(
         &self,
         query: &str,
-        top_k: usize,
-        min_score_vector_search: f32,
-        min_score_keyword_search: f32,
-        rerank: Option<(Box<dyn Client>, f32)>,
-    ) -> Result<Vec<String>> {
-        let (vector_search_result, text_search_result) = tokio::join!(
-            self.vector_search(query, top_k, min_score_vector_search),
-            self.keyword_search(query, top_k, min_score_keyword_search)
-        );
-        let vector_search_ids = vector_search_result?;
-        let keyword_search_ids = text_search_result?;
+        paraphrases: &[String],
+        options: &SearchOptions,
+        rerank: Option<&(Box<dyn Client>, f32)>,
+    ) -> Result<Vec<SearchHit>> {
+        let top_k = options.top_k;
+        let candidates_k = match rerank {
+            Some(_) => top_k * RERANK_CANDIDATES_FACTOR,
+            None => top_k,
+        };
+        let allowed_ids = options
+            .filter
+            .as_ref()
+            .map(|filter| self.data.filter_ids(filter));
+        if allowed_ids.as_ref().is_some_and(|v| v.is_empty()) {
+            return Ok(vec![]);
+        }
+        let vector_search = async {
+            if options.mode != RagSearchMode::TextOnly && options.vector_weight > 0.0 {
+                let min_score = options.min_score_vector_search;
+                let ef_search = options.ef_search.max(candidates_k);
+                self.vector_search(
+                    query,
+                    paraphrases,
+                    candidates_k,
+                    ef_search,
+                    min_score,
+                    allowed_ids.as_ref(),
+                )
+                .await
+            } else {
+                Ok((vec![], vec![]))
+            }
+        };
+        let keyword_search = async {
+            if options.mode != RagSearchMode::VectorOnly && options.text_weight > 0.0 {
+                let min_score = options.min_score_keyword_search;
+                self.keyword_search(query, candidates_k, min_score, allowed_ids.as_ref())
+                    .await
+            } else {
+                Ok(vec![])
+            }
+        };
+        let (vector_search_result, text_search_result) =
+            tokio::join!(vector_search, keyword_search);
+        let (vector_search_hits, query_embeddings) = vector_search_result?;
+        let keyword_search_hits = text_search_result?;
+        let vector_scores = best_scores(&vector_search_hits);
+        let text_scores = best_scores(&keyword_search_hits);
+        let vector_search_ids: Vec<_> = vector_search_hits.into_iter().map(|(id, _)| id).collect();
+        let keyword_search_ids: Vec<_> =
+            keyword_search_hits.into_iter().map(|(id, _)| id).collect();
         debug!(
             "vector_search_ids: {vector_search_ids:?}, keyword_search_ids: {keyword_search_ids:?}"
         );
-        let ids = match rerank {
+        let reranked = match rerank {
             Some((client, min_score)) => {
-                let min_score = min_score as f64;
-                let ids: IndexSet<DocumentId> = [vector_search_ids, keyword_search_ids]
-                    .concat()
-                    .into_iter()
+                let ids: IndexSet<DocumentId> = vector_search_ids
+                    .iter()
+                    .chain(keyword_search_ids.iter())
+                    .copied()
                     .collect();
-                let mut documents = vec![];
-                let mut documents_ids = vec![];
-                for id in ids {
-                    if let Some(document) = self.data.get(id) {
-                        documents_ids.push(id);
-                        documents.push(document.page_content.to_string());
+                match self
+                    .rerank(client.as_ref(), query, ids, top_k, *min_score)
+                    .await
+                {
+                    Ok(ids) => Some(ids),
+                    Err(err) => {
+                        let message = format!("Failed to rerank, using rank fusion: {err}");
+                        eprintln!("{}", warning_text(&message));
+                        None
                     }
                 }
-                let data = RerankData::new(query.to_string(), documents, top_k);
-                let list = client.rerank(data).await?;
-                let ids = list
-                    .into_iter()
-                    .take(top_k)
-                    .filter_map(|item| {
-                        if item.relevance_score < min_score {
-                            None
-                        } else {
-                            documents_ids.get(item.index).cloned()
-                        }
-                    })
-                    .collect();
+            }
+            None => None,
+        };
+        let ids = match reranked {
+            Some(ids) => {
                 debug!("rerank_ids: {ids:?}");
                 ids
             }
+            None if options.mmr_lambda > 0.0 => {
+                // Every candidate of both searches, so that MMR has some to choose from.
+                let mut ids = reciprocal_rank_fusion(
+                    vec![vector_search_ids, keyword_search_ids],
+                    vec![options.vector_weight, options.text_weight],
+                    options.rrf_k,
+                    top_k * 2,
+                    |id| options.recency.factor(self.data.mtime(id)),
+                );
+                ids.retain(|(_, score)| *score >= options.min_score_fused);
+                debug!("rrf_ids: {ids:?}");
+                let query = mean_vector(&query_embeddings);
+                let ids = self.data.maximal_marginal_relevance(
+                    ids,
+                    query.as_deref(),
+                    options.mmr_lambda,
+                    top_k,
+                );
+                debug!("mmr_ids: {ids:?}");
+                ids
+            }
             None => {
-                let ids = reciprocal_rank_fusion(
+                let mut ids = reciprocal_rank_fusion(
                     vec![vector_search_ids, keyword_search_ids],
-                    vec![1.0, 1.0],
+                    vec![options.vector_weight, options.text_weight],
+                    options.rrf_k,
                     top_k,
+                    |id| options.recency.factor(self.data.mtime(id)),
                 );
+                ids.retain(|(_, score)| *score >= options.min_score_fused);
                 debug!("rrf_ids: {ids:?}");
                 ids
             }
         };
+        let mut contents = match options.neighbor_chunks {
+            0 => vec![],
+            count => {
+                let document_ids: Vec<_> = ids.iter().map(|(id, _)| *id).collect();
+                self.data.neighbor_contents(&document_ids, count)
+            }
+        }
+        .into_iter();
         let output = ids
             .into_iter()
-            .filter_map(|id| {
-                let document = self.data.get(id)?;
-                Some(document.page_content.clone())
+            .filter_map(|(id, score)| {
+                let content = contents.next();
+                let mut hit = self.data.hit(id, score)?;
+                if let Some(content) = content {
+                    hit.content = content?;
+                }
+                hit.score_vector = vector_scores.get(&id).copied();
+                hit.score_text = text_scores.get(&id).copied();
+                Some(hit)
             })
             .collect();
         Ok(output)
     }
 
+    async fn rerank(
+        &self,
+        client: &dyn Client,
+        query: &str,
+        ids: IndexSet<DocumentId>,
+        top_k: usize,
+        min_score: f32,
+    ) -> Result<Vec<(DocumentId, f32)>> {
+        let min_score = min_score as f64;
+        let mut documents = vec![];
+        let mut documents_ids = vec![];
+        for id in ids {
+            if let Some(document) = self.data.get(id) {
+                documents_ids.push(id);
+                documents.push(document.page_content.to_string());
+            }
+        }
+        let data = RerankData::new(query.to_string(), documents, top_k);
+        let list = client.rerank(data).await?;
+        let ids = list
+            .into_iter()
+            .take(top_k)
+            .filter_map(|item| {
+                if item.relevance_score < min_score {
+                    None
+                } else {
+                    let id = documents_ids.get(item.index)?;
+                    Some((*id, item.relevance_score as f32))
+                }
+            })
+            .collect();
+        Ok(ids)
+    }
+
+    /// Embed `query` and its `paraphrases` in one batch, returning the union of their neighbours
+    /// sorted by their best score.
     async fn vector_search(
         &self,
         query: &str,
+        paraphrases: &[String],
         top_k: usize,
+        ef_search: usize,
         min_score: f32,
-    ) -> Result<Vec<DocumentId>> {
+        allowed_ids: Option<&Vec<DocumentId>>,
+    ) -> Result<(Vec<(DocumentId, f32)>, EmbeddingsOutput)> {
         let splitter = RecursiveCharacterTextSplitter::new(
             self.data.chunk_size,
             self.data.chunk_overlap,
             &DEFAULT_SEPARATES,
-        );
-        let texts = splitter.split_text(query);
+        )
+        .with_size_unit(self.data.chunk_size_unit);
+        let mut texts = splitter.split_text(query);
+        texts.extend(paraphrases.iter().cloned());
         let embeddings_data = EmbeddingsData::new(texts, true);
-        let embeddings = self.create_embeddings(embeddings_data, None).await?;
-        let output = self
-            .hnsw
-            .parallel_search(&embeddings, top_k, 30)
+        let embeddings = self.create_embeddings(embeddings_data, &[], None).await?;
+        // The graph identifies vectors by their position in `vectors`.
+        let neighbours = match allowed_ids {
+            Some(allowed_ids) => {
+                let mut allowed_indexes: Vec<usize> = allowed_ids
+                    .iter()
+                    .filter_map(|id| {
+                        let id = self.data.duplicates.get(id).unwrap_or(id);
+                        self.data.vectors.get_index_of(id)
+                    })
+                    .collect();
+                allowed_indexes.sort_unstable();
+                allowed_indexes.dedup();
+                embeddings
+                    .iter()
+                    .map(|v| {
+                        self.hnsw
+                            .search_filter(v, top_k, ef_search, Some(&allowed_indexes))
+                    })
+                    .collect()
+            }
+            None => self.hnsw.parallel_search(&embeddings, top_k, ef_search),
+        };
+        let distance = self.data.distance;
+        let mut output: Vec<(DocumentId, f32)> = neighbours
             .into_iter()
             .flat_map(|list| {
                 list.into_iter()
                     .filter_map(|v| {
-                        if v.distance < min_score {
+                        let score = distance.score(v.distance);
+                        if score < min_score {
                             return None;
                         }
-                        Some(v.d_id)
+                        let (id, _) = self.data.vectors.get_index(v.d_id)?;
+                        Some((*id, score))
+                    })
+                    .flat_map(|(id, score)| {
+                        self.data
+                            .sharing_ids(id)
+                            .into_iter()
+                            .map(move |id| (id, score))
+                    })
+                    .filter(|(id, _)| match allowed_ids {
+                        Some(ids) => ids.binary_search(id).is_ok(),
+                        None => true,
                     })
                     .collect::<Vec<_>>()
             })
             .collect();
-        Ok(output)
+        output.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut seen = HashSet::new();
+        output.retain(|(id, _)| seen.insert(*id));
+        Ok((output, embeddings))
     }
 
     async fn keyword_search(
@@ -476,49 +1568,198 @@ impl Rag {
         query: &str,
         top_k: usize,
         min_score: f32,
-    ) -> Result<Vec<DocumentId>> {
-        let output = self.bm25.search(query, top_k, Some(min_score as f64));
+        allowed_ids: Option<&Vec<DocumentId>>,
+    ) -> Result<Vec<(DocumentId, f32)>> {
+        let min_score = Some(min_score as f64);
+        let output = match allowed_ids {
+            Some(ids) => self
+                .bm25
+                .search_with(query, top_k, min_score, |id| ids.binary_search(id).is_ok()),
+            None => self.bm25.search(query, top_k, min_score),
+        };
+        let output = output
+            .into_iter()
+            .map(|(id, score)| (id, score as f32))
+            .collect();
         Ok(output)
     }
 
+    /// Embed `data`, reusing cached embeddings. `sources` labels each text in error messages.
     async fn create_embeddings(
         &self,
         data: EmbeddingsData,
+        sources: &[String],
         spinner: Option<Spinner>,
     ) -> Result<EmbeddingsOutput> {
         let EmbeddingsData { texts, query } = data;
-        let mut output = vec![];
-        let batch_chunks = texts.chunks(self.embedding_model.max_batch_size());
+        // Queries may be embedded differently from documents, so only documents are cached.
+        let cache = self.embedding_cache.as_ref().filter(|_| !query);
+        let model_id = self.embedding_model.id();
+        let mut output: Vec<Option<Vec<f32>>> = texts
+            .iter()
+            .map(|text| cache.and_then(|cache| cache.get(&model_id, text)))
+            .collect();
+        // Identical texts are embedded once, the result is copied to each of their positions.
+        let mut duplicates: IndexMap<&str, Vec<usize>> = IndexMap::new();
+        for (i, text) in texts.iter().enumerate() {
+            if output[i].is_none() {
+                duplicates.entry(text.as_str()).or_default().push(i);
+            }
+        }
+        let missing: Vec<usize> = duplicates.values().map(|v| v[0]).collect();
+        if missing.len() < texts.len() {
+            debug!(
+                "reuse {} cached or duplicated embeddings",
+                texts.len() - missing.len()
+            );
+        }
+        let batch_chunks = missing.chunks(self.embedding_model.max_batch_size());
         let batch_chunks_len = batch_chunks.len();
-        for (index, texts) in batch_chunks.enumerate() {
+        // Output positions are tracked by `indexes`, so batches may complete in any order.
+        // Returning early drops the stream, which cancels the requests still in flight.
+        let tasks: Vec<_> = batch_chunks
+            .enumerate()
+            .map(|(chunk_index, indexes)| {
+                let chunk_data = EmbeddingsData {
+                    texts: indexes.iter().map(|i| texts[*i].clone()).collect(),
+                    query,
+                };
+                let spinner = spinner.clone();
+                async move {
+                    let chunk = (chunk_index + 1, batch_chunks_len);
+                    let ret = self
+                        .embeddings_with_retry(chunk_data, chunk, &spinner)
+                        .await;
+                    (indexes, ret)
+                }
+            })
+            .collect();
+        let concurrency = self
+            .embedding_model
+            .max_concurrent_requests()
+            .unwrap_or(self.embedding_concurrency)
+            .max(1);
+        let mut tasks = stream::iter(tasks).buffer_unordered(concurrency);
+        let mut completed = 0;
+        progress(
+            &spinner,
+            format!("Creating embeddings [{completed}/{batch_chunks_len}]"),
+        );
+        while let Some((indexes, ret)) = tasks.next().await {
+            let chunk_output = ret.with_context(|| {
+                let sources: Vec<&String> =
+                    indexes.iter().filter_map(|i| sources.get(*i)).collect();
+                match sources.as_slice() {
+                    [] => "Failed to create embedding".to_string(),
+                    [source] => format!("Failed to create embedding for {source}"),
+                    [first, .., last] => {
+                        format!("Failed to create embeddings for {first} .. {last}")
+                    }
+                }
+            })?;
+            if chunk_output.len() != indexes.len() {
+                bail!(
+                    "Expected {} embeddings, but got {}",
+                    indexes.len(),
+                    chunk_output.len()
+                );
+            }
+            for (i, vector) in indexes.iter().zip(chunk_output) {
+                if let Some(cache) = cache {
+                    if let Err(err) = cache.put(&model_id, &texts[*i], &vector) {
+                        debug!("Failed to cache embedding, {err}");
+                    }
+                }
+                for j in &duplicates[texts[*i].as_str()] {
+                    output[*j] = Some(vector.clone());
+                }
+            }
+            completed += 1;
             progress(
                 &spinner,
-                format!("Creating embeddings [{}/{batch_chunks_len}]", index + 1),
+                format!("Creating embeddings [{completed}/{batch_chunks_len}]"),
             );
-            let chunk_data = EmbeddingsData {
-                texts: texts.to_vec(),
-                query,
-            };
-            let chunk_output = self
-                .embedding_client
-                .embeddings(chunk_data)
-                .await
-                .context("Failed to create embedding")?;
-            output.extend(chunk_output);
         }
-        Ok(output)
+        if let (Some(cache), false) = (cache, missing.is_empty()) {
+            if let Err(err) = cache.evict() {
+                debug!("Failed to evict cached embeddings, {err}");
+            }
+        }
+        Ok(output.into_iter().map(|v| v.unwrap_or_default()).collect())
+    }
+
+    /// Call the embeddings api, retrying rate limits, server errors and network failures with
+    /// backoff. `chunk` is the position of this batch and the number of batches, for progress.
+    async fn embeddings_with_retry(
+        &self,
+        data: EmbeddingsData,
+        chunk: (usize, usize),
+        spinner: &Option<Spinner>,
+    ) -> Result<EmbeddingsOutput> {
+        let mut retries = 0;
+        loop {
+            match self.embedding_client.embeddings(data.clone()).await {
+                Ok(output) => return Ok(output),
+                Err(err) if retries < self.embedding_retries && is_retryable_error(&err) => {
+                    retries += 1;
+                    let delay = retry_delay(&err, retries);
+                    debug!("retry embeddings in {delay:?}, {err:?}");
+                    progress(
+                        spinner,
+                        format!(
+                            "Retrying chunk {}/{} [{retries}/{}]",
+                            chunk.0, chunk.1, self.embedding_retries
+                        ),
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
     }
 }
 
+/// The saved form of a rag, stored with bincode after `RAG_FILE_MAGIC`.
+///
+/// bincode can't skip unknown fields or default missing ones, so `version` comes first and any
+/// change to the layout must bump `RAG_DATA_VERSION`.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RagData {
+    #[serde(default)]
+    pub version: u32,
     pub embedding_model: String,
     pub chunk_size: usize,
     pub chunk_overlap: usize,
+    #[serde(default)]
+    pub chunk_size_unit: ChunkSizeUnit,
     pub next_file_id: FileId,
     pub document_paths: Vec<String>,
     pub files: IndexMap<FileId, RagFile>,
-    pub vectors: IndexMap<DocumentId, Vec<f32>>,
+    /// How `vectors` are stored, in memory and on disk.
+    #[serde(default)]
+    pub vector_compression: RagVectorCompression,
+    pub vectors: RagVectors,
+    /// Documents whose content is identical to another one, mapped to the id holding the vector.
+    #[serde(default)]
+    pub duplicates: IndexMap<DocumentId, DocumentId>,
+    /// The reverse of `duplicates`, rebuilt by `index_duplicates` whenever it changes.
+    #[serde(skip)]
+    shared_ids: HashMap<DocumentId, Vec<DocumentId>>,
+    #[serde(default)]
+    pub dimension: Option<usize>,
+    #[serde(default)]
+    pub distance: RagDistance,
+    #[serde(default = "default_hnsw_max_connections")]
+    pub hnsw_max_connections: usize,
+    #[serde(default = "default_hnsw_ef_construction")]
+    pub hnsw_ef_construction: usize,
+    #[serde(default = "default_hnsw_max_layers")]
+    pub hnsw_max_layers: usize,
+    #[serde(default)]
+    pub bm25: BM25Options,
+    /// Chunking parameters of files with the given extension, overriding the rag-wide ones.
+    #[serde(default)]
+    pub chunk_overrides: IndexMap<String, RagChunkOverride>,
 }
 
 impl Debug for RagData {
@@ -537,204 +1778,3886 @@ impl Debug for RagData {
 impl RagData {
     pub fn new(embedding_model: String, chunk_size: usize, chunk_overlap: usize) -> Self {
         Self {
+            version: RAG_DATA_VERSION,
             embedding_model,
             chunk_size,
             chunk_overlap,
+            chunk_size_unit: Default::default(),
             next_file_id: 0,
             document_paths: Default::default(),
             files: Default::default(),
+            vector_compression: Default::default(),
             vectors: Default::default(),
+            duplicates: Default::default(),
+            shared_ids: Default::default(),
+            dimension: None,
+            distance: Default::default(),
+            hnsw_max_connections: default_hnsw_max_connections(),
+            hnsw_ef_construction: default_hnsw_ef_construction(),
+            hnsw_max_layers: default_hnsw_max_layers(),
+            bm25: Default::default(),
+            chunk_overrides: Default::default(),
         }
     }
 
-    pub fn get(&self, id: DocumentId) -> Option<&RagDocument> {
-        let (file_index, document_index) = split_document_id(id);
-        let file = self.files.get(&file_index)?;
-        let document = file.documents.get(document_index)?;
-        Some(document)
+    pub fn set_bm25_params(&mut self, config: &Config) -> Result<()> {
+        let language = &config.rag_bm25_language;
+        if BM25Options::stemmer_algorithm(language).is_none() {
+            bail!("Invalid rag_bm25_language, '{language}' has no stemmer");
+        }
+        self.bm25 = BM25Options::default();
+        self.bm25.language = language.clone();
+        self.bm25.stopwords = config.rag_bm25_stopwords.clone();
+        self.bm25.stemming = config.rag_bm25_stemming;
+        self.bm25.cjk = config.rag_bm25_cjk;
+        Ok(())
     }
 
-    pub fn del(&mut self, file_ids: Vec<FileId>) {
-        for file_id in file_ids {
-            if let Some(file) = self.files.swap_remove(&file_id) {
-                for (document_index, _) in file.documents.iter().enumerate() {
-                    let document_id = combine_document_id(file_id, document_index);
-                    self.vectors.swap_remove(&document_id);
-                }
+    pub fn set_chunk_overrides(&mut self, config: &Config) -> Result<()> {
+        check_chunk_overrides(&config.rag_chunk_overrides)?;
+        self.chunk_overrides = config.rag_chunk_overrides.clone();
+        for extension in self.chunk_overrides.keys() {
+            let (chunk_size, chunk_overlap) = self.chunk_params(extension);
+            if chunk_overlap >= chunk_size {
+                bail!("Invalid rag_chunk_overrides for '{extension}', chunk_overlap {chunk_overlap} must be less than chunk_size {chunk_size}");
             }
         }
+        Ok(())
     }
 
-    pub fn add(
-        &mut self,
-        next_file_id: FileId,
-        files: Vec<(FileId, RagFile)>,
-        document_ids: Vec<DocumentId>,
-        embeddings: EmbeddingsOutput,
-    ) {
-        self.next_file_id = next_file_id;
-        self.files.extend(files);
-        self.vectors
-            .extend(document_ids.into_iter().zip(embeddings));
+    /// The chunk size and overlap of files with `extension`.
+    pub fn chunk_params(&self, extension: &str) -> (usize, usize) {
+        match self.chunk_overrides.get(extension) {
+            Some(v) => (
+                v.chunk_size.unwrap_or(self.chunk_size),
+                v.chunk_overlap.unwrap_or(self.chunk_overlap),
+            ),
+            None => (self.chunk_size, self.chunk_overlap),
+        }
     }
 
-    pub fn build_hnsw(&self) -> Hnsw<'static, f32, DistCosine> {
-        let hnsw = Hnsw::new(32, self.vectors.len(), 16, 200, DistCosine {});
-        let list: Vec<_> = self.vectors.iter().map(|(k, v)| (v, *k)).collect();
-        hnsw.parallel_insert(&list);
-        hnsw
+    /// Switch how vectors are stored, converting the ones already stored.
+    pub fn set_vector_compression(&mut self, compression: RagVectorCompression) {
+        if compression != self.vector_compression {
+            for vector in self.vectors.values_mut() {
+                *vector = compression.store(vector.to_f32().into_owned());
+            }
+        }
+        self.vector_compression = compression;
     }
 
-    pub fn build_bm25(&self) -> BM25<DocumentId> {
-        let mut corpus = vec![];
-        for (file_index, file) in self.files.iter() {
-            for (document_index, document) in file.documents.iter().enumerate() {
-                let id = combine_document_id(*file_index, document_index);
-                corpus.push((id, document.page_content.clone()));
+    pub fn set_hnsw_params(&mut self, config: &Config) -> Result<()> {
+        self.distance = config.rag_distance;
+        if let Some(value) = config.rag_hnsw_max_connections {
+            if !(1..=256).contains(&value) {
+                bail!("Invalid rag_hnsw_max_connections, it must be between 1 and 256");
+            }
+            self.hnsw_max_connections = value;
+        }
+        if let Some(value) = config.rag_hnsw_ef_construction {
+            if value == 0 {
+                bail!("Invalid rag_hnsw_ef_construction, it must be positive");
+            }
+            self.hnsw_ef_construction = value;
+        }
+        if let Some(value) = config.rag_hnsw_max_layers {
+            if !(1..=16).contains(&value) {
+                bail!("Invalid rag_hnsw_max_layers, it must be between 1 and 16");
             }
+            self.hnsw_max_layers = value;
         }
-        BM25::new(corpus, BM25Options::default())
+        Ok(())
     }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RagFile {
-    hash: String,
-    path: String,
-    documents: Vec<RagDocument>,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct RagDocument {
-    pub page_content: String,
-    pub metadata: RagMetadata,
-}
 
-impl RagDocument {
-    pub fn new<S: Into<String>>(page_content: S) -> Self {
-        RagDocument {
-            page_content: page_content.into(),
-            metadata: IndexMap::new(),
+    /// Write the settings as the first line, then one line per chunk with its embedding unless
+    /// `with_vectors` is false. The first chunk of a file also carries the file's hash, mtime
+    /// and size. Returns how many chunks were written.
+    pub fn write_jsonl<W: Write>(&self, writer: &mut W, with_vectors: bool) -> Result<usize> {
+        let mut settings = Self::new(
+            self.embedding_model.clone(),
+            self.chunk_size,
+            self.chunk_overlap,
+        );
+        settings.chunk_size_unit = self.chunk_size_unit;
+        settings.document_paths = self.document_paths.clone();
+        settings.dimension = self.dimension;
+        settings.distance = self.distance;
+        settings.hnsw_max_connections = self.hnsw_max_connections;
+        settings.hnsw_ef_construction = self.hnsw_ef_construction;
+        settings.hnsw_max_layers = self.hnsw_max_layers;
+        settings.bm25 = self.bm25.clone();
+        settings.chunk_overrides = self.chunk_overrides.clone();
+        settings.vector_compression = self.vector_compression;
+        writeln!(writer, "{}", json!({ "rag": settings }))?;
+        let mut count = 0;
+        for (file_id, file) in &self.files {
+            for (index, document) in file.documents.iter().enumerate() {
+                let id = pack_document_id(*file_id, index);
+                let mut chunk = json!({
+                    "id": id,
+                    "file": file.path,
+                    "index": index,
+                    "content": document.page_content,
+                    "metadata": document.metadata,
+                });
+                if index == 0 {
+                    chunk["file_hash"] = json!(file.hash);
+                    chunk["file_mtime"] = json!(file.mtime);
+                    chunk["file_size"] = json!(file.size);
+                }
+                if with_vectors {
+                    chunk["embedding"] = json!(self.vector(id));
+                }
+                writeln!(writer, "{chunk}")?;
+                count += 1;
+            }
         }
+        Ok(count)
     }
-}
 
-impl Default for RagDocument {
-    fn default() -> Self {
-        RagDocument {
-            page_content: "".to_string(),
-            metadata: IndexMap::new(),
+    /// Rebuild from the output of `write_jsonl`, which must include the embeddings. Duplicate
+    /// chunks get a vector of their own.
+    pub fn read_jsonl(content: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Chunk {
+            id: DocumentId,
+            file: String,
+            index: usize,
+            content: String,
+            #[serde(default)]
+            metadata: RagMetadata,
+            embedding: Option<Vec<f32>>,
+            file_hash: Option<String>,
+            #[serde(default)]
+            file_mtime: u64,
+            #[serde(default)]
+            file_size: u64,
+        }
+        let mut lines = content
+            .lines()
+            .enumerate()
+            .filter(|(_, v)| !v.trim().is_empty());
+        let mut data: Self = match lines.next() {
+            Some((_, line)) => {
+                let mut value: Value = serde_json::from_str(line)?;
+                match value.get_mut("rag") {
+                    Some(settings) => serde_json::from_value(settings.take())?,
+                    None => bail!("Missing the rag settings on the first line"),
+                }
+            }
+            None => bail!("Empty rag export"),
+        };
+        let mut files: IndexMap<FileId, RagFile> = IndexMap::new();
+        let mut document_ids = vec![];
+        let mut embeddings = vec![];
+        for (line_index, line) in lines {
+            let chunk: Chunk = serde_json::from_str(line)
+                .with_context(|| format!("Invalid chunk at line {}", line_index + 1))?;
+            let (file_id, document_index) = split_document_id(chunk.id);
+            if document_index != chunk.index {
+                bail!(
+                    "Chunk {} has index {}, expected {document_index}",
+                    chunk.id,
+                    chunk.index
+                );
+            }
+            let embedding = match chunk.embedding {
+                Some(v) => v,
+                None => bail!(
+                    "Chunk {} has no embedding, it was exported without vectors",
+                    chunk.id
+                ),
+            };
+            let file = files.entry(file_id).or_insert_with(|| RagFile {
+                hash: chunk.file_hash.clone().unwrap_or_default(),
+                path: chunk.file.clone(),
+                mtime: chunk.file_mtime,
+                size: chunk.file_size,
+                documents: vec![],
+            });
+            if file.path != chunk.file || file.documents.len() != document_index {
+                bail!("Chunk {} is out of order", chunk.id);
+            }
+            file.documents.push(RagDocument {
+                page_content: chunk.content,
+                metadata: chunk.metadata,
+            });
+            document_ids.push(chunk.id);
+            embeddings.push(embedding);
         }
+        // Exports without the file hash predate it, derive one from the chunks instead.
+        for file in files.values_mut().filter(|v| v.hash.is_empty()) {
+            let contents: Vec<&str> = file
+                .documents
+                .iter()
+                .map(|v| chunk_content(&v.page_content))
+                .collect();
+            file.hash = sha256(&contents.join("\n"));
+        }
+        let next_file_id = files.keys().max().map(|v| v + 1).unwrap_or_default();
+        data.add(
+            next_file_id,
+            files.into_iter().collect(),
+            document_ids,
+            embeddings,
+        )?;
+        data.version = RAG_DATA_VERSION;
+        Ok(data)
     }
-}
-
-pub type RagMetadata = IndexMap<String, String>;
-
-pub type FileId = usize;
-pub type DocumentId = usize;
 
-pub fn combine_document_id(file_index: usize, document_index: usize) -> DocumentId {
-    file_index << (usize::BITS / 2) | document_index
-}
+    /// Write the rag in the layout read by `decode`.
+    pub fn encode<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(RAG_FILE_MAGIC)?;
+        bincode::serialize_into(writer, self)?;
+        Ok(())
+    }
 
-pub fn split_document_id(value: DocumentId) -> (usize, usize) {
-    let low_mask = (1 << (usize::BITS / 2)) - 1;
-    let low = value & low_mask;
-    let high = value >> (usize::BITS / 2);
-    (high, low)
-}
+    /// Decode a saved rag, falling back to the legacy layout for files without a version.
+    pub fn decode(content: &[u8]) -> Result<Self> {
+        let mut data: Self = match content.strip_prefix(RAG_FILE_MAGIC) {
+            Some(content) => {
+                let version: u32 = bincode::deserialize(content)?;
+                if version > RAG_DATA_VERSION {
+                    bail!(
+                        "Unsupported rag format version {version}, it was saved by a newer version"
+                    );
+                }
+                bincode::deserialize(content)?
+            }
+            None => bincode::deserialize::<LegacyRagData>(content)?.into(),
+        };
+        if data.version < 3 {
+            // Mtimes were stored in seconds, sizes not at all, so these files get checked again.
+            for file in data.files.values_mut() {
+                file.mtime = file.mtime.saturating_mul(NANOS_PER_SEC);
+            }
+        }
+        if data.version < 2 && usize::BITS == 32 {
+            // Earlier 32-bit builds packed document ids into 16-bit halves.
+            data.vectors = std::mem::take(&mut data.vectors.map)
+                .into_iter()
+                .map(|(id, vector)| {
+                    let id = pack_document_id((id >> 16) as usize, (id & 0xffff) as usize);
+                    (id, vector)
+                })
+                .collect();
+        }
+        // Older layouts are upgraded in memory and written back in the current one on save.
+        data.version = RAG_DATA_VERSION;
+        data.index_duplicates();
+        Ok(data)
+    }
 
-fn select_embedding_model(models: &[&Model]) -> Result<String> {
-    let models: Vec<_> = models
-        .iter()
-        .map(|v| SelectOption::new(v.id(), v.description()))
-        .collect();
-    let result = Select::new("Select embedding model:", models).prompt()?;
-    Ok(result.value)
-}
+    pub fn get(&self, id: DocumentId) -> Option<&RagDocument> {
+        let (file_index, document_index) = split_document_id(id);
+        let file = self.files.get(&file_index)?;
+        let document = file.documents.get(document_index)?;
+        Some(document)
+    }
+
+    /// Collect the sorted ids of all documents accepted by `filter`.
+    pub fn filter_ids(&self, filter: &MetadataFilter) -> Vec<DocumentId> {
+        let mut ids = vec![];
+        for (file_index, file) in &self.files {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                if filter.is_match(&file.path, document_index, &document.metadata) {
+                    ids.push(pack_document_id(*file_index, document_index));
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids
+    }
 
-fn set_chunk_size(model: &Model) -> Result<usize> {
-    let default_value = model.default_chunk_size().to_string();
-    let help_message = model
-        .max_input_tokens()
-        .map(|v| format!("The model's max_input_token is {v}"));
+    /// The content of each document along with up to `count` documents before and after it in
+    /// its file, in the order of `ids`. A document already shown around a previous one is shown
+    /// once, so the later id gets `None`, as does an id without a document.
+    pub fn neighbor_contents(&self, ids: &[DocumentId], count: usize) -> Vec<Option<String>> {
+        let mut shown = HashSet::new();
+        ids.iter()
+            .map(|id| {
+                let (file_index, document_index) = split_document_id(*id);
+                let documents = &self.files.get(&file_index)?.documents;
+                if document_index >= documents.len() || !shown.insert(*id) {
+                    return None;
+                }
+                let mut start = document_index;
+                while start > 0
+                    && document_index - start < count
+                    && shown.insert(pack_document_id(file_index, start - 1))
+                {
+                    start -= 1;
+                }
+                let mut end = document_index;
+                while end + 1 < documents.len()
+                    && end - document_index < count
+                    && shown.insert(pack_document_id(file_index, end + 1))
+                {
+                    end += 1;
+                }
+                let mut content = documents[start].page_content.clone();
+                for document in &documents[start + 1..=end] {
+                    append_chunk(&mut content, chunk_content(&document.page_content));
+                }
+                Some(content)
+            })
+            .collect()
+    }
 
-    let mut text = Text::new("Set chunk size:")
-        .with_default(&default_value)
-        .with_validator(move |text: &str| {
-            let out = match text.parse::<usize>() {
-                Ok(_) => Validation::Valid,
-                Err(_) => Validation::Invalid("Must be a integer".into()),
+    /// When the file of a document was last modified in seconds since the epoch, 0 if unknown.
+    pub fn mtime(&self, id: DocumentId) -> u64 {
+        let (file_index, _) = split_document_id(id);
+        self.files
+            .get(&file_index)
+            .map(|v| v.mtime / NANOS_PER_SEC)
+            .unwrap_or_default()
+    }
+
+    pub fn hit(&self, id: DocumentId, score: f32) -> Option<SearchHit> {
+        let (file_index, document_index) = split_document_id(id);
+        let file = self.files.get(&file_index)?;
+        let document = file.documents.get(document_index)?;
+        Some(SearchHit {
+            path: file.path.clone(),
+            chunk_index: document_index,
+            score_vector: None,
+            score_text: None,
+            score,
+            content: document.page_content.clone(),
+            rag: None,
+        })
+    }
+
+    pub fn del(&mut self, file_ids: Vec<FileId>) {
+        let mut removed = HashSet::new();
+        for file_id in file_ids {
+            if let Some(file) = self.files.swap_remove(&file_id) {
+                for (document_index, _) in file.documents.iter().enumerate() {
+                    removed.insert(pack_document_id(file_id, document_index));
+                }
+            }
+        }
+        if removed.is_empty() {
+            return;
+        }
+        self.duplicates.retain(|id, _| !removed.contains(id));
+        // A removed vector moves to the first remaining duplicate, which becomes the new canonical one.
+        let mut promoted: HashMap<DocumentId, DocumentId> = HashMap::new();
+        for (id, canonical_id) in &self.duplicates {
+            if removed.contains(canonical_id) {
+                promoted.entry(*canonical_id).or_insert(*id);
+            }
+        }
+        for id in &removed {
+            if let Some(vector) = self.vectors.swap_remove(id) {
+                if let Some(new_id) = promoted.get(id) {
+                    self.vectors.insert(*new_id, vector);
+                }
+            }
+        }
+        self.duplicates
+            .retain(|id, canonical_id| match promoted.get(canonical_id) {
+                Some(new_id) if new_id == id => false,
+                Some(new_id) => {
+                    *canonical_id = *new_id;
+                    true
+                }
+                None => true,
+            });
+        self.update_dedup_counts();
+    }
+
+    /// Map every document holding a shared vector to the documents sharing it.
+    pub fn index_duplicates(&mut self) {
+        self.shared_ids.clear();
+        for (id, canonical_id) in &self.duplicates {
+            self.shared_ids.entry(*canonical_id).or_default().push(*id);
+        }
+    }
+
+    /// Re-index `duplicates` and record in the metadata of every document holding a shared vector
+    /// how many documents share it.
+    pub fn update_dedup_counts(&mut self) {
+        self.index_duplicates();
+        for (file_id, file) in self.files.iter_mut() {
+            for (document_index, document) in file.documents.iter_mut().enumerate() {
+                match self
+                    .shared_ids
+                    .get(&pack_document_id(*file_id, document_index))
+                {
+                    Some(ids) => {
+                        let count = ids.len() + 1;
+                        document
+                            .metadata
+                            .insert(DEDUP_COUNT_METADATA.into(), count.to_string());
+                    }
+                    None => {
+                        document.metadata.swap_remove(DEDUP_COUNT_METADATA);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Index the content of every document holding a vector, to find near-duplicates of it.
+    fn shingle_index(&self, threshold: f64) -> ShingleIndex<DocumentId> {
+        let mut index = ShingleIndex::new(threshold);
+        for (file_index, file) in &self.files {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                let id = pack_document_id(*file_index, document_index);
+                if self.vectors.contains_key(&id) {
+                    index.insert(id, chunk_content(&document.page_content));
+                }
+            }
+        }
+        index
+    }
+
+    /// Map the content hash of every document holding a vector to its id.
+    pub fn canonical_ids(&self) -> HashMap<String, DocumentId> {
+        let mut output = HashMap::new();
+        for (file_index, file) in &self.files {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                let id = pack_document_id(*file_index, document_index);
+                if self.vectors.contains_key(&id) {
+                    output.insert(sha256(chunk_content(&document.page_content)), id);
+                }
+            }
+        }
+        output
+    }
+
+    /// The vector of a document, shared with the documents it duplicates.
+    pub fn vector(&self, id: DocumentId) -> Option<Cow<'_, [f32]>> {
+        let id = self.duplicates.get(&id).unwrap_or(&id);
+        self.vectors.get(id).map(|v| v.to_f32())
+    }
+
+    /// Pick `top_k` of the ranked `candidates` one at a time, maximizing
+    /// `lambda * relevance - (1 - lambda) * max similarity to the picked ones`.
+    ///
+    /// Relevance is the cosine similarity to `query`, or the candidate score scaled to [0, 1]
+    /// without a query embedding. Picked candidates keep their original score.
+    pub fn maximal_marginal_relevance(
+        &self,
+        candidates: Vec<(DocumentId, f32)>,
+        query: Option<&[f32]>,
+        lambda: f32,
+        top_k: usize,
+    ) -> Vec<(DocumentId, f32)> {
+        type Candidate<'a> = (DocumentId, f32, Option<Cow<'a, [f32]>>, f32);
+        let max_score = candidates
+            .iter()
+            .map(|(_, score)| *score)
+            .fold(f32::MIN, f32::max);
+        let mut candidates: Vec<Candidate> = candidates
+            .into_iter()
+            .map(|(id, score)| {
+                let vector = self.vector(id);
+                let relevance = match (query, &vector) {
+                    (Some(query), Some(vector)) => cosine_similarity(query, vector),
+                    _ if max_score > 0.0 => score / max_score,
+                    _ => 0.0,
+                };
+                (id, score, vector, relevance)
+            })
+            .collect();
+        let mut selected: Vec<Candidate> = vec![];
+        while selected.len() < top_k && !candidates.is_empty() {
+            let mmr_score = |(_, _, vector, relevance): &Candidate| {
+                let redundancy = selected
+                    .iter()
+                    .filter_map(|(_, _, other, _)| {
+                        Some(cosine_similarity(vector.as_ref()?, other.as_ref()?))
+                    })
+                    .fold(0.0, f32::max);
+                lambda * relevance - (1.0 - lambda) * redundancy
             };
-            Ok(out)
-        });
-    if let Some(help_message) = &help_message {
-        text = text.with_help_message(help_message);
+            let (index, _) = candidates
+                .iter()
+                .enumerate()
+                .map(|(index, candidate)| (index, mmr_score(candidate)))
+                .fold((0, f32::MIN), |best, v| if v.1 > best.1 { v } else { best });
+            selected.push(candidates.remove(index));
+        }
+        selected
+            .into_iter()
+            .map(|(id, score, _, _)| (id, score))
+            .collect()
+    }
+
+    /// The ids of the documents sharing the vector stored under `id`, starting with `id` itself.
+    pub fn sharing_ids(&self, id: DocumentId) -> Vec<DocumentId> {
+        let mut ids = vec![id];
+        if let Some(shared_ids) = self.shared_ids.get(&id) {
+            ids.extend(shared_ids);
+        }
+        ids
+    }
+
+    /// The text to embed for a document. A vector shared by documents of several files is
+    /// embedded without the header naming the file, so it doesn't lean towards one of them.
+    pub fn embedding_text<'a>(&self, id: DocumentId, page_content: &'a str) -> &'a str {
+        if self.shared_ids.contains_key(&id) {
+            chunk_content(page_content)
+        } else {
+            page_content
+        }
+    }
+
+    /// Remove the files matched by `paths` (files, directories or globs), returning their paths.
+    ///
+    /// File ids are never reused, so removing files leaves the remaining document ids intact.
+    pub fn remove_paths(&mut self, paths: &[String]) -> Vec<String> {
+        let is_matched = |path: &str| paths.iter().any(|pattern| match_path(pattern, path));
+        let mut file_ids = vec![];
+        let mut removed = vec![];
+        for (file_id, file) in &self.files {
+            if is_matched(&file.path) {
+                file_ids.push(*file_id);
+                removed.push(file.path.clone());
+            }
+        }
+        self.document_paths.retain(|v| !is_matched(v));
+        self.del(file_ids);
+        removed
+    }
+
+    /// Renumber the files from 0 in order and store the vectors in document order, dropping the
+    /// ones no document refers to. Returns false if there was nothing to renumber or drop.
+    pub fn compact(&mut self) -> bool {
+        let file_ids: HashMap<FileId, FileId> = self
+            .files
+            .keys()
+            .enumerate()
+            .map(|(new_id, old_id)| (*old_id, new_id))
+            .collect();
+        let mut id_map: IndexMap<DocumentId, DocumentId> = IndexMap::new();
+        for (file_id, file) in &self.files {
+            for document_index in 0..file.documents.len() {
+                id_map.insert(
+                    pack_document_id(*file_id, document_index),
+                    pack_document_id(file_ids[file_id], document_index),
+                );
+            }
+        }
+        let vector_ids: Vec<DocumentId> = id_map
+            .keys()
+            .filter(|id| self.vectors.contains_key(*id))
+            .copied()
+            .collect();
+        let is_compact = self.next_file_id == self.files.len()
+            && file_ids.iter().all(|(old_id, new_id)| old_id == new_id)
+            && self.vectors.keys().eq(vector_ids.iter())
+            && self.duplicates.keys().all(|id| id_map.contains_key(id));
+        if is_compact {
+            return false;
+        }
+
+        let mut vectors = std::mem::take(&mut *self.vectors);
+        for id in vector_ids {
+            if let Some(vector) = vectors.swap_remove(&id) {
+                self.vectors.insert(id_map[&id], vector);
+            }
+        }
+        self.vectors.shrink_to_fit();
+        self.duplicates = std::mem::take(&mut self.duplicates)
+            .into_iter()
+            .filter_map(|(id, canonical_id)| Some((*id_map.get(&id)?, *id_map.get(&canonical_id)?)))
+            .collect();
+        self.index_duplicates();
+        self.files = std::mem::take(&mut self.files)
+            .into_iter()
+            .map(|(file_id, file)| (file_ids[&file_id], file))
+            .collect();
+        self.next_file_id = self.files.len();
+        true
+    }
+
+    pub fn add(
+        &mut self,
+        next_file_id: FileId,
+        files: Vec<(FileId, RagFile)>,
+        document_ids: Vec<DocumentId>,
+        embeddings: EmbeddingsOutput,
+    ) -> Result<()> {
+        if document_ids.len() != embeddings.len() {
+            bail!(
+                "Expected {} embeddings, but got {}",
+                document_ids.len(),
+                embeddings.len()
+            );
+        }
+        let dimension = self
+            .dimension
+            .or_else(|| self.vectors.values().next().map(|v| v.dimension()));
+        self.dimension = check_dimension(dimension, embeddings.iter().map(|v| v.len()))?;
+        for (file_id, file) in &files {
+            combine_document_id(*file_id, file.documents.len().saturating_sub(1))?;
+        }
+        self.next_file_id = next_file_id;
+        self.files.extend(files);
+        self.store_vectors(document_ids, embeddings);
+        Ok(())
+    }
+
+    /// Store `embeddings` as `vector_compression` asks.
+    pub fn store_vectors(&mut self, document_ids: Vec<DocumentId>, embeddings: EmbeddingsOutput) {
+        let compression = self.vector_compression;
+        let embeddings = embeddings.into_iter().map(|v| compression.store(v));
+        self.vectors
+            .extend(document_ids.into_iter().zip(embeddings));
+    }
+
+    /// Ensure every stored vector has the dimension of the embedding model.
+    pub fn check_dimension(&self) -> Result<()> {
+        check_dimension(self.dimension, self.vectors.values().map(|v| v.dimension()))?;
+        Ok(())
+    }
+
+    /// Identifies the vectors a persisted HNSW graph was built from, their values included so
+    /// re-embedding the same documents invalidates the graph.
+    pub fn hnsw_fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "{}:{}:{}:{}:{}:{}",
+            self.embedding_model,
+            self.distance.as_str(),
+            self.hnsw_max_connections,
+            self.hnsw_ef_construction,
+            self.hnsw_max_layers,
+            self.vectors.len()
+        ));
+        let mut bytes = vec![];
+        for (id, vector) in self.vectors.iter() {
+            bytes.clear();
+            bytes.extend(id.to_le_bytes());
+            bytes.extend(vector.to_f32().iter().flat_map(|v| v.to_le_bytes()));
+            hasher.update(&bytes);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub fn build_hnsw(&self) -> Result<RagHnsw> {
+        self.check_dimension()?;
+        let hnsw = RagHnsw::new(
+            self.distance,
+            self.hnsw_max_connections,
+            self.vectors.len(),
+            self.hnsw_max_layers,
+            self.hnsw_ef_construction,
+        );
+        // Int8 vectors are only expanded for as long as the graph is being built.
+        let vectors: Vec<_> = self.vectors.values().map(|v| v.to_f32()).collect();
+        let list: Vec<_> = vectors.iter().map(|v| v.as_ref()).zip(0..).collect();
+        hnsw.parallel_insert(&list);
+        Ok(hnsw)
+    }
+
+    pub fn build_bm25(&self) -> BM25<DocumentId> {
+        let mut corpus = vec![];
+        for (file_index, file) in self.files.iter() {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                let id = pack_document_id(*file_index, document_index);
+                corpus.push((id, document.page_content.clone()));
+            }
+        }
+        BM25::new(corpus, self.bm25.clone())
     }
-    let value = text.prompt()?;
-    value.parse().map_err(|_| anyhow!("Invalid chunk_size"))
 }
 
-fn set_chunk_overlay(default_value: usize) -> Result<usize> {
-    let value = Text::new("Set chunk overlay:")
-        .with_default(&default_value.to_string())
-        .with_validator(move |text: &str| {
-            let out = match text.parse::<usize>() {
-                Ok(_) => Validation::Valid,
-                Err(_) => Validation::Invalid("Must be a integer".into()),
-            };
-            Ok(out)
-        })
-        .prompt()?;
-    value.parse().map_err(|_| anyhow!("Invalid chunk_overlay"))
+/// How the vectors of a rag are stored, in memory and on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RagVectorCompression {
+    #[default]
+    None,
+    /// One byte per value with a per-vector scale, about a quarter of the size.
+    Int8,
 }
 
-fn add_documents() -> Result<Vec<String>> {
-    let text = Text::new("Add documents:")
-        .with_validator(required!("This field is required"))
-        .with_help_message("e.g. file;dir/;dir/**/*.{md,mdx};solo-url;site-url/**")
-        .prompt()?;
-    let paths = text
-        .split(';')
-        .filter_map(|v| {
-            let v = v.trim().to_string();
-            if v.is_empty() {
-                None
-            } else {
-                Some(v)
+impl RagVectorCompression {
+    /// The vector in the form this compression keeps it.
+    pub fn store(&self, vector: Vec<f32>) -> RagVector {
+        match self {
+            Self::None => RagVector::F32(vector),
+            Self::Int8 => {
+                let (scale, values) = quantize(&vector);
+                RagVector::Int8 { scale, values }
             }
-        })
-        .collect();
-    Ok(paths)
+        }
+    }
 }
 
-fn progress(spinner: &Option<Spinner>, message: String) {
-    if let Some(spinner) = spinner {
-        let _ = spinner.set_message(message);
+/// An embedding as stored by a rag.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RagVector {
+    F32(Vec<f32>),
+    /// Quantized values, each to be multiplied by `scale`.
+    Int8 {
+        scale: f32,
+        values: Vec<i8>,
+    },
+}
+
+impl RagVector {
+    pub fn dimension(&self) -> usize {
+        match self {
+            Self::F32(vector) => vector.len(),
+            Self::Int8 { values, .. } => values.len(),
+        }
+    }
+
+    pub fn to_f32(&self) -> Cow<'_, [f32]> {
+        match self {
+            Self::F32(vector) => Cow::Borrowed(vector.as_slice()),
+            Self::Int8 { scale, values } => Cow::Owned(dequantize(*scale, values)),
+        }
     }
 }
 
-fn reciprocal_rank_fusion(
-    list_of_document_ids: Vec<Vec<DocumentId>>,
-    list_of_weights: Vec<f32>,
-    top_k: usize,
-) -> Vec<DocumentId> {
-    let rrf_k = top_k * 2;
-    let mut map: IndexMap<DocumentId, f32> = IndexMap::new();
-    for (document_ids, weight) in list_of_document_ids
-        .into_iter()
-        .zip(list_of_weights.into_iter())
-    {
-        for (index, &item) in document_ids.iter().enumerate() {
-            *map.entry(item).or_default() += (1.0 / ((rrf_k + index + 1) as f32)) * weight;
+impl From<Vec<f32>> for RagVector {
+    fn from(vector: Vec<f32>) -> Self {
+        Self::F32(vector)
+    }
+}
+
+/// The embeddings of a rag by document id.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RagVectors {
+    map: IndexMap<DocumentId, RagVector>,
+}
+
+impl std::ops::Deref for RagVectors {
+    type Target = IndexMap<DocumentId, RagVector>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl std::ops::DerefMut for RagVectors {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.map
+    }
+}
+
+impl FromIterator<(DocumentId, RagVector)> for RagVectors {
+    fn from_iter<T: IntoIterator<Item = (DocumentId, RagVector)>>(iter: T) -> Self {
+        Self {
+            map: iter.into_iter().collect(),
         }
     }
-    let mut sorted_items: Vec<(DocumentId, f32)> = map.into_iter().collect();
-    sorted_items.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+}
 
-    sorted_items
-        .into_iter()
-        .take(top_k)
-        .map(|(v, _)| v)
-        .collect()
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RagChunkOverride {
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    #[serde(default)]
+    pub chunk_overlap: Option<usize>,
+}
+
+/// Check the overrides that can be checked without the chunk size of a rag.
+pub fn check_chunk_overrides(overrides: &IndexMap<String, RagChunkOverride>) -> Result<()> {
+    for (extension, value) in overrides {
+        match (value.chunk_size, value.chunk_overlap) {
+            (Some(0), _) => {
+                bail!("Invalid rag_chunk_overrides for '{extension}', chunk_size must be positive")
+            }
+            (Some(chunk_size), Some(chunk_overlap)) if chunk_overlap >= chunk_size => {
+                bail!("Invalid rag_chunk_overrides for '{extension}', chunk_overlap {chunk_overlap} must be less than chunk_size {chunk_size}")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RagFile {
+    hash: String,
+    path: String,
+    /// Nanoseconds since the epoch, so an edit made in the second the file was indexed is seen.
+    #[serde(default)]
+    mtime: u64,
+    #[serde(default)]
+    size: u64,
+    documents: Vec<RagDocument>,
+}
+
+/// The layout of rag files saved before they carried a version.
+#[derive(Deserialize)]
+struct LegacyRagData {
+    embedding_model: String,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    next_file_id: FileId,
+    document_paths: Vec<String>,
+    files: IndexMap<FileId, LegacyRagFile>,
+    vectors: IndexMap<DocumentId, Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct LegacyRagFile {
+    hash: String,
+    path: String,
+    documents: Vec<RagDocument>,
+}
+
+impl From<LegacyRagData> for RagData {
+    fn from(data: LegacyRagData) -> Self {
+        let files = data
+            .files
+            .into_iter()
+            .map(|(file_id, file)| {
+                let file = RagFile {
+                    hash: file.hash,
+                    path: file.path,
+                    mtime: 0,
+                    size: 0,
+                    documents: file.documents,
+                };
+                (file_id, file)
+            })
+            .collect();
+        Self {
+            version: 0,
+            embedding_model: data.embedding_model,
+            chunk_size: data.chunk_size,
+            chunk_overlap: data.chunk_overlap,
+            chunk_size_unit: Default::default(),
+            next_file_id: data.next_file_id,
+            document_paths: data.document_paths,
+            files,
+            vector_compression: Default::default(),
+            vectors: data
+                .vectors
+                .into_iter()
+                .map(|(id, vector)| (id, vector.into()))
+                .collect(),
+            duplicates: Default::default(),
+            shared_ids: Default::default(),
+            dimension: None,
+            distance: Default::default(),
+            hnsw_max_connections: default_hnsw_max_connections(),
+            hnsw_ef_construction: default_hnsw_ef_construction(),
+            hnsw_max_layers: default_hnsw_max_layers(),
+            bm25: Default::default(),
+            chunk_overrides: Default::default(),
+        }
+    }
+}
+
+fn default_hnsw_max_connections() -> usize {
+    32
+}
+
+fn default_hnsw_ef_construction() -> usize {
+    200
+}
+
+fn default_hnsw_max_layers() -> usize {
+    16
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RagDocument {
+    pub page_content: String,
+    pub metadata: RagMetadata,
+}
+
+impl RagDocument {
+    pub fn new<S: Into<String>>(page_content: S) -> Self {
+        RagDocument {
+            page_content: page_content.into(),
+            metadata: IndexMap::new(),
+        }
+    }
+}
+
+impl Default for RagDocument {
+    fn default() -> Self {
+        RagDocument {
+            page_content: "".to_string(),
+            metadata: IndexMap::new(),
+        }
+    }
+}
+
+pub type RagMetadata = IndexMap<String, String>;
+
+/// Restricts rag search to a subset of documents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataFilter {
+    PathGlob(String),
+    Equals(String, String),
+}
+
+impl MetadataFilter {
+    /// Parse `path=<file|dir|glob>` or `<key>=<value>`, also accepting `:` as the separator.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (key, value) = match value.split_once('=').or_else(|| value.split_once(':')) {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => bail!("Invalid rag filter '{value}', expected `<key>=<value>`"),
+        };
+        if key == "path" {
+            let pattern = if Rag::is_url_path(value) {
+                value.to_string()
+            } else {
+                Path::new(value).absolutize()?.display().to_string()
+            };
+            Ok(Self::PathGlob(pattern))
+        } else {
+            Ok(Self::Equals(key.to_string(), value.to_string()))
+        }
+    }
+
+    pub fn is_match(&self, path: &str, chunk_index: usize, metadata: &RagMetadata) -> bool {
+        match self {
+            Self::PathGlob(pattern) => match_path(pattern, path),
+            Self::Equals(key, value) => match key.as_str() {
+                SOURCE_METADATA => path == value,
+                CHUNK_INDEX_METADATA => chunk_index.to_string() == *value,
+                _ => metadata.get(key) == Some(value),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for MetadataFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PathGlob(pattern) => write!(f, "path={pattern}"),
+            Self::Equals(key, value) => write!(f, "{key}={value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RagSearchMode {
+    #[default]
+    Hybrid,
+    VectorOnly,
+    TextOnly,
+}
+
+impl RagSearchMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hybrid => "hybrid",
+            Self::VectorOnly => "vector_only",
+            Self::TextOnly => "text_only",
+        }
+    }
+}
+
+impl std::str::FromStr for RagSearchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hybrid" => Ok(Self::Hybrid),
+            "vector_only" => Ok(Self::VectorOnly),
+            "text_only" => Ok(Self::TextOnly),
+            _ => bail!("Invalid rag search mode '{s}', expected hybrid, vector_only or text_only"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SearchOptions {
+    mode: RagSearchMode,
+    top_k: usize,
+    min_score_vector_search: f32,
+    min_score_keyword_search: f32,
+    min_score_fused: f32,
+    vector_weight: f32,
+    text_weight: f32,
+    rrf_k: usize,
+    mmr_lambda: f32,
+    recency: RecencyBoost,
+    ef_search: usize,
+    multi_query: usize,
+    neighbor_chunks: usize,
+    filter: Option<MetadataFilter>,
+}
+
+impl SearchOptions {
+    fn new(config: &Config) -> Result<Self> {
+        check_search_options(config)?;
+        Ok(Self {
+            mode: config.rag_search_mode,
+            top_k: config.rag_top_k,
+            min_score_vector_search: config.rag_min_score_vector_search,
+            min_score_keyword_search: config.rag_min_score_keyword_search,
+            min_score_fused: config.rag_min_score_fused,
+            vector_weight: config.rag_rrf_vector_weight,
+            text_weight: config.rag_rrf_text_weight,
+            rrf_k: config.rag_rrf_k,
+            mmr_lambda: config.rag_mmr_lambda,
+            recency: RecencyBoost {
+                weight: config.rag_recency_weight,
+                half_life_days: config.rag_recency_half_life_days,
+                now: std::time::SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|v| v.as_secs())
+                    .unwrap_or_default(),
+            },
+            ef_search: config.rag_ef_search,
+            multi_query: config.rag_multi_query,
+            neighbor_chunks: config.rag_neighbor_chunks,
+            filter: config.rag_filter.clone(),
+        })
+    }
+}
+
+/// Check the search settings of `config`, when loading it and on `.set` as well as before
+/// searching.
+pub fn check_search_options(config: &Config) -> Result<()> {
+    let vector_weight = config.rag_rrf_vector_weight;
+    let text_weight = config.rag_rrf_text_weight;
+    if vector_weight < 0.0 || text_weight < 0.0 {
+        bail!("Invalid rag_rrf_vector_weight/rag_rrf_text_weight, weights must not be negative");
+    }
+    if vector_weight == 0.0 && text_weight == 0.0 {
+        bail!("Invalid rag_rrf_vector_weight/rag_rrf_text_weight, at least one weight must be positive");
+    }
+    if !(0.0..=1.0).contains(&config.rag_mmr_lambda) {
+        bail!("Invalid rag_mmr_lambda, it must be between 0 and 1");
+    }
+    if config.rag_recency_weight < 0.0 {
+        bail!("Invalid rag_recency_weight, it must not be negative");
+    }
+    if config.rag_recency_half_life_days <= 0.0 {
+        bail!("Invalid rag_recency_half_life_days, it must be positive");
+    }
+    if config.rag_ef_search < config.rag_top_k {
+        bail!("Invalid rag_ef_search, it must not be less than rag_top_k");
+    }
+    Ok(())
+}
+
+/// Favors the documents of recently modified files in rank fusion.
+#[derive(Debug, Clone, Copy)]
+struct RecencyBoost {
+    weight: f32,
+    half_life_days: f32,
+    now: u64,
+}
+
+impl RecencyBoost {
+    /// `1 + weight * 0.5^(age / half_life_days)`, or exactly 1 without a weight or an mtime.
+    fn factor(&self, mtime: u64) -> f32 {
+        if self.weight == 0.0 || mtime == 0 {
+            return 1.0;
+        }
+        let age_days = self.now.saturating_sub(mtime) as f32 / 86400.0;
+        1.0 + self.weight * 0.5f32.powf(age_days / self.half_life_days)
+    }
+}
+
+/// An indexed file, a file without chunks hinting at a loader problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RagFileInfo {
+    pub path: String,
+    pub chunk_count: usize,
+    /// Characters of the chunks, without their metadata header.
+    pub total_chars: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub path: String,
+    pub chunk_index: usize,
+    /// The best score among the vector search hits, if found by it.
+    pub score_vector: Option<f32>,
+    /// The BM25 score, if found by the keyword search.
+    pub score_text: Option<f32>,
+    /// The fused, reranked or MMR score the hits are ordered by.
+    #[serde(rename = "score_fused")]
+    pub score: f32,
+    pub content: String,
+    /// The rag the hit comes from, when several are searched at once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rag: Option<String>,
+}
+
+impl SearchHit {
+    /// Render with `__SOURCE__`, `__CHUNK_INDEX__`, `__SCORE__` and `__CONTENT__` placeholders.
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("__SOURCE__", &self.path)
+            .replace("__CHUNK_INDEX__", &self.chunk_index.to_string())
+            .replace("__SCORE__", &format!("{:.4}", self.score))
+            .replace("__CONTENT__", &self.content)
+    }
+}
+
+/// The rendered hits of a search, fitted to the input budget of the chat model.
+#[derive(Debug, Clone, Default)]
+pub struct RagContext {
+    pub text: String,
+    /// Hits included in `text`, the last of which may be truncated.
+    pub used: usize,
+    pub retrieved: usize,
+}
+
+impl RagContext {
+    /// Whether no hit passed the minimum scores, so the rag template is better skipped.
+    pub fn is_empty(&self) -> bool {
+        self.retrieved == 0
+    }
+}
+
+pub type FileId = usize;
+pub type DocumentId = u64;
+
+/// Pack a file index and a document index into 32 bits each, the same on every target.
+pub fn combine_document_id(file_index: usize, document_index: usize) -> Result<DocumentId> {
+    let (Ok(file), Ok(document)) = (u32::try_from(file_index), u32::try_from(document_index))
+    else {
+        bail!("Document id overflows at file {file_index}, chunk {document_index}");
+    };
+    Ok(pack_document_id(file as usize, document as usize))
+}
+
+/// Pack the id of a stored document, whose indexes `RagData::add` has checked.
+fn pack_document_id(file_index: usize, document_index: usize) -> DocumentId {
+    (file_index as u64) << 32 | document_index as u64
+}
+
+pub fn split_document_id(value: DocumentId) -> (usize, usize) {
+    ((value >> 32) as usize, (value & u32::MAX as u64) as usize)
+}
+
+/// Settles what `Rag::init` can't take from the config when creating a rag.
+pub trait RagConfigResolver {
+    /// Pick the embedding model, `configured` being `rag_embedding_model`.
+    fn embedding_model(&self, configured: Option<String>, models: &[&Model]) -> Result<String>;
+
+    /// Pick the chunk size, `configured` being `rag_chunk_size`.
+    fn chunk_size(
+        &self,
+        configured: Option<usize>,
+        model: &Model,
+        unit: ChunkSizeUnit,
+    ) -> Result<usize>;
+
+    /// Pick the chunk overlap, `configured` being `rag_chunk_overlap`.
+    fn chunk_overlap(&self, configured: Option<usize>, chunk_size: usize) -> Result<usize>;
+
+    /// The documents to load when none were given.
+    fn document_paths(&self) -> Result<Vec<String>>;
+
+    /// Whether to resume from the checkpoint of an interrupted run.
+    fn resume_checkpoint(&self) -> Result<bool>;
+
+    fn report(&self, message: &str);
+}
+
+/// Prompts for whatever isn't configured, or takes the defaults when stdout isn't a terminal.
+pub struct TerminalRagConfigResolver;
+
+impl RagConfigResolver for TerminalRagConfigResolver {
+    fn embedding_model(&self, configured: Option<String>, models: &[&Model]) -> Result<String> {
+        if let Some(value) = configured {
+            println!("Select embedding model: {value}");
+            return Ok(value);
+        }
+        if models.is_empty() {
+            bail!("No available embedding model");
+        }
+        if *IS_STDOUT_TERMINAL {
+            select_embedding_model(models)
+        } else {
+            let value = models[0].id();
+            println!("Select embedding model: {value}");
+            Ok(value)
+        }
+    }
+
+    fn chunk_size(
+        &self,
+        configured: Option<usize>,
+        model: &Model,
+        unit: ChunkSizeUnit,
+    ) -> Result<usize> {
+        let unit_name = unit.as_str();
+        if let Some(value) = configured {
+            println!("Set chunk size: {value} {unit_name}");
+            return Ok(value);
+        }
+        if *IS_STDOUT_TERMINAL {
+            set_chunk_size(model, unit)
+        } else {
+            let value = model.default_chunk_size();
+            println!("Set chunk size: {value} {unit_name}");
+            Ok(value)
+        }
+    }
+
+    fn chunk_overlap(&self, configured: Option<usize>, chunk_size: usize) -> Result<usize> {
+        if let Some(value) = configured {
+            println!("Set chunk overlay: {value}");
+            return Ok(value);
+        }
+        let value = chunk_size / 20;
+        if *IS_STDOUT_TERMINAL {
+            set_chunk_overlay(value)
+        } else {
+            println!("Set chunk overlay: {value}");
+            Ok(value)
+        }
+    }
+
+    fn document_paths(&self) -> Result<Vec<String>> {
+        add_documents()
+    }
+
+    fn resume_checkpoint(&self) -> Result<bool> {
+        if !*IS_STDOUT_TERMINAL {
+            return Ok(true);
+        }
+        let ans = Confirm::new("Resume from the checkpoint of an interrupted run?")
+            .with_default(true)
+            .prompt()?;
+        Ok(ans)
+    }
+
+    fn report(&self, message: &str) {
+        println!("{message}");
+    }
+}
+
+/// Everything `Rag::build` needs to create a rag without asking. Settings left out fall back to
+/// the config, then to the defaults `init` would offer.
+#[derive(Default)]
+pub struct RagBuildOptions {
+    pub name: String,
+    pub save_path: PathBuf,
+    pub document_paths: Vec<String>,
+    pub embedding_model: Option<String>,
+    pub chunk_size: Option<usize>,
+    pub chunk_overlap: Option<usize>,
+    /// Resume from the checkpoint of an interrupted run instead of discarding it.
+    pub resume: bool,
+    /// Embed with this client instead of one for the configured embedding model.
+    pub embedding_client: Option<Box<dyn Client>>,
+}
+
+impl RagConfigResolver for RagBuildOptions {
+    fn embedding_model(&self, configured: Option<String>, models: &[&Model]) -> Result<String> {
+        match self.embedding_model.clone().or(configured) {
+            Some(value) => Ok(value),
+            None => match models.first() {
+                Some(model) => Ok(model.id()),
+                None => bail!("No available embedding model"),
+            },
+        }
+    }
+
+    fn chunk_size(
+        &self,
+        configured: Option<usize>,
+        model: &Model,
+        _unit: ChunkSizeUnit,
+    ) -> Result<usize> {
+        Ok(self
+            .chunk_size
+            .or(configured)
+            .unwrap_or_else(|| model.default_chunk_size()))
+    }
+
+    fn chunk_overlap(&self, configured: Option<usize>, chunk_size: usize) -> Result<usize> {
+        Ok(self.chunk_overlap.or(configured).unwrap_or(chunk_size / 20))
+    }
+
+    fn document_paths(&self) -> Result<Vec<String>> {
+        bail!("No document paths to build rag '{}' from", self.name)
+    }
+
+    fn resume_checkpoint(&self) -> Result<bool> {
+        Ok(self.resume)
+    }
+
+    fn report(&self, message: &str) {
+        debug!("{message}");
+    }
+}
+
+fn select_embedding_model(models: &[&Model]) -> Result<String> {
+    let models: Vec<_> = models
+        .iter()
+        .map(|v| SelectOption::new(v.id(), v.description()))
+        .collect();
+    let result = Select::new("Select embedding model:", models).prompt()?;
+    Ok(result.value)
+}
+
+fn set_chunk_size(model: &Model, unit: ChunkSizeUnit) -> Result<usize> {
+    let mut default_value = model.default_chunk_size();
+    let max_input_tokens = model.max_input_tokens();
+    let help_message = max_input_tokens.map(|v| format!("The model's max_input_token is {v}"));
+
+    let prompt = match (unit, max_input_tokens) {
+        (ChunkSizeUnit::Tokens, Some(max)) => {
+            default_value = default_value.min(max);
+            format!("Set chunk size (tokens, at most {max}):")
+        }
+        _ => format!("Set chunk size ({}):", unit.as_str()),
+    };
+    let default_value = default_value.to_string();
+    let mut text = Text::new(&prompt)
+        .with_default(&default_value)
+        .with_validator(move |text: &str| {
+            let out = match text.parse::<usize>() {
+                Ok(value) => match (unit, max_input_tokens) {
+                    (ChunkSizeUnit::Tokens, Some(max)) if value > max => {
+                        Validation::Invalid(format!("Must not exceed {max} tokens").into())
+                    }
+                    _ => Validation::Valid,
+                },
+                Err(_) => Validation::Invalid("Must be a integer".into()),
+            };
+            Ok(out)
+        });
+    if let Some(help_message) = &help_message {
+        text = text.with_help_message(help_message);
+    }
+    let value = text.prompt()?;
+    value.parse().map_err(|_| anyhow!("Invalid chunk_size"))
+}
+
+fn set_chunk_overlay(default_value: usize) -> Result<usize> {
+    let value = Text::new("Set chunk overlay:")
+        .with_default(&default_value.to_string())
+        .with_validator(move |text: &str| {
+            let out = match text.parse::<usize>() {
+                Ok(_) => Validation::Valid,
+                Err(_) => Validation::Invalid("Must be a integer".into()),
+            };
+            Ok(out)
+        })
+        .prompt()?;
+    value.parse().map_err(|_| anyhow!("Invalid chunk_overlay"))
+}
+
+/// Where an unfinished rag is checkpointed until it is saved to `path`.
+fn partial_path(path: &Path) -> PathBuf {
+    path.with_extension("partial")
+}
+
+/// A rag saved in the rags directory, or the temp rag, which only lives in memory.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SavedRag {
+    pub name: String,
+    /// `None` for the temp rag.
+    pub size: Option<u64>,
+    /// `None` if the rag can't be decoded.
+    pub embedding_model: Option<String>,
+    pub num_files: Option<usize>,
+    /// Seconds since the epoch, `None` for the temp rag.
+    pub modified: Option<u64>,
+    pub temporary: bool,
+}
+
+/// The rags saved in `dir` followed by `temp_rag`, if any.
+pub fn list_saved_rags(dir: &Path, temp_rag: Option<&Rag>) -> Result<Vec<SavedRag>> {
+    let mut output = read_saved_rags(dir)?;
+    if let Some(rag) = temp_rag {
+        output.retain(|v| !v.temporary);
+        output.push(SavedRag {
+            name: rag.name.clone(),
+            size: None,
+            embedding_model: Some(rag.data.embedding_model.clone()),
+            num_files: Some(rag.data.files.len()),
+            modified: None,
+            temporary: true,
+        });
+    }
+    Ok(output)
+}
+
+/// The rags saved in `dir`, sorted by name.
+fn read_saved_rags(dir: &Path) -> Result<Vec<SavedRag>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut output = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .and_then(|v| v.strip_suffix(RAG_FILE_SUFFIX))
+        else {
+            continue;
+        };
+        let metadata = std::fs::metadata(&path)?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let data = std::fs::read(&path)
+            .ok()
+            .and_then(|v| RagData::decode(&v).ok());
+        output.push(SavedRag {
+            name: name.to_string(),
+            size: Some(metadata.len()),
+            embedding_model: data.as_ref().map(|v| v.embedding_model.clone()),
+            num_files: data.as_ref().map(|v| v.files.len()),
+            modified: Some(file_mtime(&path.display().to_string())),
+            temporary: name == TEMP_RAG_NAME,
+        });
+    }
+    output.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(output)
+}
+
+/// Delete the rag saved in `dir` as `name`, along with its HNSW graph and what interrupted runs
+/// left behind.
+pub fn delete_saved_rag(dir: &Path, name: &str) -> Result<()> {
+    let path = saved_rag_path(dir, name)?;
+    for path in saved_rag_paths(&path) {
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).with_context(|| format!("Failed to delete '{}'", path.display()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Rename the rag saved in `dir` as `name`, moving its HNSW graph along.
+pub fn rename_saved_rag(dir: &Path, name: &str, new_name: &str) -> Result<()> {
+    check_rag_name(new_name)?;
+    if new_name == TEMP_RAG_NAME {
+        bail!("The rag name '{TEMP_RAG_NAME}' is reserved");
+    }
+    let path = saved_rag_path(dir, name)?;
+    let new_path = rag_file_path(dir, new_name);
+    if new_path.exists() {
+        bail!("Rag '{new_name}' already exists");
+    }
+    for (from, to) in saved_rag_paths(&path)
+        .into_iter()
+        .zip(saved_rag_paths(&new_path))
+    {
+        if from.exists() {
+            std::fs::rename(&from, &to)
+                .with_context(|| format!("Failed to rename '{}'", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Where the rag named `name` is saved in `dir`, whether it exists or not.
+pub fn rag_file_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{name}{RAG_FILE_SUFFIX}"))
+}
+
+/// The file of the rag saved in `dir` as `name`.
+pub fn saved_rag_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    check_rag_name(name)?;
+    let path = rag_file_path(dir, name);
+    if !path.is_file() {
+        bail!("Unknown rag '{name}'");
+    }
+    Ok(path)
+}
+
+/// Reject names that would point outside the rags directory.
+fn check_rag_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("Invalid rag name '{name}'");
+    }
+    Ok(())
+}
+
+/// The rag file at `path` followed by every file kept next to it.
+fn saved_rag_paths(path: &Path) -> Vec<PathBuf> {
+    let mut output = vec![
+        path.to_path_buf(),
+        path.with_extension("tmp"),
+        partial_path(path),
+    ];
+    output.extend(hnsw_paths(path));
+    output
+}
+
+fn add_documents() -> Result<Vec<String>> {
+    let text = Text::new("Add documents:")
+        .with_validator(required!("This field is required"))
+        .with_help_message(
+            "e.g. file;dir/;dir/**/*.{md,mdx};!dir/generated/;!node_modules;!**/target/**;solo-url;site-url/**;!site-url/blog/**;site-url/sitemap.xml",
+        )
+        .prompt()?;
+    let paths = text
+        .split(';')
+        .filter_map(|v| {
+            let v = v.trim().to_string();
+            if v.is_empty() {
+                None
+            } else {
+                Some(v)
+            }
+        })
+        .collect();
+    Ok(paths)
+}
+
+fn match_path(pattern: &str, path: &str) -> bool {
+    if Rag::is_url_path(pattern) {
+        match pattern.strip_suffix("**") {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == pattern,
+        }
+    } else {
+        path_matches_glob(pattern, path)
+    }
+}
+
+/// The modification time in nanoseconds since the epoch and the size of a file, or zeros.
+fn file_stamp(path: &str) -> (u64, u64) {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return (0, 0);
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+        .and_then(|v| u64::try_from(v.as_nanos()).ok())
+        .unwrap_or_default();
+    (mtime, metadata.len())
+}
+
+fn file_mtime(path: &str) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|v| v.modified())
+        .ok()
+        .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+/// Check that all `dimensions` equal `dimension`, or the first one if it is `None`.
+fn check_dimension(
+    dimension: Option<usize>,
+    dimensions: impl IntoIterator<Item = usize>,
+) -> Result<Option<usize>> {
+    let mut dimension = dimension;
+    for value in dimensions {
+        match dimension {
+            Some(expect) if expect != value => {
+                bail!("Expected {expect}-dim embedding, got {value}")
+            }
+            Some(_) => {}
+            None => dimension = Some(value),
+        }
+    }
+    Ok(dimension)
+}
+
+/// The text of a chunk without the metadata header naming its file.
+fn chunk_content(page_content: &str) -> &str {
+    match page_content.split_once("</document_metadata>\n\n") {
+        Some((header, content)) if header.starts_with("<document_metadata>") => content,
+        _ => page_content,
+    }
+}
+
+/// Ask the active chat model for up to `count` paraphrases of `query`, none without a chat client.
+async fn expand_query(
+    config: &GlobalConfig,
+    query: &str,
+    count: usize,
+    abort_signal: AbortSignal,
+) -> Result<Vec<String>> {
+    let prompt = format!(
+        "Rewrite the user's search query in {count} different ways that could match relevant documents. \
+Reply with one rewrite per line and nothing else."
+    );
+    let mut role = Role::new(TEMP_ROLE_NAME, &prompt);
+    role.set_model(config.read().extract_role().model());
+    let input = Input::from_str(config, query, Some(role));
+    let client = match input.create_client() {
+        Ok(client) => client,
+        Err(err) => {
+            debug!("skip query expansion: {err}");
+            return Ok(vec![]);
+        }
+    };
+    let output = client.chat_completions(input, abort_signal).await?;
+    Ok(parse_paraphrases(&output.text, query, count))
+}
+
+/// Take one paraphrase per line, stripping list markers and quotes and skipping repeats.
+fn parse_paraphrases(text: &str, query: &str, count: usize) -> Vec<String> {
+    let mut seen = HashSet::from([query.trim().to_lowercase()]);
+    text.lines()
+        .map(|line| {
+            let line = line.trim();
+            let line = LIST_MARKER_RE.replace(line, "");
+            line.trim_matches(['"', '\'']).trim().to_string()
+        })
+        .filter(|line| !line.is_empty() && seen.insert(line.to_lowercase()))
+        .take(count)
+        .collect()
+}
+
+/// The paths of a filesystem event that match one of the indexed `patterns`.
+fn changed_paths(event: notify::Result<notify::Event>, patterns: &[String]) -> Vec<String> {
+    let event = match event {
+        Ok(event) if !event.kind.is_access() => event,
+        Ok(_) => return vec![],
+        Err(err) => {
+            debug!("watch error: {err}");
+            return vec![];
+        }
+    };
+    event
+        .paths
+        .into_iter()
+        .map(|path| path.display().to_string())
+        .filter(|path| patterns.iter().any(|v| path_matches_glob(v, path)))
+        .collect()
+}
+
+/// Split a loaded file into chunks headed by its metadata, with `path` and `extension` already
+/// taken out of its metadata.
+fn split_loaded_file(
+    path: &str,
+    extension: &str,
+    file: LoadedFile,
+    (chunk_size, chunk_overlap): (usize, usize),
+    size_unit: ChunkSizeUnit,
+) -> Vec<RagDocument> {
+    let LoadedFile {
+        contents,
+        metadata,
+        sections,
+    } = file;
+    let metadata_entries = metadata.clone();
+    let metadata = metadata
+        .iter()
+        .map(|(k, v)| format!("{k}: {v}\n"))
+        .collect::<Vec<String>>()
+        .join("");
+    let sections = if sections.is_empty() {
+        vec![RagDocument::new(contents)]
+    } else {
+        sections
+    };
+    let mut split_documents = vec![];
+    for mut section in sections {
+        // A section can be split differently from its file, e.g. the cells of a notebook.
+        let extension = section
+            .metadata
+            .swap_remove(EXTENSION_METADATA)
+            .unwrap_or_else(|| extension.to_string());
+        let mut document = RagDocument::new(section.page_content);
+        document.metadata = metadata_entries.clone();
+        let mut metadata = metadata.clone();
+        for (key, value) in section.metadata {
+            metadata.push_str(&format!("{key}: {value}\n"));
+            document.metadata.insert(key, value);
+        }
+        let split_options = SplitterChunkHeaderOptions::default().with_chunk_header(&format!(
+            "<document_metadata>\npath: {path}\n{metadata}</document_metadata>\n\n"
+        ));
+        split_documents.extend(split_by_extension(
+            &extension,
+            chunk_size,
+            chunk_overlap,
+            size_unit,
+            &[document],
+            &split_options,
+        ));
+    }
+    for (index, document) in split_documents.iter_mut().enumerate() {
+        document
+            .metadata
+            .insert(SOURCE_METADATA.into(), path.to_string());
+        document
+            .metadata
+            .insert(CHUNK_INDEX_METADATA.into(), index.to_string());
+    }
+    split_documents
+}
+
+/// `42310` as `42,310`.
+fn format_count(value: usize) -> String {
+    let digits = value.to_string();
+    let mut output = String::new();
+    for (index, c) in digits.chars().enumerate() {
+        if index > 0 && index % 3 == digits.len() % 3 {
+            output.push(',');
+        }
+        output.push(c);
+    }
+    output
+}
+
+/// `8_100_000` as `8.1M`.
+fn format_tokens(value: usize) -> String {
+    match value {
+        0..=999 => value.to_string(),
+        1_000..=999_999 => format!("{:.1}K", value as f64 / 1_000.0),
+        _ => format!("{:.1}M", value as f64 / 1_000_000.0),
+    }
+}
+
+fn progress(spinner: &Option<Spinner>, message: String) {
+    if let Some(spinner) = spinner {
+        let _ = spinner.set_message(message);
+    }
+}
+
+/// Render `hits` in rank order until `max_tokens` is used up. The first hit that doesn't fit is
+/// cut at a sentence boundary if some of it fits, and the rest are dropped.
+fn render_hits(hits: &[SearchHit], template: &str, max_tokens: Option<usize>) -> Vec<String> {
+    let Some(mut remaining) = max_tokens else {
+        return hits.iter().map(|hit| hit.render(template)).collect();
+    };
+    let mut output = vec![];
+    for hit in hits {
+        let chunk = hit.render(template);
+        let tokens = estimate_token_length(&chunk);
+        if tokens <= remaining {
+            remaining -= tokens;
+            output.push(chunk);
+            continue;
+        }
+        let mut truncated = None;
+        for end in sentence_ends(&hit.content) {
+            let hit = SearchHit {
+                content: hit.content[..end].to_string(),
+                ..hit.clone()
+            };
+            let chunk = hit.render(template);
+            if estimate_token_length(&chunk) > remaining {
+                break;
+            }
+            truncated = Some(chunk);
+        }
+        output.extend(truncated);
+        break;
+    }
+    output
+}
+
+/// Append the next chunk of a file to `content`, leaving out the text both chunks overlap on.
+fn append_chunk(content: &mut String, chunk: &str) {
+    let overlap = (MIN_CHUNK_OVERLAP..=chunk.len().min(content.len()))
+        .rev()
+        .find(|end| chunk.is_char_boundary(*end) && content.ends_with(&chunk[..*end]));
+    match overlap {
+        Some(end) => content.push_str(&chunk[end..]),
+        None => {
+            content.push_str("\n\n");
+            content.push_str(chunk);
+        }
+    }
+}
+
+/// Byte offsets just after each sentence of `text`, including the last one.
+fn sentence_ends(text: &str) -> Vec<usize> {
+    let mut output = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let end = index + c.len_utf8();
+        let is_end = match c {
+            '.' | '!' | '?' => match chars.peek() {
+                Some((_, next)) => next.is_whitespace(),
+                None => true,
+            },
+            '。' | '！' | '？' | '\n' => true,
+            _ => false,
+        };
+        if is_end {
+            output.push(end);
+        }
+    }
+    if output.last() != Some(&text.len()) {
+        output.push(text.len());
+    }
+    output
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// The element-wise mean of `vectors`, used to represent a query split into several chunks.
+fn mean_vector(vectors: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let first = vectors.first()?;
+    let mut output = vec![0.0; first.len()];
+    for vector in vectors {
+        for (sum, v) in output.iter_mut().zip(vector) {
+            *sum += v;
+        }
+    }
+    let len = vectors.len() as f32;
+    Some(output.into_iter().map(|v| v / len).collect())
+}
+
+/// The highest score of each document, as one may be hit by several query chunks.
+fn best_scores(hits: &[(DocumentId, f32)]) -> HashMap<DocumentId, f32> {
+    let mut scores: HashMap<DocumentId, f32> = HashMap::new();
+    for &(id, score) in hits {
+        let entry = scores.entry(id).or_insert(score);
+        *entry = entry.max(score);
+    }
+    scores
+}
+
+/// Fuse the hits of several rags by reciprocal rank fusion, tagging each hit with its rag.
+///
+/// The scores of different rags are not comparable: they may come from different embedding
+/// models, BM25 statistics of different corpora or a reranker, so only the rank of a hit within
+/// its rag counts. The fused score of a hit is `1 / (rrf_k + rank)`, summed when rags share a
+/// chunk with the same content, so the top hits of every rag are interleaved. Ties keep the
+/// order of the rags, and at most `top_k` hits are kept overall, not per rag.
+fn fuse_rag_hits(
+    list_of_hits: Vec<(String, Vec<SearchHit>)>,
+    rrf_k: usize,
+    top_k: usize,
+) -> Vec<SearchHit> {
+    let mut map: IndexMap<String, SearchHit> = IndexMap::new();
+    for (name, hits) in list_of_hits {
+        for (index, hit) in hits.into_iter().enumerate() {
+            let score = 1.0 / ((rrf_k + index + 1) as f32);
+            match map.get_mut(&hit.content) {
+                Some(existing) => existing.score += score,
+                None => {
+                    let hit = SearchHit {
+                        score,
+                        rag: Some(name.clone()),
+                        ..hit
+                    };
+                    map.insert(hit.content.clone(), hit);
+                }
+            }
+        }
+    }
+    let mut hits: Vec<SearchHit> = map.into_values().collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(top_k);
+    hits
+}
+
+/// Fuse ranked lists, multiplying each fused score by `recency_factor` of its document.
+fn reciprocal_rank_fusion(
+    list_of_document_ids: Vec<Vec<DocumentId>>,
+    list_of_weights: Vec<f32>,
+    rrf_k: usize,
+    top_k: usize,
+    recency_factor: impl Fn(DocumentId) -> f32,
+) -> Vec<(DocumentId, f32)> {
+    let mut map: IndexMap<DocumentId, f32> = IndexMap::new();
+    for (document_ids, weight) in list_of_document_ids
+        .into_iter()
+        .zip(list_of_weights.into_iter())
+    {
+        if weight <= 0.0 {
+            continue;
+        }
+        for (index, &item) in document_ids.iter().enumerate() {
+            *map.entry(item).or_default() += (1.0 / ((rrf_k + index + 1) as f32)) * weight;
+        }
+    }
+    for (id, score) in map.iter_mut() {
+        *score *= recency_factor(*id);
+    }
+    let mut sorted_items: Vec<(DocumentId, f32)> = map.into_iter().collect();
+    sorted_items.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+    sorted_items.into_iter().take(top_k).collect()
+}
+
+/// Scale `vector` into int8, returning the scale that maps the values back.
+fn quantize(vector: &[f32]) -> (f32, Vec<i8>) {
+    let max = vector.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+    let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+    let values = vector
+        .iter()
+        .map(|v| (v / scale).round().clamp(-127.0, 127.0) as i8)
+        .collect();
+    (scale, values)
+}
+
+fn dequantize(scale: f32, values: &[i8]) -> Vec<f32> {
+    values.iter().map(|v| *v as f32 * scale).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &RagData) -> Vec<u8> {
+        let mut content = vec![];
+        data.encode(&mut content).unwrap();
+        content
+    }
+
+    /// Deterministic vectors in [-0.25, 0.25), from a linear congruential generator.
+    fn random_vectors(seed: u64, count: usize, dimension: usize) -> Vec<Vec<f32>> {
+        let mut seed = seed;
+        let mut random = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((seed >> 33) as f32 / u32::MAX as f32) - 0.25
+        };
+        (0..count)
+            .map(|_| (0..dimension).map(|_| random()).collect())
+            .collect()
+    }
+
+    fn build_data() -> RagData {
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        let file = RagFile {
+            hash: sha256("foo bar"),
+            path: "/tmp/foo.md".into(),
+            mtime: 1,
+            size: 0,
+            documents: vec![RagDocument::new("foo"), RagDocument::new("bar")],
+        };
+        data.add(
+            1,
+            vec![(0, file)],
+            vec![pack_document_id(0, 0), pack_document_id(0, 1)],
+            vec![vec![0.5, -1.25], vec![3.0, 0.0]],
+        )
+        .unwrap();
+        data
+    }
+
+    /// A rag with one file per path, holding a chunk per text. Each chunk is embedded in its own
+    /// direction, as graphs return ties between vectors pointing the same way in any order, and
+    /// the graph has a single layer, as tiny graphs with more can miss some points.
+    fn rag_data_from(paths: &[(&str, &[&str])]) -> RagData {
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        data.hnsw_max_layers = 1;
+        let mut files = vec![];
+        let mut document_ids = vec![];
+        let mut embeddings = vec![];
+        for (file_id, (path, texts)) in paths.iter().enumerate() {
+            let file = RagFile {
+                hash: sha256(&texts.join("\n")),
+                path: path.to_string(),
+                mtime: 1,
+                size: texts.iter().map(|v| v.len() as u64).sum(),
+                documents: texts.iter().map(|v| RagDocument::new(*v)).collect(),
+            };
+            files.push((file_id, file));
+            for index in 0..texts.len() {
+                document_ids.push(pack_document_id(file_id, index));
+                embeddings.push(vec![1.0 + index as f32, file_id as f32 * 3.0 + 0.5]);
+            }
+        }
+        data.add(paths.len(), files, document_ids, embeddings)
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_rag_data_remove_paths() {
+        let mut data = rag_data_from(&[
+            ("/tmp/0.md", &["apple pie"]),
+            ("/tmp/1.md", &["banana bread"]),
+            ("/tmp/2.md", &["cherry tart"]),
+        ]);
+        data.document_paths = vec!["/tmp/0.md".into(), "/tmp/1.md".into(), "/tmp/2.md".into()];
+        let mut rag = build_rag(data.clone());
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+
+        let removed = data.remove_paths(&["/tmp/1.md".into(), "/tmp/9.md".into()]);
+        assert_eq!(removed, vec!["/tmp/1.md".to_string()]);
+        assert!(data.remove_paths(&["/tmp/**/*.txt".into()]).is_empty());
+        assert_eq!(data.document_paths, vec!["/tmp/0.md", "/tmp/2.md"]);
+        assert_eq!(data.vectors.len(), 2);
+
+        assert!(data.get(pack_document_id(1, 0)).is_none());
+
+        let bm25 = data.build_bm25();
+        let ids: Vec<_> = bm25
+            .search("banana bread", 4, None)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert!(!ids.contains(&pack_document_id(1, 0)));
+        assert_eq!(
+            data.get(pack_document_id(2, 0)).unwrap().page_content,
+            "cherry tart"
+        );
+
+        let removed = rag.remove_paths(&["/tmp/1.md".into()]).await.unwrap();
+        assert_eq!(removed, vec!["/tmp/1.md".to_string()]);
+        // The removed vector was the closest one to this query.
+        let neighbours = rag.hnsw.search_filter(&[1.0, 3.5], 4, 30, None);
+        let ids: Vec<_> = neighbours
+            .iter()
+            .map(|v| *rag.data.vectors.get_index(v.d_id).unwrap().0)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&pack_document_id(1, 0)));
+        let (hits, _) = rag
+            .vector_search("banana bread", &[], 4, 30, f32::MIN, None)
+            .await
+            .unwrap();
+        let ids: Vec<_> = hits.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&pack_document_id(1, 0)));
+    }
+
+    #[test]
+    fn test_rag_data_dimension_mismatch() {
+        let mut data = build_data();
+        assert_eq!(data.dimension, Some(2));
+        let file = RagFile {
+            hash: sha256("baz"),
+            path: "/tmp/baz.md".into(),
+            mtime: 1,
+            size: 0,
+            documents: vec![RagDocument::new("baz")],
+        };
+        let err = data
+            .add(
+                2,
+                vec![(1, file)],
+                vec![pack_document_id(1, 0)],
+                vec![vec![1.0, 2.0, 3.0]],
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Expected 2-dim embedding, got 3");
+        assert_eq!(data.files.len(), 1);
+
+        data.vectors
+            .insert(pack_document_id(1, 0), vec![1.0].into());
+        let err = data.build_hnsw().err().unwrap();
+        assert_eq!(err.to_string(), "Expected 2-dim embedding, got 1");
+    }
+
+    #[test]
+    fn test_rag_data_filter_ids() {
+        let data = rag_data_from(&[
+            (
+                "/docs/api/auth.md",
+                &["install the client", "install the client, again"],
+            ),
+            (
+                "/docs/api/users.md",
+                &["install the client", "install the client, again"],
+            ),
+            (
+                "/docs/guide/setup.md",
+                &["install the client", "install the client, again"],
+            ),
+        ]);
+
+        let filter = MetadataFilter::PathGlob("/docs/api/**/*.md".into());
+        let allowed_ids = data.filter_ids(&filter);
+        assert_eq!(allowed_ids.len(), 4);
+        let bm25 = data.build_bm25();
+        let ids = bm25.search_with("install client", 6, None, |id| {
+            allowed_ids.binary_search(id).is_ok()
+        });
+        assert_eq!(ids.len(), 4);
+        for (id, _) in ids {
+            assert!(data.hit(id, 0.0).unwrap().path.starts_with("/docs/api/"));
+        }
+
+        let filter = MetadataFilter::Equals("chunk_index".into(), "1".into());
+        assert_eq!(data.filter_ids(&filter).len(), 3);
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let vector_ids = vec![1, 2, 3];
+        let keyword_ids = vec![3, 4, 1];
+        let ids = |weights: Vec<f32>| -> Vec<DocumentId> {
+            reciprocal_rank_fusion(
+                vec![vector_ids.clone(), keyword_ids.clone()],
+                weights,
+                60,
+                3,
+                |_| 1.0,
+            )
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+        };
+        assert_eq!(ids(vec![1.0, 1.0]), vec![1, 3, 2]);
+        assert_eq!(ids(vec![0.2, 1.0]), vec![3, 1, 4]);
+        assert_eq!(ids(vec![1.0, 0.0]), vector_ids);
+        assert_eq!(ids(vec![0.0, 1.0]), keyword_ids);
+
+        // Ranked first by one search each, so tied until the newer document is boosted.
+        let now = 100 * 86400;
+        let mtimes = HashMap::from([(1, now - 30 * 86400), (2, now - 86400)]);
+        let fuse = |weight: f32| {
+            let recency = RecencyBoost {
+                weight,
+                half_life_days: 30.0,
+                now,
+            };
+            reciprocal_rank_fusion(vec![vec![1], vec![2]], vec![1.0, 1.0], 60, 2, |id| {
+                recency.factor(mtimes[&id])
+            })
+        };
+        let fused = fuse(0.0);
+        assert_eq!(fused[0].1, fused[1].1);
+        assert_eq!(
+            fused,
+            reciprocal_rank_fusion(vec![vec![1], vec![2]], vec![1.0, 1.0], 60, 2, |_| 1.0)
+        );
+        let fused = fuse(0.5);
+        assert_eq!(fused[0].0, 2);
+        assert!(fused[0].1 > fused[1].1);
+        assert!((fused[1].1 / (1.0 / 61.0) - 1.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_fuse_rag_hits() {
+        let hit = |content: &str, score: f32| SearchHit {
+            path: format!("/tmp/{content}.md"),
+            chunk_index: 0,
+            score_vector: None,
+            score_text: Some(score),
+            score,
+            content: content.into(),
+            rag: None,
+        };
+        // BM25 scores of a large corpus dwarf those of a small one, only ranks are compared.
+        let docs = vec![hit("a", 120.0), hit("b", 80.0), hit("shared", 40.0)];
+        let notes = vec![hit("x", 0.3), hit("shared", 0.2), hit("y", 0.1)];
+        let hits = fuse_rag_hits(vec![("docs".into(), docs), ("notes".into(), notes)], 60, 4);
+        let output: Vec<(&str, &str)> = hits
+            .iter()
+            .map(|v| (v.rag.as_deref().unwrap(), v.content.as_str()))
+            .collect();
+        assert_eq!(
+            output,
+            [
+                ("docs", "shared"),
+                ("docs", "a"),
+                ("notes", "x"),
+                ("docs", "b")
+            ]
+        );
+        assert_eq!(hits[1].score, 1.0 / 61.0);
+        assert_eq!(hits[0].score, 1.0 / 63.0 + 1.0 / 62.0);
+
+        // Each rag fills `top_k` on its own, the merged hits alternate between them.
+        let docs = vec![hit("a", 0.9), hit("b", 0.8), hit("c", 0.7)];
+        let notes = vec![hit("x", 12.0), hit("y", 11.0), hit("z", 10.0)];
+        let hits = fuse_rag_hits(vec![("docs".into(), docs), ("notes".into(), notes)], 60, 3);
+        let output: Vec<&str> = hits.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(output, ["a", "x", "b"]);
+    }
+
+    #[test]
+    fn test_parse_paraphrases() {
+        let text = "1. How do I install rust?\n\"Install Rust\"\n\n2) install rust\nrust setup guide\nmore";
+        assert_eq!(
+            parse_paraphrases(text, "Install rust", 3),
+            vec!["How do I install rust?", "rust setup guide", "more"]
+        );
+        // Only numbered list markers are stripped, not leading numbers or symbols.
+        let text = "10) 2024 roadmap\n-rust flags\n*args in rust";
+        assert_eq!(
+            parse_paraphrases(text, "roadmap", 3),
+            vec!["2024 roadmap", "-rust flags", "*args in rust"]
+        );
+        assert!(parse_paraphrases("", "rust", 3).is_empty());
+    }
+
+    #[test]
+    fn test_neighbor_contents() {
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        let file = |path: &str, texts: &[&str]| RagFile {
+            hash: sha256(path),
+            path: path.into(),
+            mtime: 0,
+            size: 0,
+            documents: texts.iter().map(|v| RagDocument::new(*v)).collect(),
+        };
+        let texts = [
+            "Alpha one. Shared tail text",
+            "Shared tail text then beta.",
+            "Gamma.",
+            "Delta.",
+            "Epsilon.",
+        ];
+        let files = vec![
+            (0, file("/tmp/a.md", &texts)),
+            (1, file("/tmp/b.md", &["Other."])),
+        ];
+        let ids: Vec<_> = (0..5)
+            .map(|v| pack_document_id(0, v))
+            .chain([pack_document_id(1, 0)])
+            .collect();
+        let embeddings = vec![vec![1.0]; ids.len()];
+        data.add(2, files, ids, embeddings).unwrap();
+
+        let ids = [
+            pack_document_id(0, 1),
+            pack_document_id(0, 3),
+            pack_document_id(1, 0),
+            pack_document_id(0, 0),
+            pack_document_id(7, 0),
+        ];
+        assert_eq!(
+            data.neighbor_contents(&ids, 1),
+            [
+                Some("Alpha one. Shared tail text then beta.\n\nGamma.".into()),
+                Some("Delta.\n\nEpsilon.".into()),
+                Some("Other.".into()),
+                None,
+                None,
+            ]
+        );
+        let contents = data.neighbor_contents(&ids[..2], 0);
+        assert_eq!(contents, [Some(texts[1].into()), Some(texts[3].into())]);
+    }
+
+    #[test]
+    fn test_render_hits_within_budget() {
+        let hits: Vec<SearchHit> = [
+            "First hit. It is short.",
+            "Second hit has two sentences. The second one is longer than the first.",
+            "Third hit.",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(index, content)| SearchHit {
+            path: "docs/guide.md".into(),
+            chunk_index: index,
+            score_vector: None,
+            score_text: None,
+            score: 1.0,
+            content: content.to_string(),
+            rag: None,
+        })
+        .collect();
+        let template = "__CONTENT__";
+        assert_eq!(render_hits(&hits, template, None).len(), 3);
+
+        let first = estimate_token_length(&hits[0].content);
+        let partial = estimate_token_length("Second hit has two sentences.");
+        let chunks = render_hits(&hits, template, Some(first + partial + 2));
+        assert_eq!(
+            chunks,
+            vec![
+                "First hit. It is short.".to_string(),
+                "Second hit has two sentences.".to_string()
+            ]
+        );
+        // Not even the first sentence of the second hit fits.
+        assert_eq!(render_hits(&hits, template, Some(first + 2)).len(), 1);
+        assert!(render_hits(&hits, template, Some(0)).is_empty());
+
+        assert_eq!(sentence_ends("v1.2 is out! 好。Next"), vec![12, 19, 23]);
+    }
+
+    #[test]
+    fn test_maximal_marginal_relevance() {
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        // Three near copies of one section, and two chunks about other things.
+        for (index, vector) in [
+            vec![1.0, 0.0, 0.0],
+            vec![0.99, 0.01, 0.0],
+            vec![0.98, 0.02, 0.0],
+            vec![0.6, 0.8, 0.0],
+            vec![0.6, 0.0, 0.8],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            data.vectors
+                .insert(pack_document_id(0, index), vector.into());
+        }
+        let candidates: Vec<(DocumentId, f32)> = (0..5)
+            .map(|index| (pack_document_id(0, index), 1.0 - index as f32 * 0.1))
+            .collect();
+        let query = [1.0, 0.0, 0.0];
+        let picked = |lambda: f32| -> Vec<usize> {
+            data.maximal_marginal_relevance(candidates.clone(), Some(&query), lambda, 3)
+                .into_iter()
+                .map(|(id, _)| split_document_id(id).1)
+                .collect()
+        };
+        assert_eq!(picked(1.0), vec![0, 1, 2]);
+        assert_eq!(picked(0.3), vec![0, 3, 4]);
+
+        // Without a query embedding, relevance comes from the fused scores.
+        let ids = data.maximal_marginal_relevance(candidates.clone(), None, 0.5, 2);
+        assert_eq!(ids, vec![candidates[0], candidates[3]]);
+    }
+
+    /// Returns `dimension`-sized embeddings, or panics when it is `None`.
+    /// Each call first fails with the next status in `failures`, if any.
+    struct ScriptedEmbeddingsClient {
+        global_config: GlobalConfig,
+        model: Model,
+        dimension: Option<usize>,
+        failures: std::sync::Mutex<Vec<u16>>,
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        retry_after: Option<std::time::Duration>,
+        /// Fail every call from this one on, counting from 1.
+        fail_from: Option<usize>,
+        /// How long each embeddings call takes.
+        latency: Option<std::time::Duration>,
+        in_flight: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        max_in_flight: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl ScriptedEmbeddingsClient {
+        fn new(dimension: Option<usize>) -> Self {
+            Self {
+                global_config: Default::default(),
+                model: Model::default(),
+                dimension,
+                failures: Default::default(),
+                calls: Default::default(),
+                retry_after: None,
+                fail_from: None,
+                latency: None,
+                in_flight: Default::default(),
+                max_in_flight: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Client for ScriptedEmbeddingsClient {
+        fn global_config(&self) -> &GlobalConfig {
+            &self.global_config
+        }
+
+        fn extra_config(&self) -> Option<&ExtraConfig> {
+            None
+        }
+
+        fn patches_config(&self) -> Option<&ModelPatches> {
+            None
+        }
+
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        fn model(&self) -> &Model {
+            &self.model
+        }
+
+        fn model_mut(&mut self) -> &mut Model {
+            &mut self.model
+        }
+
+        async fn embeddings(&self, data: EmbeddingsData) -> Result<Vec<Vec<f32>>> {
+            let call = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            if self.fail_from.is_some_and(|v| call >= v) {
+                bail!("mock failure (call: {call})");
+            }
+            if let Some(status) = self.failures.lock().unwrap().pop() {
+                let message = format!("mock failure (status: {status})");
+                let retry_after = self.retry_after.filter(|_| status == 429);
+                return Err(ClientError::from_status(status, None, message, retry_after).into());
+            }
+            if let Some(latency) = self.latency {
+                use std::sync::atomic::Ordering;
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(latency).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+            let dimension = self.dimension.expect("embeddings must not be called");
+            let output = data
+                .texts
+                .iter()
+                .map(|text| vec![text.len() as f32; dimension])
+                .collect();
+            Ok(output)
+        }
+
+        /// Ranks documents by the density of the query in them.
+        async fn rerank(&self, data: RerankData) -> Result<RerankOutput> {
+            if self.fail_from.is_some() {
+                bail!("mock failure");
+            }
+            let mut output: Vec<_> = data
+                .documents
+                .iter()
+                .enumerate()
+                .map(|(index, document)| RerankResult {
+                    index,
+                    relevance_score: document.matches(&data.query).count() as f64
+                        / document.len() as f64,
+                })
+                .collect();
+            output.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+            Ok(output)
+        }
+
+        async fn chat_completions_inner(
+            &self,
+            _client: &reqwest::Client,
+            _data: ChatCompletionsData,
+        ) -> Result<ChatCompletionsOutput> {
+            unreachable!()
+        }
+
+        async fn chat_completions_streaming_inner(
+            &self,
+            _client: &reqwest::Client,
+            _handler: &mut SseHandler,
+            _data: ChatCompletionsData,
+        ) -> Result<()> {
+            unreachable!()
+        }
+    }
+
+    fn build_rag(data: RagData) -> Rag {
+        Rag {
+            name: "test".into(),
+            path: String::new(),
+            embedding_model: Model::default(),
+            hnsw: data.build_hnsw().unwrap(),
+            bm25: data.build_bm25(),
+            data,
+            embedding_client: Box::new(ScriptedEmbeddingsClient::new(None)),
+            embedding_cache: None,
+            embedding_retries: 0,
+            embedding_concurrency: 4,
+            dedup_similarity: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_embeddings_uses_cache() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-cache-{}", std::process::id()));
+        let mut rag = build_rag(build_data());
+        rag.embedding_cache = Some(EmbeddingCache::new(dir.clone(), 100));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let texts = vec!["foo".to_string(), "barbaz".to_string()];
+        let output = rag
+            .create_embeddings(EmbeddingsData::new(texts.clone(), false), &[], None)
+            .await
+            .unwrap();
+        assert_eq!(output, vec![vec![3.0, 3.0], vec![6.0, 6.0]]);
+
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(None));
+        let cached = rag
+            .create_embeddings(EmbeddingsData::new(texts, false), &[], None)
+            .await
+            .unwrap();
+        assert_eq!(cached, output);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_embeddings_calls_client_once() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-calls-{}", std::process::id()));
+        let mut rag = build_rag(build_data());
+        rag.embedding_cache = Some(EmbeddingCache::new(dir.clone(), 100));
+        let client = ScriptedEmbeddingsClient::new(Some(2));
+        let calls = client.calls.clone();
+        rag.embedding_client = Box::new(client);
+        let texts = vec!["same".to_string(), "same".to_string()];
+        for _ in 0..2 {
+            let output = rag
+                .create_embeddings(EmbeddingsData::new(texts.clone(), false), &[], None)
+                .await
+                .unwrap();
+            assert_eq!(output, vec![vec![4.0, 4.0], vec![4.0, 4.0]]);
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_embeddings_concurrently() {
+        let mut rag = build_rag(build_data());
+        rag.embedding_model.data_mut().max_batch_size = Some(2);
+        let texts: Vec<String> = (1..=9).map(|n| "x".repeat(n)).collect();
+        let expected: Vec<Vec<f32>> = (1..=9).map(|n| vec![n as f32]).collect();
+        // The model's max_concurrent_requests wins over rag_embedding_concurrency.
+        for (max_concurrent_requests, expect) in [(Some(3), 3), (None, 4)] {
+            rag.embedding_model.data_mut().max_concurrent_requests = max_concurrent_requests;
+            let client = ScriptedEmbeddingsClient {
+                latency: Some(std::time::Duration::from_millis(50)),
+                ..ScriptedEmbeddingsClient::new(Some(1))
+            };
+            let max_in_flight = client.max_in_flight.clone();
+            rag.embedding_client = Box::new(client);
+            let output = rag
+                .create_embeddings(EmbeddingsData::new(texts.clone(), false), &[], None)
+                .await
+                .unwrap();
+            assert_eq!(output, expected);
+            let max_in_flight = max_in_flight.load(std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(max_in_flight, expect);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_embeddings_retries() {
+        let mut rag = build_rag(build_data());
+        rag.embedding_retries = 1;
+        let texts = vec!["foo".to_string()];
+        let sources = vec!["docs/foo.md chunk 0".to_string()];
+
+        let client = ScriptedEmbeddingsClient::new(Some(2));
+        *client.failures.lock().unwrap() = vec![503];
+        rag.embedding_client = Box::new(client);
+        let output = rag
+            .create_embeddings(EmbeddingsData::new(texts.clone(), false), &sources, None)
+            .await
+            .unwrap();
+        assert_eq!(output, vec![vec![3.0, 3.0]]);
+
+        let client = ScriptedEmbeddingsClient {
+            retry_after: Some(std::time::Duration::ZERO),
+            ..ScriptedEmbeddingsClient::new(Some(2))
+        };
+        *client.failures.lock().unwrap() = vec![503, 429];
+        let calls = client.calls.clone();
+        rag.embedding_client = Box::new(client);
+        rag.embedding_retries = 2;
+        let output = rag
+            .create_embeddings(EmbeddingsData::new(texts.clone(), false), &sources, None)
+            .await
+            .unwrap();
+        assert_eq!(output, vec![vec![3.0, 3.0]]);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+        rag.embedding_retries = 1;
+
+        let client = ScriptedEmbeddingsClient::new(Some(2));
+        *client.failures.lock().unwrap() = vec![400];
+        rag.embedding_client = Box::new(client);
+        let err = rag
+            .create_embeddings(EmbeddingsData::new(texts.clone(), false), &sources, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to create embedding for docs/foo.md chunk 0"
+        );
+
+        let client = ScriptedEmbeddingsClient::new(Some(2));
+        *client.failures.lock().unwrap() = vec![429, 429];
+        rag.embedding_client = Box::new(client);
+        let err = rag
+            .create_embeddings(EmbeddingsData::new(texts, false), &sources, None)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("status: 429"));
+    }
+
+    #[tokio::test]
+    async fn test_update_embeddings_replaces_vectors() {
+        let mut rag = build_rag(build_data());
+        let model = Model::new("mock", "embedding-v2");
+        let client = ScriptedEmbeddingsClient {
+            model: model.clone(),
+            ..ScriptedEmbeddingsClient::new(Some(3))
+        };
+        rag.update_embeddings(model, Box::new(client), None)
+            .await
+            .unwrap();
+        assert_eq!(rag.data.embedding_model, "mock:embedding-v2");
+        assert_eq!(rag.data.vectors.len(), 2);
+        assert!(rag.data.vectors.values().all(|v| v.dimension() == 3));
+        assert_eq!(rag.hnsw.get_nb_point(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_load_paths_keeps_file_order() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-order-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Files of varied sizes finish loading and splitting out of order.
+        for index in 0..40 {
+            let text = format!("file {index} ").repeat((40 - index) * 50);
+            std::fs::write(dir.join(format!("{index:02}.txt")), text).unwrap();
+        }
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 200, 0));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let path = dir.display().to_string();
+        let listed = list_paths(&[&path], &[], false).await.unwrap().files;
+        rag.load_paths(LoaderOptions::default(), &[path], None)
+            .await
+            .unwrap();
+        let paths: Vec<String> = rag.data.files.values().map(|v| v.path.clone()).collect();
+        assert_eq!(paths.len(), 40);
+        assert_eq!(paths, listed);
+        let (file_id, file) = rag.data.files.iter().next().unwrap();
+        let name = Path::new(&file.path).file_stem().unwrap().to_string_lossy();
+        let text = format!("file {} ", name.parse::<usize>().unwrap());
+        assert!(file.documents[0].page_content.contains(&text));
+        assert!(rag
+            .data
+            .vectors
+            .contains_key(&pack_document_id(*file_id, file.documents.len() - 1)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_same_second_edit() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-stamp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.md"), "first note").unwrap();
+        std::fs::write(dir.join("b.md"), "other note").unwrap();
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let paths = vec![dir.display().to_string()];
+        rag.load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap();
+        let file_id = |rag: &Rag, name: &str| {
+            let path = dir.join(name).display().to_string();
+            rag.data
+                .files
+                .iter()
+                .find(|(_, v)| v.path == path)
+                .map(|v| *v.0)
+        };
+        let other_id = file_id(&rag, "b.md");
+
+        // Same size, and most likely the same second as the indexing.
+        std::fs::write(dir.join("a.md"), "fresh note").unwrap();
+        rag.load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap();
+        assert_eq!(file_id(&rag, "b.md"), other_id);
+        let file = &rag.data.files[&file_id(&rag, "a.md").unwrap()];
+        assert_eq!(chunk_content(&file.documents[0].page_content), "fresh note");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Models are listed once per process, so every test here lists the same mock client.
+    fn mock_config(rag_search_mode: RagSearchMode, dry_run: bool) -> GlobalConfig {
+        let config = Config {
+            clients: vec![serde_yaml::from_str("type: mock").unwrap()],
+            rag_embedding_model: Some("mock:embedding".into()),
+            rag_search_mode,
+            dry_run,
+            ..Default::default()
+        };
+        std::sync::Arc::new(parking_lot::RwLock::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_mock_rag_roundtrip() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/corpus");
+        let dir = std::env::temp_dir().join(format!("aichat-rag-mock-{}", std::process::id()));
+        let save_path = dir.join("corpus.bin");
+        let config = mock_config(RagSearchMode::Hybrid, false);
+        let options = RagBuildOptions {
+            name: "corpus".into(),
+            save_path: save_path.clone(),
+            document_paths: vec![corpus.into()],
+            ..Default::default()
+        };
+        let rag = Rag::build(&config, options).await.unwrap();
+        assert_eq!(rag.embedding_model.id(), "mock:embedding");
+        assert_eq!(rag.data.files.len(), 3);
+        assert_eq!(rag.data.dimension, Some(256));
+
+        let loaded = Rag::load(&config, "corpus", &save_path).unwrap();
+        assert_eq!(loaded.data.files.len(), 3);
+        assert_eq!(loaded.data.vectors, rag.data.vectors);
+        assert_eq!(loaded.hnsw.get_nb_point(), rag.hnsw.get_nb_point());
+
+        for mode in [RagSearchMode::Hybrid, RagSearchMode::VectorOnly] {
+            let config = mock_config(mode, false);
+            let options = SearchOptions::new(&config.read()).unwrap();
+            for (query, expect) in [
+                ("When are deploys rolled out?", "deploys.md"),
+                ("Who answers pages from the alerting system?", "oncall.md"),
+                ("Where is lunch served?", "office.md"),
+            ] {
+                let hits = loaded
+                    .hybird_search(query, &[], &options, None)
+                    .await
+                    .unwrap();
+                assert!(hits[0].path.ends_with(expect), "{query}: {}", hits[0].path);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rag_files() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/corpus");
+        let dir = std::env::temp_dir().join(format!("aichat-rag-files-{}", std::process::id()));
+        let config = mock_config(RagSearchMode::Hybrid, false);
+        let options = RagBuildOptions {
+            name: "corpus".into(),
+            save_path: dir.join("corpus.bin"),
+            document_paths: vec![corpus.into()],
+            ..Default::default()
+        };
+        let rag = Rag::build(&config, options).await.unwrap();
+        let mut files: Vec<_> = rag
+            .files()
+            .into_iter()
+            .map(|v| {
+                let name = v.path.rsplit('/').next().unwrap().to_string();
+                (name, v.chunk_count, v.total_chars)
+            })
+            .collect();
+        files.sort();
+        // Each file fits in one chunk, which starts with its title so has no breadcrumb.
+        assert_eq!(
+            files,
+            [
+                ("deploys.md".to_string(), 1, 176),
+                ("office.md".to_string(), 1, 151),
+                ("oncall.md".to_string(), 1, 162),
+            ]
+        );
+        assert_eq!(rag.chunk_count(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_init_rag_dry_run() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/corpus");
+        let dir = std::env::temp_dir().join(format!("aichat-rag-dry-run-{}", std::process::id()));
+        let save_path = dir.join("corpus.bin");
+        let config = mock_config(RagSearchMode::Hybrid, true);
+        let rag = Rag::init(
+            &config,
+            "corpus",
+            &save_path,
+            &[corpus.into()],
+            create_abort_signal(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(rag.data.files.len(), 3);
+        assert!(!save_path.exists());
+        assert!(!partial_path(&save_path).exists());
+        let report = rag.dry_run_report();
+        assert!(
+            report.starts_with("Dry run: 3 files, 3 chunks, about "),
+            "{report}"
+        );
+        assert!(
+            report.ends_with("tokens to embed with mock:embedding"),
+            "{report}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compact_rag() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/corpus");
+        let dir = std::env::temp_dir().join(format!("aichat-rag-compact-{}", std::process::id()));
+        let config = mock_config(RagSearchMode::Hybrid, false);
+        // hnsw_rs may leave points of a tiny multi-layer graph unreachable, so keep one layer.
+        config.write().rag_hnsw_max_layers = Some(1);
+        let options = RagBuildOptions {
+            name: "corpus".into(),
+            save_path: dir.join("corpus.bin"),
+            document_paths: vec![corpus.into()],
+            ..Default::default()
+        };
+        let mut rag = Rag::build(&config, options).await.unwrap();
+        let search_options = SearchOptions::new(&config.read()).unwrap();
+        let queries = [
+            "When are deploys rolled out?",
+            "Who answers pages from the alerting system?",
+            "Where is lunch served?",
+        ];
+        let mut expected = vec![];
+        for query in queries {
+            let hits = rag
+                .hybird_search(query, &[], &search_options, None)
+                .await
+                .unwrap();
+            expected.push(
+                hits.into_iter()
+                    .map(|v| (v.path, v.content, v.score))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // Leave gaps between the file ids, shuffle the vectors and keep one nobody refers to.
+        let files = std::mem::take(&mut rag.data.files);
+        let mut vectors = std::mem::take(&mut *rag.data.vectors);
+        for (file_id, file) in files {
+            let new_file_id = file_id * 10 + 7;
+            for document_index in 0..file.documents.len() {
+                let vector = vectors
+                    .swap_remove(&pack_document_id(file_id, document_index))
+                    .unwrap();
+                rag.data
+                    .vectors
+                    .insert(pack_document_id(new_file_id, document_index), vector);
+            }
+            rag.data.files.insert(new_file_id, file);
+        }
+        rag.data.vectors.reverse();
+        let stale: Vec<f32> = rag.data.vectors[0].to_f32().iter().map(|v| -v).collect();
+        rag.data
+            .vectors
+            .insert(pack_document_id(99, 0), stale.into());
+        rag.data.next_file_id = 100;
+
+        assert!(rag.compact().unwrap());
+        assert_eq!(rag.data.next_file_id, 3);
+        assert_eq!(
+            rag.data.files.keys().copied().collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(
+            rag.data.vectors.keys().copied().collect::<Vec<_>>(),
+            [0, 1 << 32, 2 << 32]
+        );
+        assert_eq!(rag.hnsw.get_nb_point(), 3);
+        for (query, expected) in queries.into_iter().zip(expected) {
+            let hits = rag
+                .hybird_search(query, &[], &search_options, None)
+                .await
+                .unwrap();
+            let hits: Vec<_> = hits
+                .into_iter()
+                .map(|v| (v.path, v.content, v.score))
+                .collect();
+            assert_eq!(hits, expected, "{query}");
+        }
+        assert!(!rag.compact().unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_cost_limit() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-cost-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.md");
+        std::fs::write(&path, "word ".repeat(2000)).unwrap();
+        let paths = [path.display().to_string()];
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 0));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        rag.embedding_model.data_mut().input_price = Some(10.0);
+
+        let options = LoaderOptions {
+            cost_limit: Some(0.001),
+            ..Default::default()
+        };
+        let err = rag.load_paths(options, &paths, None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Embedding 10 chunks (~2.7K tokens, est. $0.03) exceeds rag_cost_limit $0.001"
+        );
+        assert!(rag.data.files.is_empty());
+
+        let options = LoaderOptions {
+            cost_limit: Some(1.0),
+            ..Default::default()
+        };
+        rag.load_paths(options, &paths, None).await.unwrap();
+        assert_eq!(rag.data.files.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_cost_estimate() {
+        assert_eq!(format_count(7), "7");
+        assert_eq!(format_count(42310), "42,310");
+        assert_eq!(format_count(1234567), "1,234,567");
+        assert_eq!(format_tokens(999), "999");
+        assert_eq!(format_tokens(12_340), "12.3K");
+        assert_eq!(format_tokens(8_100_000), "8.1M");
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_dedup() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-dedup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<String> = [("a.md", "license"), ("b.md", "license"), ("c.md", "readme")]
+            .iter()
+            .map(|(name, text)| {
+                let path = dir.join(name);
+                std::fs::write(&path, text).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        let client = ScriptedEmbeddingsClient::new(Some(2));
+        let calls = client.calls.clone();
+        rag.embedding_client = Box::new(client);
+        let options = LoaderOptions {
+            dedup: true,
+            ..Default::default()
+        };
+        rag.load_paths(options, &paths, None).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(rag.data.vectors.len(), 2);
+        assert_eq!(rag.data.duplicates.len(), 1);
+        assert_eq!(rag.hnsw.get_nb_point(), 2);
+        // The mock embeds a text as its length, so the shared vector left out the file header.
+        let license_id = pack_document_id(0, 0);
+        assert_eq!(rag.data.sharing_ids(license_id).len(), 2);
+        assert_eq!(
+            rag.data.vector(license_id).as_deref(),
+            Some([7.0, 7.0].as_slice())
+        );
+        assert!(rag.data.vector(pack_document_id(2, 0)).unwrap()[0] > 6.0);
+
+        let (ids, _) = rag
+            .vector_search("query", &[], 10, 30, 0.0, None)
+            .await
+            .unwrap();
+        assert_eq!(ids.len(), 3);
+        rag.embedding_model.data_mut().max_batch_size = Some(3);
+        let paraphrases = vec!["question".to_string(), "search".to_string()];
+        let (ids, embeddings) = rag
+            .vector_search("query", &paraphrases, 10, 30, 0.0, None)
+            .await
+            .unwrap();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(embeddings.len(), 3);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 4);
+        let filter = MetadataFilter::parse(&format!("path={}", paths[1])).unwrap();
+        let allowed_ids = rag.data.filter_ids(&filter);
+        let (ids, _) = rag
+            .vector_search("query", &[], 10, 30, 0.0, Some(&allowed_ids))
+            .await
+            .unwrap();
+        let ids: Vec<_> = ids.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, allowed_ids);
+
+        let removed = rag.remove_paths(&paths[..1]).await.unwrap();
+        assert_eq!(removed, vec![paths[0].clone()]);
+        assert!(rag.data.duplicates.is_empty());
+        assert_eq!(rag.data.vectors.len(), 2);
+        let (ids, _) = rag
+            .vector_search("query", &[], 10, 30, 0.0, None)
+            .await
+            .unwrap();
+        assert_eq!(ids.len(), 2);
+
+        // An indexed chunk that becomes shared later is embedded again without its header.
+        let path = dir.join("d.md").display().to_string();
+        std::fs::write(&path, "readme").unwrap();
+        let options = LoaderOptions {
+            dedup: true,
+            ..Default::default()
+        };
+        let paths = [paths[1].clone(), paths[2].clone(), path];
+        rag.load_paths(options, &paths, None).await.unwrap();
+        assert_eq!(rag.data.duplicates.len(), 1);
+        assert_eq!(
+            rag.data.vector(pack_document_id(2, 0)).as_deref(),
+            Some([6.0, 6.0].as_slice())
+        );
+        assert_eq!(rag.hnsw.get_nb_point(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_dedup_repeated_paragraph() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-repeat-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("changelog.txt");
+        let stanza = "Thanks to everyone who reported bugs and sent patches for this release.";
+        let contents = [
+            "Release 1.2.0 adds exports.",
+            stanza,
+            "Release 1.1.0 adds imports.",
+            stanza,
+            "Release 1.0.0 is the first one.",
+            "Thanks to everyone who reported bugs and sent patches for the first release.",
+        ]
+        .join("\n\n");
+        std::fs::write(&path, contents).unwrap();
+        let paths = [path.display().to_string()];
+        let options = LoaderOptions {
+            dedup: true,
+            ..Default::default()
+        };
+        let count_chunks = |rag: &Rag, text: &str| -> Vec<Option<String>> {
+            let file = rag.data.files.values().next().unwrap();
+            file.documents
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| chunk_content(&v.page_content) == text)
+                .filter(|(index, _)| rag.data.vectors.contains_key(&pack_document_id(0, *index)))
+                .map(|(_, v)| v.metadata.get(DEDUP_COUNT_METADATA).cloned())
+                .collect()
+        };
+
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 80, 0));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        rag.load_paths(options.clone(), &paths, None).await.unwrap();
+        assert_eq!(rag.data.files[0].documents.len(), 6);
+        assert_eq!(rag.data.vectors.len(), 5);
+        assert_eq!(count_chunks(&rag, stanza), [Some("2".to_string())]);
+
+        // Near-duplicates share the vector too once a similarity is set.
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 80, 0));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        rag.dedup_similarity = Some(0.5);
+        rag.load_paths(options, &paths, None).await.unwrap();
+        assert_eq!(rag.data.vectors.len(), 4);
+        assert_eq!(count_chunks(&rag, stanza), [Some("3".to_string())]);
+        assert_eq!(rag.data.duplicates.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_texts() {
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let items = vec![
+            ("faq".into(), "Deploys happen on Tuesdays".into()),
+            ("oncall".into(), "Page the platform team".into()),
+            ("faq".into(), "Deploys happen on Thursdays".into()),
+        ];
+        rag.add_texts(items, true, None).await.unwrap();
+        let paths: Vec<_> = rag.data.files.values().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths, ["text:faq", "text:oncall"]);
+
+        let items = vec![("faq".into(), "Deploys are frozen in December".into())];
+        rag.add_texts(items, true, None).await.unwrap();
+        assert_eq!(rag.data.files.len(), 2);
+        assert_eq!(rag.data.vectors.len(), 2);
+        assert_eq!(rag.hnsw.get_nb_point(), 2);
+        let (file_id, file) = rag
+            .data
+            .files
+            .iter()
+            .find(|(_, v)| v.path == "text:faq")
+            .unwrap();
+        assert_eq!(file.documents[0].metadata[SOURCE_METADATA], "text:faq");
+        let hit = rag.data.hit(pack_document_id(*file_id, 0), 1.0).unwrap();
+        assert_eq!(hit.path, "text:faq");
+        assert!(hit.content.contains("frozen in December"));
+        let empty = vec![("empty".into(), " ".into())];
+        assert!(rag.add_texts(empty, true, None).await.is_err());
+
+        let content = encode(&rag.data);
+        let data = RagData::decode(&content).unwrap();
+        let paths: Vec<_> = data.files.values().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains(&"text:faq") && paths.contains(&"text:oncall"));
+
+        let paths: Vec<String> = vec![];
+        rag.load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap();
+        assert_eq!(rag.data.files.len(), 2);
+        let removed = rag.remove_paths(&["text:oncall".into()]).await.unwrap();
+        assert_eq!(removed, ["text:oncall"]);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_overrides() {
+        let mut config = Config::default();
+        let mut data = RagData::new("mock:embedding".into(), 100, 10);
+        let value = |chunk_size, chunk_overlap| RagChunkOverride {
+            chunk_size,
+            chunk_overlap,
+        };
+        config.rag_chunk_overrides = [("rs".into(), value(Some(0), None))].into();
+        assert!(check_chunk_overrides(&config.rag_chunk_overrides).is_err());
+        config.rag_chunk_overrides = [("rs".into(), value(Some(20), Some(20)))].into();
+        let err = check_chunk_overrides(&config.rag_chunk_overrides).unwrap_err();
+        assert!(err.to_string().contains("'rs'"));
+        config.rag_chunk_overrides = [("md".into(), value(None, Some(100)))].into();
+        check_chunk_overrides(&config.rag_chunk_overrides).unwrap();
+        let err = data.set_chunk_overrides(&config).unwrap_err();
+        assert!(err.to_string().contains("'md'"));
+
+        config.rag_chunk_overrides = [("rs".into(), value(Some(20), Some(0)))].into();
+        data.set_chunk_overrides(&config).unwrap();
+        assert_eq!(data.chunk_params("rs"), (20, 0));
+        assert_eq!(data.chunk_params("md"), (100, 10));
+
+        let dir = std::env::temp_dir().join(format!("aichat-rag-overrides-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "alpha beta gamma delta epsilon zeta eta theta";
+        let paths: Vec<String> = ["a.md", "a.rs"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                std::fs::write(&path, text).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        let mut rag = build_rag(data);
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        rag.load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap();
+        let num_chunks = |path: &str| {
+            let file = rag.data.files.values().find(|v| v.path == path).unwrap();
+            file.documents.len()
+        };
+        assert_eq!(num_chunks(&paths[0]), 1);
+        assert!(num_chunks(&paths[1]) > 1);
+
+        let content = encode(&rag.data);
+        let data = RagData::decode(&content).unwrap();
+        assert_eq!(data.chunk_params("rs"), (20, 0));
+        let output: serde_yaml::Value = serde_yaml::from_str(&rag.export().unwrap()).unwrap();
+        assert_eq!(output["chunk_overrides"]["rs"]["chunk_size"], 20);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_notebook_cells() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.ipynb");
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 60, 0));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        rag.load_paths(LoaderOptions::default(), &[path.to_string()], None)
+            .await
+            .unwrap();
+        let file = rag.data.files.values().next().unwrap();
+        assert!(file.documents.len() > 4);
+        for document in &file.documents {
+            let content = document
+                .page_content
+                .split("</document_metadata>\n\n")
+                .last();
+            let content = content.unwrap();
+            match document.metadata[CELL_INDEX_METADATA].as_str() {
+                "0" | "5" => assert_eq!(document.metadata[CELL_TYPE_METADATA], "markdown"),
+                "1" => assert!(!content.contains("Sales") && !content.contains("plot")),
+                "2" => assert!(content.contains("df.plot")),
+                index => panic!("unexpected cell {index}"),
+            }
+            assert!(!document.metadata.contains_key(EXTENSION_METADATA));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_partial_failures() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-failures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("corrupt.docx"), "not a zip archive").unwrap();
+        let paths = vec![dir.display().to_string()];
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let err = rag
+            .load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap_err();
+        let failures = err.downcast_ref::<LoadFailures>().unwrap();
+        assert!(failures.0[0].0.ends_with("corrupt.docx"));
+        assert!(rag.data.document_paths.is_empty());
+
+        std::fs::write(dir.join("valid.md"), "# Valid").unwrap();
+        rag.load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap();
+        assert_eq!(rag.data.files.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupted_rag() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-corrupted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.bin");
+        let content = encode(&build_data());
+        std::fs::write(&path, &content[..content.len() / 2]).unwrap();
+        let err = Rag::load(&Default::default(), "test", &path).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains(&path.display().to_string()), "{message}");
+        assert!(message.contains("create the rag again"), "{message}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_paths_checkpoint() {
+        let dir =
+            std::env::temp_dir().join(format!("aichat-rag-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<String> = ["a.md", "b.md", "c.md"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                std::fs::write(&path, format!("content of {name}")).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        let rag_path = dir.join("test.bin");
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        rag.path = rag_path.display().to_string();
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient {
+            fail_from: Some(3),
+            ..ScriptedEmbeddingsClient::new(Some(2))
+        });
+        let options = LoaderOptions {
+            batch_files: 1,
+            ..Default::default()
+        };
+        assert!(rag.load_paths(options, &paths, None).await.is_err());
+
+        // The run died on the third batch, the first two were checkpointed.
+        assert!(!rag_path.exists());
+        let partial_path = partial_path(&rag_path);
+        let data = RagData::decode(&std::fs::read(&partial_path).unwrap()).unwrap();
+        assert_eq!(data.files.len(), 2);
+        assert_eq!(data.vectors.len(), 2);
+        assert_eq!(data.document_paths, paths);
+        assert!(!rag_path.with_extension("tmp").exists());
+
+        // Resuming only embeds the file missing from the checkpoint.
+        let mut rag = build_rag(data);
+        rag.path = rag_path.display().to_string();
+        let client = ScriptedEmbeddingsClient::new(Some(2));
+        let calls = client.calls.clone();
+        rag.embedding_client = Box::new(client);
+        let options = LoaderOptions {
+            batch_files: 1,
+            ..Default::default()
+        };
+        rag.load_paths(options, &paths, None).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(rag.data.files.len(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_reindexes_changed_files() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-watch-{}", std::process::id()));
+        let notes_dir = dir.join("notes");
+        std::fs::create_dir_all(&notes_dir).unwrap();
+        std::fs::write(notes_dir.join("a.md"), "first note").unwrap();
+        let rag_path = dir.join("test.bin");
+        let mut rag = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let paths = vec![notes_dir.display().to_string()];
+        rag.load_paths(LoaderOptions::default(), &paths, None)
+            .await
+            .unwrap();
+        assert_eq!(rag.data.files.len(), 1);
+
+        let config: GlobalConfig = Default::default();
+        let abort_signal = create_abort_signal();
+        let edit = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            // A burst of writes, as editors do on save.
+            std::fs::write(notes_dir.join("b.md"), "second").unwrap();
+            std::fs::write(notes_dir.join("b.md"), "second note").unwrap();
+            // Same size as before, within a second of the indexing.
+            std::fs::write(notes_dir.join("a.md"), "first edit").unwrap();
+            for _ in 0..100 {
+                if rag_path.exists() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            abort_signal.set_ctrlc();
+        };
+        let (ret, _) = tokio::join!(rag.watch(&config, &rag_path, abort_signal.clone()), edit);
+        ret.unwrap();
+
+        let data = RagData::decode(&std::fs::read(&rag_path).unwrap()).unwrap();
+        let mut contents: Vec<_> = data
+            .files
+            .values()
+            .map(|file| chunk_content(&file.documents[0].page_content).to_string())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec!["first edit", "second note"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_text_only_search_skips_embeddings() {
+        let rag = build_rag(build_data());
+        let config = Config {
+            rag_search_mode: RagSearchMode::TextOnly,
+            ..Default::default()
+        };
+        let options = SearchOptions::new(&config).unwrap();
+        let hits = rag.hybird_search("foo", &[], &options, None).await.unwrap();
+        assert_eq!(hits[0].content, "foo");
+    }
+
+    #[tokio::test]
+    async fn test_search_filter_matching_nothing() {
+        let rag = build_rag(build_data());
+        let filter = MetadataFilter::parse("path:/tmp/bar.md").unwrap();
+        assert_eq!(filter, MetadataFilter::PathGlob("/tmp/bar.md".into()));
+        assert_eq!(filter.to_string(), "path=/tmp/bar.md");
+        let config = Config {
+            rag_filter: Some(filter),
+            ..Default::default()
+        };
+        let options = SearchOptions::new(&config).unwrap();
+        let hits = rag.hybird_search("foo", &[], &options, None).await.unwrap();
+        assert!(hits.is_empty());
+
+        let config = Config {
+            rag_filter: Some(MetadataFilter::parse("path=/tmp/foo.md").unwrap()),
+            rag_search_mode: RagSearchMode::TextOnly,
+            ..Default::default()
+        };
+        let options = SearchOptions::new(&config).unwrap();
+        let hits = rag.hybird_search("foo", &[], &options, None).await.unwrap();
+        assert_eq!(hits[0].content, "foo");
+    }
+
+    #[tokio::test]
+    async fn test_search_many_rags() {
+        let mut docs = build_rag(RagData::new("mock:embedding".into(), 1000, 50));
+        docs.name = "docs".into();
+        docs.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let items = vec![
+            (
+                "deploy".into(),
+                "Deploys happen on Tuesdays, deploys are rolled out slowly".into(),
+            ),
+            (
+                "release".into(),
+                "Releases are tagged after the deploy".into(),
+            ),
+        ];
+        docs.add_texts(items, true, None).await.unwrap();
+        // Embedded by another model, with vectors of another size.
+        let mut notes = build_rag(RagData::new("mock:embedding-large".into(), 1000, 50));
+        notes.name = "notes".into();
+        notes.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(3)));
+        let items = vec![
+            (
+                "oncall".into(),
+                "Page the platform team if a deploy fails".into(),
+            ),
+            ("lunch".into(), "Lunch is at noon".into()),
+        ];
+        notes.add_texts(items, true, None).await.unwrap();
+
+        let config: GlobalConfig = std::sync::Arc::new(parking_lot::RwLock::new(Config {
+            rag_search_mode: RagSearchMode::TextOnly,
+            ..Default::default()
+        }));
+        let hits =
+            Rag::search_many_detailed(&[&docs, &notes], &config, "deploy", create_abort_signal())
+                .await
+                .unwrap();
+        let output: Vec<(&str, &str)> = hits
+            .iter()
+            .map(|v| (v.rag.as_deref().unwrap(), v.path.as_str()))
+            .collect();
+        assert_eq!(
+            output,
+            [
+                ("docs", "text:deploy"),
+                ("notes", "text:oncall"),
+                ("docs", "text:release"),
+                ("notes", "text:lunch")
+            ]
+        );
+        let hits = Rag::search_many_detailed(&[&notes], &config, "deploy", create_abort_signal())
+            .await
+            .unwrap();
+        assert_eq!(hits[0].path, "text:oncall");
+        assert!(hits.iter().all(|v| v.rag.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_search_hits_sorted_by_score() {
+        let data = rag_data_from(&[
+            ("/tmp/a.md", &["rust rust rust"]),
+            ("/tmp/b.md", &["rust"]),
+            ("/tmp/c.md", &["python"]),
+            ("/tmp/d.md", &["go"]),
+            ("/tmp/e.md", &["java"]),
+        ]);
+        let rag = build_rag(data);
+        let config = Config {
+            rag_search_mode: RagSearchMode::TextOnly,
+            ..Default::default()
+        };
+        let options = SearchOptions::new(&config).unwrap();
+        let hits = rag
+            .hybird_search("rust", &[], &options, None)
+            .await
+            .unwrap();
+        assert_eq!(hits[0].path, "/tmp/a.md");
+        assert_eq!(hits[1].path, "/tmp/b.md");
+        assert!(hits.windows(2).all(|v| v[0].score >= v[1].score));
+        assert_eq!(
+            hits[1].render("[source: __SOURCE__#__CHUNK_INDEX__]\n__CONTENT__"),
+            "[source: /tmp/b.md#0]\nrust"
+        );
+
+        let reranker: Box<dyn Client> = Box::new(ScriptedEmbeddingsClient::new(None));
+        let reranked = rag
+            .hybird_search("rust", &[], &options, Some(&(reranker, 0.0)))
+            .await
+            .unwrap();
+        assert_eq!(reranked[0].path, "/tmp/b.md");
+        assert_eq!(reranked[1].path, "/tmp/a.md");
+
+        let mut reranker = ScriptedEmbeddingsClient::new(None);
+        reranker.fail_from = Some(1);
+        let fallback = rag
+            .hybird_search("rust", &[], &options, Some(&(Box::new(reranker), 0.0)))
+            .await
+            .unwrap();
+        assert_eq!(fallback, hits);
+
+        // Ranked first by the keyword search alone, `a` scores 1/61 and `b` 1/62.
+        let config = Config {
+            rag_search_mode: RagSearchMode::TextOnly,
+            rag_min_score_fused: 0.0162,
+            ..Default::default()
+        };
+        let options = SearchOptions::new(&config).unwrap();
+        let hits = rag
+            .hybird_search("rust", &[], &options, None)
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].path, "/tmp/a.md");
+    }
+
+    #[tokio::test]
+    async fn test_rag_data_jsonl_roundtrip() {
+        let mut data = rag_data_from(&[
+            ("/tmp/0.md", &["rust is fast", "rust is fast, really"]),
+            ("/tmp/1.md", &["rust is slow", "rust is slow, really"]),
+            ("/tmp/2.md", &["rust is safe", "rust is safe, really"]),
+            ("/tmp/3.md", &["python is easy", "python is easy, really"]),
+            ("/tmp/4.md", &["go is simple", "go is simple, really"]),
+            ("/tmp/5.md", &["c is old", "c is old, really"]),
+        ]);
+        // Leave gaps in the file ids, and a `next_file_id` past the last one.
+        data.remove_paths(&["/tmp/1.md".into(), "/tmp/5.md".into()]);
+        for file in data.files.values_mut() {
+            let document = &mut file.documents[0];
+            let lang = document.page_content[..2].to_string();
+            document.metadata.insert("lang".into(), lang);
+        }
+        data.document_paths = vec!["/tmp".into()];
+
+        let mut output = vec![];
+        assert_eq!(data.write_jsonl(&mut output, true).unwrap(), 8);
+        let imported = RagData::read_jsonl(&String::from_utf8(output).unwrap()).unwrap();
+        assert_eq!(imported.vectors, data.vectors);
+        assert_eq!(imported.document_paths, data.document_paths);
+        assert_eq!(data.next_file_id, 6);
+        assert_eq!(imported.next_file_id, 5);
+        for (file_id, file) in &data.files {
+            let imported_file = &imported.files[file_id];
+            assert_eq!(imported_file.hash, file.hash);
+            assert_eq!(imported_file.mtime, file.mtime);
+            assert_eq!(imported_file.size, file.size);
+        }
+
+        let mut rag = build_rag(data);
+        let mut imported = build_rag(imported);
+        rag.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        imported.embedding_client = Box::new(ScriptedEmbeddingsClient::new(Some(2)));
+        let options = SearchOptions::new(&Config::default()).unwrap();
+        for query in ["rust", "easy python", "really"] {
+            let hits = rag.hybird_search(query, &[], &options, None).await.unwrap();
+            let imported_hits = imported
+                .hybird_search(query, &[], &options, None)
+                .await
+                .unwrap();
+            assert!(!hits.is_empty());
+            assert_eq!(imported_hits, hits, "{query}");
+        }
+
+        let mut output = vec![];
+        rag.data.write_jsonl(&mut output, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("\"embedding\""));
+        assert!(RagData::read_jsonl(&output).is_err());
+    }
+
+    #[test]
+    fn test_hnsw_ef_construction_recall() {
+        let dimension = 16;
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        let mut vectors = random_vectors(42, 2000 + 50, dimension);
+        let queries = vectors.split_off(2000);
+        for (index, vector) in vectors.into_iter().enumerate() {
+            data.vectors.insert(index as DocumentId, vector.into());
+        }
+        let top_k = 10;
+        let exact: Vec<Vec<DocumentId>> = queries
+            .iter()
+            .map(|query| {
+                let mut scores: Vec<(DocumentId, f32)> = data
+                    .vectors
+                    .iter()
+                    .map(|(id, v)| (*id, DistCosine {}.eval(query, &v.to_f32())))
+                    .collect();
+                scores.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                scores.into_iter().take(top_k).map(|(id, _)| id).collect()
+            })
+            .collect();
+        let recall = |data: &RagData| {
+            let hnsw = data.build_hnsw().unwrap();
+            let mut found = 0;
+            for (query, exact) in queries.iter().zip(exact.iter()) {
+                found += hnsw
+                    .search_filter(query, top_k, 30, None)
+                    .iter()
+                    .filter_map(|v| data.vectors.get_index(v.d_id))
+                    .filter(|(id, _)| exact.contains(id))
+                    .count();
+            }
+            found as f32 / (queries.len() * top_k) as f32
+        };
+        data.hnsw_ef_construction = 8;
+        let low_recall = recall(&data);
+        data.hnsw_ef_construction = default_hnsw_ef_construction();
+        let default_recall = recall(&data);
+        assert!(default_recall >= low_recall);
+        assert!(default_recall > 0.9);
+    }
+
+    #[test]
+    fn test_vector_compression_recall() {
+        let dimension = 32;
+        let mut data = RagData::new("openai:text-embedding-3-small".into(), 1000, 50);
+        let mut vectors = random_vectors(7, 1000 + 50, dimension);
+        let queries = vectors.split_off(1000);
+        for (index, vector) in vectors.into_iter().enumerate() {
+            data.vectors.insert(index as DocumentId, vector.into());
+        }
+        let top_k = 10;
+        let search = |data: &RagData| {
+            let hnsw = data.build_hnsw().unwrap();
+            let results: Vec<Vec<DocumentId>> = queries
+                .iter()
+                .map(|query| {
+                    hnsw.search_filter(query, top_k, 30, None)
+                        .iter()
+                        .filter_map(|v| data.vectors.get_index(v.d_id).map(|(id, _)| *id))
+                        .collect()
+                })
+                .collect();
+            results
+        };
+        let baseline = search(&data);
+        let plain = data.clone();
+        let plain_size = encode(&plain).len();
+
+        data.set_vector_compression(RagVectorCompression::Int8);
+        let content = encode(&data);
+        assert!(content.len() * 2 < plain_size);
+        let output = RagData::decode(&content).unwrap();
+        assert_eq!(output.vector_compression, RagVectorCompression::Int8);
+        // The vectors stay quantized in memory, before saving and after loading.
+        assert_eq!(output.vectors, data.vectors);
+        for (id, vector) in output.vectors.iter() {
+            assert!(matches!(vector, RagVector::Int8 { .. }));
+            let error = vector
+                .to_f32()
+                .iter()
+                .zip(plain.vectors[id].to_f32().iter())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            assert!(error < 0.005, "{id}: {error}");
+        }
+        let found: usize = search(&output)
+            .iter()
+            .zip(&baseline)
+            .map(|(ids, expect)| ids.iter().filter(|v| expect.contains(v)).count())
+            .sum();
+        assert!(found as f32 / (queries.len() * top_k) as f32 > 0.9);
+
+        // Vectors added later are compressed too, and reload unchanged.
+        let mut output = output;
+        output
+            .add(1, vec![], vec![5000], vec![vec![0.3; dimension]])
+            .unwrap();
+        let content = encode(&output);
+        let reloaded = RagData::decode(&content).unwrap();
+        assert_eq!(reloaded.vectors[&5000], output.vectors[&5000]);
+        assert!(matches!(output.vectors[&5000], RagVector::Int8 { .. }));
+
+        output.set_vector_compression(RagVectorCompression::None);
+        assert!(output
+            .vectors
+            .values()
+            .all(|v| matches!(v, RagVector::F32(_))));
+    }
+
+    #[test]
+    fn test_search_options_weights() {
+        let mut config = Config::default();
+        assert!(SearchOptions::new(&config).is_ok());
+        config.rag_rrf_text_weight = 0.0;
+        assert!(SearchOptions::new(&config).is_ok());
+        config.rag_rrf_vector_weight = 0.0;
+        assert!(SearchOptions::new(&config).is_err());
+        config.rag_rrf_vector_weight = -1.0;
+        config.rag_rrf_text_weight = 1.0;
+        assert!(SearchOptions::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_export_stats() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rag = build_rag(build_data());
+        let path = dir.join("test.bin");
+        rag.path = path.display().to_string();
+        let output: serde_yaml::Value = serde_yaml::from_str(&rag.export().unwrap()).unwrap();
+        assert_eq!(output["size"], serde_yaml::Value::Null);
+        assert_eq!(output["num_vectors"], 2);
+        assert_eq!(output["dimension"], 2);
+        assert_eq!(output["bm25_vocab_size"], 2);
+        assert_eq!(output["chunk_size_unit"], "chars");
+        assert_eq!(output["files"][0]["path"], "/tmp/foo.md");
+        assert_eq!(output["files"][0]["num_chunks"], 2);
+        assert_eq!(output["files"][0]["num_chars"], 6);
+        assert_eq!(output["num_chunks"], 2);
+
+        std::fs::write(&path, "0123456789").unwrap();
+        let output: serde_yaml::Value = serde_yaml::from_str(&rag.export().unwrap()).unwrap();
+        assert_eq!(output["size"], 10);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rag_data_roundtrip() {
+        let mut data = build_data();
+        let content = encode(&data);
+        assert!(content.starts_with(RAG_FILE_MAGIC));
+        let output = RagData::decode(&content).unwrap();
+        assert_eq!(output.files[0].mtime, 1);
+        assert_eq!(output.vectors, data.vectors);
+
+        data.version = 2;
+        let output = RagData::decode(&encode(&data)).unwrap();
+        assert_eq!(output.files[0].mtime, NANOS_PER_SEC);
+        assert_eq!(output.mtime(pack_document_id(0, 0)), 1);
+    }
+
+    #[test]
+    fn test_rag_data_bm25_stemming() {
+        let mut config = Config::default();
+        let mut data = build_data();
+        config.rag_bm25_language = "klingon".into();
+        assert!(data.set_bm25_params(&config).is_err());
+        config.rag_bm25_language = "english".into();
+        config.rag_bm25_stemming = true;
+        config.rag_bm25_cjk = true;
+        data.set_bm25_params(&config).unwrap();
+        let file = RagFile {
+            hash: sha256("run"),
+            path: "/tmp/run.md".into(),
+            mtime: 1,
+            size: 0,
+            documents: vec![RagDocument::new("They run every morning")],
+        };
+        data.add(
+            1,
+            vec![(1, file)],
+            vec![pack_document_id(1, 0)],
+            vec![vec![1.0, 1.0]],
+        )
+        .unwrap();
+
+        let content = encode(&data);
+        let data = RagData::decode(&content).unwrap();
+        assert!(data.bm25.stemming);
+        assert!(data.bm25.cjk);
+        let hits = data.build_bm25().search("Running", 3, Some(0.0));
+        assert_eq!(hits[0].0, pack_document_id(1, 0));
+        assert!(hits[0].1 > 0.0);
+
+        let data = build_data();
+        assert!(!data.bm25.stemming);
+        let hits = data.build_bm25().search("Running", 3, None);
+        assert!(hits.iter().all(|(_, score)| *score == 0.0));
+    }
+
+    #[test]
+    fn test_rag_data_decode_legacy() {
+        #[derive(Serialize)]
+        struct V0File<'a> {
+            hash: &'a str,
+            path: &'a str,
+            documents: &'a [RagDocument],
+        }
+        let data = build_data();
+        let files: IndexMap<FileId, V0File> = data
+            .files
+            .iter()
+            .map(|(k, v)| {
+                let file = V0File {
+                    hash: &v.hash,
+                    path: &v.path,
+                    documents: &v.documents,
+                };
+                (*k, file)
+            })
+            .collect();
+        let content = bincode::serialize(&(
+            &data.embedding_model,
+            data.chunk_size,
+            data.chunk_overlap,
+            data.next_file_id,
+            &data.document_paths,
+            files,
+            data.vectors
+                .iter()
+                .map(|(id, v)| (*id, v.to_f32().into_owned()))
+                .collect::<IndexMap<_, _>>(),
+        ))
+        .unwrap();
+        let output = RagData::decode(&content).unwrap();
+        assert_eq!(output.files[0].path, "/tmp/foo.md");
+        assert_eq!(output.files[0].mtime, 0);
+        assert_eq!(output.vectors, data.vectors);
+        assert_eq!(output.version, RAG_DATA_VERSION);
+    }
+
+    #[test]
+    fn test_document_id_roundtrip() {
+        let max = u32::MAX as usize;
+        for (file_index, document_index) in [(0, 0), (1, 70_000), (70_000, 1), (max, max)] {
+            let id = combine_document_id(file_index, document_index).unwrap();
+            assert_eq!(split_document_id(id), (file_index, document_index));
+        }
+        #[cfg(target_pointer_width = "64")]
+        {
+            assert!(combine_document_id(max + 1, 0).is_err());
+            assert!(combine_document_id(0, max + 1).is_err());
+            let file = RagFile {
+                hash: sha256("overflow"),
+                path: "/tmp/overflow.md".into(),
+                mtime: 1,
+                size: 0,
+                documents: vec![RagDocument::new("overflow")],
+            };
+            let err = build_data()
+                .add(max + 2, vec![(max + 1, file)], vec![], vec![])
+                .unwrap_err();
+            assert!(err.to_string().contains("Document id overflows"));
+        }
+
+        let mut data = build_data();
+        let documents: Vec<RagDocument> = (0..70_000)
+            .map(|i| RagDocument::new(format!("chunk {i}")))
+            .collect();
+        let document_ids = (0..documents.len())
+            .map(|i| pack_document_id(1, i))
+            .collect();
+        let embeddings = vec![vec![1.0, 0.0]; documents.len()];
+        let file = RagFile {
+            hash: sha256("large"),
+            path: "/tmp/large.md".into(),
+            mtime: 1,
+            size: 0,
+            documents,
+        };
+        data.add(2, vec![(1, file)], document_ids, embeddings)
+            .unwrap();
+        assert_eq!(data.vectors.len(), 70_002);
+        let document = data.get(pack_document_id(1, 69_999)).unwrap();
+        assert_eq!(document.page_content, "chunk 69999");
+        assert_eq!(
+            data.get(pack_document_id(0, 1)).unwrap().page_content,
+            "bar"
+        );
+    }
+
+    #[test]
+    fn test_rag_data_decode_version() {
+        let mut data = build_data();
+        data.version = RAG_DATA_VERSION + 1;
+        let err = RagData::decode(&encode(&data)).unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+    }
+
+    #[test]
+    fn test_saved_rags() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-saved-{}", std::process::id()));
+        let rag = build_rag(build_data());
+        rag.save(&dir.join("docs.bin")).unwrap();
+        rag.save(&dir.join(format!("{TEMP_RAG_NAME}.bin"))).unwrap();
+        std::fs::write(dir.join("broken.bin"), "{").unwrap();
+        std::fs::write(dir.join("docs.partial"), "{}").unwrap();
+
+        let rags = list_saved_rags(&dir, None).unwrap();
+        let names: Vec<_> = rags.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["broken", "docs", TEMP_RAG_NAME]);
+        assert_eq!(rags[0].embedding_model, None);
+        assert_eq!(
+            rags[1].embedding_model.as_deref(),
+            Some("openai:text-embedding-3-small")
+        );
+        assert_eq!(rags[1].num_files, Some(1));
+        assert!(rags[1].size > Some(0) && rags[1].modified > Some(0));
+        assert!(!rags[1].temporary && rags[2].temporary);
+
+        // The temp rag in use is listed from memory, in place of any file left behind.
+        let mut temp_rag = build_rag(build_data());
+        temp_rag.name = TEMP_RAG_NAME.into();
+        let rags = list_saved_rags(&dir, Some(&temp_rag)).unwrap();
+        let temp = rags.last().unwrap();
+        assert_eq!(rags.len(), 3);
+        assert_eq!(temp.name, TEMP_RAG_NAME);
+        assert_eq!((temp.size, temp.modified), (None, None));
+        assert_eq!(temp.num_files, Some(1));
+        assert!(temp.temporary);
+
+        assert!(rename_saved_rag(&dir, "docs", "broken").is_err());
+        assert!(rename_saved_rag(&dir, "docs", "../docs").is_err());
+        assert!(rename_saved_rag(&dir, "missing", "other").is_err());
+        let outside = dir.with_extension("bin");
+        rag.save(&outside).unwrap();
+        let name = format!("../{}", dir.file_name().unwrap().to_string_lossy());
+        let err = rename_saved_rag(&dir, &name, "other").unwrap_err();
+        assert_eq!(err.to_string(), format!("Invalid rag name '{name}'"));
+        assert!(delete_saved_rag(&dir, &name).is_err());
+        assert!(outside.exists());
+        std::fs::remove_file(&outside).unwrap();
+        let graph_files = hnsw_paths(&dir.join("docs.bin"));
+        rename_saved_rag(&dir, "docs", "notes").unwrap();
+        assert!(!dir.join("docs.bin").exists() && !dir.join("docs.partial").exists());
+        assert!(dir.join("notes.bin").exists() && dir.join("notes.partial").exists());
+        for (old, new) in graph_files.iter().zip(hnsw_paths(&dir.join("notes.bin"))) {
+            assert!(!old.exists() && new.exists());
+        }
+
+        delete_saved_rag(&dir, "notes").unwrap();
+        delete_saved_rag(&dir, TEMP_RAG_NAME).unwrap();
+        assert!(delete_saved_rag(&dir, "notes").is_err());
+        let names: Vec<_> = list_saved_rags(&dir, None)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, ["broken"]);
+        let mut leftovers: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|v| v.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        leftovers.sort();
+        assert_eq!(leftovers, ["broken.bin"]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
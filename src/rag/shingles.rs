@@ -0,0 +1,87 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Words per shingle.
+const SHINGLE_SIZE: usize = 3;
+
+/// Finds the indexed text sharing the most word shingles with a given text, as long as their
+/// Jaccard similarity reaches `threshold`.
+#[derive(Debug)]
+pub struct ShingleIndex<K> {
+    threshold: f64,
+    sizes: HashMap<K, usize>,
+    postings: HashMap<u64, Vec<K>>,
+}
+
+impl<K: Copy + Ord + Hash> ShingleIndex<K> {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            sizes: HashMap::new(),
+            postings: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, text: &str) {
+        let shingles = shingles(text);
+        self.sizes.insert(key, shingles.len());
+        for shingle in shingles {
+            self.postings.entry(shingle).or_default().push(key);
+        }
+    }
+
+    /// The most similar indexed text, the lowest key winning ties.
+    pub fn find(&self, text: &str) -> Option<K> {
+        let shingles = shingles(text);
+        let mut overlaps: HashMap<K, usize> = HashMap::new();
+        for shingle in &shingles {
+            for key in self.postings.get(shingle).into_iter().flatten() {
+                *overlaps.entry(*key).or_default() += 1;
+            }
+        }
+        overlaps
+            .into_iter()
+            .map(|(key, overlap)| {
+                let union = shingles.len() + self.sizes[&key] - overlap;
+                (key, overlap as f64 / union as f64)
+            })
+            .filter(|(_, similarity)| *similarity >= self.threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(key, _)| key)
+    }
+}
+
+/// Hashes of the runs of `SHINGLE_SIZE` lowercased words, or of all the words of a shorter text.
+fn shingles(text: &str) -> HashSet<u64> {
+    let words: Vec<String> = text.unicode_words().map(|v| v.to_lowercase()).collect();
+    words
+        .windows(SHINGLE_SIZE.min(words.len()).max(1))
+        .map(|window| {
+            let mut hasher = DefaultHasher::new();
+            window.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shingle_index() {
+        let mut index = ShingleIndex::new(0.4);
+        index.insert(1, "Deploys happen on Tuesdays after the standup meeting");
+        index.insert(2, "Lunch is served at noon in the main kitchen");
+        assert_eq!(
+            index.find("deploys happen on Tuesdays after the weekly standup meeting"),
+            Some(1)
+        );
+        assert_eq!(index.find("Deploys happen on Thursdays"), None);
+        assert_eq!(index.find(""), None);
+
+        index.insert(0, "Short note");
+        assert_eq!(index.find("short NOTE"), Some(0));
+    }
+}
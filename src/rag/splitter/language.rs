@@ -4,6 +4,7 @@ pub enum Language {
     Go,
     Java,
     Js,
+    Ts,
     Php,
     Proto,
     Python,
@@ -84,6 +85,30 @@ impl Language {
                 " ",
                 "",
             ],
+            Language::Ts => vec![
+                "\nexport ",
+                "\nenum ",
+                "\ninterface ",
+                "\nnamespace ",
+                "\ntype ",
+                "\nclass ",
+                "\nfunction ",
+                "\nasync function ",
+                "\nconst ",
+                "\nlet ",
+                "\nvar ",
+                "\n  async ",
+                "\nif ",
+                "\nfor ",
+                "\nwhile ",
+                "\nswitch ",
+                "\ncase ",
+                "\ndefault ",
+                "\n\n",
+                "\n",
+                " ",
+                "",
+            ],
             Language::Php => vec![
                 "\nfunction ",
                 "\nclass ",
@@ -110,7 +135,18 @@ impl Language {
                 " ",
                 "",
             ],
-            Language::Python => vec!["\nclass ", "\ndef ", "\n\tdef ", "\n\n", "\n", " ", ""],
+            Language::Python => vec![
+                "\nclass ",
+                "\ndef ",
+                "\nasync def ",
+                "\n    def ",
+                "\n    async def ",
+                "\n\tdef ",
+                "\n\n",
+                "\n",
+                " ",
+                "",
+            ],
             Language::Rst => vec![
                 "\n===\n", "\n---\n", "\n***\n", "\n.. ", "\n\n", "\n", " ", "",
             ],
@@ -130,8 +166,29 @@ impl Language {
                 "",
             ],
             Language::Rust => vec![
-                "\nfn ", "\nconst ", "\nlet ", "\nif ", "\nwhile ", "\nfor ", "\nloop ",
-                "\nmatch ", "\nconst ", "\n\n", "\n", " ", "",
+                "\nimpl ",
+                "\npub fn ",
+                "\nfn ",
+                "\npub struct ",
+                "\nstruct ",
+                "\npub enum ",
+                "\nenum ",
+                "\npub trait ",
+                "\ntrait ",
+                "\nmod ",
+                "\n    pub fn ",
+                "\n    fn ",
+                "\nconst ",
+                "\nlet ",
+                "\nif ",
+                "\nwhile ",
+                "\nfor ",
+                "\nloop ",
+                "\nmatch ",
+                "\n\n",
+                "\n",
+                " ",
+                "",
             ],
             Language::Scala => vec![
                 "\nclass ",
@@ -3,15 +3,22 @@ mod language;
 pub use self::language::*;
 
 use super::{RagDocument, RagMetadata};
+use crate::utils::estimate_token_length;
+
+use serde::{Deserialize, Serialize};
 
 pub const DEFAULT_SEPARATES: [&str; 4] = ["\n\n", "\n", " ", ""];
+pub const BREADCRUMB_METADATA: &str = "breadcrumb";
+/// Set on chunks holding a code block that alone exceeds `chunk_size`.
+pub const OVERSIZED_METADATA: &str = "oversized";
 
 pub fn get_separators(extension: &str) -> Vec<&'static str> {
     match extension {
-        "c" | "cc" | "cpp" => Language::Cpp.separators(),
+        "c" | "cc" | "cpp" | "h" | "hpp" => Language::Cpp.separators(),
         "go" => Language::Go.separators(),
         "java" => Language::Java.separators(),
-        "js" | "mjs" | "cjs" => Language::Js.separators(),
+        "js" | "mjs" | "cjs" | "jsx" => Language::Js.separators(),
+        "ts" | "mts" | "cts" | "tsx" => Language::Ts.separators(),
         "php" => Language::Php.separators(),
         "proto" => Language::Proto.separators(),
         "py" => Language::Python.separators(),
@@ -20,7 +27,7 @@ pub fn get_separators(extension: &str) -> Vec<&'static str> {
         "rs" => Language::Rust.separators(),
         "scala" => Language::Scala.separators(),
         "swift" => Language::Swift.separators(),
-        "md" | "mkd" => Language::Markdown.separators(),
+        "md" | "mkd" | "mdx" => Language::Markdown.separators(),
         "tex" => Language::Latex.separators(),
         "htm" | "html" => Language::Html.separators(),
         "sol" => Language::Sol.separators(),
@@ -28,6 +35,54 @@ pub fn get_separators(extension: &str) -> Vec<&'static str> {
     }
 }
 
+/// Split `documents` with the splitter suited to files of `extension`.
+pub fn split_by_extension(
+    extension: &str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    size_unit: ChunkSizeUnit,
+    documents: &[RagDocument],
+    chunk_header_options: &SplitterChunkHeaderOptions,
+) -> Vec<RagDocument> {
+    match extension {
+        "md" | "mkd" | "mdx" => MarkdownHeaderTextSplitter::new(chunk_size, chunk_overlap)
+            .with_size_unit(size_unit)
+            .split_documents(documents, chunk_header_options),
+        _ => RecursiveCharacterTextSplitter::new(
+            chunk_size,
+            chunk_overlap,
+            &get_separators(extension),
+        )
+        .with_size_unit(size_unit)
+        .split_documents(documents, chunk_header_options),
+    }
+}
+
+/// The unit `chunk_size` and `chunk_overlap` are measured in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkSizeUnit {
+    #[default]
+    Chars,
+    Tokens,
+}
+
+impl ChunkSizeUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Chars => "chars",
+            Self::Tokens => "tokens",
+        }
+    }
+
+    pub fn length(&self, text: &str) -> usize {
+        match self {
+            Self::Chars => text.len(),
+            Self::Tokens => estimate_token_length(text),
+        }
+    }
+}
+
 pub struct RecursiveCharacterTextSplitter {
     pub chunk_size: usize,
     pub chunk_overlap: usize,
@@ -69,6 +124,11 @@ impl RecursiveCharacterTextSplitter {
         self
     }
 
+    pub fn with_size_unit(mut self, size_unit: ChunkSizeUnit) -> Self {
+        self.length_function = Box::new(move |text| size_unit.length(text));
+        self
+    }
+
     pub fn split_documents(
         &self,
         documents: &[RagDocument],
@@ -248,6 +308,239 @@ impl RecursiveCharacterTextSplitter {
     }
 }
 
+/// Splits markdown on headings, prefixing each chunk with the breadcrumb of its headings.
+/// Sections longer than `chunk_size` are further split by the recursive splitter,
+/// except fenced code blocks, which are never split.
+pub struct MarkdownHeaderTextSplitter {
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub size_unit: ChunkSizeUnit,
+}
+
+impl MarkdownHeaderTextSplitter {
+    pub fn new(chunk_size: usize, chunk_overlap: usize) -> Self {
+        Self {
+            chunk_size,
+            chunk_overlap,
+            size_unit: Default::default(),
+        }
+    }
+
+    pub fn with_size_unit(mut self, size_unit: ChunkSizeUnit) -> Self {
+        self.size_unit = size_unit;
+        self
+    }
+
+    pub fn split_documents(
+        &self,
+        documents: &[RagDocument],
+        chunk_header_options: &SplitterChunkHeaderOptions,
+    ) -> Vec<RagDocument> {
+        let splitter = RecursiveCharacterTextSplitter::new(
+            self.chunk_size,
+            self.chunk_overlap,
+            &Language::Markdown.separators(),
+        )
+        .with_size_unit(self.size_unit);
+        let mut output = vec![];
+        for document in documents {
+            for (breadcrumb, text) in self.split_sections(&document.page_content) {
+                let mut metadata = document.metadata.clone();
+                if !breadcrumb.is_empty() {
+                    metadata.insert(BREADCRUMB_METADATA.into(), breadcrumb.clone());
+                }
+                let chunk_header = |chunk: &str| {
+                    let mut chunk_header = chunk_header_options.chunk_header.clone();
+                    let breadcrumb = chunk_breadcrumb(&breadcrumb, chunk);
+                    if !breadcrumb.is_empty() {
+                        chunk_header.push_str(&format!("{breadcrumb}\n\n"));
+                    }
+                    chunk_header
+                };
+                let text = text.trim();
+                if self.size_unit.length(text) <= self.chunk_size {
+                    output.push(RagDocument {
+                        page_content: format!("{}{text}", chunk_header(text)),
+                        metadata,
+                    });
+                    continue;
+                }
+                let chunks = if text.contains("```") || text.contains("~~~") {
+                    self.split_keeping_fences(text, &splitter)
+                } else {
+                    let chunks = splitter.split_text(text);
+                    chunks.into_iter().map(|v| (v, false)).collect()
+                };
+                for (index, (chunk, oversized)) in chunks.into_iter().enumerate() {
+                    let mut page_content = chunk_header(&chunk);
+                    if index > 0 {
+                        if let Some(overlap_header) = &chunk_header_options.chunk_overlap_header {
+                            page_content.push_str(overlap_header);
+                        }
+                    }
+                    page_content.push_str(&chunk);
+                    let mut metadata = metadata.clone();
+                    if oversized {
+                        metadata.insert(OVERSIZED_METADATA.into(), "true".into());
+                    }
+                    output.push(RagDocument {
+                        page_content,
+                        metadata,
+                    });
+                }
+            }
+        }
+        output
+    }
+
+    /// Split `text` into chunks, treating fenced code blocks as atomic and splitting only the prose
+    /// around them. Returns each chunk and whether it is a code block exceeding `chunk_size`.
+    ///
+    /// Code blocks are merged with neighbouring prose chunks that have room for them, but prose chunks
+    /// are left as the splitter made them, keeping their overlap.
+    fn split_keeping_fences(
+        &self,
+        text: &str,
+        splitter: &RecursiveCharacterTextSplitter,
+    ) -> Vec<(String, bool)> {
+        // Each piece is tagged with the prose run or code block it comes from.
+        let mut pieces: Vec<(String, bool, usize)> = vec![];
+        let push_prose = |pieces: &mut Vec<(String, bool, usize)>, prose: &str| {
+            if !prose.trim().is_empty() {
+                let segment = pieces.last().map(|v| v.2 + 1).unwrap_or_default();
+                let chunks = splitter.split_text(prose);
+                pieces.extend(chunks.into_iter().map(|v| (v, false, segment)));
+            }
+        };
+        let push_code = |pieces: &mut Vec<(String, bool, usize)>, code: &str| {
+            let segment = pieces.last().map(|v| v.2 + 1).unwrap_or_default();
+            pieces.push((code.trim().to_string(), true, segment));
+        };
+        let mut current = String::new();
+        let mut in_fence = false;
+        for line in text.split_inclusive('\n') {
+            let trimmed = line.trim_start();
+            let is_fence = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+            if is_fence && !in_fence {
+                push_prose(&mut pieces, &current);
+                current.clear();
+            }
+            current.push_str(line);
+            if is_fence && in_fence {
+                push_code(&mut pieces, &current);
+                current.clear();
+            }
+            if is_fence {
+                in_fence = !in_fence;
+            }
+        }
+        if in_fence {
+            push_code(&mut pieces, &current);
+        } else {
+            push_prose(&mut pieces, &current);
+        }
+
+        let mut chunks = vec![];
+        let mut buffer = String::new();
+        let mut buffer_segment = None;
+        for (piece, is_code, segment) in pieces {
+            let oversized = is_code && self.size_unit.length(&piece) > self.chunk_size;
+            let merged = format!("{buffer}\n\n{piece}");
+            if !buffer.is_empty()
+                && (oversized
+                    || buffer_segment == Some(segment)
+                    || self.size_unit.length(&merged) > self.chunk_size)
+            {
+                chunks.push((std::mem::take(&mut buffer), false));
+            }
+            buffer_segment = Some(segment);
+            if oversized {
+                chunks.push((piece, true));
+            } else if buffer.is_empty() {
+                buffer = piece;
+            } else {
+                buffer = merged;
+            }
+        }
+        if !buffer.is_empty() {
+            chunks.push((buffer, false));
+        }
+        chunks
+    }
+
+    /// Split `text` before every heading outside code fences, pairing each section with its breadcrumb.
+    pub fn split_sections(&self, text: &str) -> Vec<(String, String)> {
+        let mut sections = vec![];
+        let mut headings: Vec<(usize, String)> = vec![];
+        let mut current = String::new();
+        let mut in_fence = false;
+        for line in text.split_inclusive('\n') {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_fence = !in_fence;
+            } else if !in_fence {
+                if let Some((level, title)) = parse_markdown_heading(line) {
+                    push_section(&mut sections, &headings, &mut current);
+                    headings.retain(|(v, _)| *v < level);
+                    headings.push((level, title));
+                }
+            }
+            current.push_str(line);
+        }
+        push_section(&mut sections, &headings, &mut current);
+        sections
+    }
+}
+
+fn push_section(
+    sections: &mut Vec<(String, String)>,
+    headings: &[(usize, String)],
+    current: &mut String,
+) {
+    let text = std::mem::take(current);
+    if text.trim().is_empty() {
+        return;
+    }
+    let breadcrumb = headings
+        .iter()
+        .map(|(_, title)| title.as_str())
+        .collect::<Vec<&str>>()
+        .join(" > ");
+    sections.push((breadcrumb, text));
+}
+
+/// The breadcrumb to prefix `chunk` with, leaving out the last heading if the chunk starts with it.
+fn chunk_breadcrumb<'a>(breadcrumb: &'a str, chunk: &str) -> &'a str {
+    let first_line = chunk.lines().next().unwrap_or_default();
+    let Some((_, title)) = parse_markdown_heading(first_line) else {
+        return breadcrumb;
+    };
+    if breadcrumb == title {
+        return "";
+    }
+    breadcrumb
+        .strip_suffix(&title)
+        .and_then(|v| v.strip_suffix(" > "))
+        .unwrap_or(breadcrumb)
+}
+
+fn parse_markdown_heading(line: &str) -> Option<(usize, String)> {
+    let line = line.trim_end();
+    let level = line.chars().take_while(|v| *v == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim();
+    if title.is_empty() {
+        return None;
+    }
+    Some((level, title.to_string()))
+}
+
 pub struct SplitterChunkHeaderOptions {
     pub chunk_header: String,
     pub chunk_overlap_header: Option<String>,
@@ -420,6 +713,255 @@ As an open source project in a rapidly developing field, we are extremely open t
         assert_eq!(output, expected_output);
     }
 
+    #[test]
+    fn test_markdown_header_splitter() {
+        let text = r#"Intro
+
+# Guide
+
+Read me.
+
+## Install
+
+```bash
+# not a heading
+cargo install aichat
+```
+
+### Linux ###
+
+Use the package manager.
+
+# FAQ
+
+Ask away."#;
+        let splitter = MarkdownHeaderTextSplitter::new(1000, 0);
+        let sections: Vec<String> = splitter
+            .split_sections(text)
+            .into_iter()
+            .map(|(breadcrumb, _)| breadcrumb)
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                "",
+                "Guide",
+                "Guide > Install",
+                "Guide > Install > Linux",
+                "FAQ"
+            ]
+        );
+
+        let options = SplitterChunkHeaderOptions::default().with_chunk_header("HEADER\n");
+        let output = splitter.split_documents(&[RagDocument::new(text)], &options);
+        assert_eq!(output.len(), 5);
+        assert_eq!(
+            output[2].page_content,
+            "HEADER\nGuide\n\n## Install\n\n```bash\n# not a heading\ncargo install aichat\n```"
+        );
+        assert_eq!(output[2].metadata[BREADCRUMB_METADATA], "Guide > Install");
+        assert!(!output[0].metadata.contains_key(BREADCRUMB_METADATA));
+        assert_eq!(output[1].page_content, "HEADER\n# Guide\n\nRead me.");
+
+        let splitter = MarkdownHeaderTextSplitter::new(30, 0);
+        let output = splitter.split_documents(
+            &[RagDocument::new(
+                "# Long\n\nThe first paragraph.\n\nThe second paragraph.",
+            )],
+            &SplitterChunkHeaderOptions::default(),
+        );
+        let contents: Vec<&str> = output.iter().map(|v| v.page_content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "# Long\n\nThe first paragraph.",
+                "Long\n\nThe second paragraph."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_markdown_splitter_keeps_code_fences() {
+        let code = (0..20)
+            .map(|i| format!("let value_{i} = compute({i});"))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let text = format!(
+            "# Usage\n\nSome intro text before the example.\n\n```rust\n{code}\n\nprintln!(\"done\");\n```\n\nSome closing words after the example."
+        );
+        let splitter = MarkdownHeaderTextSplitter::new(100, 0);
+        let output = splitter.split_documents(
+            &[RagDocument::new(text)],
+            &SplitterChunkHeaderOptions::default(),
+        );
+        let fenced: Vec<&RagDocument> = output
+            .iter()
+            .filter(|v| v.page_content.contains("```"))
+            .collect();
+        assert_eq!(fenced.len(), 1);
+        assert_eq!(
+            fenced[0].page_content,
+            format!("Usage\n\n```rust\n{code}\n\nprintln!(\"done\");\n```")
+        );
+        assert_eq!(fenced[0].metadata[OVERSIZED_METADATA], "true");
+        let contents: Vec<&str> = output.iter().map(|v| v.page_content.as_str()).collect();
+        assert_eq!(
+            contents[0],
+            "# Usage\n\nSome intro text before the example."
+        );
+        assert_eq!(
+            contents[2],
+            "Usage\n\nSome closing words after the example."
+        );
+        assert!(!output[0].metadata.contains_key(OVERSIZED_METADATA));
+    }
+
+    #[test]
+    fn test_markdown_splitter_keeps_overlap() {
+        let prose = "alpha beta gamma delta epsilon zeta eta theta iota kappa lambda mu nu xi";
+        let splitter = MarkdownHeaderTextSplitter::new(30, 12);
+        let recursive =
+            RecursiveCharacterTextSplitter::new(30, 12, &Language::Markdown.separators());
+        let output = splitter.split_documents(
+            &[RagDocument::new(format!("# Notes\n\n{prose}"))],
+            &SplitterChunkHeaderOptions::default(),
+        );
+        let contents: Vec<&str> = output.iter().map(|v| v.page_content.as_str()).collect();
+        let expected: Vec<String> = recursive
+            .split_text(&format!("# Notes\n\n{prose}"))
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| if i == 0 { v } else { format!("Notes\n\n{v}") })
+            .collect();
+        assert_eq!(contents, expected);
+
+        let text = format!("# Notes\n\n{prose}\n\n```\nrun\n```\n\n{prose}");
+        let output = splitter.split_documents(
+            &[RagDocument::new(text)],
+            &SplitterChunkHeaderOptions::default(),
+        );
+        let contents: Vec<&str> = output.iter().map(|v| v.page_content.as_str()).collect();
+        // The prose around the code block keeps the overlap between its chunks.
+        let prose_chunks = [
+            "Notes\n\nalpha beta gamma delta",
+            "Notes\n\ngamma delta epsilon zeta eta",
+            "Notes\n\nzeta eta theta iota kappa",
+            "Notes\n\niota kappa lambda mu nu xi",
+        ];
+        assert_eq!(contents[0], "# Notes");
+        assert_eq!(contents[1..5], prose_chunks);
+        assert_eq!(contents[5], "Notes\n\n```\nrun\n```");
+        assert_eq!(contents[6..], prose_chunks);
+    }
+
+    #[test]
+    fn test_token_size_unit() {
+        let text = "数据库连接池配置 缓存失效策略 日志轮转规则";
+        let splitter = RecursiveCharacterTextSplitter::new(30, 0, &[" "]);
+        assert_eq!(splitter.split_text(text).len(), 3);
+        let splitter = splitter.with_size_unit(ChunkSizeUnit::Tokens);
+        assert_eq!(splitter.split_text(text), vec![text]);
+    }
+
+    #[test]
+    fn test_token_size_unit_uniform_chunks() {
+        let ascii = vec!["The cache keeps the most recent entries in memory."; 60].join("\n");
+        let cjk = vec!["缓存在内存中保存最近使用的条目。"; 60].join("\n");
+        let max_token_length = |size_unit: ChunkSizeUnit, chunk_size: usize, text: &str| {
+            let splitter = RecursiveCharacterTextSplitter::new(chunk_size, 0, &DEFAULT_SEPARATES)
+                .with_size_unit(size_unit);
+            let chunks = splitter.split_text(text);
+            chunks
+                .iter()
+                .map(|v| estimate_token_length(v))
+                .max()
+                .unwrap_or_default() as f32
+        };
+        let ascii_max = max_token_length(ChunkSizeUnit::Chars, 400, &ascii);
+        let cjk_max = max_token_length(ChunkSizeUnit::Chars, 400, &cjk);
+        assert!(cjk_max > 100.0 && cjk_max / ascii_max > 1.3);
+
+        let ascii_max = max_token_length(ChunkSizeUnit::Tokens, 100, &ascii);
+        let cjk_max = max_token_length(ChunkSizeUnit::Tokens, 100, &cjk);
+        assert!(ascii_max <= 100.0 && cjk_max <= 100.0);
+        assert!(cjk_max / ascii_max < 1.2);
+    }
+
+    #[test]
+    fn test_rust_splitter() {
+        let text = r#"use std::fmt;
+
+pub struct Point {
+    x: i32,
+    y: i32,
+}
+
+impl Point {
+    pub fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    fn norm(&self) -> f64 {
+        ((self.x * self.x + self.y * self.y) as f64).sqrt()
+    }
+}
+
+fn main() {
+    let point = Point::new(3, 4);
+    println!("{}", point.norm());
+}"#;
+        let splitter = RecursiveCharacterTextSplitter::new(100, 0, &get_separators("rs"));
+        let output = splitter.split_text(text);
+        let starts: Vec<&str> = output
+            .iter()
+            .map(|v| v.lines().next().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            starts,
+            vec![
+                "use std::fmt;",
+                "impl Point {",
+                "pub fn new(x: i32, y: i32) -> Self {",
+                "fn norm(&self) -> f64 {",
+                "fn main() {",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_python_splitter() {
+        let text = r#"import math
+
+
+class Point:
+    def __init__(self, x, y):
+        self.x = x
+        self.y = y
+
+    def norm(self):
+        return math.sqrt(self.x ** 2 + self.y ** 2)
+
+
+def main():
+    print(Point(3, 4).norm())"#;
+        let splitter = RecursiveCharacterTextSplitter::new(80, 0, &get_separators("py"));
+        let output = splitter.split_text(text);
+        let starts: Vec<&str> = output
+            .iter()
+            .map(|v| v.lines().next().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            starts,
+            vec![
+                "import math",
+                "class Point:",
+                "def __init__(self, x, y):",
+                "def norm(self):",
+                "def main():",
+            ]
+        );
+    }
+
     #[test]
     fn test_html_splitter() {
         let text = r#"<!DOCTYPE html>
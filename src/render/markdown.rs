@@ -4,8 +4,12 @@ use crossterm::style::{Color, Stylize};
 use crossterm::terminal;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
-use syntect::highlighting::{Color as SyntectColor, FontStyle, Style, Theme};
-use syntect::parsing::SyntaxSet;
+use std::path::Path;
+use syntect::highlighting::{
+    Color as SyntectColor, FontStyle, HighlightIterator, HighlightState, Highlighter, Style, Theme,
+    ThemeSet,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
 use syntect::{easy::HighlightLines, parsing::SyntaxReference};
 
 /// Comes from https://github.com/sharkdp/bat/raw/5e77ca37e89c873e4490b42ff556370dc5c6ba4f/assets/syntaxes.bin
@@ -18,23 +22,26 @@ lazy_static! {
         m.insert("php".into(), "PHP Source".into());
         m
     };
+    /// Decoded once, as decoding takes tens of milliseconds and a render is set up per reply.
+    static ref SYNTAX_SET: SyntaxSet =
+        bincode::deserialize_from(SYNTAXES).expect("MarkdownRender: invalid syntaxes binary");
+    static ref DEFAULT_THEMES: ThemeSet = ThemeSet::load_defaults();
 }
 
 pub struct MarkdownRender {
     options: RenderOptions,
-    syntax_set: SyntaxSet,
+    syntax_set: &'static SyntaxSet,
     code_color: Option<Color>,
     md_syntax: SyntaxReference,
     code_syntax: Option<SyntaxReference>,
+    code_state: Option<CodeHighlightState>,
     prev_line_type: LineType,
     wrap_width: Option<u16>,
 }
 
 impl MarkdownRender {
     pub fn init(options: RenderOptions) -> Result<Self> {
-        let syntax_set: SyntaxSet = bincode::deserialize_from(SYNTAXES)
-            .with_context(|| "MarkdownRender: invalid syntaxes binary")?;
-
+        let syntax_set: &'static SyntaxSet = &SYNTAX_SET;
         let code_color = options
             .theme
             .as_ref()
@@ -62,6 +69,7 @@ impl MarkdownRender {
             code_color,
             md_syntax,
             code_syntax: None,
+            code_state: None,
             prev_line_type: line_type,
             wrap_width,
             options,
@@ -69,6 +77,9 @@ impl MarkdownRender {
     }
 
     pub fn render(&mut self, text: &str) -> String {
+        if self.is_plain() {
+            return text.to_string();
+        }
         text.split('\n')
             .map(|line| self.render_line_mut(line))
             .collect::<Vec<String>>()
@@ -76,20 +87,33 @@ impl MarkdownRender {
     }
 
     pub fn render_line(&self, line: &str) -> String {
+        if self.is_plain() {
+            return line.to_string();
+        }
         let (_, code_syntax, is_code) = self.check_line(line);
         if is_code {
-            self.highlight_code_line(line, &code_syntax)
+            let mut code_state = self.code_state.clone();
+            self.highlight_code_line(line, &code_syntax, &mut code_state)
         } else {
-            self.highlight_line(line, &self.md_syntax, false)
+            self.highlight_markdown_line(line)
         }
     }
 
+    /// Without a theme or wrapping, rendering leaves the text as it is.
+    fn is_plain(&self) -> bool {
+        self.options.theme.is_none() && self.wrap_width.is_none()
+    }
+
     fn render_line_mut(&mut self, line: &str) -> String {
         let (line_type, code_syntax, is_code) = self.check_line(line);
         let output = if is_code {
-            self.highlight_code_line(line, &code_syntax)
+            let mut code_state = self.code_state.take();
+            let output = self.highlight_code_line(line, &code_syntax, &mut code_state);
+            self.code_state = code_state;
+            output
         } else {
-            self.highlight_line(line, &self.md_syntax, false)
+            self.code_state = None;
+            self.highlight_markdown_line(line)
         };
         self.prev_line_type = line_type;
         self.code_syntax = code_syntax;
@@ -141,30 +165,70 @@ impl MarkdownRender {
     fn highlight_line(&self, line: &str, syntax: &SyntaxReference, is_code: bool) -> String {
         let ws: String = line.chars().take_while(|c| c.is_whitespace()).collect();
         let trimmed_line: &str = &line[ws.len()..];
-        let mut line_highlighted = None;
-        if let Some(theme) = &self.options.theme {
-            let mut highlighter = HighlightLines::new(syntax, theme);
-            if let Ok(ranges) = highlighter.highlight_line(trimmed_line, &self.syntax_set) {
-                line_highlighted = Some(format!(
-                    "{ws}{}",
-                    as_terminal_escaped(&ranges, self.options.truecolor)
-                ))
-            }
-        }
+        let line_highlighted = self
+            .highlight_text(trimmed_line, syntax)
+            .map(|v| format!("{ws}{v}"));
         let line = line_highlighted.unwrap_or_else(|| line.into());
         self.wrap_line(line, is_code)
     }
 
-    fn highlight_code_line(&self, line: &str, code_syntax: &Option<SyntaxReference>) -> String {
-        if let Some(syntax) = code_syntax {
-            self.highlight_line(line, syntax, true)
-        } else {
-            let line = match self.code_color {
+    fn highlight_text(&self, text: &str, syntax: &SyntaxReference) -> Option<String> {
+        let theme = self.options.theme.as_ref()?;
+        let mut highlighter = HighlightLines::new(syntax, theme);
+        let ranges = highlighter.highlight_line(text, self.syntax_set).ok()?;
+        Some(as_terminal_escaped(&ranges, self.options.truecolor))
+    }
+
+    /// Highlight a markdown line, styling its inline code, bold and italic spans explicitly as
+    /// highlighting a single line with the markdown syntax gets them wrong.
+    fn highlight_markdown_line(&self, line: &str) -> String {
+        let spans = parse_inline_spans(line);
+        let unchanged = match spans.as_slice() {
+            [] => true,
+            [InlineSpan::Text(text)] => text == line,
+            _ => false,
+        };
+        if self.options.theme.is_none() || unchanged {
+            return self.highlight_line(line, &self.md_syntax, false);
+        }
+        let mut output = String::new();
+        for span in spans {
+            match span {
+                InlineSpan::Text(text) => match self.highlight_text(&text, &self.md_syntax) {
+                    Some(text) => output.push_str(&text),
+                    None => output.push_str(&text),
+                },
+                InlineSpan::Code(text) => match self.code_color {
+                    Some(color) => output.push_str(&text.with(color).to_string()),
+                    None => output.push_str(text),
+                },
+                InlineSpan::Bold(text) => output.push_str(&text.bold().to_string()),
+                InlineSpan::Italic(text) => output.push_str(&text.italic().to_string()),
+            }
+        }
+        self.wrap_line(output, false)
+    }
+
+    /// Highlight a line inside a code block, continuing from `code_state` which is created at the
+    /// first highlighted line of the block.
+    fn highlight_code_line(
+        &self,
+        line: &str,
+        code_syntax: &Option<SyntaxReference>,
+        code_state: &mut Option<CodeHighlightState>,
+    ) -> String {
+        let line = match (code_syntax, &self.options.theme) {
+            (Some(syntax), Some(theme)) => code_state
+                .get_or_insert_with(|| CodeHighlightState::new(syntax, theme))
+                .highlight_line(line, theme, self.syntax_set, self.options.truecolor)
+                .unwrap_or_else(|| line.into()),
+            (Some(_), None) => line.into(),
+            (None, _) => match self.code_color {
                 Some(color) => line.with(color).to_string(),
                 None => line.to_string(),
-            };
-            self.wrap_line(line, true)
-        }
+            },
+        };
+        self.wrap_line(line, true)
     }
 
     fn wrap_line(&self, line: String, is_code: bool) -> String {
@@ -189,6 +253,20 @@ impl MarkdownRender {
     }
 }
 
+/// Find a builtin syntect theme by name, or load a `.tmTheme` file when `name` is a path.
+/// "auto" gives `None`, leaving the choice to the terminal background.
+pub fn load_theme(name: &str) -> Result<Option<Theme>> {
+    if name == "auto" {
+        return Ok(None);
+    }
+    if name.ends_with(".tmTheme") {
+        let theme = ThemeSet::get_theme(Path::new(name))
+            .with_context(|| format!("Invalid theme at {name}"))?;
+        return Ok(Some(theme));
+    }
+    Ok(DEFAULT_THEMES.themes.get(name).cloned())
+}
+
 fn wrap(text: &str, width: usize) -> String {
     let indent: usize = text.chars().take_while(|c| *c == ' ').count();
     let wrap_options = textwrap::Options::new(width)
@@ -221,6 +299,43 @@ impl RenderOptions {
     }
 }
 
+/// The parser and highlighter state of a code block, carried from line to line so constructs
+/// spanning lines, like block comments and multi-line strings, are highlighted as a whole.
+#[derive(Debug, Clone)]
+struct CodeHighlightState {
+    parse_state: ParseState,
+    highlight_state: HighlightState,
+}
+
+impl CodeHighlightState {
+    fn new(syntax: &SyntaxReference, theme: &Theme) -> Self {
+        let highlighter = Highlighter::new(theme);
+        Self {
+            parse_state: ParseState::new(syntax),
+            highlight_state: HighlightState::new(&highlighter, ScopeStack::new()),
+        }
+    }
+
+    fn highlight_line(
+        &mut self,
+        line: &str,
+        theme: &Theme,
+        syntax_set: &SyntaxSet,
+        truecolor: bool,
+    ) -> Option<String> {
+        // Syntaxes expect the newline, e.g. to end line comments.
+        let line = format!("{line}\n");
+        let ops = self.parse_state.parse_line(&line, syntax_set).ok()?;
+        let highlighter = Highlighter::new(theme);
+        let ranges: Vec<(Style, &str)> =
+            HighlightIterator::new(&mut self.highlight_state, &ops, &line, &highlighter)
+                .map(|(style, text)| (style, text.strip_suffix('\n').unwrap_or(text)))
+                .filter(|(_, text)| !text.is_empty())
+                .collect();
+        Some(as_terminal_escaped(&ranges, truecolor))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LineType {
     Normal,
@@ -279,6 +394,109 @@ fn blend_fg_color(fg: SyntectColor, bg: SyntectColor) -> SyntectColor {
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+enum InlineSpan<'a> {
+    Text(String),
+    Code(&'a str),
+    Bold(&'a str),
+    Italic(&'a str),
+}
+
+/// Split a line into inline markdown spans. Markers without a match, e.g. in a line still being
+/// streamed, stay as text.
+fn parse_inline_spans(line: &str) -> Vec<InlineSpan<'_>> {
+    let mut spans = vec![];
+    let mut text = String::new();
+    let mut pos = 0;
+    while let Some(c) = line[pos..].chars().next() {
+        let rest = &line[pos..];
+        let mut span = None;
+        let mut marker = &rest[..c.len_utf8()];
+        match c {
+            '\\' => {
+                if let Some(next) = rest[1..].chars().next().filter(|v| "`*_\\".contains(*v)) {
+                    text.push(next);
+                    pos += 1 + next.len_utf8();
+                    continue;
+                }
+            }
+            '`' => {
+                marker = &rest[..rest.len() - rest.trim_start_matches('`').len()];
+                let body = &rest[marker.len()..];
+                if let Some(end) = find_closing_backticks(body, marker.len()) {
+                    span = Some((InlineSpan::Code(&body[..end]), end));
+                }
+            }
+            '*' | '_' => {
+                if rest[1..].starts_with(c) {
+                    marker = &rest[..2];
+                }
+                let body = &rest[marker.len()..];
+                let prev = line[..pos].chars().next_back();
+                let opens = body.starts_with(|v: char| !v.is_whitespace())
+                    && !(c == '_' && prev.is_some_and(char::is_alphanumeric));
+                if opens {
+                    if let Some(end) = find_closing_emphasis(body, marker) {
+                        let span_body = &body[..end];
+                        span = match marker.len() {
+                            2 => Some((InlineSpan::Bold(span_body), end)),
+                            _ => Some((InlineSpan::Italic(span_body), end)),
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+        match span {
+            Some((span, end)) => {
+                if !text.is_empty() {
+                    spans.push(InlineSpan::Text(std::mem::take(&mut text)));
+                }
+                spans.push(span);
+                pos += marker.len() * 2 + end;
+            }
+            None => {
+                text.push_str(marker);
+                pos += marker.len();
+            }
+        }
+    }
+    if !text.is_empty() {
+        spans.push(InlineSpan::Text(text));
+    }
+    spans
+}
+
+/// Find the run of exactly `count` backticks closing a code span.
+fn find_closing_backticks(body: &str, count: usize) -> Option<usize> {
+    let mut pos = 0;
+    while let Some(start) = body[pos..].find('`').map(|v| pos + v) {
+        let run = body[start..].len() - body[start..].trim_start_matches('`').len();
+        if run == count && start > 0 {
+            return Some(start);
+        }
+        pos = start + run;
+    }
+    None
+}
+
+fn find_closing_emphasis(body: &str, marker: &str) -> Option<usize> {
+    let c = marker.chars().next()?;
+    let mut pos = 0;
+    while let Some(start) = body[pos..].find(marker).map(|v| pos + v) {
+        let before = body[..start].chars().next_back();
+        let after = body[start + marker.len()..].chars().next();
+        let closes = before.is_some_and(|v| !v.is_whitespace() && v != c)
+            && after != Some(c)
+            && !(c == '_' && after.is_some_and(char::is_alphanumeric));
+        if closes {
+            return Some(start);
+        }
+        pos = start + marker.len();
+    }
+    None
+}
+
 fn detect_code_block(line: &str) -> Option<String> {
     let line = line.trim_start();
     if !line.starts_with("```") {
@@ -344,6 +562,27 @@ std::error::Error>> {
     todo!()
 }
 ```
+"#;
+
+    const TINY_THEME: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+  <key>name</key>
+  <string>Tiny</string>
+  <key>settings</key>
+  <array>
+    <dict>
+      <key>settings</key>
+      <dict>
+        <key>background</key>
+        <string>#FFFFFF</string>
+        <key>foreground</key>
+        <string>#000000</string>
+      </dict>
+    </dict>
+  </array>
+</dict>
+</plist>
 "#;
 
     #[test]
@@ -361,6 +600,16 @@ std::error::Error>> {
         assert_eq!(TEXT, output);
     }
 
+    #[test]
+    fn test_plain_mode() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        let text = format!("{TEXT}\nuse `zip`, **really** *now*\n```\nplain code\n```");
+        let output = render.render(&text);
+        assert!(!output.contains("\x1b["));
+        assert_eq!(output, text);
+        assert_eq!(render.render_line("see `zi"), "see `zi");
+    }
+
     #[test]
     fn no_wrap_code() {
         let options = RenderOptions::default();
@@ -382,6 +631,120 @@ std::error::Error>> {
         assert_eq!(TEXT_WRAP_ALL, output);
     }
 
+    #[test]
+    fn test_init_reuses_syntax_set() {
+        let render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        let other = MarkdownRender::init(RenderOptions::default()).unwrap();
+        assert!(std::ptr::eq(render.syntax_set, other.syntax_set));
+        let theme = load_theme("InspiredGitHub").unwrap().unwrap();
+        assert_eq!(theme.name, DEFAULT_THEMES.themes["InspiredGitHub"].name);
+    }
+
+    #[test]
+    fn test_load_theme() {
+        assert!(load_theme("auto").unwrap().is_none());
+        assert!(load_theme("Solarized (dark)").unwrap().is_some());
+        assert!(load_theme("InspiredGitHub").unwrap().is_some());
+        assert!(load_theme("Solarized").unwrap().is_none());
+
+        let dir = std::env::temp_dir().join(format!("aichat-theme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tiny.tmTheme").display().to_string();
+        assert!(load_theme(&path).is_err());
+        std::fs::write(&path, TINY_THEME).unwrap();
+        let theme = load_theme(&path).unwrap().unwrap();
+        assert_eq!(theme.name.as_deref(), Some("Tiny"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_inline_spans() {
+        use InlineSpan::*;
+        let text = |v: &str| Text(v.to_string());
+        assert_eq!(
+            parse_inline_spans("use the `zip`, **really** *now*"),
+            [
+                text("use the "),
+                Code("zip"),
+                text(", "),
+                Bold("really"),
+                text(" "),
+                Italic("now")
+            ]
+        );
+        assert_eq!(
+            parse_inline_spans("``a ` b`` and __c__ _d_"),
+            [
+                Code("a ` b"),
+                text(" and "),
+                Bold("c"),
+                text(" "),
+                Italic("d")
+            ]
+        );
+        assert_eq!(
+            parse_inline_spans(r"\`not code\` in snake_case_name"),
+            [text("`not code` in snake_case_name")]
+        );
+        assert_eq!(parse_inline_spans("* item 2 * 3"), [text("* item 2 * 3")]);
+        assert_eq!(parse_inline_spans("see `zi"), [text("see `zi")]);
+        assert_eq!(parse_inline_spans("**bo"), [text("**bo")]);
+    }
+
+    #[test]
+    fn test_render_inline_spans() {
+        let options = RenderOptions {
+            theme: load_theme("Solarized (dark)").unwrap(),
+            ..Default::default()
+        };
+        let render = MarkdownRender::init(options).unwrap();
+        let code_color = render.code_color.unwrap();
+        let output = render.render_line("use `zip`, **really** *now*");
+        assert!(output.contains(&"zip".with(code_color).to_string()));
+        assert!(output.contains(&"really".bold().to_string()));
+        assert!(output.contains(&"now".italic().to_string()));
+        assert!(!output.contains('`') && !output.contains('*'));
+
+        let output = render.render_line("see `zi");
+        assert_eq!(
+            output,
+            render.highlight_line("see `zi", &render.md_syntax, false)
+        );
+        let output = render.render_line(r"\`zip\`");
+        assert!(!output.contains('\\'));
+    }
+
+    #[test]
+    fn test_code_block_multi_line_constructs() {
+        let theme = load_theme("Solarized (dark)").unwrap().unwrap();
+        let options = RenderOptions {
+            theme: Some(theme.clone()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let syntax = render.find_syntax("rust").unwrap().clone();
+        let comment = HighlightLines::new(&syntax, &theme)
+            .highlight_line("/* comment", render.syntax_set)
+            .unwrap()
+            .last()
+            .unwrap()
+            .0;
+        let comment_color = convert_color(comment.foreground, false);
+
+        let output =
+            render.render("```rust\n/* open\nlet x = 1; */\nlet s = \"a\nb\";\n```\nlet y");
+        let lines: Vec<&str> = output.split('\n').collect();
+        assert!(lines[2].contains(&"let x = 1; ".with(comment_color).to_string()));
+        let mut fresh = CodeHighlightState::new(&syntax, &theme);
+        let fresh = fresh.highlight_line("b\";", &theme, render.syntax_set, false);
+        assert_ne!(Some(lines[4].to_string()), fresh);
+        assert!(render.code_state.is_none());
+
+        render.render("```rust\n/* open");
+        assert_eq!(render.render_line("let x = 1; */"), lines[2]);
+        assert_eq!(render.render_line("let x = 1; */"), lines[2]);
+    }
+
     #[test]
     fn test_detect_code_block() {
         assert_eq!(detect_code_block("```rust"), Some("rust".into()));
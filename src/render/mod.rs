@@ -1,7 +1,7 @@
 mod markdown;
 mod stream;
 
-pub use self::markdown::{MarkdownRender, RenderOptions};
+pub use self::markdown::{load_theme, MarkdownRender, RenderOptions};
 use self::stream::{markdown_stream, raw_stream};
 
 use crate::utils::{error_text, AbortSignal, IS_STDOUT_TERMINAL};
@@ -17,11 +17,18 @@ pub async fn render_stream(
 ) -> Result<()> {
     if *IS_STDOUT_TERMINAL {
         let render_options = config.read().render_options()?;
-        let mut render = MarkdownRender::init(render_options)?;
-        markdown_stream(rx, &mut render, &abort).await
-    } else {
-        raw_stream(rx, &abort).await
+        // Plain output, without cursor movements either, when there is neither color nor wrapping.
+        if render_options.theme.is_some() || render_options.wrap.is_some() {
+            // The highlighter state of syntect isn't `Send`, so rendering keeps to its own thread.
+            let handle = tokio::runtime::Handle::current();
+            return tokio::task::spawn_blocking(move || {
+                let mut render = MarkdownRender::init(render_options)?;
+                handle.block_on(markdown_stream(rx, &mut render, &abort))
+            })
+            .await?;
+        }
     }
+    raw_stream(rx, &abort).await
 }
 
 pub fn render_error(err: anyhow::Error, highlight: bool) {
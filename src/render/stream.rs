@@ -16,6 +16,9 @@ use std::{
 use textwrap::core::display_width;
 use tokio::sync::mpsc::UnboundedReceiver;
 
+/// How long to wait for an event before checking for an abort again.
+const RECV_TIMEOUT: Duration = Duration::from_millis(50);
+
 pub async fn markdown_stream(
     rx: UnboundedReceiver<SseEvent>,
     render: &mut MarkdownRender,
@@ -31,21 +34,27 @@ pub async fn markdown_stream(
     ret
 }
 
-pub async fn raw_stream(mut rx: UnboundedReceiver<SseEvent>, abort: &AbortSignal) -> Result<()> {
+pub async fn raw_stream(rx: UnboundedReceiver<SseEvent>, abort: &AbortSignal) -> Result<()> {
+    raw_stream_inner(rx, abort, &mut stdout()).await
+}
+
+async fn raw_stream_inner<W: Write>(
+    mut rx: UnboundedReceiver<SseEvent>,
+    abort: &AbortSignal,
+    writer: &mut W,
+) -> Result<()> {
     loop {
         if abort.aborted() {
             return Ok(());
         }
-        if let Some(evt) = rx.recv().await {
-            match evt {
-                SseEvent::Text(text) => {
-                    print!("{}", text);
-                    stdout().flush()?;
-                }
-                SseEvent::Done => {
-                    break;
-                }
+        match tokio::time::timeout(RECV_TIMEOUT, rx.recv()).await {
+            Ok(Some(SseEvent::Text(text))) => {
+                write!(writer, "{text}")?;
+                writer.flush()?;
             }
+            // A closed channel will not deliver `Done` anymore.
+            Ok(Some(SseEvent::Done)) | Ok(None) => break,
+            Err(_) => {}
         }
     }
     Ok(())
@@ -99,17 +108,11 @@ async fn markdown_stream_inner(
                     // No guarantee that text returned by render will not be re-layouted, so it is better to clear it.
                     queue!(writer, terminal::Clear(terminal::ClearType::FromCursorDown))?;
 
-                    if text.contains('\n') {
-                        let text = format!("{buffer}{text}");
-                        let (head, tail) = split_line_tail(&text);
-                        let output = render.render(head);
-                        print_block(writer, &output, columns)?;
-                        buffer = tail.to_string();
-                    } else {
-                        buffer = format!("{buffer}{text}");
+                    let (block, output) = render_text(render, &mut buffer, &text);
+                    if let Some(block) = block {
+                        print_block(writer, &block, columns)?;
                     }
 
-                    let output = render.render_line(&buffer);
                     if output.contains('\n') {
                         let (head, tail) = split_line_tail(&output);
                         buffer_rows = print_block(writer, head, columns)?;
@@ -159,17 +162,17 @@ async fn gather_events(rx: &mut UnboundedReceiver<SseEvent>) -> Vec<SseEvent> {
     let mut done = false;
     tokio::select! {
         _ = async {
-            while let Some(reply_event) = rx.recv().await {
-                match reply_event {
-                    SseEvent::Text(v) => texts.push(v),
-                    SseEvent::Done => {
+            loop {
+                match rx.recv().await {
+                    Some(SseEvent::Text(v)) => texts.push(v),
+                    Some(SseEvent::Done) | None => {
                         done = true;
                         break;
                     }
                 }
             }
         } => {}
-        _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+        _ = tokio::time::sleep(RECV_TIMEOUT) => {}
     };
     let mut events = vec![];
     if !texts.is_empty() {
@@ -181,6 +184,26 @@ async fn gather_events(rx: &mut UnboundedReceiver<SseEvent>) -> Vec<SseEvent> {
     events
 }
 
+/// Append `text` to the partial line in `buffer`, returning the rendering of the lines it
+/// completed and of the partial line left over. The partial line is shown as it grows, without
+/// advancing the render state, and rendered for good once its newline arrives.
+fn render_text(
+    render: &mut MarkdownRender,
+    buffer: &mut String,
+    text: &str,
+) -> (Option<String>, String) {
+    let mut block = None;
+    if text.contains('\n') {
+        let text = format!("{buffer}{text}");
+        let (head, tail) = split_line_tail(&text);
+        block = Some(render.render(head));
+        *buffer = tail.to_string();
+    } else {
+        buffer.push_str(text);
+    }
+    (block, render.render_line(buffer))
+}
+
 fn print_block(writer: &mut Stdout, text: &str, columns: u16) -> Result<u16> {
     let mut num = 0;
     for line in text.split('\n') {
@@ -207,3 +230,78 @@ fn need_rows(text: &str, columns: u16) -> u16 {
     let buffer_width = display_width(text).max(1) as u16;
     (buffer_width + columns - 1) / columns
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::RenderOptions;
+    use crate::utils::create_abort_signal;
+
+    #[test]
+    fn test_render_text_partial_lines() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        let mut buffer = String::new();
+        let burst = "lorem ipsum ".repeat(200);
+        let (block, output) = render_text(&mut render, &mut buffer, &burst);
+        assert_eq!(block, None);
+        assert_eq!(output, burst);
+
+        let (block, output) = render_text(&mut render, &mut buffer, "dolor\n```rust\nfn main");
+        assert_eq!(block.unwrap(), format!("{burst}dolor\n```rust"));
+        assert_eq!(output, "fn main");
+        assert_eq!(buffer, "fn main");
+
+        let (block, output) = render_text(&mut render, &mut buffer, "() {}\n");
+        assert_eq!(block.unwrap(), "fn main() {}");
+        assert_eq!(output, "");
+    }
+
+    #[tokio::test]
+    async fn test_stream_burst_of_events() {
+        let texts: Vec<String> = (0..500).map(|i| format!("{i} ")).collect();
+        let send_all = |done: bool| {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            for text in &texts {
+                tx.send(SseEvent::Text(text.clone())).unwrap();
+            }
+            if done {
+                tx.send(SseEvent::Done).unwrap();
+            }
+            (tx, rx)
+        };
+
+        let (_tx, mut rx) = send_all(true);
+        match gather_events(&mut rx).await.as_slice() {
+            [SseEvent::Text(text), SseEvent::Done] => assert_eq!(*text, texts.join("")),
+            events => panic!("unexpected events {events:?}"),
+        }
+
+        let abort = create_abort_signal();
+        let (_tx, rx) = send_all(true);
+        let mut output = vec![];
+        raw_stream_inner(rx, &abort, &mut output).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), texts.join(""));
+
+        // Ends without `Done` once the sender is gone.
+        let (tx, rx) = send_all(false);
+        drop(tx);
+        let mut output = vec![];
+        raw_stream_inner(rx, &abort, &mut output).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), texts.join(""));
+
+        // Notices an abort while waiting for events.
+        let (_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let signal = abort.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            signal.set_ctrlc();
+        });
+        let mut output = vec![];
+        let ret = raw_stream_inner(rx, &abort, &mut output);
+        tokio::time::timeout(Duration::from_secs(5), ret)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(output.is_empty());
+    }
+}
@@ -10,7 +10,7 @@ use crate::client::chat_completion_streaming;
 use crate::config::{AssertState, Config, GlobalConfig, Input, StateFlags};
 use crate::function::need_send_tool_results;
 use crate::render::render_error;
-use crate::utils::{create_abort_signal, set_text, temp_file, AbortSignal};
+use crate::utils::{create_abort_signal, dimmed_text, set_text, temp_file, AbortSignal};
 
 use anyhow::{bail, Context, Result};
 use async_recursion::async_recursion;
@@ -33,7 +33,7 @@ lazy_static! {
 const MENU_NAME: &str = "completion_menu";
 
 lazy_static! {
-    static ref REPL_COMMANDS: [ReplCommand; 28] = [
+    static ref REPL_COMMANDS: [ReplCommand; 36] = [
         ReplCommand::new(".help", "Show this help message", AssertState::pass()),
         ReplCommand::new(".info", "View system info", AssertState::pass()),
         ReplCommand::new(".model", "Change the current LLM", AssertState::pass()),
@@ -89,7 +89,7 @@ lazy_static! {
         ),
         ReplCommand::new(
             ".rag",
-            "Init or use the RAG",
+            "Init or use the RAG, or several as name1,name2",
             AssertState::False(StateFlags::AGENT)
         ),
         ReplCommand::new(
@@ -102,6 +102,34 @@ lazy_static! {
             "Rebuild the RAG to sync document changes",
             AssertState::True(StateFlags::RAG),
         ),
+        ReplCommand::new(
+            ".reembed rag",
+            "Re-embed the RAG with another embedding model",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".compact rag",
+            "Renumber the RAG documents to reclaim space after removals",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".add rag",
+            "Add a text snippet to the RAG",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".remove rag",
+            "Remove documents from the RAG",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(".info rags", "List the saved RAGs", AssertState::pass(),),
+        ReplCommand::new(".rename rag", "Rename a saved RAG", AssertState::pass(),),
+        ReplCommand::new(".delete rag", "Delete a saved RAG", AssertState::pass(),),
+        ReplCommand::new(
+            ".clear embedding-cache",
+            "Remove all cached RAG embeddings",
+            AssertState::pass(),
+        ),
         ReplCommand::new(
             ".exit rag",
             "Leave the RAG",
@@ -231,6 +259,10 @@ impl Repl {
                         let info = self.config.read().rag_info()?;
                         println!("{}", info);
                     }
+                    Some("rags") => {
+                        let info = self.config.read().saved_rags_info()?;
+                        println!("{}", info);
+                    }
                     Some("agent") => {
                         let info = self.config.read().agent_info()?;
                         println!("{}", info);
@@ -337,6 +369,94 @@ impl Repl {
                         }
                     }
                 }
+                ".reembed" => {
+                    match args.map(|v| match v.split_once(' ') {
+                        Some((subcmd, args)) => (subcmd, Some(args.trim())),
+                        None => (v, None),
+                    }) {
+                        Some(("rag", Some(model_id))) => {
+                            Config::reembed_rag(&self.config, model_id, self.abort_signal.clone())
+                                .await?;
+                        }
+                        _ => {
+                            println!(r#"Usage: .reembed rag <embedding-model>"#)
+                        }
+                    }
+                }
+                ".rename" => {
+                    match args.map(|v| match v.split_once(' ') {
+                        Some((subcmd, args)) => (subcmd, Some(args.trim())),
+                        None => (v, None),
+                    }) {
+                        Some(("rag", Some(args))) => match args.split_once(char::is_whitespace) {
+                            Some((name, new_name)) => {
+                                Config::rename_rag(&self.config, name, new_name.trim())?;
+                            }
+                            None => println!(r#"Usage: .rename rag <name> <new-name>"#),
+                        },
+                        _ => {
+                            println!(r#"Usage: .rename rag <name> <new-name>"#)
+                        }
+                    }
+                }
+                ".delete" => {
+                    match args.map(|v| match v.split_once(' ') {
+                        Some((subcmd, args)) => (subcmd, Some(args.trim())),
+                        None => (v, None),
+                    }) {
+                        Some(("rag", Some(name))) => {
+                            Config::delete_rag(&self.config, name)?;
+                        }
+                        _ => {
+                            println!(r#"Usage: .delete rag <name>"#)
+                        }
+                    }
+                }
+                ".compact" => match args {
+                    Some("rag") => {
+                        Config::compact_rag(&self.config)?;
+                    }
+                    _ => {
+                        println!(r#"Usage: .compact rag"#)
+                    }
+                },
+                ".add" => {
+                    match args.map(|v| match v.split_once(' ') {
+                        Some((subcmd, args)) => (subcmd, Some(args.trim())),
+                        None => (v, None),
+                    }) {
+                        Some(("rag", Some(args))) => match args.split_once(char::is_whitespace) {
+                            Some((label, text)) => {
+                                Config::add_rag_text(
+                                    &self.config,
+                                    label,
+                                    text.trim(),
+                                    self.abort_signal.clone(),
+                                )
+                                .await?;
+                            }
+                            None => println!(r#"Usage: .add rag <label> <text>..."#),
+                        },
+                        _ => {
+                            println!(r#"Usage: .add rag <label> <text>..."#)
+                        }
+                    }
+                }
+                ".remove" => {
+                    match args.map(|v| match v.split_once(' ') {
+                        Some((subcmd, args)) => (subcmd, Some(args.trim())),
+                        None => (v, None),
+                    }) {
+                        Some(("rag", Some(paths))) => {
+                            let paths =
+                                shell_words::split(paths).with_context(|| "Invalid args")?;
+                            Config::remove_rag_documents(&self.config, &paths).await?;
+                        }
+                        _ => {
+                            println!(r#"Usage: .remove rag <path>..."#)
+                        }
+                    }
+                }
                 ".file" => match args {
                     Some(args) => {
                         let (files, text) = split_files_text(args);
@@ -397,6 +517,9 @@ impl Repl {
                     Some("messages") => {
                         self.config.write().clear_session_messages()?;
                     }
+                    Some("embedding-cache") => {
+                        Config::clear_embedding_cache()?;
+                    }
                     _ => unknown_command()?,
                 },
                 _ => unknown_command()?,
@@ -562,6 +685,16 @@ async fn ask(
     }
     if with_embeddings {
         input.use_embeddings(abort_signal.clone()).await?;
+        if let Some((used, retrieved)) = input.rag_chunks() {
+            if retrieved == 0 {
+                println!("{}", dimmed_text("(no relevant chunks retrieved)"));
+            } else if used < retrieved {
+                println!(
+                    "{}",
+                    dimmed_text(&format!("(used {used}/{retrieved} retrieved chunks)"))
+                );
+            }
+        }
     }
     while config.read().is_compressing_session() {
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
@@ -569,11 +702,14 @@ async fn ask(
 
     let client = input.create_client()?;
     config.write().before_chat_completion(&input)?;
-    let (output, tool_results) =
+    let (output, tool_results, _) =
         chat_completion_streaming(&input, client.as_ref(), config, abort_signal.clone()).await?;
     config
         .write()
         .after_chat_completion(&input, &output, &tool_results)?;
+    if let Some(usage) = config.read().usage_info() {
+        println!("{}", dimmed_text(&usage));
+    }
     if need_send_tool_results(&tool_results) {
         ask(
             config,
@@ -637,7 +773,10 @@ fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
 async fn compress_session(config: &GlobalConfig) -> Result<()> {
     let input = Input::from_str(config, config.read().summarize_prompt(), None);
     let client = input.create_client()?;
-    let summary = client.chat_completions(input).await?.text;
+    let summary = client
+        .chat_completions(input, create_abort_signal())
+        .await?
+        .text;
     config.write().compress_session(&summary);
     Ok(())
 }
@@ -225,8 +225,11 @@ impl Server {
             messages,
             temperature,
             top_p,
+            presence_penalty,
+            frequency_penalty,
             max_tokens,
             stream,
+            response_format,
         } = req_body;
 
         let config = Config {
@@ -262,8 +265,11 @@ impl Server {
             messages,
             temperature,
             top_p,
+            presence_penalty,
+            frequency_penalty,
             functions: None,
             stream,
+            response_format,
         };
 
         if stream {
@@ -419,9 +425,12 @@ struct ChatCompletionsReqBody {
     messages: Vec<Message>,
     temperature: Option<f64>,
     top_p: Option<f64>,
+    presence_penalty: Option<f64>,
+    frequency_penalty: Option<f64>,
     max_tokens: Option<isize>,
     #[serde(default)]
     stream: bool,
+    response_format: Option<Value>,
 }
 
 #[derive(Debug, Deserialize)]
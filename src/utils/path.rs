@@ -1,6 +1,13 @@
+use std::collections::HashSet;
 use std::path::{Component, Path, PathBuf};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+use log::debug;
+
+pub const RAGIGNORE_FILE_NAME: &str = ".ragignore";
+pub const GITIGNORE_FILE_NAME: &str = ".gitignore";
 
 pub fn safe_join_path<T1: AsRef<Path>, T2: AsRef<Path>>(
     base_path: T1,
@@ -29,7 +36,36 @@ pub fn safe_join_path<T1: AsRef<Path>, T2: AsRef<Path>>(
 }
 
 pub async fn expand_glob_paths<T: AsRef<str>>(paths: &[T]) -> Result<Vec<String>> {
-    let mut new_paths = vec![];
+    expand_glob_paths_excluding(paths, &[]).await
+}
+
+/// Like `expand_glob_paths`, but skip files and prune directories matched by `excludes`.
+/// Exclusions win over inclusions; see `build_excludes` for the pattern syntax.
+pub async fn expand_glob_paths_excluding<T: AsRef<str>>(
+    paths: &[T],
+    excludes: &[String],
+) -> Result<Vec<String>> {
+    Ok(list_paths(paths, excludes, false).await?.files)
+}
+
+/// The files found by `list_paths`.
+#[derive(Debug, Default)]
+pub struct ListedPaths {
+    pub files: Vec<String>,
+    /// Files and directories skipped by exclusion patterns or ignore files.
+    pub excluded: usize,
+}
+
+/// Expand `paths` like `expand_glob_paths_excluding`, walking directories with the rules of their
+/// `.ragignore` files, and of the `.gitignore` files of the enclosing git repository when
+/// `respect_gitignore` is set. Ignore files themselves are never listed.
+pub async fn list_paths<T: AsRef<str>>(
+    paths: &[T],
+    excludes: &[String],
+    respect_gitignore: bool,
+) -> Result<ListedPaths> {
+    let excludes = build_excludes(excludes)?;
+    let mut output = ListedPaths::default();
     for path in paths {
         let (path_str, suffixes) = parse_glob(path.as_ref())?;
         let suffixes = if suffixes.is_empty() {
@@ -37,9 +73,117 @@ pub async fn expand_glob_paths<T: AsRef<str>>(paths: &[T]) -> Result<Vec<String>
         } else {
             Some(&suffixes)
         };
-        list_files(&mut new_paths, Path::new(&path_str), suffixes).await?;
+        let path = Path::new(&path_str);
+        if !path.exists() {
+            bail!("Not found: {}", path.display());
+        }
+        if is_excluded(&excludes, path, path.is_dir()) {
+            output.excluded += 1;
+        } else if path.is_file() {
+            add_file(&mut output.files, suffixes, path);
+        } else if path.is_dir() {
+            walk_dir(&mut output, path, suffixes, &excludes, respect_gitignore)?;
+        } else {
+            bail!("Not a directory: {:?}", path);
+        }
+    }
+    Ok(output)
+}
+
+/// Build a matcher for exclusion patterns, which follow the gitignore syntax: a pattern without a
+/// path separator (e.g. `node_modules`) matches any file or directory of that name, one starting
+/// with `**/` matches at any depth, others are absolute paths or globs.
+fn build_excludes(excludes: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("/");
+    for pattern in excludes {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid exclusion pattern '{pattern}'"))?;
+    }
+    Ok(builder.build()?)
+}
+
+fn is_excluded(excludes: &Gitignore, path: &Path, is_dir: bool) -> bool {
+    path.has_root()
+        && excludes
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+}
+
+fn is_ignore_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|v| v == GITIGNORE_FILE_NAME || v == RAGIGNORE_FILE_NAME)
+        && !path.is_dir()
+}
+
+fn walk_dir(
+    output: &mut ListedPaths,
+    root: &Path,
+    suffixes: Option<&Vec<String>>,
+    excludes: &Gitignore,
+    respect_gitignore: bool,
+) -> Result<()> {
+    let filter_excludes = excludes.clone();
+    let walker = WalkBuilder::new(root)
+        .standard_filters(false)
+        .follow_links(true)
+        .git_ignore(respect_gitignore)
+        .git_exclude(respect_gitignore)
+        .parents(respect_gitignore)
+        .add_custom_ignore_filename(RAGIGNORE_FILE_NAME)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|v| v.is_dir());
+            let is_git_dir = respect_gitignore && is_dir && entry.file_name() == ".git";
+            !is_git_dir && !is_excluded(&filter_excludes, entry.path(), is_dir)
+        })
+        .build();
+    let mut walked = HashSet::new();
+    let mut dirs = vec![];
+    for entry in walker {
+        let entry = match entry {
+            Ok(v) => v,
+            Err(err) => {
+                debug!("Skip listing, {err}");
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.file_type().is_some_and(|v| v.is_dir()) {
+            dirs.push(path.to_path_buf());
+        } else if !is_ignore_file(path) {
+            add_file(&mut output.files, suffixes, path);
+        }
+        walked.insert(path.to_path_buf());
+    }
+    // The walker doesn't report what it leaves out, so count the entries it didn't visit.
+    for dir in dirs {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !walked.contains(&path) && !is_ignore_file(&path) {
+                output.excluded += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Strip any trailing glob pattern, leaving the directory or file it is rooted at.
+pub fn glob_base_path(path: &str) -> Result<String> {
+    let (base_path, _) = parse_glob(path)?;
+    Ok(base_path)
+}
+
+/// Check whether `path` is covered by `pattern`, which may be a file, a directory or a glob.
+pub fn path_matches_glob(pattern: &str, path: &str) -> bool {
+    let (base_path, suffixes) = match parse_glob(pattern) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    if path == base_path {
+        return true;
     }
-    Ok(new_paths)
+    let path = Path::new(path);
+    path.starts_with(&base_path) && is_valid_extension(Some(&suffixes), path)
 }
 
 pub fn get_patch_extension(path: &str) -> Option<String> {
@@ -75,34 +219,6 @@ fn parse_glob(path_str: &str) -> Result<(String, Vec<String>)> {
     }
 }
 
-#[async_recursion::async_recursion]
-async fn list_files(
-    files: &mut Vec<String>,
-    entry_path: &Path,
-    suffixes: Option<&Vec<String>>,
-) -> Result<()> {
-    if !entry_path.exists() {
-        bail!("Not found: {}", entry_path.display());
-    }
-    if entry_path.is_file() {
-        add_file(files, suffixes, entry_path);
-        return Ok(());
-    }
-    if !entry_path.is_dir() {
-        bail!("Not a directory: {:?}", entry_path);
-    }
-    let mut reader = tokio::fs::read_dir(entry_path).await?;
-    while let Some(entry) = reader.next_entry().await? {
-        let path = entry.path();
-        if path.is_file() {
-            add_file(files, suffixes, &path);
-        } else if path.is_dir() {
-            list_files(files, &path, suffixes).await?;
-        }
-    }
-    Ok(())
-}
-
 fn add_file(files: &mut Vec<String>, suffixes: Option<&Vec<String>>, path: &Path) {
     if is_valid_extension(suffixes, path) {
         let path = path.display().to_string();
@@ -149,4 +265,148 @@ mod tests {
             ("C:\\dir".into(), vec!["md".into(), "txt".into()])
         );
     }
+
+    #[tokio::test]
+    async fn test_expand_glob_paths_excluding() {
+        let dir = std::env::temp_dir().join(format!("aichat-glob-{}", std::process::id()));
+        for file in [
+            "src/main.rs",
+            "src/lib.md",
+            "target/debug/build.rs",
+            "docs/guide.md",
+            "docs/generated/api.md",
+            "node_modules/pkg/index.md",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "").unwrap();
+        }
+        let root = dir.display().to_string();
+        let excludes = vec![
+            "target".to_string(),
+            "node_modules".to_string(),
+            format!("{root}/docs/generated/**"),
+            format!("{root}/src/**/*.{{md,txt}}"),
+        ];
+        let mut files = expand_glob_paths_excluding(&[&root], &excludes)
+            .await
+            .unwrap();
+        files.sort();
+        let expected: Vec<String> = ["docs/guide.md", "src/main.rs"]
+            .iter()
+            .map(|v| dir.join(v).display().to_string())
+            .collect();
+        assert_eq!(files, expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ragignore_nested() {
+        let dir = std::env::temp_dir().join(format!("aichat-ragignore-{}", std::process::id()));
+        for file in [
+            "a.md",
+            "a.log",
+            "keep.log",
+            "draft-2.md",
+            "build/out.md",
+            "docs/guide.md",
+            "docs/draft-1.md",
+            "docs/debug.log",
+            "docs/important.log",
+            "docs/build/x.md",
+            "docs/api/y.md",
+            "docs/api/gen/z.md",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "").unwrap();
+        }
+        std::fs::write(
+            dir.join(RAGIGNORE_FILE_NAME),
+            "# outer rules\n*.log\n!keep.log\n/build/\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("docs").join(RAGIGNORE_FILE_NAME),
+            "draft-*\n!important.log\napi/gen/\n",
+        )
+        .unwrap();
+        let root = dir.display().to_string();
+        let mut files = expand_glob_paths(&[&root]).await.unwrap();
+        files.sort();
+        let expected: Vec<String> = [
+            "a.md",
+            "docs/api/y.md",
+            "docs/build/x.md",
+            "docs/guide.md",
+            "docs/important.log",
+            "draft-2.md",
+            "keep.log",
+        ]
+        .iter()
+        .map(|v| dir.join(v).display().to_string())
+        .collect();
+        assert_eq!(files, expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_paths_respect_gitignore() {
+        let dir = std::env::temp_dir().join(format!("aichat-gitignore-{}", std::process::id()));
+        for file in [
+            ".git/HEAD",
+            "Cargo.lock",
+            "README.md",
+            "target/debug/out.md",
+            "docs/guide.md",
+            "docs/notes.tmp",
+            "docs/build/api.md",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "").unwrap();
+        }
+        std::fs::write(dir.join(GITIGNORE_FILE_NAME), "/target/\n*.tmp\nbuild/\n").unwrap();
+        std::fs::write(dir.join("docs").join(GITIGNORE_FILE_NAME), "!notes.tmp\n").unwrap();
+        let excludes = vec!["Cargo.lock".to_string()];
+
+        let root = dir.display().to_string();
+        let listed = list_paths(&[&root], &excludes, true).await.unwrap();
+        let mut files = listed.files;
+        files.sort();
+        let expected: Vec<String> = ["README.md", "docs/guide.md", "docs/notes.tmp"]
+            .iter()
+            .map(|v| dir.join(v).display().to_string())
+            .collect();
+        assert_eq!(files, expected);
+        // .git, Cargo.lock, target/ and docs/build/
+        assert_eq!(listed.excluded, 4);
+
+        // Rules of the repository root apply when listing a subdirectory.
+        let docs = dir.join("docs").display().to_string();
+        let listed = list_paths(&[&docs], &[], true).await.unwrap();
+        assert_eq!(listed.files.len(), 2);
+        assert_eq!(listed.excluded, 1);
+
+        let listed = list_paths(&[&root], &excludes, false).await.unwrap();
+        assert_eq!(listed.files.len(), 6);
+        assert_eq!(listed.excluded, 1);
+
+        // A leading `**/` matches at any depth.
+        let excludes = vec!["**/build/**".to_string(), "**/target/**".to_string()];
+        let listed = list_paths(&[&root], &excludes, false).await.unwrap();
+        assert_eq!(listed.files.len(), 5);
+        assert_eq!(listed.excluded, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_matches_glob() {
+        assert!(path_matches_glob("/dir/file.md", "/dir/file.md"));
+        assert!(path_matches_glob("/dir", "/dir/sub/file.md"));
+        assert!(path_matches_glob("/dir/**", "/dir/sub/file.md"));
+        assert!(path_matches_glob("/dir/**/*.{md,txt}", "/dir/sub/file.txt"));
+        assert!(!path_matches_glob("/dir/**/*.md", "/dir/sub/file.txt"));
+        assert!(!path_matches_glob("/dir", "/dir2/file.md"));
+    }
 }
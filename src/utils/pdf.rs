@@ -0,0 +1,71 @@
+use anyhow::{bail, Context, Result};
+
+/// Form feed, which `pdftotext` and similar tools emit between pages.
+pub const PAGE_BREAK: char = '\x0c';
+
+/// Extract the text of each page of a PDF as `(page number, text)` pairs, numbered from 1.
+/// Pages without a text layer, such as scanned images, come back empty.
+pub fn read_pdf_pages(data: &[u8]) -> Result<Vec<(u32, String)>> {
+    let mut document = lopdf::Document::load_mem(data).context("Invalid PDF")?;
+    // Many PDFs are encrypted with an empty user password just to restrict editing.
+    if document.is_encrypted() && document.decrypt("").is_err() {
+        bail!("Encrypted PDF, unable to extract text without a password");
+    }
+    let mut output = vec![];
+    for page_number in document.get_pages().into_keys() {
+        let text = match document.extract_text(&[page_number]) {
+            Ok(v) => v.trim().to_string(),
+            Err(err) => {
+                debug!("failed to extract text of page {page_number}, {err}");
+                String::new()
+            }
+        };
+        output.push((page_number, text));
+    }
+    Ok(output)
+}
+
+/// Extract the text of a PDF with pages separated by `PAGE_BREAK`.
+pub fn read_pdf_text(data: &[u8]) -> Result<String> {
+    let pages: Vec<String> = read_pdf_pages(data)?
+        .into_iter()
+        .map(|(_, text)| text)
+        .collect();
+    Ok(pages.join(&PAGE_BREAK.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_PDF: &[u8] = include_bytes!("../../tests/fixtures/sample.pdf");
+
+    #[test]
+    fn test_read_pdf_pages() {
+        let pages = read_pdf_pages(SAMPLE_PDF).unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].0, 1);
+        assert!(pages[0].1.contains("Installation guide"));
+        assert!(pages[0].1.contains("Run cargo install aichat."));
+        assert_eq!(pages[1], (2, String::new()));
+        assert!(pages[2].1.contains("Configuration reference"));
+    }
+
+    #[test]
+    fn test_read_pdf_encrypted() {
+        let mut document = lopdf::Document::load_mem(SAMPLE_PDF).unwrap();
+        let mut encrypt = lopdf::Dictionary::new();
+        encrypt.set("Filter", lopdf::Object::Name(b"Standard".to_vec()));
+        encrypt.set("V", 1);
+        encrypt.set("R", 2);
+        encrypt.set("O", lopdf::Object::string_literal(vec![0u8; 32]));
+        encrypt.set("U", lopdf::Object::string_literal(vec![0u8; 32]));
+        encrypt.set("P", -4);
+        let encrypt = document.add_object(encrypt);
+        document.trailer.set("Encrypt", encrypt);
+        let mut data = vec![];
+        document.save_to(&mut data).unwrap();
+        let err = read_pdf_pages(&data).unwrap_err();
+        assert!(err.to_string().contains("Encrypted PDF"));
+    }
+}
@@ -1,6 +1,8 @@
 use super::*;
 
 use anyhow::{bail, Result};
+use fancy_regex::{Captures, Regex};
+use html2text::render::text_renderer::{TaggedLine, TextDecorator};
 use http::header::CONTENT_TYPE;
 use lazy_static::lazy_static;
 use std::{collections::HashMap, time::Duration};
@@ -12,6 +14,14 @@ pub const MEDIA_URL_EXTENSION: &str = "media_url";
 pub const DEFAULT_EXTENSION: &str = "txt";
 
 lazy_static! {
+    static ref HTML_BOILERPLATE_RE: Regex =
+        Regex::new(r"(?is)<(script|style|noscript|nav|header|footer|aside)\b[^>]*>.*?</\1\s*>")
+            .unwrap();
+    static ref HTML_PRE_RE: Regex = Regex::new(r"(?is)<pre\b[^>]*>(.*?)</pre\s*>").unwrap();
+    static ref HTML_CODE_TAG_RE: Regex = Regex::new(r"(?i)</?code\b[^>]*>").unwrap();
+    static ref HTML_LANGUAGE_RE: Regex =
+        Regex::new(r#"(?i)class\s*=\s*["'][^"']*\blang(?:uage)?-([\w+#-]+)"#).unwrap();
+    static ref HTML_TITLE_RE: Regex = Regex::new(r"(?is)<title\b[^>]*>(.*?)</title\s*>").unwrap();
     static ref CLIENT: Result<reqwest::Client> = {
         let builder = reqwest::ClientBuilder::new().timeout(Duration::from_secs(30));
         let builder = set_proxy(builder, None)?;
@@ -104,15 +114,20 @@ pub async fn fetch(
                 } else {
                     run_loader_command(&save_path, &extension, loader_command)?
                 };
-                (contents, DEFAULT_EXTENSION.into())
+                // The text of a PDF keeps its extension, so its pages can be told apart.
+                match extension.as_str() {
+                    "pdf" => (contents, extension),
+                    _ => (contents, DEFAULT_EXTENSION.into()),
+                }
+            }
+            None if extension == "pdf" => {
+                let data = res.bytes().await?;
+                (read_pdf_text(&data)?, extension)
             }
             None => {
                 let contents = res.text().await?;
                 if extension == "html" {
-                    (
-                        html2text::from_read(contents.as_bytes(), usize::MAX),
-                        "md".into(),
-                    )
+                    (html_to_md(&contents), "md".into())
                 } else {
                     (contents, extension)
                 }
@@ -121,3 +136,184 @@ pub async fn fetch(
     };
     Ok(result)
 }
+
+/// Fetch a web page, returning its final url after redirects and its html, or `None` if it is not html.
+pub async fn fetch_html(url: &str) -> Result<Option<(String, String)>> {
+    let client = match *CLIENT {
+        Ok(ref client) => client,
+        Err(ref err) => bail!("{err}"),
+    };
+    let res = client.get(url).send().await?;
+    if !res.status().is_success() {
+        bail!("Invalid status: {}", res.status());
+    }
+    let is_html = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start().starts_with("text/html"))
+        .unwrap_or(true);
+    if !is_html {
+        return Ok(None);
+    }
+    let final_url = res.url().to_string();
+    let html = res.text().await?;
+    Ok(Some((final_url, html)))
+}
+
+/// Convert html to markdown, keeping headings, lists, tables and code blocks. Links keep their
+/// text only.
+pub fn html_to_md(html: &str) -> String {
+    let html = fence_code_blocks(&strip_html_boilerplate(html));
+    html2text::config::with_decorator(MarkdownDecorator)
+        .string_from_read(html.as_bytes(), usize::MAX)
+        .unwrap_or_else(|_| html2text::from_read(html.as_bytes(), usize::MAX))
+}
+
+/// The text of the `<title>` of a page.
+pub fn html_title(html: &str) -> Option<String> {
+    let captures = HTML_TITLE_RE.captures(html).ok()??;
+    let title = html_to_md(captures.get(1)?.as_str()).trim().to_string();
+    (!title.is_empty()).then_some(title)
+}
+
+/// Surround `<pre>` blocks with markdown fences, tagged with the language of their class.
+fn fence_code_blocks(html: &str) -> String {
+    HTML_PRE_RE
+        .replace_all(html, |captures: &Captures| {
+            let lang = HTML_LANGUAGE_RE
+                .captures(&captures[0])
+                .ok()
+                .flatten()
+                .and_then(|v| v.get(1))
+                .map(|v| v.as_str().to_ascii_lowercase())
+                .unwrap_or_default();
+            let code = HTML_CODE_TAG_RE.replace_all(&captures[1], "");
+            // Inside the block, as paragraphs around it would leave blank lines in the fences.
+            let code = code.strip_prefix('\n').unwrap_or(&code).trim_end();
+            format!("<pre>```{lang}\n{code}\n```</pre>")
+        })
+        .to_string()
+}
+
+/// Renders emphasis, inline code, headings and lists as markdown, and links as their text.
+#[derive(Debug, Clone)]
+struct MarkdownDecorator;
+
+impl TextDecorator for MarkdownDecorator {
+    type Annotation = ();
+
+    fn decorate_link_start(&mut self, _url: &str) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_link_end(&mut self) -> String {
+        String::new()
+    }
+
+    fn decorate_em_start(&self) -> (String, Self::Annotation) {
+        ("*".into(), ())
+    }
+
+    fn decorate_em_end(&self) -> String {
+        "*".into()
+    }
+
+    fn decorate_strong_start(&self) -> (String, Self::Annotation) {
+        ("**".into(), ())
+    }
+
+    fn decorate_strong_end(&self) -> String {
+        "**".into()
+    }
+
+    fn decorate_strikeout_start(&self) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_strikeout_end(&self) -> String {
+        String::new()
+    }
+
+    fn decorate_code_start(&self) -> (String, Self::Annotation) {
+        ("`".into(), ())
+    }
+
+    fn decorate_code_end(&self) -> String {
+        "`".into()
+    }
+
+    fn decorate_preformat_first(&self) -> Self::Annotation {}
+
+    fn decorate_preformat_cont(&self) -> Self::Annotation {}
+
+    fn decorate_image(&mut self, _src: &str, title: &str) -> (String, Self::Annotation) {
+        (title.into(), ())
+    }
+
+    fn header_prefix(&self, level: usize) -> String {
+        "#".repeat(level) + " "
+    }
+
+    fn quote_prefix(&self) -> String {
+        "> ".into()
+    }
+
+    fn unordered_item_prefix(&self) -> String {
+        "* ".into()
+    }
+
+    fn ordered_item_prefix(&self, i: i64) -> String {
+        format!("{i}. ")
+    }
+
+    fn make_subblock_decorator(&self) -> Self {
+        self.clone()
+    }
+
+    fn finalise(&mut self, _links: Vec<String>) -> Vec<TaggedLine<Self::Annotation>> {
+        vec![]
+    }
+}
+
+/// Drop scripts, styles and page chrome such as navigation bars before converting html to text.
+fn strip_html_boilerplate(html: &str) -> String {
+    HTML_BOILERPLATE_RE.replace_all(html, "").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_html_boilerplate() {
+        let html = r#"<html><head><style>p { color: red; }</style></head><body>
+<nav class="top"><a href="/">Home</a></nav><script>alert(1)</script>
+<p>Hello <b>world</b></p><footer>Copyright</footer></body></html>"#;
+        let output = strip_html_boilerplate(html);
+        assert!(output.contains("<p>Hello <b>world</b></p>"));
+        assert!(!output.contains("Home"));
+        assert!(!output.contains("alert"));
+        assert!(!output.contains("color"));
+        assert!(!output.contains("Copyright"));
+    }
+
+    #[test]
+    fn test_html_to_md() {
+        let html = include_str!("../../tests/fixtures/sample.html");
+        let output = html_to_md(html);
+        assert!(!Regex::new(r"</?[a-z]+[^>]*>")
+            .unwrap()
+            .is_match(&output)
+            .unwrap());
+        assert!(output.contains("# Getting started"));
+        assert!(output.contains("## Features"));
+        assert!(output.contains("* Fast **rendering**"));
+        assert!(output.contains("2. Build the project"));
+        assert!(output.contains("read the API reference."));
+        assert!(output.contains("```rust\nfn main() {\n    let total = 1 < 2;"));
+        assert!(output.contains("max_widgets"));
+        assert!(!output.contains("Home") && !output.contains("analytics"));
+        assert_eq!(html_title(html).unwrap(), "Widgets & Gadgets Guide");
+    }
+}
@@ -0,0 +1,70 @@
+use aichat::config::{Config, GlobalConfig};
+use aichat::rag::{Rag, RagBuildOptions};
+
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+fn mock_config() -> GlobalConfig {
+    let config = Config {
+        clients: vec![serde_yaml::from_str("type: mock").unwrap()],
+        ..Default::default()
+    };
+    Arc::new(RwLock::new(config))
+}
+
+#[tokio::test]
+async fn test_build_rag_headless() {
+    let dir = std::env::temp_dir().join(format!("aichat-rag-build-{}", std::process::id()));
+    let docs_dir = dir.join("docs");
+    std::fs::create_dir_all(&docs_dir).unwrap();
+    std::fs::write(
+        docs_dir.join("a.md"),
+        "# Deploys\nDeploys happen on Tuesdays",
+    )
+    .unwrap();
+    std::fs::write(docs_dir.join("b.md"), "# Lunch\nLunch is at noon").unwrap();
+    let save_path = dir.join("docs.bin");
+    let partial_path = save_path.with_extension("partial");
+    // A checkpoint that isn't resumed is discarded rather than read.
+    std::fs::write(&partial_path, "corrupted").unwrap();
+
+    let config = mock_config();
+    let options = RagBuildOptions {
+        name: "docs".into(),
+        save_path: save_path.clone(),
+        document_paths: vec![docs_dir.display().to_string()],
+        embedding_model: Some("mock:embedding".into()),
+        chunk_size: Some(500),
+        ..Default::default()
+    };
+    let rag = Rag::build(&config, options).await.unwrap();
+    assert_eq!(rag.files().len(), 2);
+    assert_eq!(rag.chunk_count(), 2);
+    let info: serde_yaml::Value = serde_yaml::from_str(&rag.export().unwrap()).unwrap();
+    assert_eq!(info["embedding_model"], "mock:embedding");
+    assert_eq!(info["chunk_size"], 500);
+    assert_eq!(info["chunk_overlap"], 25);
+    assert_eq!(info["num_vectors"], 2);
+    assert_eq!(
+        info["document_paths"][0].as_str(),
+        Some(docs_dir.display().to_string().as_str())
+    );
+    assert!(save_path.exists());
+    assert!(!partial_path.exists());
+
+    let loaded = Rag::load(&config, "docs", &save_path).unwrap();
+    assert_eq!(loaded.files(), rag.files());
+
+    let options = RagBuildOptions {
+        name: "empty".into(),
+        save_path: dir.join("empty.bin"),
+        embedding_model: Some("mock:embedding".into()),
+        ..Default::default()
+    };
+    let err = Rag::build(&config, options).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No document paths to build rag 'empty' from"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}